@@ -1,3 +1,39 @@
-pub const DASHSCOPE_API_KEY: &str = "sk-619560edb9664aa5884397e62ac8b712";
-// backup api key if needed
-// pub const DASHSCOPE_API_KEY: &str = "sk-7ae122c42ceb4240aaa7ad34df284362";
\ No newline at end of file
+use crate::config::Config;
+use std::env;
+use std::error::Error;
+
+pub const API_KEY_ENV: &str = "DASHSCOPE_API_KEY";
+
+/// Find the DashScope API key: an explicit value first, then the
+/// `DASHSCOPE_API_KEY` environment variable, then the config file
+/// (the variable named by `api_key_env`, or `dashscope_api_key`).
+pub fn resolve(explicit: Option<&str>) -> Result<String, Box<dyn Error>> {
+    if let Some(key) = explicit.map(str::trim).filter(|k| !k.is_empty()) {
+        return Ok(key.to_string());
+    }
+
+    if let Some(key) = env_key(API_KEY_ENV) {
+        return Ok(key);
+    }
+
+    if let Some(config) = Config::load()? {
+        if let Some(key) = config.api_key_env.as_deref().and_then(env_key) {
+            return Ok(key);
+        }
+        if let Some(key) = config.dashscope_api_key.filter(|k| !k.trim().is_empty()) {
+            return Ok(key.trim().to_string());
+        }
+        if let Some(var) = config.api_key_env {
+            return Err(format!("no API key found; set {var}").into());
+        }
+    }
+
+    Err(format!("no API key found; set {API_KEY_ENV}").into())
+}
+
+fn env_key(var: &str) -> Option<String> {
+    env::var(var)
+        .ok()
+        .map(|k| k.trim().to_string())
+        .filter(|k| !k.is_empty())
+}
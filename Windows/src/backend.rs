@@ -0,0 +1,225 @@
+use serde_json::Value;
+use std::thread;
+use std::time::Duration;
+
+use crate::api_key;
+use crate::error::AppError;
+use crate::llm::{
+    call_chat_api, call_chat_api_stream, ApiClient, ApiExchange, ChatEndpoint, GenParams, DASHSCOPE_BASE_URL,
+};
+use crate::ollama::call_ollama_chat_stream;
+
+/// Everything one chat call needs apart from the backend's own settings.
+pub struct ChatRequest<'a> {
+    pub api: &'a ApiClient,
+    pub model: &'a str,
+    pub messages: &'a [Value],
+    pub params: &'a GenParams,
+}
+
+/// A model server that answers chat messages. `SessionManager` only talks
+/// to this trait, so a new provider is one more impl.
+pub trait LlmBackend {
+    /// Name as written in the `provider` config key.
+    fn name(&self) -> &'static str;
+
+    /// Stream a completion, handing each piece of text to `on_chunk`.
+    /// The raw exchange, if there was one, is recorded into `last`.
+    fn stream(
+        &self,
+        req: &ChatRequest,
+        last: &mut Option<ApiExchange>,
+        on_chunk: &mut dyn FnMut(&str),
+    ) -> Result<String, AppError>;
+
+    /// The whole completion at once (summaries).
+    fn complete(&self, req: &ChatRequest, last: &mut Option<ApiExchange>) -> Result<String, AppError> {
+        self.stream(req, last, &mut |_| {})
+    }
+}
+
+/// Names accepted by `backend_for`.
+pub const PROVIDERS: &[&str] = &["dashscope", "openai", "ollama", "mock"];
+
+/// Server addresses and keys from the config file, for `backend_for`.
+#[derive(Debug, Clone, Default)]
+pub struct ProviderSettings {
+    pub ollama_url: String,
+    pub openai_base_url: Option<String>,
+    pub openai_api_key: Option<String>,
+}
+
+/// The backend for a `provider` name.
+pub fn backend_for(provider: &str, settings: &ProviderSettings) -> Result<Box<dyn LlmBackend>, String> {
+    match provider {
+        "dashscope" => Ok(Box::new(DashScopeBackend)),
+        "openai" => {
+            let base_url = settings
+                .openai_base_url
+                .clone()
+                .ok_or("provider 'openai' needs `openai_base_url` in the config file")?;
+            Ok(Box::new(OpenAiCompatBackend { base_url, api_key: settings.openai_api_key.clone() }))
+        }
+        "ollama" => Ok(Box::new(OllamaBackend { url: settings.ollama_url.clone() })),
+        "mock" => Ok(Box::new(MockBackend::default())),
+        other => Err(format!("Unknown provider '{other}'. Available: {}", PROVIDERS.join(", "))),
+    }
+}
+
+/// DashScope's OpenAI-compatible chat endpoint, with the key from
+/// `api_key::resolve`.
+pub struct DashScopeBackend;
+
+impl DashScopeBackend {
+    fn endpoint(api: &ApiClient) -> Result<ChatEndpoint, AppError> {
+        Ok(ChatEndpoint {
+            provider: "DashScope",
+            base_url: DASHSCOPE_BASE_URL.to_string(),
+            api_key: Some(api_key::resolve(api.api_key.as_deref())?),
+        })
+    }
+}
+
+impl LlmBackend for DashScopeBackend {
+    fn name(&self) -> &'static str {
+        "dashscope"
+    }
+
+    fn stream(
+        &self,
+        req: &ChatRequest,
+        last: &mut Option<ApiExchange>,
+        on_chunk: &mut dyn FnMut(&str),
+    ) -> Result<String, AppError> {
+        call_chat_api_stream(&Self::endpoint(req.api)?, req, last, on_chunk)
+    }
+
+    fn complete(&self, req: &ChatRequest, last: &mut Option<ApiExchange>) -> Result<String, AppError> {
+        call_chat_api(&Self::endpoint(req.api)?, req, last)
+    }
+}
+
+/// Any server speaking OpenAI's `/v1/chat/completions` (LM Studio, vLLM,
+/// llama.cpp server, ...). The model is the session's, as for DashScope.
+pub struct OpenAiCompatBackend {
+    /// e.g. `http://localhost:1234/v1`.
+    pub base_url: String,
+    pub api_key: Option<String>,
+}
+
+impl OpenAiCompatBackend {
+    fn endpoint(&self) -> ChatEndpoint {
+        ChatEndpoint {
+            provider: "OpenAI-compatible server",
+            base_url: self.base_url.clone(),
+            api_key: self.api_key.clone(),
+        }
+    }
+}
+
+impl LlmBackend for OpenAiCompatBackend {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    fn stream(
+        &self,
+        req: &ChatRequest,
+        last: &mut Option<ApiExchange>,
+        on_chunk: &mut dyn FnMut(&str),
+    ) -> Result<String, AppError> {
+        call_chat_api_stream(&self.endpoint(), req, last, on_chunk)
+    }
+
+    fn complete(&self, req: &ChatRequest, last: &mut Option<ApiExchange>) -> Result<String, AppError> {
+        call_chat_api(&self.endpoint(), req, last)
+    }
+}
+
+/// Local Ollama server (`/api/chat`).
+pub struct OllamaBackend {
+    pub url: String,
+}
+
+impl LlmBackend for OllamaBackend {
+    fn name(&self) -> &'static str {
+        "ollama"
+    }
+
+    fn stream(
+        &self,
+        req: &ChatRequest,
+        last: &mut Option<ApiExchange>,
+        on_chunk: &mut dyn FnMut(&str),
+    ) -> Result<String, AppError> {
+        call_ollama_chat_stream(&self.url, req, last, on_chunk)
+    }
+}
+
+/// Canned replies without any network (`provider = "mock"`), for trying
+/// the streaming pipeline offline.
+pub struct MockBackend {
+    pub reply: String,
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        Self { reply: "This is a canned reply from the mock backend.".to_string() }
+    }
+}
+
+impl LlmBackend for MockBackend {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn stream(
+        &self,
+        _req: &ChatRequest,
+        _last: &mut Option<ApiExchange>,
+        on_chunk: &mut dyn FnMut(&str),
+    ) -> Result<String, AppError> {
+        // Word by word, so the reply streams in like a real one.
+        for piece in self.reply.split_inclusive(' ') {
+            on_chunk(piece);
+            thread::sleep(Duration::from_millis(30));
+        }
+        Ok(self.reply.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::tests::{fake_server, http_response, quick_api};
+
+    fn name_of(provider: &str, settings: &ProviderSettings) -> Result<&'static str, String> {
+        backend_for(provider, settings).map(|b| b.name())
+    }
+
+    #[test]
+    fn backend_for_knows_every_listed_provider() {
+        let settings = ProviderSettings { openai_base_url: Some("http://localhost:1234/v1".into()), ..Default::default() };
+        for provider in PROVIDERS {
+            assert_eq!(name_of(provider, &settings).as_deref(), Ok(*provider));
+        }
+    }
+
+    #[test]
+    fn backend_for_rejects_unknown_or_unconfigured_providers() {
+        let err = name_of("claude", &ProviderSettings::default()).unwrap_err();
+        assert!(err.contains("Available: dashscope, openai, ollama, mock"), "{err}");
+        let err = name_of("openai", &ProviderSettings::default()).unwrap_err();
+        assert!(err.contains("openai_base_url"), "{err}");
+    }
+
+    #[test]
+    fn openai_compat_backend_uses_its_base_url() {
+        let base_url = fake_server(vec![http_response("200 OK", r#"{"choices": [{"message": {"content": "hi"}}]}"#)]);
+        let backend = OpenAiCompatBackend { base_url, api_key: None };
+        let api = quick_api();
+        let req = ChatRequest { api: &api, model: "local", messages: &[], params: &GenParams::default() };
+
+        assert_eq!(backend.complete(&req, &mut None).unwrap(), "hi");
+    }
+}
@@ -0,0 +1,133 @@
+use crate::llm::EmptyResponse;
+use crate::mcp::Shell;
+use crate::mcp_client::McpServerConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Contents of `~/.config/rustai/config.toml`. Every field is optional.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    /// LLM provider: `dashscope` (default), `openai`, `ollama` or `mock`.
+    pub provider: Option<String>,
+    /// Model used when the session starts.
+    pub model: Option<String>,
+    /// Name of the environment variable holding the API key.
+    pub api_key_env: Option<String>,
+    /// API key stored in plain text (prefer `api_key_env`).
+    pub dashscope_api_key: Option<String>,
+    /// Footer template appended to exported transcripts.
+    pub export_footer: Option<String>,
+    /// Wrapper for `/prompt-file` contents; `{{path}}` and `{{content}}` are expanded.
+    pub prompt_file_template: Option<String>,
+    /// `retry`, `marker` or `drop` for whitespace-only answers.
+    pub empty_response: Option<EmptyResponse>,
+    /// Base URL of an OpenAI-compatible server for the `openai` provider,
+    /// e.g. `http://localhost:1234/v1`.
+    pub openai_base_url: Option<String>,
+    /// Bearer token for `openai_base_url`, if the server wants one.
+    pub openai_api_key: Option<String>,
+    /// Ollama server for `/model warmup` and the `ollama` provider
+    /// (default `OLLAMA_HOST` / localhost:11434).
+    pub ollama_url: Option<String>,
+    /// Warm the model up on the Ollama server at startup.
+    pub warmup_on_start: Option<bool>,
+    /// Seconds a `shell.run` command may take before it is killed (default 30).
+    pub shell_timeout_secs: Option<u64>,
+    /// Shell for `shell.run`: `cmd` (Windows default), `powershell`, `pwsh`,
+    /// `sh` (default elsewhere), `bash` or `zsh`.
+    pub shell: Option<Shell>,
+    /// Directories the agent's file tools may read, write and list
+    /// (default: the working directory).
+    pub allowed_roots: Option<Vec<PathBuf>>,
+    /// Copy files to `logs/backups/` before the agent overwrites them (default true).
+    pub backup_on_write: Option<bool>,
+    /// Model calls one `/mcp` run may make before it is stopped (default 10).
+    pub max_agent_steps: Option<usize>,
+    /// Refuse file changes and shell commands in `/mcp` runs, like `--safe`.
+    pub safe_mode: Option<bool>,
+    /// External MCP servers started with the CLI (`[[mcp_servers]]`).
+    pub mcp_servers: Option<Vec<McpServerConfig>>,
+    /// Permanently delete trash entries older than this many days at
+    /// startup (default: keep them until `/trash empty`).
+    pub trash_days: Option<u64>,
+    /// Extra headers sent with every API request (`[headers]`), e.g. a
+    /// gateway key.
+    pub headers: Option<BTreeMap<String, String>>,
+}
+
+/// Where sessions are saved unless `RUSTAI_LOG_DIR` names another place.
+pub const DEFAULT_LOG_DIR: &str = "logs";
+
+/// The log directory asked for: `RUSTAI_LOG_DIR`, or `logs`.
+pub fn requested_log_dir() -> PathBuf {
+    env::var_os("RUSTAI_LOG_DIR")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_LOG_DIR))
+}
+
+/// `wanted`, created if missing. When it cannot be written, a directory
+/// under the system temp dir is used instead, and the second value is a
+/// warning to print once.
+pub fn usable_log_dir(wanted: &Path) -> (PathBuf, Option<String>) {
+    let Err(e) = check_writable(wanted) else {
+        return (wanted.to_path_buf(), None);
+    };
+    let fallback = env::temp_dir().join("rustai-logs");
+    let warning = match check_writable(&fallback) {
+        Ok(()) => format!(
+            "⚠️ Cannot write to {}: {e}\n   Saving sessions to {} instead (set RUSTAI_LOG_DIR to choose).",
+            wanted.display(),
+            fallback.display()
+        ),
+        Err(e2) => format!(
+            "⚠️ Cannot write to {} ({e}) or {} ({e2}); sessions will not be saved.",
+            wanted.display(),
+            fallback.display()
+        ),
+    };
+    (fallback, Some(warning))
+}
+
+/// Create `dir` and write and remove a probe file in it.
+fn check_writable(dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let probe = dir.join(".write_test");
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)
+}
+
+/// Location of the optional config file (`~/.config/rustai/config.toml`).
+pub fn config_path() -> Option<PathBuf> {
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))?;
+    Some(PathBuf::from(home).join(".config").join("rustai").join("config.toml"))
+}
+
+impl Config {
+    /// Read the config file; `None` if it does not exist.
+    pub fn load() -> Result<Option<Config>, Box<dyn Error>> {
+        let Some(path) = config_path() else {
+            return Ok(None);
+        };
+        let Ok(text) = fs::read_to_string(&path) else {
+            return Ok(None);
+        };
+        let config = toml::from_str(&text)
+            .map_err(|e| format!("invalid config file {}: {e}", path.display()))?;
+        Ok(Some(config))
+    }
+
+    /// Write the config file, creating its directory. Returns the path.
+    pub fn save(&self) -> Result<PathBuf, Box<dyn Error>> {
+        let path = config_path().ok_or("cannot locate home directory")?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, toml::to_string_pretty(self)?)?;
+        Ok(path)
+    }
+}
@@ -0,0 +1,70 @@
+use std::error::Error;
+use thiserror::Error;
+
+/// Failures callers may want to tell apart. It converts into
+/// `Box<dyn Error>`, so code that has not moved over yet can still use `?`.
+#[derive(Debug, Error)]
+pub enum AppError {
+    /// The request never got a usable HTTP response.
+    #[error("{context}: {source}")]
+    Http {
+        context: String,
+        #[source]
+        source: reqwest::Error,
+    },
+    /// The server answered with an error status or an error event.
+    #[error("{provider} API error ({status}): {message}")]
+    Api {
+        provider: &'static str,
+        status: u16,
+        message: String,
+    },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("invalid response: {0}")]
+    Parse(#[from] serde_json::Error),
+    /// A file tool was pointed outside its allowed directories.
+    #[error("{0}")]
+    Sandbox(String),
+    /// The user stopped the operation (Ctrl+C during `/mcp`).
+    #[error("cancelled")]
+    Cancelled,
+    /// A proxy or header setting that cannot be used.
+    #[error("{0}")]
+    Config(String),
+    /// Errors from code that still returns `Box<dyn Error>`.
+    #[error("{0}")]
+    Other(String),
+}
+
+impl From<reqwest::Error> for AppError {
+    fn from(source: reqwest::Error) -> Self {
+        AppError::Http { context: "request failed".into(), source }
+    }
+}
+
+impl From<Box<dyn Error>> for AppError {
+    fn from(e: Box<dyn Error>) -> Self {
+        AppError::Other(e.to_string())
+    }
+}
+
+impl AppError {
+    /// What the user can do about this error, if anything obvious.
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            AppError::Api { status: 401 | 403, .. } => {
+                Some("check your API key (DASHSCOPE_API_KEY or ~/.config/rustai/config.toml)")
+            }
+            AppError::Api { status: 429 | 500..=599, .. } | AppError::Http { .. } => {
+                Some("this is usually temporary; try again in a moment")
+            }
+            _ => None,
+        }
+    }
+
+    /// `hint` for an error that may or may not be an `AppError`.
+    pub fn hint_for(e: &(dyn Error + 'static)) -> Option<&'static str> {
+        e.downcast_ref::<AppError>().and_then(AppError::hint)
+    }
+}
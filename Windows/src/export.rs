@@ -0,0 +1,256 @@
+use regex::Regex;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::session::Session;
+
+/// Footer used by `/share footer default`.
+pub const DEFAULT_FOOTER: &str = "Generated by MyCLI · model {{model}} · {{date}} · {{count}} messages";
+
+/// Render one branch as a human-readable Markdown transcript, ending with
+/// `footer` when given. Message bodies are written verbatim so code fences survive.
+pub fn render_markdown(session: &Session, footer: Option<&str>) -> String {
+    let mut out = format!("# Session {} — branch `{}`\n\n", session.id, session.branch);
+
+    if let Some(summary) = &session.summary {
+        out.push_str("> **Summary:** ");
+        out.push_str(&summary.replace('\n', "\n> "));
+        out.push_str("\n\n");
+    }
+
+    for m in &session.messages {
+        let label = match m.role.as_str() {
+            "user" => "You",
+            "assistant" => "AI",
+            _ => "Tool",
+        };
+        out.push_str(&format!("**{label}:**\n\n{}\n\n", m.content.trim_end()));
+    }
+
+    if let Some(footer) = footer {
+        out.push_str(&format!("---\n\n_{footer}_\n"));
+    }
+
+    out
+}
+
+/// Expand `{{model}}`, `{{date}}`, `{{count}}` and the session's variables
+/// in a footer template.
+pub fn expand_footer(template: &str, session: &Session, model: &str, date: &str) -> Result<String, Box<dyn Error>> {
+    let count = session.messages.len().to_string();
+    session.expand_vars(template, &[("model", model), ("date", date), ("count", &count)])
+}
+
+/// Today's date (UTC) as `YYYY-MM-DD`.
+pub fn today() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    date_of(secs)
+}
+
+/// The UTC date of `secs` since the Unix epoch, as `YYYY-MM-DD`.
+pub fn date_of(secs: u64) -> String {
+    // Days since 1970-01-01 to a civil date (Howard Hinnant's algorithm).
+    let z = (secs / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Write `text` to `<dir>/exports/<name>` and return the path.
+pub fn write_export(dir: &Path, name: &str, text: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let export_dir = dir.join("exports");
+    fs::create_dir_all(&export_dir)?;
+    let path = export_dir.join(name);
+    fs::write(&path, text)?;
+    Ok(path)
+}
+
+/// Replaces sensitive text with placeholders before a transcript is shared.
+#[derive(Clone)]
+pub struct Redactor {
+    rules: Vec<(Regex, String)>,
+}
+
+impl Default for Redactor {
+    /// API keys, email addresses and absolute home-directory paths.
+    fn default() -> Self {
+        let mut redactor = Self { rules: Vec::new() };
+        redactor
+            .add(r"\b(sk|pk|rk)-[A-Za-z0-9_\-]{16,}", "[API_KEY]")
+            .expect("valid builtin pattern");
+        redactor
+            .add(r"(?i)\bBearer\s+[A-Za-z0-9._\-]{16,}", "Bearer [API_KEY]")
+            .expect("valid builtin pattern");
+        redactor
+            .add(r"[A-Za-z0-9._%+\-]+@[A-Za-z0-9.\-]+\.[A-Za-z]{2,}", "[EMAIL]")
+            .expect("valid builtin pattern");
+        redactor
+            .add(r"(/Users/|/home/|[A-Za-z]:\\Users\\)[^/\\\s]+", "~")
+            .expect("valid builtin pattern");
+        redactor
+    }
+}
+
+impl Redactor {
+    /// Add a pattern whose matches are replaced by `placeholder`.
+    pub fn add(&mut self, pattern: &str, placeholder: &str) -> Result<&mut Self, regex::Error> {
+        self.rules.push((Regex::new(pattern)?, placeholder.to_string()));
+        Ok(self)
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        self.rules
+            .iter()
+            .fold(text.to_string(), |acc, (re, placeholder)| {
+                re.replace_all(&acc, placeholder.as_str()).into_owned()
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::SessionManager;
+
+    use crate::session::Message;
+
+    fn message(role: &str, content: &str) -> Message {
+        Message { role: role.into(), content: content.into() }
+    }
+
+    #[test]
+    fn redactor_hides_keys_emails_and_home_paths() {
+        let text = "key sk-abcdefghijklmnop1234, auth Bearer abcdefghijklmnopqrstu, \
+                    mail ada@example.com, file /home/ada/notes.txt or C:\\Users\\ada\\x.txt";
+        let redacted = Redactor::default().redact(text);
+        assert_eq!(
+            redacted,
+            "key [API_KEY], auth Bearer [API_KEY], mail [EMAIL], file ~/notes.txt or ~\\x.txt"
+        );
+    }
+
+    #[test]
+    fn redactor_takes_extra_patterns() {
+        let mut redactor = Redactor::default();
+        redactor.add(r"Project \w+", "[REDACTED]").unwrap();
+        assert_eq!(redactor.redact("about Project Falcon"), "about [REDACTED]");
+        assert!(redactor.add("(unclosed", "x").is_err());
+    }
+
+    #[test]
+    fn render_markdown_labels_messages_and_keeps_code() {
+        let mut manager = SessionManager::new(std::env::temp_dir());
+        manager.session.summary = Some("one\ntwo".into());
+        manager.session.messages = vec![message("user", "hi"), message("assistant", "```rs\nfn f() {}\n```\n")];
+
+        let md = render_markdown(&manager.session, Some("footer"));
+
+        assert!(md.contains("> **Summary:** one\n> two\n"), "{md}");
+        assert!(md.contains("**You:**\n\nhi\n\n**AI:**\n\n```rs\nfn f() {}\n```\n\n"), "{md}");
+        assert!(md.ends_with("---\n\n_footer_\n"), "{md}");
+    }
+
+    #[test]
+    fn share_writes_a_redacted_transcript() {
+        let dir = std::env::temp_dir().join(format!("mycli-share-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut manager = SessionManager::new(dir.clone());
+        manager.export_footer = None;
+        manager.session.messages = vec![message("user", "my key is sk-abcdefghijklmnop1234")];
+
+        manager.handle_share_command("/share").unwrap();
+
+        let name = format!("{}_main_shared.md", manager.session.id);
+        let shared = fs::read_to_string(dir.join("exports").join(name)).unwrap();
+        assert!(shared.contains("my key is [API_KEY]"), "{shared}");
+        assert!(!shared.contains("sk-abcdefghijklmnop1234"));
+
+        // Names are escaped as in log file names, so a branch cannot
+        // point the file somewhere else.
+        manager.session.branch = "../a_b".into();
+        manager.handle_share_command("/share").unwrap();
+        let name = format!("{}_..%2Fa%5Fb_shared.md", manager.session.id);
+        assert!(dir.join("exports").join(name).is_file());
+    }
+
+    #[test]
+    fn date_of_gives_the_utc_date() {
+        assert_eq!(date_of(0), "1970-01-01");
+        assert_eq!(date_of(951_782_400), "2000-02-29");
+        assert_eq!(date_of(1_709_251_199), "2024-02-29");
+        assert_eq!(date_of(1_709_251_200), "2024-03-01");
+    }
+
+    #[test]
+    fn share_footer_can_be_set_reset_and_turned_off() {
+        let dir = std::env::temp_dir().join(format!("mycli-footer-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut manager = SessionManager::new(dir.clone());
+        manager.model = "m1".into();
+        let shared = dir.join("exports").join(format!("{}_main_shared.md", manager.session.id));
+
+        manager.handle_share_command("/share footer Made by {{model}}").unwrap();
+        assert_eq!(manager.export_footer.as_deref(), Some("Made by {{model}}"));
+        manager.handle_share_command("/share").unwrap();
+        assert!(fs::read_to_string(&shared).unwrap().ends_with("---\n\n_Made by m1_\n"));
+
+        manager.handle_share_command("/share footer default").unwrap();
+        assert_eq!(manager.export_footer.as_deref(), Some(DEFAULT_FOOTER));
+
+        manager.handle_share_command("/share footer off").unwrap();
+        manager.handle_share_command("/share").unwrap();
+        assert!(!fs::read_to_string(&shared).unwrap().contains("---"));
+    }
+
+    #[test]
+    fn export_md_adds_the_footer_once() {
+        let dir = std::env::temp_dir().join(format!("mycli-export-footer-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut manager = SessionManager::new(dir.clone());
+        manager.model = "m1".into();
+        manager.export_footer = Some("Made by {{model}}".into());
+        manager.session.messages = vec![message("user", "hi")];
+        let mut other = manager.session.clone();
+        other.branch = "alt".into();
+        manager.branches.insert("alt".into(), other);
+        manager.branches.insert("main".into(), manager.session.clone());
+        let exported = dir.join("exports").join(format!("{}.md", manager.session.id));
+
+        manager.handle_export_command("/export md").unwrap();
+        assert!(fs::read_to_string(&exported).unwrap().ends_with("---\n\n_Made by m1_\n"));
+
+        manager.handle_export_command("/export md all").unwrap();
+        let md = fs::read_to_string(&exported).unwrap();
+        assert!(md.contains("branch `alt`") && md.contains("branch `main`"), "{md}");
+        assert_eq!(md.matches("_Made by m1_").count(), 1, "{md}");
+        assert!(md.ends_with("---\n\n_Made by m1_\n"), "{md}");
+    }
+
+    #[test]
+    fn default_footer_expands_built_ins() {
+        let manager = SessionManager::new(std::env::temp_dir());
+        let footer = expand_footer(DEFAULT_FOOTER, &manager.session, "m1", "2024-01-02").unwrap();
+        assert_eq!(footer, "Generated by MyCLI · model m1 · 2024-01-02 · 0 messages");
+    }
+
+    #[test]
+    fn footer_uses_session_vars_and_rejects_unknown_ones() {
+        let mut manager = SessionManager::new(std::env::temp_dir());
+        manager.session.vars.insert("team".into(), "infra".into());
+        let footer = expand_footer("{{team}} / {{model}}", &manager.session, "m1", "d").unwrap();
+        assert_eq!(footer, "infra / m1");
+        assert!(expand_footer("{{nope}}", &manager.session, "m1", "d").is_err());
+    }
+}
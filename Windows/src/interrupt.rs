@@ -0,0 +1,66 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Ctrl+C handling for the agent loop. While armed, Ctrl+C only asks the
+/// running agent to stop; otherwise it exits the CLI as usual.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupt {
+    armed: Arc<AtomicBool>,
+    requested: Arc<AtomicBool>,
+}
+
+impl Interrupt {
+    /// Register the process-wide Ctrl+C handler.
+    pub fn install(&self) -> Result<(), ctrlc::Error> {
+        let this = self.clone();
+        ctrlc::set_handler(move || {
+            if this.armed.load(Ordering::SeqCst) {
+                println!("\n⏹️  Stopping agent...");
+                this.request();
+            } else {
+                std::process::exit(130);
+            }
+        })
+    }
+
+    /// Start catching Ctrl+C, clearing any earlier request.
+    pub fn arm(&self) {
+        self.requested.store(false, Ordering::SeqCst);
+        self.armed.store(true, Ordering::SeqCst);
+    }
+
+    pub fn disarm(&self) {
+        self.armed.store(false, Ordering::SeqCst);
+        self.requested.store(false, Ordering::SeqCst);
+    }
+
+    /// Ask the running agent to stop at its next check.
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_shared_by_clones_and_cleared_by_arm_and_disarm() {
+        let interrupt = Interrupt::default();
+        let handler = interrupt.clone();
+
+        handler.request();
+        assert!(interrupt.is_requested());
+        interrupt.arm();
+        assert!(!interrupt.is_requested());
+
+        handler.request();
+        assert!(interrupt.is_requested());
+        interrupt.disarm();
+        assert!(!interrupt.is_requested());
+    }
+}
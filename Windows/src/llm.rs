@@ -1,83 +1,761 @@
 use crate::session::{Message, SessionManager};
-use reqwest::blocking::Client;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{NoProxy, Proxy, StatusCode, Url};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::env;
 use std::error::Error;
-use std::io::Write;
-use crate::api_key::DASHSCOPE_API_KEY;
+use std::io::{BufRead, BufReader, Write};
+use std::thread;
+use std::time::Duration;
+use crate::backend::ChatRequest;
+use crate::error::AppError;
+use crate::mcp::normalize_escaped_content;
 
-pub fn call_chat_api(
-    client: &Client,
-    model: &str,
-    messages: &[Value],
-) -> Result<String, Box<dyn Error>> {
-
-    // 直接使用来自独立文件的 Key
-    let api_key = DASHSCOPE_API_KEY;
-
-    let url = "https://dashscope.aliyuncs.com/compatible-mode/v1/chat/completions";
-
-    let resp = client
-        .post(url)
-        .header("Authorization", format!("Bearer {}", api_key))
-        .header("Content-Type", "application/json")
-        .json(&json!({
-            "model": model,
-            "messages": messages,
-        }))
-        .send()?;
+/// DashScope's OpenAI-compatible API.
+pub const DASHSCOPE_BASE_URL: &str = "https://dashscope.aliyuncs.com/compatible-mode/v1";
 
-    let status = resp.status();
-    let body: Value = resp.json()?;
+/// An OpenAI-compatible server: `POST {base_url}/chat/completions`.
+pub struct ChatEndpoint {
+    /// Name used in error messages, e.g. "DashScope".
+    pub provider: &'static str,
+    /// Everything before `/chat/completions`, e.g. `http://localhost:1234/v1`.
+    pub base_url: String,
+    /// Sent as a bearer token; local servers often need none.
+    pub api_key: Option<String>,
+}
 
-    if !status.is_success() {
+impl ChatEndpoint {
+    fn url(&self) -> String {
+        format!("{}/chat/completions", self.base_url.trim_end_matches('/'))
+    }
+
+    /// The POST request with auth and content type set.
+    fn post(&self, api: &ApiClient, url: &str, body: &Value) -> RequestBuilder {
+        let mut request = api.client.post(url).header("Content-Type", "application/json");
+        if let Some(key) = &self.api_key {
+            request = request.header("Authorization", format!("Bearer {key}"));
+        }
+        request.json(body)
+    }
+
+    /// Headers as they should be shown to the user (Authorization redacted).
+    fn redacted_headers(&self) -> Vec<(String, String)> {
+        let mut headers = Vec::new();
+        if self.api_key.is_some() {
+            headers.push(("Authorization".into(), "Bearer <redacted>".into()));
+        }
+        headers.push(("Content-Type".into(), "application/json".into()));
+        headers
+    }
+
+    /// Turn an error body into a readable error using its `error.message` field.
+    fn error(&self, status: StatusCode, raw: &str) -> AppError {
+        let body: Value = serde_json::from_str(raw).unwrap_or(Value::Null);
         let msg = body
             .get("error")
             .and_then(|e| e.get("message"))
             .and_then(|m| m.as_str())
             .unwrap_or("unknown error from API");
-        return Err(format!("DashScope API error ({status}): {msg}").into());
+        AppError::Api { provider: self.provider, status: status.as_u16(), message: msg.to_string() }
+    }
+}
+
+/// Timeouts and retry policy for API requests.
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    pub connect_timeout: Duration,
+    /// Maximum wait for any single read/write on the connection.
+    pub read_timeout: Duration,
+    /// Extra attempts after the first one for transient failures.
+    pub max_retries: u32,
+    /// Delay before the first retry; doubled after each attempt.
+    pub initial_backoff: Duration,
+    /// Longest wait a `Retry-After` header can ask for before a retry.
+    pub max_retry_after: Duration,
+    /// Sent with every request (`[headers]` in the config file).
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(60),
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_retry_after: Duration::from_secs(60),
+            headers: BTreeMap::new(),
+        }
+    }
+}
+
+/// Build the shared blocking client with the configured timeouts, headers
+/// and the proxy from the environment.
+pub fn http_client(cfg: &HttpConfig) -> Result<Client, AppError> {
+    let mut builder = Client::builder()
+        .connect_timeout(cfg.connect_timeout)
+        .timeout(cfg.read_timeout)
+        .default_headers(default_headers(&cfg.headers)?);
+    for proxy in env_proxies()? {
+        builder = builder.proxy(proxy);
+    }
+    builder.build().map_err(|e| AppError::Config(format!("cannot set up the HTTP client: {e}")))
+}
+
+/// `[headers]` from the config file, checked for names and values HTTP allows.
+fn default_headers(headers: &BTreeMap<String, String>) -> Result<HeaderMap, AppError> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        let header = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| AppError::Config(format!("invalid header name in [headers]: `{name}`")))?;
+        let mut value = HeaderValue::from_str(value)
+            .map_err(|_| AppError::Config(format!("invalid value for header `{name}` in [headers]")))?;
+        // Gateway keys stay out of debug output.
+        value.set_sensitive(true);
+        map.insert(header, value);
+    }
+    Ok(map)
+}
+
+/// Proxies named by `HTTPS_PROXY`, `HTTP_PROXY` and `ALL_PROXY` (or their
+/// lower-case forms); hosts in `NO_PROXY` bypass them.
+fn env_proxies() -> Result<Vec<Proxy>, AppError> {
+    let mut proxies = Vec::new();
+    for (var, make) in [
+        ("HTTPS_PROXY", Proxy::https::<Url> as fn(Url) -> reqwest::Result<Proxy>),
+        ("HTTP_PROXY", Proxy::http::<Url>),
+        ("ALL_PROXY", Proxy::all::<Url>),
+    ] {
+        let lower = var.to_lowercase();
+        let Some((var, value)) = [var, lower.as_str()]
+            .into_iter()
+            .find_map(|name| env::var(name).ok().filter(|v| !v.trim().is_empty()).map(|v| (name, v)))
+        else {
+            continue;
+        };
+        let proxy = make(proxy_url(var, value.trim())?).map_err(|e| AppError::Config(format!("{var}: {e}")))?;
+        proxies.push(proxy.no_proxy(NoProxy::from_env()));
+    }
+    Ok(proxies)
+}
+
+/// `value` as an `http://` or `https://` proxy URL. The value itself is
+/// left out of errors, since it may carry a password.
+fn proxy_url(var: &str, value: &str) -> Result<Url, AppError> {
+    let bad = |why: String| {
+        AppError::Config(format!("{var} is not a usable proxy URL ({why}); expected e.g. http://proxy.example.com:8080"))
+    };
+    let url = Url::parse(value).map_err(|e| bad(e.to_string()))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(bad(format!("unsupported scheme `{}`", url.scheme())));
+    }
+    if url.host_str().is_none() {
+        return Err(bad("no host".to_string()));
+    }
+    Ok(url)
+}
+
+
+/// HTTP client plus the settings every API call needs.
+#[derive(Clone)]
+pub struct ApiClient {
+    pub client: Client,
+    pub http: HttpConfig,
+    /// API key given explicitly (otherwise resolved from env / config file).
+    pub api_key: Option<String>,
+}
+
+impl ApiClient {
+    pub fn new(http: HttpConfig) -> Self {
+        Self {
+            // Checked at startup, so this only falls back if the
+            // environment changed since.
+            client: http_client(&http).unwrap_or_else(|_| Client::new()),
+            http,
+            api_key: None,
+        }
+    }
+
+    /// Send the request built by `make`, retrying transient failures
+    /// (connection errors, timeouts, HTTP 429/500/502/503) with exponential
+    /// backoff. A `Retry-After` header on a 429 or 503 sets the wait instead,
+    /// up to `max_retry_after`.
+    pub(crate) fn send_with_retry(&self, provider: &str, make: impl Fn() -> RequestBuilder) -> Result<Response, AppError> {
+        let mut backoff = self.http.initial_backoff;
+        let mut attempt = 0;
+
+        loop {
+            let retries_left = attempt < self.http.max_retries;
+            let wait = match make().send() {
+                Ok(resp) if retries_left && is_transient_status(resp.status()) => {
+                    let wait = retry_after(&resp).map_or(backoff, |w| w.min(self.http.max_retry_after));
+                    if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+                        // stderr, so `--once` output stays clean.
+                        eprintln!("⏳ Rate limited by {provider}, retrying in {}s...", wait.as_millis().div_ceil(1000));
+                    }
+                    wait
+                }
+                Ok(resp) => return Ok(resp),
+                Err(e) if retries_left && (e.is_connect() || e.is_timeout() || e.is_request()) => backoff,
+                Err(source) => {
+                    return Err(AppError::Http {
+                        context: format!("request to {provider} failed after {} attempt(s)", attempt + 1),
+                        source,
+                    })
+                }
+            };
+
+            attempt += 1;
+            thread::sleep(wait);
+            backoff *= 2;
+        }
+    }
+}
+
+fn is_transient_status(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 429 | 500 | 502 | 503)
+}
+
+/// The wait a 429 or 503 response asks for in `Retry-After`: seconds, or
+/// an HTTP date.
+fn retry_after(resp: &Response) -> Option<Duration> {
+    let value = resp.headers().get(reqwest::header::RETRY_AFTER)?.to_str().ok()?;
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).ok()?.as_secs();
+    parse_retry_after(resp.status().as_u16(), value, now)
+}
+
+/// `retry_after` for a response `status` and header `value`, with `now` in
+/// seconds since the epoch.
+fn parse_retry_after(status: u16, value: &str, now: u64) -> Option<Duration> {
+    if !matches!(status, 429 | 503) {
+        return None;
+    }
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = parse_http_date(value)?;
+    Some(Duration::from_secs(at.saturating_sub(now)))
+}
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Seconds since the epoch of an HTTP date, "Sun, 06 Nov 1994 08:49:37 GMT".
+/// The header comes from the server, so out-of-range fields are rejected
+/// before any arithmetic rather than left to overflow.
+fn parse_http_date(value: &str) -> Option<u64> {
+    let mut parts = value.split_whitespace().skip(1);
+    let day: u64 = parts.next()?.parse().ok()?;
+    let month_name = parts.next()?;
+    let month = MONTHS.iter().position(|m| *m == month_name)? as u64 + 1;
+    let year: u64 = parts.next()?.parse().ok()?;
+    let mut time = parts.next()?.split(':').map(|p| p.parse::<u64>().ok());
+    let (h, m, s) = (time.next()??, time.next()??, time.next()??);
+    // 60 allows a leap second.
+    if !(1..=31).contains(&day) || !(1970..=9999).contains(&year) || h > 23 || m > 59 || s > 60 {
+        return None;
+    }
+
+    // Days from the civil date (Howard Hinnant's algorithm).
+    let (y, mo) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * mo + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = (era * 146_097 + doe).checked_sub(719_468)?;
+    Some(days * 86_400 + h * 3600 + m * 60 + s)
+}
+
+/// What to do when the model answers with only whitespace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmptyResponse {
+    /// Ask again once, then fall back to the marker.
+    Retry,
+    /// Keep the turn, showing `EMPTY_RESPONSE_MARKER`.
+    #[default]
+    Marker,
+    /// Do not store an assistant message at all.
+    Drop,
+}
+
+pub(crate) const EMPTY_RESPONSE_MARKER: &str = "[empty response]";
+
+/// Raw request/response pair of a single API call, kept for `/debug last`.
+#[derive(Debug, Clone)]
+pub struct ApiExchange {
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub request_body: Value,
+    pub status: u16,
+    pub response_body: String,
+}
+
+impl ApiExchange {
+    /// Pretty-print the exchange for the terminal.
+    pub fn render(&self) -> String {
+        let mut out = format!("➡️  POST {}\n", self.url);
+        for (name, value) in &self.headers {
+            out.push_str(&format!("{name}: {value}\n"));
+        }
+        out.push('\n');
+        out.push_str(&serde_json::to_string_pretty(&self.request_body).unwrap_or_default());
+        out.push_str(&format!("\n\n⬅️  HTTP {}\n", self.status));
+        out.push_str(&self.response_body);
+        out
+    }
+}
+
+/// Optional generation settings, only serialized when set.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GenParams {
+    /// Sequences at which the model should stop generating.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    /// Sampling temperature, 0.0 to 2.0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    /// Nucleus sampling cutoff, above 0.0 and at most 1.0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    /// Upper bound on generated tokens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+}
+
+impl GenParams {
+    /// Merge the settings into an OpenAI-compatible request body.
+    /// (Ollama nests the same settings under `options`, with `num_predict`
+    /// for `max_tokens`.)
+    pub fn apply(&self, body: &mut Value) {
+        if !self.stop.is_empty() {
+            body["stop"] = json!(self.stop);
+        }
+        if let Some(t) = self.temperature {
+            body["temperature"] = json!(t);
+        }
+        if let Some(p) = self.top_p {
+            body["top_p"] = json!(p);
+        }
+        if let Some(n) = self.max_tokens {
+            body["max_tokens"] = json!(n);
+        }
     }
 
-    Ok(body["choices"][0]["message"]["content"]
-        .as_str()
-        .unwrap_or("")
-        .to_string())
+    /// Merge the settings into an Ollama request body's `options`.
+    pub fn apply_ollama(&self, body: &mut Value) {
+        let mut options = json!({});
+        if !self.stop.is_empty() {
+            options["stop"] = json!(self.stop);
+        }
+        if let Some(t) = self.temperature {
+            options["temperature"] = json!(t);
+        }
+        if let Some(p) = self.top_p {
+            options["top_p"] = json!(p);
+        }
+        if let Some(n) = self.max_tokens {
+            options["num_predict"] = json!(n);
+        }
+        if options.as_object().is_some_and(|o| !o.is_empty()) {
+            body["options"] = options;
+        }
+    }
+
+    /// Set one setting from user input; `off` unsets it (for `stop`,
+    /// clears every sequence). Out-of-range values are rejected with a
+    /// message saying why.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let value = value.trim();
+        let off = value == "off";
+        match name {
+            "stop" if off => self.stop.clear(),
+            "stop" => {
+                // `"\n\n"` and `\n\n` both mean two newlines.
+                let quoted = value.len() >= 2 && value.starts_with('"') && value.ends_with('"');
+                let seq = normalize_escaped_content(if quoted { &value[1..value.len() - 1] } else { value });
+                if seq.is_empty() {
+                    return Err("stop sequence must not be empty".into());
+                }
+                if !self.stop.contains(&seq) {
+                    self.stop.push(seq);
+                }
+            }
+            "temperature" => {
+                self.temperature = if off { None } else { Some(parse_in_range(value, 0.0, 2.0, name)?) };
+            }
+            "top_p" => {
+                let p = if off { None } else { Some(parse_in_range(value, 0.0, 1.0, name)?) };
+                if p == Some(0.0) {
+                    return Err("top_p must be greater than 0".into());
+                }
+                self.top_p = p;
+            }
+            "max_tokens" => {
+                self.max_tokens = if off {
+                    None
+                } else {
+                    match value.parse::<u32>() {
+                        Ok(n) if n > 0 => Some(n),
+                        _ => return Err(format!("max_tokens must be a positive whole number, got '{value}'")),
+                    }
+                };
+            }
+            _ => return Err(format!("unknown setting '{name}' (temperature, top_p, max_tokens, stop)")),
+        }
+        Ok(())
+    }
+}
+
+fn parse_in_range(value: &str, min: f32, max: f32, name: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(v) if (min..=max).contains(&v) => Ok(v),
+        _ => Err(format!("{name} must be a number between {min} and {max}, got '{value}'")),
+    }
+}
+
+/// Cuts a reply at the first stop sequence, for servers that ignore
+/// `stop`. The end of a chunk that could begin a stop sequence is held back
+/// until the next chunk shows whether it does, so a sequence split across
+/// chunks is still caught.
+pub struct StopFilter<'a> {
+    stops: Vec<&'a str>,
+    pending: String,
+    done: bool,
+}
+
+impl<'a> StopFilter<'a> {
+    pub fn new(stops: &'a [String]) -> Self {
+        Self {
+            stops: stops.iter().map(String::as_str).filter(|s| !s.is_empty()).collect(),
+            pending: String::new(),
+            done: false,
+        }
+    }
+
+    /// Feed the next chunk; returns the text that can be shown now.
+    pub fn push(&mut self, chunk: &str) -> String {
+        if self.done {
+            return String::new();
+        }
+        if self.stops.is_empty() {
+            return chunk.to_string();
+        }
+        self.pending.push_str(chunk);
+        if let Some(cut) = self.stops.iter().filter_map(|s| self.pending.find(s)).min() {
+            self.done = true;
+            self.pending.truncate(cut);
+            return std::mem::take(&mut self.pending);
+        }
+        let held = self.stops.iter().map(|s| partial_match(&self.pending, s)).max().unwrap_or(0);
+        let rest = self.pending.split_off(self.pending.len() - held);
+        std::mem::replace(&mut self.pending, rest)
+    }
+
+    /// Whether a stop sequence was seen; later text is dropped.
+    pub fn done(&self) -> bool {
+        self.done
+    }
+
+    /// Text still held back when the reply ends without a stop sequence.
+    pub fn finish(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Length of the longest end of `text` that is the start of `stop`.
+fn partial_match(text: &str, stop: &str) -> usize {
+    (1..stop.len())
+        .rev()
+        .find(|&n| stop.is_char_boundary(n) && text.ends_with(&stop[..n]))
+        .unwrap_or(0)
+}
+
+/// Build the JSON body sent to the chat completions endpoint.
+pub fn build_chat_request(model: &str, messages: &[Value], params: &GenParams) -> Value {
+    let mut body = json!({
+        "model": model,
+        "messages": messages,
+    });
+    params.apply(&mut body);
+    body
+}
+
+/// Non-streaming chat completion. The raw exchange is recorded into `last`.
+pub fn call_chat_api(
+    endpoint: &ChatEndpoint,
+    req: &ChatRequest,
+    last: &mut Option<ApiExchange>,
+) -> Result<String, AppError> {
+    let url = endpoint.url();
+    let request_body = build_chat_request(req.model, req.messages, req.params);
+
+    let resp = req
+        .api
+        .send_with_retry(endpoint.provider, || endpoint.post(req.api, &url, &request_body))?;
+
+    let status = resp.status();
+    let raw = resp.text()?;
+
+    *last = Some(ApiExchange {
+        url,
+        headers: endpoint.redacted_headers(),
+        request_body,
+        status: status.as_u16(),
+        response_body: raw.clone(),
+    });
+
+    if !status.is_success() {
+        return Err(endpoint.error(status, &raw));
+    }
+
+    let body: Value = serde_json::from_str(&raw)?;
+
+    let mut stop = StopFilter::new(&req.params.stop);
+    let mut answer = stop.push(body["choices"][0]["message"]["content"].as_str().unwrap_or(""));
+    answer.push_str(&stop.finish());
+    Ok(answer)
+}
+
+/// Streaming chat completion: sets `"stream": true`, parses the SSE `data:`
+/// lines and hands each `choices[0].delta.content` piece to `on_chunk`.
+/// The reply ends at the first stop sequence even if the server ignores
+/// `stop`. Returns the full concatenated answer.
+pub fn call_chat_api_stream(
+    endpoint: &ChatEndpoint,
+    req: &ChatRequest,
+    last: &mut Option<ApiExchange>,
+    mut on_chunk: impl FnMut(&str),
+) -> Result<String, AppError> {
+    let url = endpoint.url();
+    let mut request_body = build_chat_request(req.model, req.messages, req.params);
+    request_body["stream"] = json!(true);
+
+    let resp = req
+        .api
+        .send_with_retry(endpoint.provider, || endpoint.post(req.api, &url, &request_body))?;
+
+    let status = resp.status();
+    let mut exchange = ApiExchange {
+        url,
+        headers: endpoint.redacted_headers(),
+        request_body,
+        status: status.as_u16(),
+        response_body: String::new(),
+    };
+
+    if !status.is_success() {
+        exchange.response_body = resp.text()?;
+        let err = endpoint.error(status, &exchange.response_body);
+        *last = Some(exchange);
+        return Err(err);
+    }
+
+    let mut answer = String::new();
+    let mut stop = StopFilter::new(&req.params.stop);
+    for line in BufReader::new(resp).lines() {
+        let line = line?;
+        exchange.response_body.push_str(&line);
+        exchange.response_body.push('\n');
+
+        let Some(data) = line.strip_prefix("data:") else {
+            continue;
+        };
+        let data = data.trim();
+        if data == "[DONE]" {
+            break;
+        }
+        let Ok(event) = serde_json::from_str::<Value>(data) else {
+            continue;
+        };
+        if event.get("error").is_some() {
+            let err = endpoint.error(status, data);
+            *last = Some(exchange);
+            return Err(err);
+        }
+        if let Some(delta) = event["choices"][0]["delta"]["content"].as_str() {
+            let text = stop.push(delta);
+            if !text.is_empty() {
+                answer.push_str(&text);
+                on_chunk(&text);
+            }
+            if stop.done() {
+                break;
+            }
+        }
+    }
+    let rest = stop.finish();
+    if !rest.is_empty() {
+        answer.push_str(&rest);
+        on_chunk(&rest);
+    }
+
+    *last = Some(exchange);
+    Ok(answer)
+}
+
+/// History sent to the model is trimmed to roughly this many characters.
+pub(crate) const HISTORY_MAX_CHARS: usize = 24_000;
+
+const TRIMMED_MARKER: &str = "[earlier conversation trimmed]";
+
+/// Index of the first message to keep so that the messages from there on
+/// fit in `budget` characters (`lens` are the per-message sizes). The last
+/// two messages, the latest user/assistant pair, are always kept.
+fn budget_start(lens: &[usize], budget: usize) -> usize {
+    let mut start = lens.len();
+    let mut used = 0;
+    while start > 0 {
+        let len = lens[start - 1];
+        let must_keep = lens.len() - start < 2;
+        if !must_keep && used + len > budget {
+            break;
+        }
+        used += len;
+        start -= 1;
+    }
+    start
+}
+
+/// `messages` with every `tool` message sent as `system` instead.
+fn tool_results_as_system(messages: &[Value]) -> Vec<Value> {
+    messages
+        .iter()
+        .map(|m| {
+            let mut m = m.clone();
+            if m["role"] == "tool" {
+                m["role"] = json!("system");
+            }
+            m
+        })
+        .collect()
+}
+
+/// Accumulated summaries longer than this are re-summarized.
+const SUMMARY_MAX_CHARS: usize = 2000;
+
+/// Cut `s` to at most `max` characters, marking the cut with an ellipsis.
+fn clamp_chars(s: &str, max: usize) -> String {
+    if s.chars().count() <= max {
+        return s.to_string();
+    }
+    let mut out: String = s.chars().take(max.saturating_sub(1)).collect();
+    out.push('…');
+    out
 }
 
 /// Implementation block for LLM-related functions.
 impl SessionManager {
     pub fn send_and_stream_llm(
         &mut self,
-        client: &Client,
         _prompt: &str,
     ) -> Result<(), Box<dyn Error>> {
-        let messages: Vec<Value> = self
-            .session
-            .messages
-            .iter()
-            .map(|m| {
-                json!({
-                    "role": m.role,
-                    "content": m.content,
-                })
-            })
-            .collect();
+        let messages = self.messages_within_budget(HISTORY_MAX_CHARS);
 
-        let answer = call_chat_api(client, &self.model, &messages)?;
+        let answer = self.stream_reply(&messages)?;
+        self.note("\n✅ Done.");
 
-        for ch in answer.chars() {
-            print!("{ch}");
-            std::io::stdout().flush().ok();
+        if let Some(answer) = answer {
+            self.session.messages.push(Message {
+                role: "assistant".into(),
+                content: answer,
+            });
         }
-        println!("\n✅ Done.");
 
-        self.session.messages.push(Message {
-            role: "assistant".into(),
-            content: answer.clone(),
-        });
+        self.maybe_summarize()?;
+        self.autosave();
+        Ok(())
+    }
+
+    /// Stream one completion to stdout, applying `on_empty` when the model
+    /// returns only whitespace. `None` means the reply should be dropped.
+    pub(crate) fn stream_reply(&mut self, messages: &[Value]) -> Result<Option<String>, Box<dyn Error>> {
+        let echo = self.echo_reply;
+        let mut print_chunk = |chunk: &str| {
+            if echo {
+                print!("{chunk}");
+                std::io::stdout().flush().ok();
+            }
+        };
+        let remapped;
+        let mut req = ChatRequest { api: &self.api, model: &self.model, messages, params: &self.session.params };
+
+        let mut answer = match self.backend.stream(&req, &mut self.last_exchange, &mut print_chunk) {
+            // Servers that only take `tool` messages answering native tool
+            // calls reject ours; send tool results as `system` from now on.
+            Err(AppError::Api { status: 400, .. }) if messages.iter().any(|m| m["role"] == "tool") => {
+                self.note("↻ The server rejected the `tool` role; resending tool results as `system`...");
+                self.tool_role_fallback = true;
+                remapped = tool_results_as_system(messages);
+                req.messages = &remapped;
+                self.backend.stream(&req, &mut self.last_exchange, &mut print_chunk)?
+            }
+            other => other?,
+        };
+        if answer.trim().is_empty() && self.on_empty == EmptyResponse::Retry {
+            self.note("↻ Empty response, asking again...");
+            answer = self.backend.stream(&req, &mut self.last_exchange, &mut print_chunk)?;
+        }
+
+        if !answer.trim().is_empty() {
+            return Ok(Some(answer));
+        }
+        match self.on_empty {
+            EmptyResponse::Drop => {
+                self.note("(empty response dropped)");
+                Ok(None)
+            }
+            EmptyResponse::Retry | EmptyResponse::Marker => {
+                if echo {
+                    print!("{EMPTY_RESPONSE_MARKER}");
+                }
+                Ok(Some(EMPTY_RESPONSE_MARKER.to_string()))
+            }
+        }
+    }
+
+    /// Chat messages for the API, dropping the oldest whole messages so the
+    /// rest fit in `max_chars`. When anything is dropped, a system message
+    /// with a trim marker (and the summary, if any) is put first, after the
+    /// session's own system prompt.
+    pub(crate) fn messages_within_budget(&self, max_chars: usize) -> Vec<Value> {
+        let messages = &self.session.messages;
+        let lens: Vec<usize> = messages.iter().map(|m| m.content.chars().count()).collect();
+        let start = budget_start(&lens, max_chars);
+
+        let mut out = Vec::with_capacity(messages.len() - start + 2);
+        if let Some(prompt) = &self.session.system_prompt {
+            out.push(json!({ "role": "system", "content": prompt }));
+        }
+        if start > 0 {
+            let note = match &self.session.summary {
+                Some(summary) => format!("{TRIMMED_MARKER}\nSummary so far: {summary}"),
+                None => TRIMMED_MARKER.to_string(),
+            };
+            out.push(json!({ "role": "system", "content": note }));
+        }
+        out.extend(messages[start..].iter().map(|m| {
+            json!({
+                "role": m.role,
+                "content": m.content,
+            })
+        }));
+        if self.tool_role_fallback {
+            out = tool_results_as_system(&out);
+        }
+        out
+    }
 
-        self.maybe_summarize(client)?;
-        self.save_to_logs().ok();
+    /// Write the `messages` array the next request would send, as JSON, so
+    /// a result can be reproduced elsewhere.
+    pub fn export_context(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let messages = self.messages_within_budget(HISTORY_MAX_CHARS);
+        let text = serde_json::to_string_pretty(&messages)?;
+        std::fs::write(path, text)?;
+        println!("📤 Exported {} message(s) to {}", messages.len(), path);
         Ok(())
     }
 
@@ -90,7 +768,7 @@ impl SessionManager {
             .join("\n")
     }
 
-    fn maybe_summarize(&mut self, client: &Client) -> Result<(), Box<dyn Error>> {
+    fn maybe_summarize(&mut self) -> Result<(), Box<dyn Error>> {
         const SUMMARY_TRIGGER_PAIRS: usize = 20;
 
         let pairs = self.session.messages.len() / 2;
@@ -98,7 +776,7 @@ impl SessionManager {
             return Ok(());
         }
 
-        println!("🧩 {pairs} messages reached. Summarizing...");
+        self.note(format!("🧩 {pairs} messages reached. Summarizing..."));
 
         let history = self.history_string();
 
@@ -116,7 +794,9 @@ impl SessionManager {
             }),
         ];
 
-        let summary = call_chat_api(client, &self.model, &messages)?;
+        let params = GenParams::default();
+        let req = ChatRequest { api: &self.api, model: &self.model, messages: &messages, params: &params };
+        let summary = self.backend.complete(&req, &mut self.last_exchange)?;
 
         if !summary.trim().is_empty() {
             self.session.summary = match &self.session.summary {
@@ -125,7 +805,505 @@ impl SessionManager {
             };
         }
 
-        self.save_to_logs().ok();
+        self.compact_summary()?;
+
+        self.autosave();
+        Ok(())
+    }
+
+    /// Re-summarize the accumulated summary once it grows past
+    /// `SUMMARY_MAX_CHARS`, so it never balloons over a long session.
+    fn compact_summary(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(current) = self.session.summary.clone() else {
+            return Ok(());
+        };
+        if current.chars().count() <= SUMMARY_MAX_CHARS {
+            return Ok(());
+        }
+
+        self.note("🧩 Summary is getting long. Compacting...");
+
+        let messages = vec![
+            json!({
+                "role": "system",
+                "content": "You are a helpful assistant. Merge these partial summaries into one concise summary (2-6 sentences).",
+            }),
+            json!({
+                "role": "user",
+                "content": current,
+            }),
+        ];
+
+        let params = GenParams::default();
+        let req = ChatRequest { api: &self.api, model: &self.model, messages: &messages, params: &params };
+        let compacted = self.backend.complete(&req, &mut self.last_exchange)?;
+        let compacted = if compacted.trim().is_empty() { current } else { compacted };
+
+        self.session.summary = Some(clamp_chars(compacted.trim(), SUMMARY_MAX_CHARS));
         Ok(())
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::backend::{LlmBackend, MockBackend};
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    /// Answer one connection per entry of `responses` (raw HTTP) on a local
+    /// port; returns the base URL.
+    pub(crate) fn fake_server(responses: Vec<String>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                // Read the headers, then as much body as they announce.
+                while let Ok(n @ 1..) = stream.read(&mut buf) {
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request);
+                    if let Some(end) = text.find("\r\n\r\n") {
+                        let len = text[..end]
+                            .lines()
+                            .find_map(|l| l.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().to_string()))
+                            .and_then(|v| v.parse::<usize>().ok())
+                            .unwrap_or(0);
+                        if request.len() >= end + 4 + len {
+                            break;
+                        }
+                    }
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        format!("http://{addr}/v1")
+    }
+
+    pub(crate) fn http_response(status: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    fn endpoint(base_url: String) -> ChatEndpoint {
+        ChatEndpoint { provider: "Test", base_url, api_key: Some("sk-secret".into()) }
+    }
+
+    pub(crate) fn quick_api() -> ApiClient {
+        ApiClient::new(HttpConfig { initial_backoff: Duration::from_millis(1), ..HttpConfig::default() })
+    }
+
+    #[test]
+    fn gen_params_set_adds_unique_stop_sequences() {
+        let mut params = GenParams::default();
+        params.set("stop", "\\n\\n").unwrap();
+        params.set("stop", "\"\\n\\n\"").unwrap();
+        params.set("stop", "END").unwrap();
+        assert_eq!(params.stop, ["\n\n", "END"]);
+        assert!(params.set("stop", "\"\"").is_err());
+        params.set("stop", "off").unwrap();
+        assert!(params.stop.is_empty());
+    }
+
+    #[test]
+    fn gen_params_set_rejects_out_of_range_values() {
+        let mut params = GenParams::default();
+        params.set("temperature", "0.7").unwrap();
+        params.set("top_p", "1").unwrap();
+        params.set("max_tokens", "256").unwrap();
+        for (name, value) in [
+            ("temperature", "2.5"),
+            ("temperature", "-0.1"),
+            ("temperature", "warm"),
+            ("top_p", "0"),
+            ("top_p", "1.1"),
+            ("max_tokens", "0"),
+            ("max_tokens", "-3"),
+            ("max_tokens", "1.5"),
+            ("seed", "1"),
+        ] {
+            assert!(params.set(name, value).is_err(), "{name} = {value} was accepted");
+        }
+        // Rejected values leave the earlier ones alone.
+        assert_eq!((params.temperature, params.top_p, params.max_tokens), (Some(0.7), Some(1.0), Some(256)));
+
+        params.set("temperature", "off").unwrap();
+        assert_eq!(params.temperature, None);
+    }
+
+    #[test]
+    fn gen_params_are_only_sent_when_set() {
+        let mut params = GenParams::default();
+        assert_eq!(build_chat_request("m", &[], &params), json!({"model": "m", "messages": []}));
+
+        params.set("stop", "END").unwrap();
+        params.set("max_tokens", "64").unwrap();
+        let body = build_chat_request("m", &[], &params);
+        assert_eq!((body["stop"].clone(), body["max_tokens"].clone()), (json!(["END"]), json!(64)));
+
+        let mut body = json!({});
+        params.apply_ollama(&mut body);
+        assert_eq!(body, json!({"options": {"stop": ["END"], "num_predict": 64}}));
+    }
+
+    /// A manager whose backend always answers `reply`.
+    fn manager_replying(reply: &str) -> SessionManager {
+        let mut manager = SessionManager::new(std::env::temp_dir().join("mycli-llm-tests"));
+        manager.backend = Box::new(MockBackend { reply: reply.to_string() });
+        manager
+    }
+
+    #[test]
+    fn compact_summary_leaves_short_summaries_alone() {
+        let mut manager = manager_replying("should not be used");
+        manager.session.summary = Some("short".into());
+        manager.compact_summary().unwrap();
+        assert_eq!(manager.session.summary.as_deref(), Some("short"));
+    }
+
+    #[test]
+    fn compact_summary_replaces_a_long_summary() {
+        let mut manager = manager_replying(" merged ");
+        manager.session.summary = Some("s".repeat(SUMMARY_MAX_CHARS + 1));
+        manager.compact_summary().unwrap();
+        assert_eq!(manager.session.summary.as_deref(), Some("merged"));
+    }
+
+    #[test]
+    fn compact_summary_clamps_a_reply_that_is_still_too_long() {
+        let mut manager = manager_replying(&"r".repeat(SUMMARY_MAX_CHARS * 2));
+        manager.session.summary = Some("s".repeat(SUMMARY_MAX_CHARS + 1));
+        manager.compact_summary().unwrap();
+        let summary = manager.session.summary.unwrap();
+        assert_eq!(summary.chars().count(), SUMMARY_MAX_CHARS);
+        assert!(summary.ends_with('…'));
+    }
+
+    #[test]
+    fn clamp_chars_counts_characters_not_bytes() {
+        assert_eq!(clamp_chars("héllo", 5), "héllo");
+        assert_eq!(clamp_chars("héllo wörld", 5), "héll…");
+    }
+
+    #[test]
+    fn send_with_retry_retries_transient_statuses() {
+        let base = fake_server(vec![
+            http_response("503 Service Unavailable", "{}"),
+            http_response("502 Bad Gateway", "{}"),
+            http_response("200 OK", "{}"),
+        ]);
+        let api = quick_api();
+        let resp = api.send_with_retry("Test", || api.client.get(&base)).unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn send_with_retry_gives_up_after_max_retries() {
+        let base = fake_server(vec![http_response("500 Internal Server Error", "{}"); 2]);
+        let api = ApiClient::new(HttpConfig { max_retries: 1, initial_backoff: Duration::from_millis(1), ..HttpConfig::default() });
+        let resp = api.send_with_retry("Test", || api.client.get(&base)).unwrap();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn send_with_retry_does_not_retry_client_errors() {
+        // A second request would find nobody listening.
+        let base = fake_server(vec![http_response("401 Unauthorized", "{}")]);
+        let api = quick_api();
+        let resp = api.send_with_retry("Test", || api.client.get(&base)).unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn send_with_retry_reports_attempts_when_the_server_is_down() {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let api = ApiClient::new(HttpConfig { max_retries: 2, initial_backoff: Duration::from_millis(1), ..HttpConfig::default() });
+        let err = api.send_with_retry("Test", || api.client.get(format!("http://127.0.0.1:{port}/"))).unwrap_err();
+        assert!(err.to_string().contains("failed after 3 attempt(s)"), "{err}");
+    }
+
+    #[test]
+    fn budget_start_keeps_the_newest_messages_that_fit() {
+        assert_eq!(budget_start(&[], 10), 0);
+        assert_eq!(budget_start(&[3, 3, 3], 10), 0);
+        assert_eq!(budget_start(&[5, 3, 3, 3], 10), 1);
+        assert_eq!(budget_start(&[3, 9, 3, 3], 10), 2);
+    }
+
+    #[test]
+    fn budget_start_always_keeps_the_last_pair() {
+        assert_eq!(budget_start(&[1, 50, 50], 10), 1);
+        assert_eq!(budget_start(&[50], 0), 0);
+    }
+
+    fn push(manager: &mut SessionManager, role: &str, content: &str) {
+        manager.session.messages.push(Message { role: role.into(), content: content.into() });
+    }
+
+    #[test]
+    fn messages_within_budget_sends_everything_that_fits() {
+        let mut manager = manager_replying("");
+        manager.session.system_prompt = Some("be brief".into());
+        push(&mut manager, "user", "hi");
+        push(&mut manager, "assistant", "hello");
+
+        let messages = manager.messages_within_budget(100);
+
+        assert_eq!(
+            messages,
+            [
+                json!({"role": "system", "content": "be brief"}),
+                json!({"role": "user", "content": "hi"}),
+                json!({"role": "assistant", "content": "hello"}),
+            ]
+        );
+    }
+
+    #[test]
+    fn messages_within_budget_marks_the_trim_after_the_system_prompt() {
+        let mut manager = manager_replying("");
+        manager.session.system_prompt = Some("be brief".into());
+        manager.session.summary = Some("talked about X".into());
+        push(&mut manager, "user", &"old ".repeat(10));
+        push(&mut manager, "assistant", &"older ".repeat(10));
+        push(&mut manager, "user", "new");
+        push(&mut manager, "assistant", "newer");
+
+        let messages = manager.messages_within_budget(20);
+
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0]["content"], "be brief");
+        assert_eq!(messages[1]["content"], format!("{TRIMMED_MARKER}\nSummary so far: talked about X"));
+        assert_eq!((messages[2]["content"].as_str(), messages[3]["content"].as_str()), (Some("new"), Some("newer")));
+    }
+
+    #[test]
+    fn messages_within_budget_can_send_tool_results_as_system() {
+        let mut manager = manager_replying("");
+        push(&mut manager, "tool", "result");
+        manager.tool_role_fallback = true;
+        assert_eq!(manager.messages_within_budget(100)[0]["role"], "system");
+    }
+
+    /// Answers with `replies` in turn, then with empty replies.
+    pub(crate) struct ScriptedBackend {
+        pub(crate) replies: std::cell::RefCell<Vec<&'static str>>,
+    }
+
+    impl LlmBackend for ScriptedBackend {
+        fn name(&self) -> &'static str {
+            "scripted"
+        }
+
+        fn stream(&self, _: &ChatRequest, _: &mut Option<ApiExchange>, _: &mut dyn FnMut(&str)) -> Result<String, AppError> {
+            let mut replies = self.replies.borrow_mut();
+            Ok(if replies.is_empty() { String::new() } else { replies.remove(0).to_string() })
+        }
+    }
+
+    fn scripted(on_empty: EmptyResponse, replies: Vec<&'static str>) -> SessionManager {
+        let mut manager = manager_replying("");
+        manager.backend = Box::new(ScriptedBackend { replies: replies.into() });
+        manager.on_empty = on_empty;
+        manager.echo_reply = false;
+        manager
+    }
+
+    #[test]
+    fn empty_replies_become_the_marker_by_default() {
+        let mut manager = scripted(EmptyResponse::default(), vec![" \n "]);
+        assert_eq!(manager.stream_reply(&[]).unwrap().as_deref(), Some(EMPTY_RESPONSE_MARKER));
+    }
+
+    #[test]
+    fn empty_replies_can_be_dropped() {
+        let mut manager = scripted(EmptyResponse::Drop, vec![""]);
+        assert_eq!(manager.stream_reply(&[]).unwrap(), None);
+    }
+
+    #[test]
+    fn empty_replies_are_retried_once() {
+        let mut manager = scripted(EmptyResponse::Retry, vec!["", "second try"]);
+        assert_eq!(manager.stream_reply(&[]).unwrap().as_deref(), Some("second try"));
+
+        let mut manager = scripted(EmptyResponse::Retry, vec!["", "", "third try"]);
+        assert_eq!(manager.stream_reply(&[]).unwrap().as_deref(), Some(EMPTY_RESPONSE_MARKER));
+    }
+
+    #[test]
+    fn non_empty_replies_are_kept_as_they_are() {
+        let mut manager = scripted(EmptyResponse::Drop, vec![" hi "]);
+        assert_eq!(manager.stream_reply(&[]).unwrap().as_deref(), Some(" hi "));
+    }
+
+    #[test]
+    fn call_chat_api_records_the_exchange_without_the_key() {
+        let body = r#"{"choices":[{"message":{"content":"hi there"}}]}"#;
+        let endpoint = endpoint(fake_server(vec![http_response("200 OK", body)]));
+        let api = quick_api();
+        let messages = [json!({"role": "user", "content": "hello"})];
+        let req = ChatRequest { api: &api, model: "m1", messages: &messages, params: &GenParams::default() };
+        let mut last = None;
+
+        assert_eq!(call_chat_api(&endpoint, &req, &mut last).unwrap(), "hi there");
+
+        let exchange = last.expect("exchange recorded");
+        assert!(exchange.url.ends_with("/v1/chat/completions"));
+        assert_eq!(exchange.status, 200);
+        assert_eq!(exchange.request_body["model"], "m1");
+        assert_eq!(exchange.response_body, body);
+        let shown = exchange.render();
+        assert!(shown.contains("Bearer <redacted>"), "{shown}");
+        assert!(!shown.contains("sk-secret"), "{shown}");
+    }
+
+    #[test]
+    fn call_chat_api_records_failed_exchanges_too() {
+        let body = r#"{"error":{"message":"bad model"}}"#;
+        let endpoint = endpoint(fake_server(vec![http_response("400 Bad Request", body)]));
+        let api = quick_api();
+        let req = ChatRequest { api: &api, model: "m1", messages: &[], params: &GenParams::default() };
+        let mut last = None;
+
+        let err = call_chat_api(&endpoint, &req, &mut last).unwrap_err();
+        assert!(err.to_string().contains("bad model"), "{err}");
+        assert_eq!(last.map(|e| e.status), Some(400));
+    }
+
+    #[test]
+    fn redacted_headers_only_mention_auth_when_a_key_is_set() {
+        let mut endpoint = endpoint(String::new());
+        assert_eq!(endpoint.redacted_headers()[0], ("Authorization".into(), "Bearer <redacted>".into()));
+        endpoint.api_key = None;
+        assert!(endpoint.redacted_headers().iter().all(|(name, _)| name != "Authorization"));
+    }
+
+    #[test]
+    fn parse_http_date_reads_imf_fixdate() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784_111_777));
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(parse_http_date("Thu, 29 Feb 2024 12:00:00 GMT"), Some(1_709_208_000));
+    }
+
+    #[test]
+    fn parse_http_date_rejects_out_of_range_fields() {
+        // Each of these used to underflow or overflow.
+        assert_eq!(parse_http_date("Mon, 01 Jan 0 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Mon, 00 Jan 2024 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Mon, 01 Jan 2024 99999999999999999:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Mon, 99999999999999999 Jan 2024 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Mon, 01 Jan 18446744073709551615 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Mon, 01 Jan 2024 24:00:00 GMT"), None);
+    }
+
+    #[test]
+    fn parse_http_date_rejects_garbage() {
+        assert_eq!(parse_http_date(""), None);
+        assert_eq!(parse_http_date("soon"), None);
+        assert_eq!(parse_http_date("Mon, 01 Foo 2024 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Mon, 01 Jan 2024"), None);
+        assert_eq!(parse_http_date("Mon, 01 Jan 2024 10:xx:00 GMT"), None);
+    }
+
+    #[test]
+    fn retry_after_takes_seconds_or_a_date() {
+        let now = 1_445_412_480; // Wed, 21 Oct 2015 07:28:00 GMT
+        assert_eq!(parse_retry_after(429, " 120 ", now), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(503, "Wed, 21 Oct 2015 07:29:30 GMT", now), Some(Duration::from_secs(90)));
+        // A date in the past means "now".
+        assert_eq!(parse_retry_after(429, "Wed, 21 Oct 2015 07:00:00 GMT", now), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn retry_after_ignores_other_statuses_and_bad_values() {
+        assert_eq!(parse_retry_after(500, "5", 0), None);
+        assert_eq!(parse_retry_after(200, "5", 0), None);
+        assert_eq!(parse_retry_after(429, "later", 0), None);
+        assert_eq!(parse_retry_after(429, "-5", 0), None);
+    }
+
+    #[test]
+    fn export_context_writes_the_next_request_messages() {
+        let mut manager = manager_replying("");
+        manager.session.system_prompt = Some("be brief".into());
+        push(&mut manager, "user", "hi");
+        push(&mut manager, "assistant", "hello");
+        let path = std::env::temp_dir().join(format!("mycli-context-{}.json", std::process::id()));
+
+        manager.export_context(&path.to_string_lossy()).unwrap();
+
+        let written: Vec<Value> = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written, manager.messages_within_budget(HISTORY_MAX_CHARS));
+        assert_eq!(written.len(), 3);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn stop_list(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn stop_filter_cuts_a_stop_split_across_chunks() {
+        let stops = stop_list(&["END"]);
+        let mut filter = StopFilter::new(&stops);
+        assert_eq!(filter.push("one E"), "one ");
+        assert_eq!(filter.push("N"), "");
+        assert!(!filter.done());
+        assert_eq!(filter.push("D two"), "");
+        assert!(filter.done());
+        assert_eq!(filter.push("three"), "");
+        assert_eq!(filter.finish(), "");
+    }
+
+    #[test]
+    fn stop_filter_releases_held_text_that_was_no_stop() {
+        let stops = stop_list(&["END", "\n\n"]);
+        let mut filter = StopFilter::new(&stops);
+        assert_eq!(filter.push("EN"), "");
+        assert_eq!(filter.push("TRY\n"), "ENTRY");
+        assert_eq!(filter.finish(), "\n");
+
+        let none = stop_list(&[""]);
+        let mut filter = StopFilter::new(&none);
+        assert_eq!(filter.push("END"), "END");
+    }
+
+    #[test]
+    fn stop_filter_cuts_at_the_earliest_stop() {
+        let stops = stop_list(&["b", "a"]);
+        let mut filter = StopFilter::new(&stops);
+        assert_eq!(filter.push("xxab"), "xx");
+        assert!(filter.done());
+    }
+
+    #[test]
+    fn partial_match_respects_char_boundaries() {
+        assert_eq!(partial_match("abc", "cde"), 1);
+        assert_eq!(partial_match("abcd", "cde"), 2);
+        assert_eq!(partial_match("abc", "xyz"), 0);
+        assert_eq!(partial_match("ab", "ab"), 0);
+        assert_eq!(partial_match("x\u{e9}", "\u{e9}t"), 2);
+    }
+
+    #[test]
+    fn call_chat_api_stream_stops_on_a_split_stop_sequence() {
+        let body = [r#"{"choices":[{"delta":{"content":"one E"}}]}"#, r#"{"choices":[{"delta":{"content":"ND two"}}]}"#]
+            .iter()
+            .map(|e| format!("data: {e}\n\n"))
+            .collect::<String>();
+        let base = fake_server(vec![http_response("200 OK", &format!("{body}data: [DONE]\n\n"))]);
+        let api = quick_api();
+        let params = GenParams { stop: stop_list(&["END"]), ..GenParams::default() };
+        let req = ChatRequest { api: &api, model: "m", messages: &[], params: &params };
+        let mut shown = String::new();
+
+        let answer = call_chat_api_stream(&endpoint(base), &req, &mut None, |c| shown.push_str(c)).unwrap();
+
+        assert_eq!(answer, "one ");
+        assert_eq!(shown, "one ");
+    }
+}
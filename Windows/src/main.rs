@@ -1,25 +1,170 @@
-use reqwest::blocking::Client;
 use std::error::Error;
 use std::io::{self, Write};
 
+#[path = "../../shared/audit.rs"]
+mod audit;
 mod session;
+#[path = "../../shared/trash.rs"]
+mod trash;
 mod llm;
 mod mcp;
+#[path = "../../shared/mcp_client.rs"]
+mod mcp_client;
 mod api_key;
+mod backend;
+mod config;
+mod error;
+mod export;
+mod interrupt;
+mod ollama;
+mod wizard;
 
+use config::Config;
+use error::AppError;
 use session::SessionManager;
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let client = Client::new();
-    let mut manager = SessionManager::new();
+    // `--once` answers one prompt from stdin and exits, for scripts;
+    // `--json` makes it print one JSON object instead of the bare answer.
+    let json = std::env::args().any(|a| a == "--json");
+    let once = json || std::env::args().any(|a| a == "--once");
+    let (log_dir, log_dir_warning) = config::usable_log_dir(&config::requested_log_dir());
+    let mut manager = SessionManager::new(log_dir);
+    manager.quiet = once;
+    manager.tool_policy.quiet = once;
+    manager.echo_reply = !json;
+    if let Some(warning) = log_dir_warning {
+        manager.note(warning);
+    }
+    manager.auto_approve = std::env::args().any(|a| a == "--yes" || a == "-y");
+    let no_wizard = once || std::env::args().any(|a| a == "--no-wizard");
+    if let Err(e) = manager.interrupt.install() {
+        manager.note(format!("⚠️  Ctrl+C will not stop /mcp runs: {e}"));
+    }
+
+    if !no_wizard && wizard::is_first_run() {
+        let config = wizard::run(&mut io::stdin().lock(), &mut io::stdout())?;
+        match config.save() {
+            Ok(path) => println!("💾 Saved config to {}\n", path.display()),
+            Err(e) => println!("⚠️ Could not save config: {e}\n"),
+        }
+    }
+
+    let mut provider = None;
+    let mut providers = backend::ProviderSettings::default();
+    let mut warmup = false;
+    match Config::load() {
+        Ok(Some(config)) => {
+            provider = config.provider;
+            manager.api.http.headers = config.headers.unwrap_or_default();
+            providers.openai_base_url = config.openai_base_url;
+            providers.openai_api_key = config.openai_api_key;
+            if let Some(model) = config.model {
+                manager.model = model;
+            }
+            manager.export_footer = config.export_footer;
+            manager.prompt_file_template = config.prompt_file_template;
+            manager.on_empty = config.empty_response.unwrap_or_default();
+            if let Some(url) = config.ollama_url {
+                manager.ollama_url = url;
+            }
+            if let Some(secs) = config.shell_timeout_secs {
+                manager.tool_policy.shell_timeout = std::time::Duration::from_secs(secs);
+            }
+            if let Some(shell) = config.shell {
+                manager.tool_policy.shell = shell;
+            }
+            if let Some(roots) = config.allowed_roots {
+                manager.tool_policy.allowed_roots = roots;
+            }
+            if let Some(on) = config.backup_on_write {
+                manager.tool_policy.backup_on_write = on;
+            }
+            if let Some(steps) = config.max_agent_steps {
+                manager.tool_policy.max_agent_steps = steps;
+            }
+            if let Some(on) = config.safe_mode {
+                manager.tool_policy.safe_mode = on;
+            }
+            for server in config.mcp_servers.unwrap_or_default() {
+                match mcp_client::McpClient::connect(&server) {
+                    Ok(client) => {
+                        manager.note(format!("🔌 MCP server '{}': {} tool(s)", client.name, client.tools.len()));
+                        manager.mcp_servers.push(client);
+                    }
+                    Err(e) => manager.note(format!("⚠️  MCP server '{}' not available: {e}", server.name)),
+                }
+            }
+            if let Some(days) = config.trash_days {
+                let purged = manager.purge_trash(days);
+                if purged > 0 {
+                    manager.note(format!("🗑️ Purged {purged} trash item(s) older than {days} day(s)"));
+                }
+            }
+            warmup = config.warmup_on_start == Some(true);
+        }
+        Ok(None) => {}
+        Err(e) => manager.note(format!("⚠️  {e}\n")),
+    }
+    // A bad proxy or header would send every request the wrong way, so
+    // stop here rather than carry on without it.
+    match llm::http_client(&manager.api.http) {
+        Ok(client) => manager.api.client = client,
+        Err(e) => {
+            eprintln!("❌ {e}");
+            std::process::exit(1);
+        }
+    }
+    if warmup {
+        manager.warmup_model();
+    }
+    // `--allow-root <dir>` (repeatable) widens the file tools' sandbox.
+    let args: Vec<String> = std::env::args().collect();
+    // `--provider <name>` overrides the config file for this run.
+    if let Some(pair) = args.windows(2).find(|pair| pair[0] == "--provider") {
+        provider = Some(pair[1].clone());
+    }
+    if let Some(name) = provider {
+        providers.ollama_url = manager.ollama_url.clone();
+        match backend::backend_for(&name, &providers) {
+            Ok(backend) => manager.backend = backend,
+            Err(e) => manager.note(format!("⚠️  {e}; using dashscope\n")),
+        }
+    }
+    for pair in args.windows(2).filter(|pair| pair[0] == "--allow-root") {
+        manager.tool_policy.allowed_roots.push(pair[1].clone().into());
+    }
+    // `--safe` keeps the agent read-only for this run.
+    if args.iter().any(|a| a == "--safe") {
+        manager.tool_policy.safe_mode = true;
+    }
+
+    if once {
+        if let Err(e) = run_once(&mut manager, args.iter().any(|a| a == "--mcp"), json) {
+            eprintln!("❌ Request failed: {e}");
+            if let Some(hint) = AppError::hint_for(e.as_ref()) {
+                eprintln!("   💡 {hint}");
+            }
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
     println!("╔══════════════════════════════════════════╗");
     println!("║ 🤖  Rust Cloud AI Console (Chat Client)   ║");
     println!("╚══════════════════════════════════════════╝");
-    println!("  Model in use  :  {}", manager.model);
+    println!("  Model in use  :  {} ({})", manager.model, manager.backend.name());
     println!("  Switch model  :  /use <model-name>");
+    if manager.tool_policy.safe_mode {
+        println!("  Safe mode     :  on (/mcp may only read and list files)");
+    }
     println!("  Help menu     :  /help");
     println!("  Exit          :  /quit\n");
+    if manager.backend.name() == "dashscope" {
+        if let Err(e) = api_key::resolve(manager.api.api_key.as_deref()) {
+            println!("⚠️  {e}\n");
+        }
+    }
     println!("💬 Start typing below:\n");
 
     loop {
@@ -55,13 +200,43 @@ fn main() -> Result<(), Box<dyn Error>> {
                     println!("🔄 Model switched to '{}'", name);
                 }
 
+                "/model warmup" => manager.warmup_model(),
+
                 x if x.starts_with("/mcp ") => {
                     let prompt = x.strip_prefix("/mcp ").unwrap().trim();
                     if let Err(e) = manager.handle_mcp_command(prompt) {
                         eprintln!("❌ MCP Agent Error: {e}");
+                        if let Some(hint) = AppError::hint_for(e.as_ref()) {
+                            eprintln!("   💡 {hint}");
+                        }
                     }
                 }
 
+                "/count" => {
+                    let c = manager.session.role_counts();
+                    println!(
+                        "🔢 Branch '{}': {} user, {} assistant, {} system/tool — {} total",
+                        manager.session.branch, c.user, c.assistant, c.other, c.total()
+                    );
+                }
+
+                "/coalesce" => {
+                    let merged = manager.session.coalesce();
+                    if merged == 0 {
+                        println!("👌 No adjacent same-role messages.");
+                    } else {
+                        println!("🧵 Merged {merged} message(s) into their neighbours.");
+                        if let Err(e) = manager.save_to_logs() {
+                            eprintln!("❌ Save error: {e}");
+                        }
+                    }
+                }
+
+                "/debug last" => match &manager.last_exchange {
+                    Some(exchange) => println!("{}\n", exchange.render()),
+                    None => println!("⚠️ No API call recorded yet."),
+                },
+
                 "/session clear" => manager.clear_all_sessions(),
 
                 "/branch clear" => manager.clear_other_branches(),
@@ -85,12 +260,63 @@ fn main() -> Result<(), Box<dyn Error>> {
                     }
                 }
 
+                x if x == "/prompt-file" || x.starts_with("/prompt-file ") => {
+                    let path = x.trim_start_matches("/prompt-file").trim();
+                    if path.is_empty() {
+                        println!("Usage: /prompt-file <path>");
+                    } else if let Err(e) = manager.handle_prompt_file_command(path) {
+                        eprintln!("❌ Prompt file error: {e}");
+                    }
+                }
+
+                x if x == "/share" || x.starts_with("/share ") => {
+                    if let Err(e) = manager.handle_share_command(x) {
+                        eprintln!("❌ Share error: {e}");
+                    }
+                }
+
+                x if x == "/export" || x.starts_with("/export ") => {
+                    if let Err(e) = manager.handle_export_command(x) {
+                        eprintln!("❌ Export error: {e}");
+                    }
+                }
+
+                x if x == "/system" || x.starts_with("/system ") => {
+                    if let Err(e) = manager.handle_system_command(x) {
+                        eprintln!("❌ System prompt error: {e}");
+                    }
+                }
+
+                x if x == "/set" || x.starts_with("/set ") => {
+                    if let Err(e) = manager.handle_set_command(x) {
+                        eprintln!("❌ Set error: {e}");
+                    }
+                }
+
+                x if x == "/params" || x.starts_with("/params ") => {
+                    if let Err(e) = manager.handle_params_command(x) {
+                        eprintln!("❌ Params error: {e}");
+                    }
+                }
+
                 x if x.starts_with("/session") => {
                     if let Err(e) = manager.handle_session_command(x) {
                         eprintln!("❌ Session error: {e}");
                     }
                 }
 
+                x if x == "/trash" || x.starts_with("/trash ") => {
+                    if let Err(e) = manager.handle_trash_command(x) {
+                        eprintln!("❌ Trash error: {e}");
+                    }
+                }
+
+                x if x == "/audit" || x.starts_with("/audit ") => {
+                    if let Err(e) = manager.handle_audit_command(x) {
+                        eprintln!("❌ Audit error: {e}");
+                    }
+                }
+
                 _ => println!("⚠️ Unknown command. Use /help."),
             }
             continue;
@@ -102,14 +328,77 @@ fn main() -> Result<(), Box<dyn Error>> {
             content: input.to_string(),
         });
 
-        if let Err(e) = manager.send_and_stream_llm(&client, input) {
+        if let Err(e) = manager.send_and_stream_llm(input) {
             eprintln!("❌ Request failed: {e}");
+            if let Some(hint) = AppError::hint_for(e.as_ref()) {
+                eprintln!("   💡 {hint}");
+            }
         }
     }
 
     Ok(())
 }
 
+/// `--once`: send the prompt read from stdin (the agent loop with `--mcp`)
+/// and stream the answer to stdout, or with `--json` print one object with
+/// the model, prompt, final answer and tool calls. Progress goes to stderr.
+fn run_once(manager: &mut SessionManager, mcp: bool, json: bool) -> Result<(), Box<dyn Error>> {
+    let prompt = io::read_to_string(io::stdin())?;
+    let prompt = prompt.trim();
+    let first_new = manager.session.messages.len();
+
+    let result = if prompt.is_empty() {
+        Err("no prompt on stdin".into())
+    } else if mcp {
+        manager.handle_mcp_command(prompt)
+    } else {
+        manager.session.messages.push(session::Message {
+            role: "user".into(),
+            content: prompt.to_string(),
+        });
+        manager.send_and_stream_llm(prompt)
+    };
+    if !json {
+        if result.is_ok() {
+            println!();
+        }
+        return result;
+    }
+
+    let output = once_json(manager, prompt, first_new, &result);
+    println!("{}", serde_json::to_string_pretty(&output)?);
+    result
+}
+
+/// The `--json` object for a one-shot run whose messages start at
+/// `first_new`: the last new assistant message is the response.
+fn once_json(
+    manager: &SessionManager,
+    prompt: &str,
+    first_new: usize,
+    result: &Result<(), Box<dyn Error>>,
+) -> serde_json::Value {
+    let response = manager
+        .session
+        .messages
+        .get(first_new..)
+        .unwrap_or_default()
+        .iter()
+        .rev()
+        .find(|m| m.role == "assistant")
+        .map(|m| m.content.clone());
+    let mut output = serde_json::json!({
+        "model": manager.model,
+        "prompt": prompt,
+        "response": response,
+        "tool_calls": manager.last_run_tools,
+    });
+    if let Err(e) = result {
+        output["error"] = e.to_string().into();
+    }
+    output
+}
+
 /// Help menu
 fn print_help() {
     println!(
@@ -119,31 +408,109 @@ Commands
 Model:
   /use                 Show current model
   /use <model>         Switch to another model
+  /params                   Show generation params
+  /system [text|clear]      Show, set or clear this session's system prompt
 
 Session:
   /session list             Show stored sessions
   /session current          Show current session ID
-  /session delete <id>      Delete a session
-  /session clear            Remove ALL sessions
+  /session delete <id>      Delete a session (moved to the trash)
+  /session clear            Remove ALL sessions (moved to the trash)
+
+Trash:
+  /trash [list]             Show deleted sessions and branches
+  /trash restore <name>     Put a deleted session or branch back
+  /trash delete <name>      Permanently delete one item
+  /trash empty              Permanently delete everything in the trash
+
+Audit:
+  /audit tail [n]           Show the last n (default 20) tool runs of the agent
 
 Branch:
   /branch new <name>        Create new branch
   /branch switch <name>     Switch branch
   /branch list              Show branches
   /branch current           Show current branch
-  /branch delete <name>     Delete a branch
+  /branch delete <name>     Delete a branch (moved to the trash)
   /branch rename <old> <new> Rename a branch
   /branch clear             Delete all branches except 'main'
 
 General:
+  /count                    Count messages per role in current branch
+  /coalesce                 Merge adjacent messages with the same role
+  /debug last               Show raw request/response of the last API call
   /save                     Save current branch
+  /model warmup             Load the current model on the local Ollama server
+  /prompt-file <path>       Send a file's contents as the prompt
+  /set var [name=value]     List or set variables for {{{{name}}}} in templates
+  /set temperature <v|off>  Sampling temperature (0-2); also top_p (0-1], max_tokens
+  /set stop <"seq"|off>     Add a stop sequence (escapes like \n work); off clears them
+  /share                    Export a redacted transcript of current branch
+  /share add <regex>        Also redact matches of <regex> when sharing
+  /share footer <t|off>     Footer for exports ({{{{model}}}} {{{{date}}}} {{{{count}}}}, or 'default')
+  /export md [all]          Write current (or all) branches as Markdown
+  /export context <file>    Write the messages the next request would send as JSON
   /load <session_id>        Load saved session
   /help                     Show help
   /quit                     Exit
 
+Flags:
+  --yes, -y                 Run shell.run commands without confirmation
+  --safe                    Refuse file changes and shell commands in /mcp runs
+  --no-wizard               Skip the first-run setup wizard
+  --once                    Answer one prompt read from stdin, print it and exit
+  --once --mcp              Same, running the prompt through the /mcp agent
+  --json                    Like --once, but print one JSON object (model, prompt,
+                            response, tool_calls) instead of the answer
+
 Notes:
 - History saved in logs/<session>_<branch>.json
 - Model context persists unless session/branch is cleared.
 "#
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::tests::ScriptedBackend;
+    use crate::mcp::ToolPolicy;
+    use serde_json::json;
+    use std::fs;
+
+    #[test]
+    fn once_json_reports_the_answer_and_every_tool_call() {
+        let dir = std::env::temp_dir().join(format!("mycli-once-json-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("work")).unwrap();
+        fs::write(dir.join("work/a.txt"), "hello").unwrap();
+        let mut manager = SessionManager::new(dir.join("logs"));
+        manager.echo_reply = false;
+        manager.tool_policy = ToolPolicy {
+            workspace_root: dir.join("work"),
+            allowed_roots: vec![dir.join("work")],
+            ..ToolPolicy::for_log_dir(&dir.join("logs"))
+        };
+        let replies = vec![r#"<use_tool name="filesystem.read" params={"path": "a.txt"} />"#, "It says hello. Done."];
+        manager.backend = Box::new(ScriptedBackend { replies: replies.into() });
+
+        let result = manager.handle_mcp_command("read a.txt");
+        let output = once_json(&manager, "read a.txt", 0, &result);
+
+        assert_eq!(output["prompt"], "read a.txt");
+        assert_eq!(output["response"], "It says hello. Done.");
+        assert_eq!(output["tool_calls"][0]["name"], "filesystem.read");
+        assert_eq!(output["tool_calls"][0]["params"], json!({"path": "a.txt"}));
+        assert!(output["tool_calls"][0]["result"].as_str().unwrap().contains("hello"));
+        assert!(output.get("error").is_none());
+    }
+
+    #[test]
+    fn once_json_adds_the_error_of_a_failed_run() {
+        let manager = SessionManager::new(std::env::temp_dir().join("mycli-once-json-failed"));
+        let output = once_json(&manager, "", 0, &Err("no prompt on stdin".into()));
+        assert_eq!(output["response"], serde_json::Value::Null);
+        assert_eq!(output["tool_calls"], json!([]));
+        assert_eq!(output["error"], "no prompt on stdin");
+    }
+}
@@ -1,91 +1,324 @@
-use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use crate::session::{Message, SessionManager};
-use crate::llm::call_chat_api;
+use crate::audit::{self, AuditEntry};
+use crate::config::DEFAULT_LOG_DIR;
+use crate::trash::move_to_trash;
+use crate::session::{ask_confirm, Message, SessionManager};
+use crate::error::AppError;
+use crate::llm::HISTORY_MAX_CHARS;
+use crate::mcp_client::prompt_lines;
 use std::error::Error;
 use std::fs;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use regex::Regex;
 
+/// Restrictions applied to filesystem tools.
+#[derive(Debug, Clone)]
+pub struct ToolPolicy {
+    /// Directory that filesystem.move / filesystem.delete may not escape.
+    pub workspace_root: PathBuf,
+    /// Directories filesystem.read / write / list may touch.
+    pub allowed_roots: Vec<PathBuf>,
+    /// Copy files into `backup_dir` before filesystem.write overwrites them.
+    pub backup_on_write: bool,
+    pub backup_dir: PathBuf,
+    /// Model calls one agent run may make before it is stopped.
+    pub max_agent_steps: usize,
+    /// Move deleted files into `trash_dir` instead of unlinking them.
+    pub safe_delete: bool,
+    pub trash_dir: PathBuf,
+    /// Largest file filesystem.read (and `/prompt-file`) will load, in bytes.
+    pub max_read_bytes: u64,
+    /// How long shell.run may take before the command is killed.
+    pub shell_timeout: Duration,
+    /// Interpreter shell.run commands are handed to.
+    pub shell: Shell,
+    /// Refuse every tool that changes files or runs commands (`--safe`).
+    pub safe_mode: bool,
+    /// JSON-lines record of every built-in tool run (`/audit tail`).
+    pub audit_log: PathBuf,
+    /// Send tool progress to stderr, so `--once` keeps stdout for the answer.
+    pub quiet: bool,
+}
+
+impl ToolPolicy {
+    /// The defaults, with the trash and backups inside `log_dir`.
+    pub fn for_log_dir(log_dir: &Path) -> Self {
+        Self {
+            trash_dir: log_dir.join(".trash"),
+            backup_dir: log_dir.join("backups"),
+            audit_log: log_dir.join(AUDIT_LOG_FILE),
+            ..Self::default()
+        }
+    }
+
+    /// Print a tool progress line, like `SessionManager::note`.
+    fn note(&self, text: impl std::fmt::Display) {
+        if self.quiet {
+            eprintln!("{text}");
+        } else {
+            println!("{text}");
+        }
+    }
+}
+
+impl Default for ToolPolicy {
+    fn default() -> Self {
+        Self {
+            workspace_root: std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")),
+            allowed_roots: vec![std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."))],
+            safe_delete: true,
+            trash_dir: PathBuf::from(DEFAULT_LOG_DIR).join(".trash"),
+            backup_on_write: true,
+            backup_dir: PathBuf::from(DEFAULT_LOG_DIR).join("backups"),
+            max_agent_steps: 10,
+            max_read_bytes: 256 * 1024,
+            shell_timeout: Duration::from_secs(30),
+            shell: Shell::default(),
+            safe_mode: false,
+            audit_log: PathBuf::from(DEFAULT_LOG_DIR).join(AUDIT_LOG_FILE),
+            quiet: false,
+        }
+    }
+}
+
+/// Name of the audit log inside the log directory. It is not a session
+/// log, so deleting sessions leaves it alone.
+pub const AUDIT_LOG_FILE: &str = "audit.jsonl";
+
+/// What a tool refused in safe mode returns to the model.
+const SAFE_MODE_REFUSAL: &str = "tool disabled in safe mode";
+
+/// Built-in tools that change files or run commands.
+fn is_mutating(name: &str) -> bool {
+    matches!(
+        name,
+        "filesystem.write" | "filesystem.move" | "filesystem.delete" | "filesystem.restore" | "shell.run"
+    )
+}
+
+/// Tool lines of the agent prompt that safe mode leaves out.
+const WRITE_TOOLS_PROMPT: &str = "\
+    - filesystem.write — write text into a file. Example: <use_tool name=\"filesystem.write\" params={\"path\": \"output.txt\", \"content\": \"Hello\"} />\n\
+    - filesystem.move  — move or rename a file. Example: <use_tool name=\"filesystem.move\" params={\"src\": \"old.txt\", \"dst\": \"new.txt\"} />\n\
+    - filesystem.delete — delete a file. Example: <use_tool name=\"filesystem.delete\" params={\"path\": \"old.txt\"} />\n\
+    - filesystem.restore — put back a backup made by filesystem.write. Example: <use_tool name=\"filesystem.restore\" params={\"src\": \"logs/backups/notes.txt.1700000000000\", \"dst\": \"notes.txt\"} />\n\
+    - shell.run — run shell commands. Example: <use_tool name=\"shell.run\" params={\"content\": \"mkdir Playground\"} />\n";
+
+/// Told to the model instead of `WRITE_TOOLS_PROMPT` in safe mode.
+const SAFE_MODE_PROMPT: &str = "Safe mode is on: only the tools above are available. Writing, moving, \
+    deleting or restoring files, running shell commands and tools of other servers are disabled, so do not try them.\n";
+
+/// Interpreter for shell.run: `cmd`, `powershell` or `pwsh` on Windows,
+/// `sh`, `bash` or `zsh` elsewhere (`shell` in the config file).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Shell {
+    Cmd,
+    Powershell,
+    Pwsh,
+    Sh,
+    Bash,
+    Zsh,
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        if cfg!(target_os = "windows") {
+            Shell::Cmd
+        } else {
+            Shell::Sh
+        }
+    }
+}
+
+impl Shell {
+    pub fn name(self) -> &'static str {
+        match self {
+            Shell::Cmd => "cmd",
+            Shell::Powershell => "powershell",
+            Shell::Pwsh => "pwsh",
+            Shell::Sh => "sh",
+            Shell::Bash => "bash",
+            Shell::Zsh => "zsh",
+        }
+    }
+
+    /// Program and arguments that run `command`.
+    fn command(self, command: &str) -> Command {
+        let mut cmd = Command::new(self.name());
+        match self {
+            Shell::Cmd => cmd.args(["/C", command]),
+            Shell::Powershell | Shell::Pwsh => cmd.args(["-NoProfile", "-Command", command]),
+            Shell::Sh | Shell::Bash | Shell::Zsh => cmd.args(["-c", command]),
+        };
+        cmd
+    }
+
+    /// Line for the system prompt telling the model which syntax to use.
+    fn prompt_note(self) -> String {
+        match self {
+            Shell::Cmd => "shell.run commands run in cmd.exe (`cmd /C`): write cmd syntax, and add /q where a command would otherwise ask for confirmation.".to_string(),
+            Shell::Powershell | Shell::Pwsh => format!(
+                "shell.run commands run in PowerShell (`{} -NoProfile -Command`): write PowerShell syntax.",
+                self.name()
+            ),
+            Shell::Sh | Shell::Bash | Shell::Zsh => {
+                format!("shell.run commands run in {0} (`{0} -c`): write POSIX shell syntax.", self.name())
+            }
+        }
+    }
+}
+
 /// Agentic workflow bound to SessionManager
 impl SessionManager {
+    /// Run the agent on `prompt`. Ctrl+C stops it before the next model
+    /// call or tool run; everything up to that point stays in the log.
     pub fn handle_mcp_command(&mut self, prompt: &str) -> Result<(), Box<dyn Error>> {
-        let client = Client::new();
+        self.interrupt.arm();
+        self.last_run_tools.clear();
+        let result = match self.run_agent(prompt) {
+            Err(e) if matches!(e.downcast_ref::<AppError>(), Some(AppError::Cancelled)) => {
+                self.note("⏹️  Agent interrupted.\n");
+                Ok(())
+            }
+            other => other,
+        };
+        self.interrupt.disarm();
+        result
+    }
 
+    fn run_agent(&mut self, prompt: &str) -> Result<(), Box<dyn Error>> {
         // store initial user prompt into session log
         self.session.messages.push(Message {
             role: "user".into(),
             content: prompt.into(),
         });
-        self.save_to_logs().ok();
+        self.autosave();
 
-        let system_mcp_prompt = format!(
+        let safe_mode = self.tool_policy.safe_mode;
+        let system_mcp_prompt =
             "You are an AI assistant with access to MCP tools.\n\
             Available tools:\n\
-            - filesystem.read  — read file content. Example: <use_tool name=\"filesystem.read\" params={{\"path\": \"src/main.rs\"}} />\n\
-            - filesystem.write — write text into a file. Example: <use_tool name=\"filesystem.write\" params={{\"path\": \"output.txt\", \"content\": \"Hello\"}} />\n\
-            - shell.run — run shell commands. Example: <use_tool name=\"shell.run\" params={{\"content\": \"mkdir Playground\"}} />\n\
-            Notice that those commands working on windows system. Try add /q if necessary.\n\
+            - filesystem.read  — read file content. Example: <use_tool name=\"filesystem.read\" params={\"path\": \"src/main.rs\"} />\n\
+              For big files add \"start_line\" / \"end_line\" (1-based, inclusive) to read a part: params={\"path\": \"src/main.rs\", \"start_line\": 1, \"end_line\": 100}\n\
+            - filesystem.list  — list a directory (defaults to \".\"). Example: <use_tool name=\"filesystem.list\" params={\"path\": \"src\"} />\n\
+            {write_tools}{servers}{shell}\n\
             When using a tool, use EXACTLY this XML-style syntax.\n\
             You can add some explaining information after a tool call, but take care of format for readability.\n\
             You can use **only one <use_tool> command per message.** If you need to use multiple tools, call them one by one — wait for the tool's result before issuing the next <use_tool>.\n\
             You can use **only one <use_tool> command per message.**\n\
             You can use **only one <use_tool> command per message.**\n\
             When you are done, end your final output with 'Done.'\n"
-        );
+            .replace("{write_tools}", if safe_mode { SAFE_MODE_PROMPT } else { WRITE_TOOLS_PROMPT })
+            .replace("{servers}", &if safe_mode { String::new() } else { prompt_lines(&self.mcp_servers) })
+            .replace("{shell}", &if safe_mode { String::new() } else { self.tool_policy.shell.prompt_note() });
 
-        loop {
-            let history = self.history_string();
-
-            // 用 system + user 的 messages 调 DashScope
-            let messages = vec![
-                serde_json::json!({
-                    "role": "system",
-                    "content": system_mcp_prompt,
-                }),
-                serde_json::json!({
-                    "role": "user",
-                    "content": format!(
-                        "User initial prompt:\n{}\n\nConversation so far:\n{}\n\n\
-                         Continue reasoning or issue next tool call if needed.",
-                        prompt, history
-                    ),
-                }),
-            ];
-
-            let answers = call_chat_api(&client, &self.model, &messages)?;
+        let mut steps = 0;
+        let mut last_call: Option<ToolCall> = None;
+        'agent: loop {
+            if self.interrupt.is_requested() {
+                return Err(AppError::Cancelled.into());
+            }
+            if steps == self.tool_policy.max_agent_steps {
+                self.stop_agent(&format!("tool call limit reached after {steps} steps"));
+                break;
+            }
+            steps += 1;
+
+            // The conversation as real turns (the prompt is its last user
+            // message); the tool instructions go right after the session's
+            // own system prompt.
+            let mut messages = self.messages_within_budget(HISTORY_MAX_CHARS);
+            let at = usize::from(self.session.system_prompt.is_some());
+            messages.insert(at, serde_json::json!({ "role": "system", "content": system_mcp_prompt }));
 
             // 打印 agent 的输出
-            println!("{answers}\n");
+            let Some(answers) = self.stream_reply(&messages)? else {
+                if self.echo_reply {
+                    println!("\n");
+                }
+                break;
+            };
+            if self.echo_reply {
+                println!("\n");
+            }
 
             // store assistant output
             self.session.messages.push(Message {
                 role: "assistant".into(),
                 content: answers.clone(),
             });
-            self.save_to_logs().ok();
-
-            // detect tool call
-            if let Some(tool_call) = parse_tool_use(&answers) {
-                println!("\n⚙️  Detected MCP command: {:?}\n", tool_call);
-                let result = execute_mcp(&tool_call)?;
-
-                // log result of tool into session
-                self.session.messages.push(Message {
-                    role: "system".into(),
-                    content: format!("[Tool: {}]\nresult: {}", tool_call.name, result),
-                });
-                self.save_to_logs().ok();
+            self.autosave();
+
+            // detect tool calls (a message may contain several)
+            let tool_calls = parse_tool_uses(&answers);
+            if !tool_calls.is_empty() {
+                for tool_call in &tool_calls {
+                    if self.interrupt.is_requested() {
+                        self.note(format!("⏹️  {} was not run.", tool_call.name));
+                        return Err(AppError::Cancelled.into());
+                    }
+                    // The same call twice in a row means the model is stuck.
+                    if last_call.as_ref() == Some(tool_call) {
+                        self.stop_agent(&format!("{} repeated with the same arguments", tool_call.name));
+                        break 'agent;
+                    }
+                    last_call = Some(tool_call.clone());
+                    self.note(format!("\n⚙️  Detected MCP command: {:?}\n", tool_call));
+                    let server = self
+                        .mcp_servers
+                        .iter()
+                        .find_map(|s| s.owns(&tool_call.name).map(|tool| (s, tool)));
+                    let result = if safe_mode && server.is_some() {
+                        // Server tools may change anything; safe mode cannot tell.
+                        self.note(format!("🔒 Refused {}: safe mode is on", tool_call.name));
+                        format!("error: {SAFE_MODE_REFUSAL}")
+                    } else if let Some((server, tool)) = server {
+                        // Tools of configured servers come first; the
+                        // built-in ones below are the fallback.
+                        self.note(format!("🔌 Calling {tool} on MCP server '{}'", server.name));
+                        self.tool_caches.entry(self.session.id.clone()).or_default().invalidate(tool_call);
+                        server
+                            .call_tool(tool, tool_call.params.clone())
+                            .unwrap_or_else(|e| format!("error: {e}"))
+                    } else if tool_call.name == "shell.run" && !safe_mode && !self.approve_shell(tool_call) {
+                        self.note("❎ Command rejected.");
+                        "user rejected command".to_string()
+                    } else {
+                        // A failing tool must not abort the rest of the batch.
+                        let cache = self.tool_caches.entry(self.session.id.clone()).or_default();
+                        execute_cached(tool_call, &self.tool_policy, cache, &self.session.id)
+                            .unwrap_or_else(|e| format!("error: {e}"))
+                    };
+
+                    self.last_run_tools.push(ToolRecord {
+                        name: tool_call.name.clone(),
+                        params: tool_call.params.clone(),
+                        result: result.clone(),
+                    });
+                    // log result of tool into session
+                    self.session.messages.push(Message {
+                        role: "tool".into(),
+                        content: format!("[Tool: {}]\nresult: {}", tool_call.name, result),
+                    });
+                    self.autosave();
+                }
             } else {
-                println!("✅ No further tool use detected — session complete.");
+                self.note("✅ No further tool use detected — session complete.");
                 if answers.to_lowercase().contains("done.") {
-                    println!("🏁 Model signaled completion.\n");
+                    self.note("🏁 Model signaled completion.\n");
                 }
                 break;
             }
 
             // if model said Done -> finish agent
             if answers.to_lowercase().contains("done.") {
-                println!("🏁 Model signaled completion.\n");
+                self.note("🏁 Model signaled completion.\n");
                 break;
             }
         }
@@ -94,30 +327,214 @@ impl SessionManager {
     }
 }
 
+impl SessionManager {
+    /// End an agent run early: tell the user and leave a note in the log
+    /// so the next turn knows why the work stopped.
+    fn stop_agent(&mut self, reason: &str) {
+        self.note(format!("🛑 Agent stopped: {reason}.\n"));
+        self.session.messages.push(Message {
+            role: "system".into(),
+            content: format!("[Agent stopped: {reason}]"),
+        });
+        self.autosave();
+    }
+
+    /// Ask the user before running a model-generated shell command,
+    /// unless auto-approve (`--yes`) is on.
+    fn approve_shell(&self, tool_call: &ToolCall) -> bool {
+        if self.auto_approve {
+            return true;
+        }
+        let command = tool_call.content.as_deref().unwrap_or("");
+        // With `--once` stdin holds the prompt, not an answer.
+        if self.quiet {
+            self.note(format!("❎ Not running `{command}` without --yes."));
+            return false;
+        }
+        ask_confirm(&format!("🖥️ Run shell command `{command}`?"))
+    }
+}
+
+/// A tool call of an `/mcp` run and what it returned, for `--json`.
+#[derive(Serialize, Debug, Clone)]
+pub struct ToolRecord {
+    pub name: String,
+    pub params: Value,
+    pub result: String,
+}
+
 /// Struct for parsed tool info
-#[derive(Debug)]
+#[derive(Debug, Default, Clone, PartialEq)]
 struct ToolCall {
     name: String,
     path: Option<String>,
     content: Option<String>,
+    /// Destination for filesystem.move.
+    dst: Option<String>,
+    /// 1-based inclusive line range for filesystem.read.
+    start_line: Option<usize>,
+    end_line: Option<usize>,
+    /// The whole `params` object, passed on as the arguments of MCP
+    /// server tools.
+    params: Value,
 }
 
-/// Parse MCP-style tool command from model output
-fn parse_tool_use(output: &str) -> Option<ToolCall> {
-    // First try to capture the whole params JSON object (dot matches newlines with (?s))
-    let re_full = Regex::new(r#"(?s)<use_tool\s+name="([^"]+)"\s+params=(\{.*?\})\s*/?>"#).ok()?;
-    if let Some(caps) = re_full.captures(output) {
-        let name = caps.get(1)?.as_str().to_string();
-        let params_str = caps.get(2)?.as_str();
+impl ToolCall {
+    /// Build a call from its JSON `params`. Line numbers may be given as
+    /// numbers or numeric strings.
+    fn from_params(name: String, params: &Value) -> Self {
+        let field = |key: &str| params.get(key).and_then(|v| v.as_str()).map(|s| s.to_string());
+        let line = |key: &str| {
+            params.get(key).and_then(|v| match v {
+                Value::Number(n) => n.as_u64().map(|n| n as usize),
+                Value::String(s) => s.trim().parse().ok(),
+                _ => None,
+            })
+        };
+        ToolCall {
+            name,
+            path: field("path").or_else(|| field("src")),
+            content: field("content"),
+            dst: field("dst"),
+            start_line: line("start_line"),
+            end_line: line("end_line"),
+            params: params.clone(),
+        }
+    }
+}
+
+/// Cut `content` down to the 1-based inclusive `start..=end` lines, clamped
+/// to the file. Returns the slice and a header such as "lines 10-40 of 2000".
+fn slice_lines(content: &str, start: Option<usize>, end: Option<usize>) -> (String, String) {
+    let lines: Vec<&str> = content.lines().collect();
+    let total = lines.len();
+    let want_start = start.unwrap_or(1);
+    let want_end = end.unwrap_or(total);
+    let first = want_start.max(1);
+    let last = want_end.min(total);
+
+    if total == 0 || first > last {
+        return (String::new(), format!("no lines in range {want_start}-{want_end} (file has {total} lines)"));
+    }
+    let mut header = format!("lines {first}-{last} of {total}");
+    if first != want_start || last != want_end {
+        header.push_str(&format!(", clamped from {want_start}-{want_end}"));
+    }
+    (lines[first - 1..last].join("\n"), header)
+}
 
-        // Try parsing params as JSON
-        if let Ok(json_val) = serde_json::from_str::<Value>(params_str) {
-            let path = json_val.get("path").and_then(|v| v.as_str()).map(|s| s.to_string());
-            let content = json_val.get("content").and_then(|v| v.as_str()).map(|s| s.to_string());
-            return Some(ToolCall { name, path, content });
+/// Parse every MCP-style tool command in `output`, in order of appearance.
+/// Falls back to the single-call parser when the full pattern finds nothing.
+fn parse_tool_uses(output: &str) -> Vec<ToolCall> {
+    let calls: Vec<ToolCall> = tool_tags(output)
+        .into_iter()
+        .map(|(name, params)| ToolCall::from_params(name, &params))
+        .collect();
+
+    if calls.is_empty() {
+        parse_tool_use(output).into_iter().collect()
+    } else {
+        calls
+    }
+}
+
+/// Name and JSON params of every `<use_tool name="..." params={...}` in
+/// `output`. The params are cut out by brace matching, so quotes, braces
+/// and newlines inside string values do not end them early, and a missing
+/// `/>` does not matter. Unparseable params are skipped.
+fn tool_tags(output: &str) -> Vec<(String, Value)> {
+    let Ok(re_tag) = Regex::new(r#"<use_tool\s+name="([^"]+)"\s+params="#) else {
+        return Vec::new();
+    };
+    re_tag
+        .captures_iter(output)
+        .filter_map(|caps| {
+            let name = caps.get(1)?.as_str().to_string();
+            let rest = output[caps.get(0)?.end()..].trim_start();
+            let params = balanced_json(rest)?;
+            let value = serde_json::from_str::<Value>(params)
+                .or_else(|_| serde_json::from_str::<Value>(&escape_controls_in_strings(params)))
+                .ok()?;
+            Some((name, value))
+        })
+        .collect()
+}
+
+/// The balanced `{...}` object at the start of `s`, ignoring braces inside
+/// JSON strings. `None` if `s` does not start with `{` or never closes.
+fn balanced_json(s: &str) -> Option<&str> {
+    if !s.starts_with('{') {
+        return None;
+    }
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&s[..=i]);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Models often put raw newlines and tabs inside JSON strings; escape them
+/// so the params still parse.
+fn escape_controls_in_strings(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in s.chars() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                '\n' => {
+                    out.push_str("\\n");
+                    continue;
+                }
+                '\r' => {
+                    out.push_str("\\r");
+                    continue;
+                }
+                '\t' => {
+                    out.push_str("\\t");
+                    continue;
+                }
+                _ => {}
+            }
+        } else if c == '"' {
+            in_string = true;
         }
-        // If parsing failed, continue to fallback regexes below
+        out.push(c);
     }
+    out
+}
+
+/// Parse MCP-style tool command from model output
+fn parse_tool_use(output: &str) -> Option<ToolCall> {
+    // First try the brace-matched params JSON object
+    if let Some((name, params)) = tool_tags(output).into_iter().next() {
+        return Some(ToolCall::from_params(name, &params));
+    }
+    // If parsing failed, continue to fallback regexes below
 
     // Fallback: simple read command
     let re_read = Regex::new(
@@ -128,7 +545,7 @@ fn parse_tool_use(output: &str) -> Option<ToolCall> {
         return Some(ToolCall {
             name: "filesystem.read".into(),
             path: Some(caps[1].to_string()),
-            content: None,
+            ..Default::default()
         });
     }
 
@@ -142,6 +559,7 @@ fn parse_tool_use(output: &str) -> Option<ToolCall> {
             name: "filesystem.write".into(),
             path: Some(caps[1].to_string()),
             content: Some(caps[2].to_string()),
+            ..Default::default()
         });
     }
 
@@ -153,32 +571,270 @@ fn parse_tool_use(output: &str) -> Option<ToolCall> {
     if let Some(caps) = re_shell.captures(output) {
         return Some(ToolCall {
             name: "shell.run".into(),
-            path: None,
             content: Some(caps[1].to_string()),
+            ..Default::default()
         });
     }
 
     None
 }
 
-/// Simulate MCP tools (filesystem.read, filesystem.write, shell.run)
-fn execute_mcp(tool: &ToolCall) -> Result<String, Box<dyn Error>> {
+/// Most results `ToolCache` keeps, and their total size in bytes.
+const TOOL_CACHE_ENTRIES: usize = 32;
+const TOOL_CACHE_BYTES: usize = 1024 * 1024;
+
+/// Tool name, path, content and line range: what makes two calls identical.
+type CacheKey = (String, Option<String>, Option<String>, Option<usize>, Option<usize>);
+
+/// Results of earlier filesystem.read / filesystem.list calls in a session,
+/// so a repeated call is answered from memory while the file or directory
+/// is unchanged.
+#[derive(Debug, Default)]
+pub struct ToolCache {
+    /// Least recently used first.
+    entries: Vec<CachedResult>,
+}
+
+#[derive(Debug)]
+struct CachedResult {
+    key: CacheKey,
+    /// Modification time and size of the path when the result was made.
+    /// A directory's time changes when entries come or go, not when a file
+    /// in it grows, so sizes in a listing can lag behind edits made outside
+    /// the agent.
+    stamp: (SystemTime, u64),
+    result: String,
+}
+
+impl ToolCache {
+    fn key(tool: &ToolCall) -> CacheKey {
+        (tool.name.clone(), tool.path.clone(), tool.content.clone(), tool.start_line, tool.end_line)
+    }
+
+    /// Current modification time and size of the path `tool` reads or lists.
+    fn stamp(tool: &ToolCall, policy: &ToolPolicy) -> Option<(SystemTime, u64)> {
+        let raw = tool.path.as_deref().unwrap_or(".");
+        let meta = fs::metadata(sandbox_path(&policy.allowed_roots, Path::new(raw)).ok()?).ok()?;
+        Some((meta.modified().ok()?, meta.len()))
+    }
+
+    /// The earlier result of an identical call, if its path is unchanged.
+    fn get(&mut self, tool: &ToolCall, policy: &ToolPolicy) -> Option<String> {
+        let key = Self::key(tool);
+        let pos = self.entries.iter().position(|e| e.key == key)?;
+        let entry = self.entries.remove(pos);
+        if Self::stamp(tool, policy) != Some(entry.stamp) {
+            return None;
+        }
+        let result = entry.result.clone();
+        self.entries.push(entry);
+        Some(result)
+    }
+
+    fn put(&mut self, tool: &ToolCall, policy: &ToolPolicy, result: &str) {
+        let Some(stamp) = Self::stamp(tool, policy) else {
+            return;
+        };
+        let key = Self::key(tool);
+        self.entries.retain(|e| e.key != key);
+        self.entries.push(CachedResult { key, stamp, result: result.to_string() });
+        while self.entries.len() > TOOL_CACHE_ENTRIES
+            || self.entries.iter().map(|e| e.result.len()).sum::<usize>() > TOOL_CACHE_BYTES
+        {
+            self.entries.remove(0);
+        }
+    }
+
+    /// Forget what `tool` may change: the files a file tool touches and
+    /// every listing, or everything for shell commands and server tools.
+    fn invalidate(&mut self, tool: &ToolCall) {
+        match tool.name.as_str() {
+            "filesystem.read" | "filesystem.list" => {}
+            "filesystem.write" | "filesystem.move" | "filesystem.delete" | "filesystem.restore" => {
+                let touched = [tool.path.as_ref(), tool.dst.as_ref()];
+                self.entries
+                    .retain(|e| e.key.0 == "filesystem.read" && !touched.contains(&e.key.1.as_ref()));
+            }
+            _ => self.entries.clear(),
+        }
+    }
+}
+
+/// `execute_mcp`, with a repeated read or listing answered from `cache`
+/// (marked "(cached)") and other tools clearing what they may change.
+fn execute_cached(
+    tool: &ToolCall,
+    policy: &ToolPolicy,
+    cache: &mut ToolCache,
+    session_id: &str,
+) -> Result<String, Box<dyn Error>> {
+    if !matches!(tool.name.as_str(), "filesystem.read" | "filesystem.list") {
+        cache.invalidate(tool);
+        return execute_mcp(tool, policy, session_id);
+    }
+    if let Some(result) = cache.get(tool, policy) {
+        policy.note(format!("♻️  Reused the earlier result of {}", tool.name));
+        return Ok(format!("(cached) {result}"));
+    }
+    let result = execute_mcp(tool, policy, session_id)?;
+    cache.put(tool, policy, &result);
+    Ok(result)
+}
+
+/// Run a built-in tool and append what happened to the audit log.
+fn execute_mcp(tool: &ToolCall, policy: &ToolPolicy, session_id: &str) -> Result<String, Box<dyn Error>> {
+    let started = Instant::now();
+    let mut exit_code = None;
+    let result = run_tool(tool, policy, &mut exit_code);
+
+    let shell = tool.name == "shell.run";
+    audit::append(
+        &policy.audit_log,
+        &AuditEntry {
+            ts: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            session: session_id.to_string(),
+            tool: tool.name.clone(),
+            path: if shell { None } else { tool.path.clone() },
+            dst: tool.dst.clone(),
+            command: if shell { tool.content.clone() } else { None },
+            // Sandbox refusals come back as results for the model to read.
+            ok: result.as_ref().is_ok_and(|r| !r.starts_with("Blocked ")),
+            result_len: result.as_ref().map_or(0, |r| r.len()),
+            exit_code,
+            error: result.as_ref().err().map(|e| e.to_string()),
+            duration_ms: started.elapsed().as_millis() as u64,
+        },
+    );
+    result
+}
+
+/// Simulate MCP tools (filesystem.read, filesystem.list, filesystem.write, filesystem.move,
+/// filesystem.delete, filesystem.restore, shell.run). A shell command's exit
+/// code is left in `exit_code`.
+fn run_tool(tool: &ToolCall, policy: &ToolPolicy, exit_code: &mut Option<i32>) -> Result<String, Box<dyn Error>> {
+    if policy.safe_mode && is_mutating(&tool.name) {
+        policy.note(format!("🔒 Refused {}: safe mode is on", tool.name));
+        return Err(SAFE_MODE_REFUSAL.into());
+    }
     match tool.name.as_str() {
         "filesystem.read" => {
-            let path = tool.path.as_ref().ok_or("Missing path for filesystem.read")?;
-            let content = fs::read_to_string(path)?;
-            println!("📂 Read file '{}': {} bytes", path, content.len());
-            Ok(format!("Read file '{}' ({} bytes). Content:\n{}", path, content.len(), content))
+            let raw = tool.path.as_ref().ok_or("Missing path for filesystem.read")?;
+            let path = match sandbox_path(&policy.allowed_roots, Path::new(raw)) {
+                Ok(p) => p,
+                Err(e) => return Ok(format!("Blocked filesystem.read: {e}")),
+            };
+            if tool.start_line.is_none() && tool.end_line.is_none() {
+                let content = read_limited(&path, policy.max_read_bytes)?;
+                policy.note(format!("📂 Read file '{}': {} bytes", raw, content.len()));
+                return Ok(format!("Read file '{}' ({} bytes). Content:\n{}", raw, content.len(), content));
+            }
+
+            // A range may come from a file too big to read whole; the
+            // size limit applies to the slice instead.
+            let (content, header) = slice_lines(&fs::read_to_string(&path)?, tool.start_line, tool.end_line);
+            if content.len() as u64 > policy.max_read_bytes {
+                return Ok(format!(
+                    "Range {header} of '{raw}' is too large ({} bytes, limit {}); ask for fewer lines.",
+                    content.len(),
+                    policy.max_read_bytes
+                ));
+            }
+            policy.note(format!("📂 Read file '{}': {}", raw, header));
+            Ok(format!("Read file '{}', {}. Content:\n{}", raw, header, content))
+        }
+
+        "filesystem.list" => {
+            let raw = Path::new(tool.path.as_deref().unwrap_or("."));
+            match sandbox_path(&policy.allowed_roots, raw) {
+                Ok(path) => Ok(list_dir(&path)),
+                Err(e) => Ok(format!("Blocked filesystem.list: {e}")),
+            }
         }
 
         "filesystem.write" => {
-            let path = tool.path.as_ref().ok_or("Missing path for filesystem.write")?;
+            let raw = tool.path.as_ref().ok_or("Missing path for filesystem.write")?;
+            let path = match sandbox_path(&policy.allowed_roots, Path::new(raw)) {
+                Ok(p) => p,
+                Err(e) => return Ok(format!("Blocked filesystem.write: {e}")),
+            };
             let data_raw = tool.content.as_ref().ok_or("Missing content for filesystem.write")?;
             let data = normalize_escaped_content(data_raw);
 
-            fs::write(path, &data)?;
-            println!("💾 Wrote {} bytes to '{}'", data.len(), path);
-            Ok(format!("Wrote {} bytes to '{}'.", data.len(), path))
+            let backup = back_up_before_write(&path, policy)?;
+            fs::write(&path, &data)?;
+            policy.note(format!("💾 Wrote {} bytes to '{}'", data.len(), raw));
+            Ok(format!("Wrote {} bytes to '{}'.{}", data.len(), raw, backup_note(backup.as_ref())))
+        }
+
+        "filesystem.restore" => {
+            let backup_raw = tool.path.as_ref().ok_or("Missing src (backup file) for filesystem.restore")?;
+            let dst_raw = tool.dst.as_ref().ok_or("Missing dst for filesystem.restore")?;
+
+            // Backups are named relative to the working directory, like
+            // the paths in filesystem.write results.
+            let backup_abs = std::env::current_dir()?.join(backup_raw);
+            let backup = match sandbox_path(std::slice::from_ref(&policy.backup_dir), &backup_abs) {
+                Ok(p) => p,
+                Err(e) => return Ok(format!("Blocked filesystem.restore: {e}")),
+            };
+            let dst = match sandbox_path(&policy.allowed_roots, Path::new(dst_raw)) {
+                Ok(p) => p,
+                Err(e) => return Ok(format!("Blocked filesystem.restore: {e}")),
+            };
+            if !backup.is_file() {
+                return Ok(format!("No backup at '{}'.", backup.display()));
+            }
+
+            let current = back_up_before_write(&dst, policy)?;
+            fs::copy(&backup, &dst)?;
+            policy.note(format!("♻️ Restored '{}' from '{}'", dst_raw, backup.display()));
+            Ok(format!("Restored '{}' from '{}'.{}", dst_raw, backup.display(), backup_note(current.as_ref())))
+        }
+
+        "filesystem.move" => {
+            let src_raw = tool.path.as_ref().ok_or("Missing src for filesystem.move")?;
+            let dst_raw = tool.dst.as_ref().ok_or("Missing dst for filesystem.move")?;
+
+            let (src, dst) = match (
+                confine_to_root(&policy.workspace_root, src_raw),
+                confine_to_root(&policy.workspace_root, dst_raw),
+            ) {
+                (Ok(src), Ok(dst)) => (src, dst),
+                (Err(e), _) | (_, Err(e)) => return Ok(format!("Blocked filesystem.move: {e}")),
+            };
+
+            if dst.exists() {
+                return Ok(format!("Blocked filesystem.move: '{}' already exists.", dst.display()));
+            }
+            if let Some(parent) = dst.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(&src, &dst)?;
+            policy.note(format!("📦 Moved '{}' → '{}'", src.display(), dst.display()));
+            Ok(format!("Moved '{}' to '{}'.", src.display(), dst.display()))
+        }
+
+        "filesystem.delete" => {
+            let raw = tool.path.as_ref().ok_or("Missing path for filesystem.delete")?;
+            let path = match confine_to_root(&policy.workspace_root, raw) {
+                Ok(p) => p,
+                Err(e) => return Ok(format!("Blocked filesystem.delete: {e}")),
+            };
+
+            if policy.safe_delete {
+                let trashed = move_to_trash(&path, &policy.trash_dir)?;
+                policy.note(format!("🗑️ Moved '{}' to trash", path.display()));
+                Ok(format!("Deleted '{}' (moved to '{}').", path.display(), trashed.display()))
+            } else {
+                if path.is_dir() {
+                    // Only empty directories are removed without the trash safety net.
+                    fs::remove_dir(&path)?;
+                } else {
+                    fs::remove_file(&path)?;
+                }
+                policy.note(format!("🗑️ Deleted '{}'", path.display()));
+                Ok(format!("Deleted '{}'.", path.display()))
+            }
         }
 
         "shell.run" => {
@@ -186,38 +842,231 @@ fn execute_mcp(tool: &ToolCall) -> Result<String, Box<dyn Error>> {
                 .content
                 .as_ref()
                 .ok_or("Missing 'content' for shell.run (expected shell command)")?;
-            println!("🖥️ Running shell command: `{}`", command_raw);
+            policy.note(format!("🖥️ Running shell command: `{}`", command_raw));
+
+            let (report, code) = run_shell(command_raw, policy.shell, policy.shell_timeout)?;
+            *exit_code = code;
+            policy.note(format!("📤 {report}"));
+            Ok(report)
+        }
+
+        _ => Err(format!("Unknown MCP tool: {}", tool.name).into()),
+    }
+}
+
+/// Run `command` through the platform shell, killing it once `timeout`
+/// passes. The output pipes are drained on their own threads so a chatty
+/// command cannot stall on a full pipe. Returns the report for the model
+/// and the exit code, if the command exited by itself.
+fn run_shell(command: &str, shell: Shell, timeout: Duration) -> Result<(String, Option<i32>), Box<dyn Error>> {
+    let spawned = shell.command(command).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn();
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(format!(
+                "shell '{}' is not installed or not on PATH; change `shell` in ~/.config/rustai/config.toml",
+                shell.name()
+            )
+            .into())
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let stdout = drain_pipe(child.stdout.take());
+    let stderr = drain_pipe(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            // The readers may still be held open by grandchildren; leave them.
+            let report = format!("Command `{command}` timed out after {}s and was killed.", timeout.as_secs());
+            return Ok((report, None));
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
 
-            #[cfg(target_os = "windows")]
-            let output = std::process::Command::new("cmd")
-                .args(&["/C", command_raw])
-                .output()?;
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    let exit = match status.code() {
+        Some(code) => format!("exit code {code}"),
+        None => "no exit code (killed by a signal)".to_string(),
+    };
+    let report = format!(
+        "Command `{}` ({}) finished with {}.\nSTDOUT:\n{}\nSTDERR:\n{}",
+        command,
+        shell.name(),
+        exit,
+        stdout,
+        stderr
+    );
+    Ok((report, status.code()))
+}
 
-            #[cfg(not(target_os = "windows"))]
-            let output = std::process::Command::new("sh")
-                .args(&["-c", command_raw])
-                .output()?;
+/// Read a child's pipe to the end on a background thread.
+fn drain_pipe(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<String> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
+    })
+}
 
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            let stderr = String::from_utf8_lossy(&output.stderr);
+/// Sorted listing of `path`: directories get a trailing slash, files their size.
+/// Errors are returned as text so the agent loop can carry on.
+fn list_dir(path: &Path) -> String {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) => return format!("Cannot list '{}': {e}", path.display()),
+    };
 
-            println!("📤 Command output:\n{}", stdout);
-            if !stderr.is_empty() {
-                println!("⚠️ Command error output:\n{}", stderr);
+    let mut lines: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            match entry.metadata() {
+                Ok(meta) if meta.is_dir() => format!("{name}/"),
+                Ok(meta) => format!("{name} ({} bytes)", meta.len()),
+                Err(_) => name,
             }
+        })
+        .collect();
+    lines.sort();
+
+    if lines.is_empty() {
+        return format!("'{}' is empty.", path.display());
+    }
+    format!("Listing of '{}':\n{}", path.display(), lines.join("\n"))
+}
+
+/// Resolve `raw` for the file tools and require it to lie
+/// under one of `roots` (relative paths start at the first root). Uses the
+/// same resolution as `confine_to_root`, so `..` and symlinks cannot escape.
+fn sandbox_path(roots: &[PathBuf], raw: &Path) -> Result<PathBuf, AppError> {
+    let roots: Vec<PathBuf> = roots.iter().filter_map(|r| r.canonicalize().ok()).collect();
+    let base = roots
+        .first()
+        .ok_or_else(|| AppError::Sandbox("no accessible sandbox root".into()))?;
+    let resolved = resolve_under(base, raw);
+    if roots.iter().any(|root| resolved.starts_with(root)) {
+        Ok(resolved)
+    } else {
+        Err(AppError::Sandbox(format!("path outside sandbox: '{}'", raw.display())))
+    }
+}
 
-            Ok(format!(
-                "Command `{}` executed.\nSTDOUT:\n{}\nSTDERR:\n{}",
-                command_raw, stdout, stderr
-            ))
+/// Join `raw` onto `root` unless it is absolute, resolve `..` lexically and
+/// canonicalize the deepest existing ancestor.
+fn resolve_under(root: &Path, raw: &Path) -> PathBuf {
+    let joined = if raw.is_absolute() {
+        raw.to_path_buf()
+    } else {
+        root.join(raw)
+    };
+
+    let mut normalized = PathBuf::new();
+    for comp in joined.components() {
+        match comp {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::CurDir => {}
+            other => normalized.push(other),
         }
+    }
 
-        _ => Err(format!("Unknown MCP tool: {}", tool.name).into()),
+    // Canonicalize the part that exists, then re-attach the rest.
+    let mut existing = normalized.as_path();
+    let mut rest = Vec::new();
+    while !existing.exists() {
+        match (existing.file_name(), existing.parent()) {
+            (Some(name), Some(parent)) => {
+                rest.push(name.to_os_string());
+                existing = parent;
+            }
+            _ => break,
+        }
+    }
+    let mut resolved = existing.canonicalize().unwrap_or_else(|_| existing.to_path_buf());
+    for part in rest.iter().rev() {
+        resolved.push(part);
+    }
+    resolved
+}
+
+/// Resolve `raw` against `root` and make sure the result stays inside it.
+/// `..` is resolved lexically and the deepest existing ancestor is
+/// canonicalized, so symlinks pointing outside the root are caught too.
+fn confine_to_root(root: &Path, raw: &str) -> Result<PathBuf, AppError> {
+    let root = root.canonicalize().map_err(|e| {
+        AppError::Sandbox(format!("workspace root '{}' is not accessible: {e}", root.display()))
+    })?;
+    let resolved = resolve_under(&root, Path::new(raw));
+
+    if resolved == root {
+        return Err(AppError::Sandbox("refusing to operate on the workspace root itself".into()));
+    }
+    if !resolved.starts_with(&root) {
+        return Err(AppError::Sandbox(format!("'{raw}' is outside the workspace root '{}'", root.display())));
+    }
+    Ok(resolved)
+}
+
+/// Copy `path` into `backup_dir` with a millisecond timestamp suffix;
+/// returns the copy's location.
+fn back_up(path: &Path, backup_dir: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    fs::create_dir_all(backup_dir)?;
+    let ts = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let name = path
+        .file_name()
+        .ok_or("cannot back up a path without a file name")?
+        .to_string_lossy();
+    let target = backup_dir.join(format!("{name}.{ts}"));
+    fs::copy(path, &target)?;
+    Ok(target)
+}
+
+/// Back up `path` if it is an existing file and backups are on. A failed
+/// backup is an error, so the caller never overwrites without one.
+fn back_up_before_write(path: &Path, policy: &ToolPolicy) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    if !policy.backup_on_write || !path.is_file() {
+        return Ok(None);
+    }
+    back_up(path, &policy.backup_dir)
+        .map(Some)
+        .map_err(|e| format!("could not back up '{}' before writing, nothing was written: {e}", path.display()).into())
+}
+
+/// Suffix for a write result saying where the old contents went.
+fn backup_note(backup: Option<&PathBuf>) -> String {
+    match backup {
+        Some(b) => format!(" Previous version saved to '{}' (filesystem.restore brings it back).", b.display()),
+        None => String::new(),
+    }
+}
+
+/// Read a text file, refusing files larger than `max_bytes`.
+pub(crate) fn read_limited(path: &Path, max_bytes: u64) -> Result<String, Box<dyn Error>> {
+    let size = fs::metadata(path)
+        .map_err(|e| format!("cannot read '{}': {e}", path.display()))?
+        .len();
+    if size > max_bytes {
+        return Err(format!(
+            "'{}' is too large ({size} bytes, limit {max_bytes})",
+            path.display()
+        )
+        .into());
     }
+    Ok(fs::read_to_string(path)?)
 }
 
 /// Loosely decode escaped sequences and handle real newlines safely
-fn normalize_escaped_content(s: &str) -> String {
+pub(crate) fn normalize_escaped_content(s: &str) -> String {
     let mut out = String::new();
     let mut chars = s.chars().peekable();
 
@@ -239,3 +1088,364 @@ fn normalize_escaped_content(s: &str) -> String {
 
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{ChatRequest, LlmBackend, MockBackend};
+    use crate::interrupt::Interrupt;
+    use crate::llm::tests::ScriptedBackend;
+    use crate::llm::ApiExchange;
+    use serde_json::json;
+
+    /// A fresh, empty directory under the system temp dir.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mycli-mcp-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    /// A policy whose workspace, sandbox, trash and backups all live in `dir`.
+    fn policy_in(dir: &Path) -> ToolPolicy {
+        let work = dir.join("work");
+        fs::create_dir_all(&work).unwrap();
+        ToolPolicy {
+            workspace_root: work.clone(),
+            allowed_roots: vec![work],
+            ..ToolPolicy::for_log_dir(&dir.join("logs"))
+        }
+    }
+
+    fn call(name: &str, params: Value) -> ToolCall {
+        ToolCall::from_params(name.into(), &params)
+    }
+
+    fn run(tool: &ToolCall, policy: &ToolPolicy) -> Result<String, Box<dyn Error>> {
+        run_tool(tool, policy, &mut None)
+    }
+
+    #[test]
+    fn parse_tool_uses_returns_every_call_in_order() {
+        let output = r#"First I read, then I list.
+<use_tool name="filesystem.read" params={"path": "a.txt"} />
+and
+<use_tool name="filesystem.list" params={"path": "src"} />
+<use_tool name="shell.run" params={"content": "echo {done}"} />"#;
+        let calls = parse_tool_uses(output);
+        let names: Vec<_> = calls.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["filesystem.read", "filesystem.list", "shell.run"]);
+        assert_eq!(calls[1].path.as_deref(), Some("src"));
+        assert_eq!(calls[2].content.as_deref(), Some("echo {done}"));
+    }
+
+    #[test]
+    fn parse_tool_uses_skips_calls_with_broken_params() {
+        let output = r#"<use_tool name="filesystem.read" params={"path": } />
+<use_tool name="filesystem.list" params={"path": "."} />"#;
+        let calls = parse_tool_uses(output);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "filesystem.list");
+        assert!(parse_tool_uses("no tools here").is_empty());
+    }
+
+    #[test]
+    fn confine_to_root_accepts_paths_inside_the_root() {
+        let dir = scratch_dir("confine-inside");
+        assert_eq!(confine_to_root(&dir, "a/b.txt").unwrap(), dir.join("a/b.txt"));
+        assert_eq!(confine_to_root(&dir, "a/../c.txt").unwrap(), dir.join("c.txt"));
+        assert_eq!(confine_to_root(&dir, &dir.join("d.txt").to_string_lossy()).unwrap(), dir.join("d.txt"));
+    }
+
+    #[test]
+    fn confine_to_root_rejects_escapes_and_the_root_itself() {
+        let dir = scratch_dir("confine-escape");
+        assert!(confine_to_root(&dir, "../outside.txt").is_err());
+        assert!(confine_to_root(&dir, "a/../../outside.txt").is_err());
+        assert!(confine_to_root(&dir, "/etc/passwd").is_err());
+        assert!(confine_to_root(&dir, ".").is_err());
+        assert!(confine_to_root(&dir, "a/..").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn confine_to_root_follows_symlinks_out_of_the_root() {
+        let dir = scratch_dir("confine-symlink");
+        let root = dir.join("root");
+        fs::create_dir_all(&root).unwrap();
+        std::os::unix::fs::symlink(&dir, root.join("link")).unwrap();
+        assert!(confine_to_root(&root, "link/secret.txt").is_err());
+    }
+
+    #[test]
+    fn delete_moves_the_file_to_the_trash() {
+        let dir = scratch_dir("delete");
+        let policy = policy_in(&dir);
+        fs::write(policy.workspace_root.join("old.txt"), "bye").unwrap();
+
+        let result = run(&call("filesystem.delete", json!({"path": "old.txt"})), &policy).unwrap();
+
+        assert!(result.starts_with("Deleted "), "{result}");
+        assert!(!policy.workspace_root.join("old.txt").exists());
+        let trashed: Vec<_> = fs::read_dir(&policy.trash_dir).unwrap().flatten().collect();
+        assert_eq!(trashed.len(), 1);
+        assert_eq!(fs::read_to_string(trashed[0].path()).unwrap(), "bye");
+    }
+
+    #[test]
+    fn delete_and_move_refuse_paths_outside_the_workspace() {
+        let dir = scratch_dir("outside");
+        let policy = policy_in(&dir);
+        fs::write(dir.join("keep.txt"), "x").unwrap();
+
+        let result = run(&call("filesystem.delete", json!({"path": "../keep.txt"})), &policy).unwrap();
+        assert!(result.starts_with("Blocked filesystem.delete"), "{result}");
+        let result = run(&call("filesystem.move", json!({"src": "../keep.txt", "dst": "in.txt"})), &policy).unwrap();
+        assert!(result.starts_with("Blocked filesystem.move"), "{result}");
+        assert!(dir.join("keep.txt").exists());
+    }
+
+    #[test]
+    fn move_never_overwrites_the_destination() {
+        let dir = scratch_dir("move");
+        let policy = policy_in(&dir);
+        let work = &policy.workspace_root;
+        fs::write(work.join("a.txt"), "a").unwrap();
+        fs::write(work.join("b.txt"), "b").unwrap();
+
+        let result = run(&call("filesystem.move", json!({"src": "a.txt", "dst": "b.txt"})), &policy).unwrap();
+        assert!(result.contains("already exists"), "{result}");
+        assert_eq!(fs::read_to_string(work.join("b.txt")).unwrap(), "b");
+
+        run(&call("filesystem.move", json!({"src": "a.txt", "dst": "sub/c.txt"})), &policy).unwrap();
+        assert_eq!(fs::read_to_string(work.join("sub/c.txt")).unwrap(), "a");
+        assert!(!work.join("a.txt").exists());
+    }
+
+    /// Replies with `reply` and, like a Ctrl+C during the stream, asks the
+    /// agent to stop.
+    struct InterruptingBackend {
+        interrupt: Interrupt,
+        reply: &'static str,
+    }
+
+    impl LlmBackend for InterruptingBackend {
+        fn name(&self) -> &'static str {
+            "interrupting"
+        }
+
+        fn stream(
+            &self,
+            _req: &ChatRequest,
+            _last: &mut Option<ApiExchange>,
+            on_chunk: &mut dyn FnMut(&str),
+        ) -> Result<String, AppError> {
+            self.interrupt.request();
+            on_chunk(self.reply);
+            Ok(self.reply.to_string())
+        }
+    }
+
+    /// A manager whose logs and tool sandbox live in a scratch dir.
+    fn agent_manager(name: &str) -> (SessionManager, PathBuf) {
+        let dir = scratch_dir(name);
+        let mut manager = SessionManager::new(dir.join("logs"));
+        manager.tool_policy = policy_in(&dir);
+        manager.echo_reply = false;
+        (manager, dir)
+    }
+
+    #[test]
+    fn interrupt_stops_the_agent_before_the_next_tool_runs() {
+        let (mut manager, dir) = agent_manager("interrupt");
+        fs::write(dir.join("work/a.txt"), "secret").unwrap();
+        manager.backend = Box::new(InterruptingBackend {
+            interrupt: manager.interrupt.clone(),
+            reply: r#"<use_tool name="filesystem.read" params={"path": "a.txt"} />"#,
+        });
+
+        manager.handle_mcp_command("read a.txt").unwrap();
+
+        let roles: Vec<_> = manager.session.messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["user", "assistant"]);
+        assert!(manager.last_run_tools.is_empty());
+        assert!(!manager.interrupt.is_requested());
+    }
+
+    #[test]
+    fn sandbox_path_accepts_every_allowed_root() {
+        let dir = scratch_dir("sandbox-roots");
+        let (work, extra) = (dir.join("work"), dir.join("extra"));
+        fs::create_dir_all(&work).unwrap();
+        fs::create_dir_all(&extra).unwrap();
+        let roots = [work.clone(), extra.clone()];
+
+        assert_eq!(sandbox_path(&roots, Path::new("a.txt")).unwrap(), work.join("a.txt"));
+        assert_eq!(sandbox_path(&roots, &extra.join("b.txt")).unwrap(), extra.join("b.txt"));
+        assert_eq!(sandbox_path(&roots, Path::new(".")).unwrap(), work);
+    }
+
+    #[test]
+    fn sandbox_path_rejects_paths_outside_the_roots() {
+        let dir = scratch_dir("sandbox-escape");
+        let work = dir.join("work");
+        fs::create_dir_all(&work).unwrap();
+        let roots = [work.clone()];
+
+        assert!(sandbox_path(&roots, Path::new("../secret.txt")).is_err());
+        assert!(sandbox_path(&roots, &dir.join("secret.txt")).is_err());
+        assert!(sandbox_path(&[dir.join("missing")], Path::new("a.txt")).is_err());
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&dir, work.join("link")).unwrap();
+            assert!(sandbox_path(&roots, Path::new("link/secret.txt")).is_err());
+        }
+    }
+
+    #[test]
+    fn file_tools_answer_blocked_outside_the_sandbox() {
+        let dir = scratch_dir("sandbox-tools");
+        let policy = policy_in(&dir);
+        fs::write(dir.join("secret.txt"), "no").unwrap();
+
+        let read = run(&call("filesystem.read", json!({"path": "../secret.txt"})), &policy).unwrap();
+        assert!(read.starts_with("Blocked filesystem.read: path outside sandbox"), "{read}");
+        let list = run(&call("filesystem.list", json!({"path": ".."})), &policy).unwrap();
+        assert!(list.starts_with("Blocked filesystem.list"), "{list}");
+        let write = run(&call("filesystem.write", json!({"path": "../out.txt", "content": "x"})), &policy).unwrap();
+        assert!(write.starts_with("Blocked filesystem.write"), "{write}");
+        assert!(!dir.join("out.txt").exists());
+    }
+
+    fn roles(manager: &SessionManager) -> Vec<&str> {
+        manager.session.messages.iter().map(|m| m.role.as_str()).collect()
+    }
+
+    #[test]
+    fn agent_stops_when_a_tool_call_repeats() {
+        let (mut manager, _dir) = agent_manager("agent-repeat");
+        let reply = r#"<use_tool name="filesystem.list" params={"path": "."} />"#;
+        manager.backend = Box::new(MockBackend { reply: reply.into() });
+
+        manager.handle_mcp_command("list").unwrap();
+
+        assert_eq!(roles(&manager), ["user", "assistant", "tool", "assistant", "system"]);
+        assert_eq!(
+            manager.session.messages.last().unwrap().content,
+            "[Agent stopped: filesystem.list repeated with the same arguments]"
+        );
+        assert_eq!(manager.last_run_tools.len(), 1);
+    }
+
+    #[test]
+    fn agent_stops_at_the_step_limit() {
+        let (mut manager, _dir) = agent_manager("agent-steps");
+        manager.tool_policy.max_agent_steps = 2;
+        let replies = vec![
+            r#"<use_tool name="filesystem.list" params={"path": "."} />"#,
+            r#"<use_tool name="filesystem.read" params={"path": "a.txt"} />"#,
+            r#"<use_tool name="filesystem.read" params={"path": "b.txt"} />"#,
+        ];
+        manager.backend = Box::new(ScriptedBackend { replies: replies.into() });
+
+        manager.handle_mcp_command("look around").unwrap();
+
+        assert_eq!(roles(&manager), ["user", "assistant", "tool", "assistant", "tool", "system"]);
+        assert_eq!(
+            manager.session.messages.last().unwrap().content,
+            "[Agent stopped: tool call limit reached after 2 steps]"
+        );
+    }
+
+    #[test]
+    fn balanced_json_ignores_braces_and_quotes_in_strings() {
+        assert_eq!(balanced_json(r#"{"a": "}{\"}"} />"#), Some(r#"{"a": "}{\"}"}"#));
+        assert_eq!(balanced_json(r#"{"a": {"b": 1}} tail}"#), Some(r#"{"a": {"b": 1}}"#));
+        assert_eq!(balanced_json(r#"{"a": "never closed"#), None);
+        assert_eq!(balanced_json(r#" {"a": 1}"#), None);
+    }
+
+    #[test]
+    fn escape_controls_in_strings_only_touches_string_values() {
+        assert_eq!(escape_controls_in_strings("{\n\"a\": \"x\ty\nz\"\n}"), "{\n\"a\": \"x\\ty\\nz\"\n}");
+        assert_eq!(escape_controls_in_strings(r#"{"a": "q\"\n"}"#), r#"{"a": "q\"\n"}"#);
+    }
+
+    #[test]
+    fn parse_tool_uses_accepts_raw_newlines_and_a_missing_close() {
+        let output = "<use_tool name=\"filesystem.write\" params={\"path\": \"a.rs\", \"content\": \"fn main() {\n\tprintln!(\\\"}\\\");\n}\"}";
+        let calls = parse_tool_uses(output);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].path.as_deref(), Some("a.rs"));
+        assert_eq!(calls[0].content.as_deref(), Some("fn main() {\n\tprintln!(\"}\");\n}"));
+    }
+
+    #[test]
+    fn parse_tool_uses_falls_back_to_the_simple_patterns() {
+        // `\q` is not a JSON escape, so only the shell.run pattern matches.
+        let calls = parse_tool_uses(r#"<use_tool name="shell.run" params={"content": "grep \q x"} />"#);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "shell.run");
+        assert_eq!(calls[0].content.as_deref(), Some(r"grep \q x"));
+    }
+
+    #[test]
+    fn only_writes_moves_deletes_restores_and_shell_are_mutating() {
+        for name in ["filesystem.write", "filesystem.move", "filesystem.delete", "filesystem.restore", "shell.run"] {
+            assert!(is_mutating(name), "{name}");
+        }
+        for name in ["filesystem.read", "filesystem.list", "docs.search"] {
+            assert!(!is_mutating(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn safe_mode_refuses_mutating_tools_but_still_reads() {
+        let dir = scratch_dir("safe-mode-tools");
+        let policy = ToolPolicy { safe_mode: true, ..policy_in(&dir) };
+        fs::write(policy.workspace_root.join("a.txt"), "hello").unwrap();
+
+        let write = run(&call("filesystem.write", json!({"path": "b.txt", "content": "x"})), &policy).unwrap_err();
+        assert_eq!(write.to_string(), SAFE_MODE_REFUSAL);
+        assert!(!policy.workspace_root.join("b.txt").exists());
+        let shell = run(&call("shell.run", json!({"content": "echo hi"})), &policy).unwrap_err();
+        assert_eq!(shell.to_string(), SAFE_MODE_REFUSAL);
+        let read = run(&call("filesystem.read", json!({"path": "a.txt"})), &policy).unwrap();
+        assert!(read.ends_with("hello"), "{read}");
+    }
+
+    /// Answers with `replies` in turn and keeps the messages of each call.
+    struct RecordingBackend {
+        replies: std::cell::RefCell<Vec<&'static str>>,
+        seen: std::rc::Rc<std::cell::RefCell<Vec<Vec<Value>>>>,
+    }
+
+    impl LlmBackend for RecordingBackend {
+        fn name(&self) -> &'static str {
+            "recording"
+        }
+
+        fn stream(&self, req: &ChatRequest, _: &mut Option<ApiExchange>, _: &mut dyn FnMut(&str)) -> Result<String, AppError> {
+            self.seen.borrow_mut().push(req.messages.to_vec());
+            let mut replies = self.replies.borrow_mut();
+            Ok(if replies.is_empty() { String::new() } else { replies.remove(0).to_string() })
+        }
+    }
+
+    #[test]
+    fn safe_mode_agent_is_not_offered_and_cannot_use_write_tools() {
+        let (mut manager, dir) = agent_manager("safe-mode-agent");
+        manager.tool_policy.safe_mode = true;
+        let seen = std::rc::Rc::default();
+        let replies = vec![r#"<use_tool name="filesystem.write" params={"path": "b.txt", "content": "x"} />"#, "Done."];
+        manager.backend = Box::new(RecordingBackend { replies: replies.into(), seen: std::rc::Rc::clone(&seen) });
+
+        manager.handle_mcp_command("write b.txt").unwrap();
+
+        let prompt = seen.borrow()[0][0]["content"].as_str().unwrap().to_string();
+        assert!(prompt.contains(SAFE_MODE_PROMPT), "{prompt}");
+        assert!(!prompt.contains("filesystem.write"), "{prompt}");
+        assert_eq!(manager.last_run_tools[0].result, format!("error: {SAFE_MODE_REFUSAL}"));
+        assert!(!dir.join("work/b.txt").exists());
+    }
+}
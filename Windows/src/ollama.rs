@@ -0,0 +1,201 @@
+use serde_json::{json, Value};
+use std::env;
+use std::error::Error;
+use std::io::{BufRead, BufReader};
+use std::time::{Duration, Instant};
+
+use crate::backend::ChatRequest;
+use crate::error::AppError;
+use crate::llm::{ApiClient, ApiExchange, StopFilter};
+use crate::session::SessionManager;
+
+const DEFAULT_OLLAMA_URL: &str = "http://localhost:11434";
+
+/// Base URL of the local Ollama server: `OLLAMA_HOST`, else the default port.
+pub fn default_base_url() -> String {
+    match env::var("OLLAMA_HOST") {
+        Ok(host) if !host.trim().is_empty() => {
+            let host = host.trim().trim_end_matches('/');
+            if host.starts_with("http://") || host.starts_with("https://") {
+                host.to_string()
+            } else {
+                format!("http://{host}")
+            }
+        }
+        _ => DEFAULT_OLLAMA_URL.to_string(),
+    }
+}
+
+/// Body of an empty `/api/generate` call: Ollama loads the model into
+/// memory and answers without generating anything.
+pub fn build_warmup_request(model: &str) -> Value {
+    json!({
+        "model": model,
+        "prompt": "",
+        "keep_alive": "10m",
+        "stream": false,
+    })
+}
+
+/// Load `model` on the Ollama server at `base_url`; returns how long it took.
+pub fn warmup(api: &ApiClient, base_url: &str, model: &str) -> Result<Duration, Box<dyn Error>> {
+    let url = format!("{}/api/generate", base_url.trim_end_matches('/'));
+    let started = Instant::now();
+
+    let resp = api
+        .client
+        .post(&url)
+        .json(&build_warmup_request(model))
+        .send()
+        .map_err(|e| {
+            if e.is_connect() {
+                format!("Ollama is not reachable at {base_url} (is `ollama serve` running?)")
+            } else {
+                format!("warmup request failed: {e}")
+            }
+        })?;
+
+    let status = resp.status();
+    if !status.is_success() {
+        let body: Value = serde_json::from_str(&resp.text()?).unwrap_or(Value::Null);
+        let msg = body["error"].as_str().unwrap_or("unknown error");
+        return Err(format!("Ollama error ({status}): {msg}").into());
+    }
+
+    Ok(started.elapsed())
+}
+
+/// Streaming chat against the Ollama server at `base_url` (`/api/chat`),
+/// which sends one JSON object per line. Returns the full answer; the raw
+/// exchange is recorded into `last`.
+pub fn call_ollama_chat_stream(
+    base_url: &str,
+    req: &ChatRequest,
+    last: &mut Option<ApiExchange>,
+    on_chunk: &mut dyn FnMut(&str),
+) -> Result<String, AppError> {
+    let url = format!("{}/api/chat", base_url.trim_end_matches('/'));
+    let mut request_body = json!({
+        "model": req.model,
+        "messages": req.messages,
+        "stream": true,
+    });
+    req.params.apply_ollama(&mut request_body);
+
+    let resp = req
+        .api
+        .send_with_retry("Ollama", || req.api.client.post(&url).json(&request_body))
+        .map_err(|e| match e {
+            AppError::Http { context, source } => AppError::Http {
+                context: format!("{context} (is `ollama serve` running at {base_url}?)"),
+                source,
+            },
+            other => other,
+        })?;
+
+    let status = resp.status();
+    let mut exchange = ApiExchange {
+        url: url.clone(),
+        headers: vec![("Content-Type".into(), "application/json".into())],
+        request_body,
+        status: status.as_u16(),
+        response_body: String::new(),
+    };
+    let ollama_error = |message: &str| AppError::Api {
+        provider: "Ollama",
+        status: status.as_u16(),
+        message: message.to_string(),
+    };
+
+    if !status.is_success() {
+        exchange.response_body = resp.text()?;
+        let body: Value = serde_json::from_str(&exchange.response_body).unwrap_or(Value::Null);
+        let err = ollama_error(body["error"].as_str().unwrap_or("unknown error from API"));
+        *last = Some(exchange);
+        return Err(err);
+    }
+
+    let mut answer = String::new();
+    let mut stop = StopFilter::new(&req.params.stop);
+    for line in BufReader::new(resp).lines() {
+        let line = line?;
+        exchange.response_body.push_str(&line);
+        exchange.response_body.push('\n');
+
+        let Ok(event) = serde_json::from_str::<Value>(&line) else {
+            continue;
+        };
+        if let Some(err) = event["error"].as_str() {
+            let err = ollama_error(err);
+            *last = Some(exchange);
+            return Err(err);
+        }
+        if let Some(delta) = event["message"]["content"].as_str() {
+            let text = stop.push(delta);
+            if !text.is_empty() {
+                answer.push_str(&text);
+                on_chunk(&text);
+            }
+        }
+        if stop.done() || event["done"].as_bool() == Some(true) {
+            break;
+        }
+    }
+    let rest = stop.finish();
+    if !rest.is_empty() {
+        answer.push_str(&rest);
+        on_chunk(&rest);
+    }
+
+    *last = Some(exchange);
+    Ok(answer)
+}
+
+impl SessionManager {
+    /// `/model warmup`: load the current model on the local Ollama server.
+    pub fn warmup_model(&self) {
+        self.note(format!("🔥 Warming up '{}' on {}...", self.model, self.ollama_url));
+        match warmup(&self.api, &self.ollama_url, &self.model) {
+            Ok(took) => self.note(format!("✅ Model loaded in {:.1}s", took.as_secs_f64())),
+            Err(e) => self.note(format!("⚠️ Warmup failed: {e}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::tests::{fake_server, http_response};
+    use crate::llm::HttpConfig;
+
+    #[test]
+    fn warmup_request_loads_without_generating() {
+        assert_eq!(
+            build_warmup_request("qwen3:1.7b"),
+            json!({"model": "qwen3:1.7b", "prompt": "", "keep_alive": "10m", "stream": false})
+        );
+    }
+
+    #[test]
+    fn warmup_succeeds_on_an_ok_answer() {
+        let base = fake_server(vec![http_response("200 OK", r#"{"done":true}"#)]);
+        let api = ApiClient::new(HttpConfig::default());
+        assert!(warmup(&api, &format!("{base}/"), "m").is_ok());
+    }
+
+    #[test]
+    fn warmup_reports_ollama_errors() {
+        let base = fake_server(vec![http_response("404 Not Found", r#"{"error":"model 'm' not found"}"#)]);
+        let api = ApiClient::new(HttpConfig::default());
+        let err = warmup(&api, &base, "m").unwrap_err().to_string();
+        assert!(err.contains("model 'm' not found"), "{err}");
+    }
+
+    #[test]
+    fn warmup_explains_an_unreachable_server() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let api = ApiClient::new(HttpConfig::default());
+        let err = warmup(&api, &format!("http://127.0.0.1:{port}"), "m").unwrap_err().to_string();
+        assert!(err.contains("is `ollama serve` running?"), "{err}");
+    }
+}
@@ -1,11 +1,20 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::fs;
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::audit::{self, AuditEntry};
+use crate::backend::{DashScopeBackend, LlmBackend};
+use crate::export::{self, Redactor};
+use crate::interrupt::Interrupt;
+use crate::llm::{ApiClient, ApiExchange, EmptyResponse, GenParams, HttpConfig};
+use crate::mcp::{read_limited, ToolCache, ToolPolicy, ToolRecord};
+use crate::mcp_client::McpClient;
+use crate::trash::{self, move_to_trash, trashed_name};
+
 /// Single message
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
@@ -21,24 +30,251 @@ pub struct Session {
     pub created_at: u64,
     pub messages: Vec<Message>,
     pub summary: Option<String>,
+    /// Values for `{{name}}` placeholders in templates (`/set var`).
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
+    /// Sent as the first system message of every request (`/system`).
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    /// Generation settings sent with every chat request.
+    #[serde(default)]
+    pub params: GenParams,
+}
+
+/// Number of messages per role in a branch.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RoleCounts {
+    pub user: usize,
+    pub assistant: usize,
+    /// system / tool messages
+    pub other: usize,
+}
+
+impl RoleCounts {
+    pub fn total(&self) -> usize {
+        self.user + self.assistant + self.other
+    }
+}
+
+impl Session {
+    /// Replace `{{name}}` placeholders with `fixed` values (e.g. `model` in
+    /// export footers) or, failing that, the session's variables. Values are
+    /// inserted as they are, never expanded again. Referencing a variable
+    /// that was never set is an error.
+    pub fn expand_vars(&self, template: &str, fixed: &[(&str, &str)]) -> Result<String, Box<dyn Error>> {
+        let mut out = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            let Some(len) = rest[start + 2..].find("}}") else {
+                break;
+            };
+            let name = rest[start + 2..start + 2 + len].trim();
+            out.push_str(&rest[..start]);
+            if !is_var_name(name) {
+                // Not a placeholder (e.g. code with braces); keep it as is.
+                out.push_str(&rest[start..start + 2 + len + 2]);
+            } else if let Some((_, value)) = fixed.iter().find(|(n, _)| *n == name) {
+                out.push_str(value);
+            } else if let Some(value) = self.vars.get(name) {
+                out.push_str(value);
+            } else {
+                return Err(format!("undefined variable '{name}' (set it with /set var {name}=<value>)").into());
+            }
+            rest = &rest[start + 2 + len + 2..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    /// Merge runs of consecutive messages with the same role into one,
+    /// joined with newlines. Returns how many messages were folded away.
+    pub fn coalesce(&mut self) -> usize {
+        let before = self.messages.len();
+        let mut merged: Vec<Message> = Vec::with_capacity(before);
+        for m in self.messages.drain(..) {
+            match merged.last_mut() {
+                Some(prev) if prev.role == m.role => {
+                    prev.content.push('\n');
+                    prev.content.push_str(&m.content);
+                }
+                _ => merged.push(m),
+            }
+        }
+        self.messages = merged;
+        before - self.messages.len()
+    }
+
+    /// Count messages by role.
+    pub fn role_counts(&self) -> RoleCounts {
+        let mut counts = RoleCounts::default();
+        for m in &self.messages {
+            match m.role.as_str() {
+                "user" => counts.user += 1,
+                "assistant" => counts.assistant += 1,
+                _ => counts.other += 1,
+            }
+        }
+        counts
+    }
+}
+
+fn is_var_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-')
 }
 
 /// Core manager containing session, branches, model
-#[derive(Clone)]
 pub struct SessionManager {
     pub session: Session,
     pub branches: HashMap<String, Session>,
     pub model: String,
+    /// HTTP client, timeouts/retries and explicit API key.
+    pub api: ApiClient,
+    /// Raw request/response of the most recent API call (for `/debug last`).
+    pub last_exchange: Option<ApiExchange>,
+    /// Restrictions for the MCP filesystem tools.
+    pub tool_policy: ToolPolicy,
+    /// Run shell.run commands without asking (`--yes`).
+    pub auto_approve: bool,
+    /// Patterns scrubbed from transcripts exported with `/share`.
+    pub redactor: Redactor,
+    /// Footer template appended to exports (`{{model}}`, `{{date}}`, `{{count}}`).
+    pub export_footer: Option<String>,
+    /// Wrapper for `/prompt-file` contents (`{{path}}`, `{{content}}`).
+    pub prompt_file_template: Option<String>,
+    /// Handling of whitespace-only completions.
+    pub on_empty: EmptyResponse,
+    /// Local Ollama server used by `/model warmup` and `provider = "ollama"`.
+    pub ollama_url: String,
+    /// Ctrl+C stop request for the `/mcp` agent loop.
+    pub interrupt: Interrupt,
+    /// Model server that answers chat messages (`provider` in the config).
+    pub backend: Box<dyn LlmBackend>,
+    /// Send tool results as `system` messages; set once the server has
+    /// rejected the `tool` role.
+    pub tool_role_fallback: bool,
+    /// External MCP servers (`[[mcp_servers]]`) whose tools the agent may call.
+    pub mcp_servers: Vec<McpClient>,
+    /// Recent file tool results per session id, for repeated calls.
+    pub tool_caches: HashMap<String, ToolCache>,
+    /// Where sessions, exports, backups and the trash are kept.
+    pub log_dir: PathBuf,
+    /// `--once`: progress lines go to stderr so stdout carries only the answer.
+    pub quiet: bool,
+    /// Print replies as they stream; off for `--json`, which prints them
+    /// at the end.
+    pub echo_reply: bool,
+    /// Tool calls of the latest `/mcp` run with their results.
+    pub last_run_tools: Vec<ToolRecord>,
 }
 
-const LOG_DIR: &str = "logs";
-const DEFAULT_MODEL: &str = "qwen-plus";
+/// Escape characters that would be ambiguous or unsafe in a log file name
+/// (`%`, the `_` separator, path separators and control characters) as
+/// `%XX`, so any session id or branch name round-trips.
+fn encode_name_part(part: &str) -> String {
+    let mut out = String::with_capacity(part.len());
+    for c in part.chars() {
+        if matches!(c, '%' | '_' | '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') || c.is_control() {
+            let mut buf = [0; 4];
+            for b in c.encode_utf8(&mut buf).bytes() {
+                out.push_str(&format!("%{b:02X}"));
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
 
-impl SessionManager {
-    /// Create new session (with main branch)
-    pub fn new() -> Self {
-        fs::create_dir_all(LOG_DIR).ok();
+/// Inverse of `encode_name_part`. Malformed escapes are kept literally.
+fn decode_name_part(part: &str) -> String {
+    let bytes = part.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let Some(b) = part.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
 
+/// File name of a saved branch: `<session>_<branch>.json`, both parts escaped.
+pub(crate) fn log_file_name(id: &str, branch: &str) -> String {
+    format!("{}_{}.json", encode_name_part(id), encode_name_part(branch))
+}
+
+/// Split a log file name back into `(session, branch)`.
+pub(crate) fn parse_log_file_name(name: &str) -> Option<(String, String)> {
+    let stem = name.strip_suffix(".json")?;
+    let (id, branch) = stem.split_once('_')?;
+    Some((decode_name_part(id), decode_name_part(branch)))
+}
+
+
+/// Write `value` as pretty JSON next to `path` and rename it into place, so
+/// a crash mid-write never leaves a truncated log behind.
+fn write_json_atomic<T: Serialize>(path: &Path, value: &T) -> Result<(), Box<dyn Error>> {
+    let tmp = path.with_extension("json.tmp");
+    let file = File::create(&tmp)?;
+    serde_json::to_writer_pretty(&file, value)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Layout of a branch log, saved as its `schema_version`. Logs written
+/// before the field existed count as version 0.
+pub const LOG_SCHEMA_VERSION: u64 = 1;
+
+/// A branch as JSON for its log file, stamped with `LOG_SCHEMA_VERSION`.
+fn versioned_log(session: &Session) -> Result<serde_json::Value, Box<dyn Error>> {
+    let mut value = serde_json::to_value(session)?;
+    value["schema_version"] = LOG_SCHEMA_VERSION.into();
+    Ok(value)
+}
+
+/// Bring a branch log up to `LOG_SCHEMA_VERSION`, one version at a time,
+/// so logs saved by older builds keep loading as the layout changes.
+/// A log from a newer build is refused: read with missing fields and saved
+/// back, it would lose what this build does not know about.
+fn upgrade_log(mut value: serde_json::Value) -> Result<Session, Box<dyn Error>> {
+    let version = value.get("schema_version").and_then(|v| v.as_u64()).unwrap_or(0);
+    if version > LOG_SCHEMA_VERSION {
+        return Err(format!(
+            "saved by a newer version (log schema {version}, this one reads up to {LOG_SCHEMA_VERSION})"
+        )
+        .into());
+    }
+
+    if version < 1 {
+        // `created_at` is required; session ids are the creation time, so
+        // hand-written or trimmed logs get it back from there.
+        if value.get("created_at").is_none() {
+            let created = value["id"].as_str().and_then(|id| id.parse::<u64>().ok()).unwrap_or(0);
+            value["created_at"] = created.into();
+        }
+    }
+
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Read and upgrade the branch log at `path`.
+fn read_log(path: &Path) -> Result<Session, Box<dyn Error>> {
+    let value = serde_json::from_reader(File::open(path)?)?;
+    upgrade_log(value).map_err(|e| format!("{}: {e}", path.display()).into())
+}
+
+pub(crate) const DEFAULT_MODEL: &str = "qwen-plus";
+
+impl SessionManager {
+    /// Create new session (with main branch), saved under `log_dir`.
+    pub fn new(log_dir: PathBuf) -> Self {
         let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
         let id = ts.to_string();
 
@@ -48,35 +284,101 @@ impl SessionManager {
             created_at: ts,
             messages: vec![],
             summary: None,
+            vars: BTreeMap::new(),
+            system_prompt: None,
+            params: GenParams::default(),
         };
 
         Self {
             session: main.clone(),
             branches: HashMap::from([("main".into(), main)]),
             model: DEFAULT_MODEL.into(),
+            api: ApiClient::new(HttpConfig::default()),
+            last_exchange: None,
+            tool_policy: ToolPolicy::for_log_dir(&log_dir),
+            auto_approve: false,
+            redactor: Redactor::default(),
+            export_footer: None,
+            prompt_file_template: None,
+            on_empty: EmptyResponse::default(),
+            ollama_url: crate::ollama::default_base_url(),
+            interrupt: Interrupt::default(),
+            backend: Box::new(DashScopeBackend),
+            tool_role_fallback: false,
+            mcp_servers: Vec::new(),
+            tool_caches: HashMap::new(),
+            log_dir,
+            quiet: false,
+            echo_reply: true,
+            last_run_tools: Vec::new(),
+        }
+    }
+
+    /// Print a progress line: to stdout, or to stderr when `quiet`.
+    pub fn note(&self, text: impl std::fmt::Display) {
+        if self.quiet {
+            eprintln!("{text}");
+        } else {
+            println!("{text}");
         }
     }
 
+    /// Log file of one branch of a session.
+    fn log_path(&self, id: &str, branch: &str) -> PathBuf {
+        self.log_dir.join(log_file_name(id, branch))
+    }
+
+    /// Replace the conversation with a brand-new session, keeping settings
+    /// (model, key, params, approvals) as they are.
+    fn start_fresh_session(&mut self) {
+        let fresh = SessionManager::new(self.log_dir.clone());
+        self.session = fresh.session;
+        self.branches = fresh.branches;
+    }
+
     /// Save current branch as JSON
     pub fn save_to_logs(&self) -> Result<(), Box<dyn Error>> {
-        fs::create_dir_all(LOG_DIR)?;
-        let path = Path::new(LOG_DIR).join(format!("{}_{}.json", self.session.id, self.session.branch));
-        let file = File::create(&path)?;
-        serde_json::to_writer_pretty(file, &self.session)?;
-        println!("💾 Saved: {}", path.display());
+        fs::create_dir_all(&self.log_dir)?;
+        let path = self.log_path(&self.session.id, &self.session.branch);
+        write_json_atomic(&path, &versioned_log(&self.session)?)?;
+        self.note(format!("💾 Saved: {}", path.display()));
+        Ok(())
+    }
+
+    /// Save after a change the user did not ask to save, printing the error
+    /// instead of losing it.
+    pub fn autosave(&self) {
+        if let Err(e) = self.save_to_logs() {
+            self.note(format!("⚠️ Not saved to {}: {e}", self.log_dir.display()));
+        }
+    }
+
+    /// Move a log file to the trash (`/trash restore` brings it back). A
+    /// file that was never saved is not an error.
+    fn trash_log(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if path.exists() {
+            move_to_trash(path, &self.tool_policy.trash_dir)?;
+        }
         Ok(())
     }
 
-    /// Remove ALL sessions
+    /// Remove ALL sessions; their logs go to the trash.
     pub fn clear_all_sessions(&mut self) {
         if !ask_confirm("⚠️ Delete ALL sessions?") {
             println!("❎ Cancelled.");
             return;
         }
-        fs::remove_dir_all(LOG_DIR).ok();
-        fs::create_dir_all(LOG_DIR).ok();
-        *self = SessionManager::new();
-        println!("🔥 All sessions removed. New one started.");
+        if let Ok(entries) = fs::read_dir(&self.log_dir) {
+            for entry in entries.flatten() {
+                if parse_log_file_name(&entry.file_name().to_string_lossy()).is_some() {
+                    if let Err(e) = self.trash_log(&entry.path()) {
+                        println!("⚠️ Could not delete {}: {e}", entry.path().display());
+                    }
+                }
+            }
+        }
+        self.start_fresh_session();
+        println!("🔥 All sessions moved to the trash (see /trash). New one started.");
     }
 
     /// Remove all branches except main
@@ -89,13 +391,16 @@ impl SessionManager {
         let id = self.session.id.clone();
         for (name, _) in self.branches.clone() {
             if name != "main" {
-                let _ = fs::remove_file(Path::new(LOG_DIR).join(format!("{}_{}.json", id, name)));
+                if let Err(e) = self.trash_log(&self.log_path(&id, &name)) {
+                    println!("⚠️ Could not delete '{name}': {e}");
+                    continue;
+                }
                 self.branches.remove(&name);
             }
         }
 
         self.session.branch = "main".into();
-        println!("🌿 Only main branch kept.");
+        println!("🌿 Only main branch kept; the others are in the trash.");
     }
 
     /// Load session's main branch
@@ -105,14 +410,19 @@ impl SessionManager {
             return Ok(());
         };
 
-        let path = Path::new(LOG_DIR).join(format!("{}_main.json", id));
+        let path = self.log_path(id, "main");
         if !path.exists() {
             println!("❌ Not found: {}", path.display());
             return Ok(());
         }
 
-        let file = File::open(&path)?;
-        let main: Session = serde_json::from_reader(file)?;
+        let main = match read_log(&path) {
+            Ok(main) => main,
+            Err(e) => {
+                println!("⚠️ Not loaded: {e}");
+                return Ok(());
+            }
+        };
         self.session = main.clone();
         self.branches = HashMap::from([("main".into(), main)]);
         println!("📌 Session loaded: {}", id);
@@ -169,7 +479,7 @@ impl SessionManager {
             return Ok(());
         }
 
-        self.save_to_logs().ok();
+        self.autosave();
 
         if let Some(b) = self.branches.get(name).cloned() {
             self.session = b;
@@ -177,10 +487,15 @@ impl SessionManager {
             return Ok(());
         }
 
-        let path = Path::new(LOG_DIR).join(format!("{}_{}.json", self.session.id, name));
+        let path = self.log_path(&self.session.id, name);
         if path.exists() {
-            let file = File::open(&path)?;
-            let loaded: Session = serde_json::from_reader(file)?;
+            let loaded = match read_log(&path) {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    println!("⚠️ Not loaded: {e}");
+                    return Ok(());
+                }
+            };
             self.branches.insert(name.into(), loaded.clone());
             self.session = loaded;
             println!("🔀 Loaded '{}'", name);
@@ -209,9 +524,9 @@ impl SessionManager {
             return Ok(());
         }
 
+        self.trash_log(&self.log_path(&self.session.id, name))?;
         self.branches.remove(name);
-        let path = Path::new(LOG_DIR).join(format!("{}_{}.json", self.session.id, name));
-        let _ = fs::remove_file(path);
+        println!("🗑️ Branch '{name}' moved to the trash.");
 
         if self.session.branch == name {
             self.session = self.branches["main"].clone();
@@ -235,8 +550,8 @@ impl SessionManager {
             return Ok(());
         }
 
-        let old_path = Path::new(LOG_DIR).join(format!("{}_{}.json", self.session.id, old));
-        let new_path = Path::new(LOG_DIR).join(format!("{}_{}.json", self.session.id, new));
+        let old_path = self.log_path(&self.session.id, old);
+        let new_path = self.log_path(&self.session.id, new);
         if old_path.exists() {
             fs::rename(old_path, new_path)?;
         }
@@ -253,6 +568,207 @@ impl SessionManager {
         Ok(())
     }
 
+    /// -------- Generation params --------
+    pub fn handle_params_command(&mut self, input: &str) -> Result<(), Box<dyn Error>> {
+        let parts: Vec<&str> = input.splitn(3, ' ').collect();
+
+        match (parts.get(1).copied(), parts.get(2).copied()) {
+            (None, _) => {
+                let p = &self.session.params;
+                let show = |v: Option<String>| v.unwrap_or_else(|| "default".into());
+                println!("⚙️ Stop sequences: {:?}", p.stop);
+                println!("   temperature: {}", show(p.temperature.map(|t| t.to_string())));
+                println!("   top_p:       {}", show(p.top_p.map(|t| t.to_string())));
+                println!("   max_tokens:  {}", show(p.max_tokens.map(|n| n.to_string())));
+            }
+            // The older spelling of `/set stop`; same parsing and saving.
+            (Some("stop"), Some(seq)) => {
+                let seq = if seq.trim() == "clear" { "off" } else { seq };
+                return self.handle_set_command(&format!("/set stop {seq}"));
+            }
+            _ => println!("Usage: /params [stop <seq>|stop clear]"),
+        }
+        Ok(())
+    }
+
+    /// -------- Prompt from file --------
+    /// Send the contents of `path` as a single user message, wrapped in
+    /// `prompt_file_template` when set (`{{path}}`, `{{content}}` and session
+    /// variables are expanded).
+    pub fn handle_prompt_file_command(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        let content = read_limited(Path::new(path), self.tool_policy.max_read_bytes)?;
+        let prompt = match &self.prompt_file_template {
+            Some(template) => self
+                .session
+                .expand_vars(template, &[("path", path), ("content", &content)])?,
+            None => content,
+        };
+
+        println!("📄 Sending '{}' ({} chars) as the prompt...", path, prompt.chars().count());
+        self.session.messages.push(Message {
+            role: "user".into(),
+            content: prompt.clone(),
+        });
+        self.send_and_stream_llm(&prompt)
+    }
+
+    /// -------- Session variables --------
+    pub fn handle_set_command(&mut self, input: &str) -> Result<(), Box<dyn Error>> {
+        let rest = input.trim_start_matches("/set").trim();
+        if let Some((name @ ("temperature" | "top_p" | "max_tokens" | "stop"), value)) = rest.split_once(' ') {
+            match self.session.params.set(name, value) {
+                Ok(()) if name == "stop" => println!("⚙️ stop = {:?}", self.session.params.stop),
+                Ok(()) => println!("⚙️ {name} = {}", value.trim()),
+                Err(e) => {
+                    println!("⚠️ {e}");
+                    return Ok(());
+                }
+            }
+            return self.save_to_logs();
+        }
+        let Some(assignment) = rest.strip_prefix("var").map(str::trim) else {
+            println!("Usage: /set var [name=value] | /set <temperature|top_p|max_tokens|stop> <value|off>");
+            return Ok(());
+        };
+
+        if assignment.is_empty() {
+            if self.session.vars.is_empty() {
+                println!("(no variables)");
+            }
+            for (name, value) in &self.session.vars {
+                println!("{name} = {value}");
+            }
+            return Ok(());
+        }
+
+        let Some((name, value)) = assignment.split_once('=') else {
+            println!("Usage: /set var name=value");
+            return Ok(());
+        };
+        let name = name.trim();
+        if !is_var_name(name) {
+            println!("⚠️ Variable names may only contain letters, digits, '_' and '-'.");
+            return Ok(());
+        }
+
+        let value = value.trim();
+        if value.is_empty() {
+            self.session.vars.remove(name);
+            println!("🧹 Unset {name}");
+        } else {
+            self.session.vars.insert(name.to_string(), value.to_string());
+            println!("📝 {name} = {value}");
+        }
+        self.save_to_logs()
+    }
+
+    /// `/system <text>` sets the session's system prompt, `/system clear`
+    /// removes it and a bare `/system` prints it.
+    pub fn handle_system_command(&mut self, input: &str) -> Result<(), Box<dyn Error>> {
+        match input.trim_start_matches("/system").trim() {
+            "" => {
+                match &self.session.system_prompt {
+                    Some(prompt) => println!("🧭 System prompt: {prompt}"),
+                    None => println!("(no system prompt — set one with /system <text>)"),
+                }
+                return Ok(());
+            }
+            "clear" => {
+                self.session.system_prompt = None;
+                println!("🧹 System prompt cleared.");
+            }
+            text => {
+                self.session.system_prompt = Some(text.to_string());
+                println!("🧭 System prompt set.");
+            }
+        }
+        self.save_to_logs()
+    }
+
+    /// -------- Export --------
+    /// `/export md [all]` writes the current branch (or every loaded branch)
+    /// as Markdown; `/export context <file>` dumps the next request's messages.
+    pub fn handle_export_command(&mut self, input: &str) -> Result<(), Box<dyn Error>> {
+        let args = input.trim_start_matches("/export").trim();
+        match args.split_once(' ').unwrap_or((args, "")) {
+            ("context", file) if !file.trim().is_empty() => self.export_context(file.trim()),
+            ("md", scope @ ("" | "all")) => {
+                let footer = self.expanded_footer()?;
+                let text = if scope == "all" {
+                    // The live branch may be ahead of its copy in `branches`.
+                    let mut names: Vec<&String> = self.branches.keys().collect();
+                    names.sort();
+                    let last = names.len().saturating_sub(1);
+                    // One footer for the whole file, after the last branch.
+                    names
+                        .into_iter()
+                        .enumerate()
+                        .map(|(i, name)| {
+                            let session = match name == &self.session.branch {
+                                true => &self.session,
+                                false => &self.branches[name],
+                            };
+                            export::render_markdown(session, footer.as_deref().filter(|_| i == last))
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                } else {
+                    export::render_markdown(&self.session, footer.as_deref())
+                };
+                let name = format!("{}.md", encode_name_part(&self.session.id));
+                let path = export::write_export(&self.log_dir, &name, &text)?;
+                println!("📝 Markdown written to {}", path.display());
+                Ok(())
+            }
+            _ => {
+                println!("Usage: /export md [all] | /export context <file>");
+                Ok(())
+            }
+        }
+    }
+
+    /// `export_footer` expanded for the live branch, if one is set.
+    fn expanded_footer(&self) -> Result<Option<String>, Box<dyn Error>> {
+        match self.export_footer.as_deref() {
+            Some(t) => Ok(Some(export::expand_footer(t, &self.session, &self.model, &export::today())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// -------- Sharing --------
+    pub fn handle_share_command(&mut self, input: &str) -> Result<(), Box<dyn Error>> {
+        let parts: Vec<&str> = input.splitn(3, ' ').collect();
+
+        match (parts.get(1).copied(), parts.get(2).copied()) {
+            (None, _) => {
+                let footer = self.expanded_footer()?;
+                let text = self
+                    .redactor
+                    .redact(&export::render_markdown(&self.session, footer.as_deref()));
+                let name = format!("{}_{}_shared.md", encode_name_part(&self.session.id), encode_name_part(&self.session.branch));
+                let path = export::write_export(&self.log_dir, &name, &text)?;
+                println!("🔗 Redacted transcript written to {}", path.display());
+            }
+            (Some("add"), Some(pattern)) => {
+                self.redactor.add(pattern.trim(), "[REDACTED]")?;
+                println!("🙈 Will redact /{}/ in shared transcripts.", pattern.trim());
+            }
+            (Some("footer"), Some(template)) => {
+                self.export_footer = match template.trim() {
+                    "off" => None,
+                    "default" => Some(export::DEFAULT_FOOTER.to_string()),
+                    custom => Some(custom.to_string()),
+                };
+                match &self.export_footer {
+                    Some(t) => println!("🖋️ Export footer set: {t}"),
+                    None => println!("🖋️ Export footer disabled."),
+                }
+            }
+            _ => println!("Usage: /share [add <regex> | footer <template|default|off>]"),
+        }
+        Ok(())
+    }
+
     /// -------- Session commands --------
     pub fn handle_session_command(&mut self, input: &str) -> Result<(), Box<dyn Error>> {
         let parts: Vec<&str> = input.split_whitespace().collect();
@@ -276,18 +792,16 @@ impl SessionManager {
     }
 
     fn session_list(&self) -> Result<(), Box<dyn Error>> {
-        fs::create_dir_all(LOG_DIR)?;
+        fs::create_dir_all(&self.log_dir)?;
 
         let mut groups: HashMap<String, usize> = HashMap::new();
-        for entry in fs::read_dir(LOG_DIR)? {
+        for entry in fs::read_dir(&self.log_dir)? {
             let entry = entry?;
             let name = entry.file_name();
             let name = name.to_string_lossy();
 
-            if let Some((id, rest)) = name.split_once('_') {
-                if rest.ends_with(".json") {
-                    *groups.entry(id.into()).or_insert(0) += 1;
-                }
+            if let Some((id, _)) = parse_log_file_name(&name) {
+                *groups.entry(id).or_insert(0) += 1;
             }
         }
 
@@ -310,10 +824,10 @@ impl SessionManager {
         }
 
         let mut files = vec![];
-        for entry in fs::read_dir(LOG_DIR)? {
+        for entry in fs::read_dir(&self.log_dir)? {
             let entry = entry?;
             let name = entry.file_name().to_string_lossy().into_owned();
-            if name.starts_with(&format!("{}_", id)) && name.ends_with(".json") {
+            if parse_log_file_name(&name).is_some_and(|(owner, _)| owner == id) {
                 files.push(entry.path());
             }
         }
@@ -329,20 +843,217 @@ impl SessionManager {
         }
 
         for file in files {
-            let _ = fs::remove_file(file);
+            self.trash_log(&file)?;
         }
+        println!("🗑️ Session '{id}' moved to the trash.");
 
         if self.session.id == id {
-            *self = SessionManager::new();
+            self.start_fresh_session();
             println!("🚮 Deleted current session. New session created.");
         }
 
         Ok(())
     }
+
+    /// -------- Trash --------
+    pub fn handle_trash_command(&mut self, input: &str) -> Result<(), Box<dyn Error>> {
+        let parts: Vec<&str> = input.split_whitespace().collect();
+
+        match (parts.get(1).copied(), parts.get(2).copied()) {
+            (None | Some("list"), _) => self.trash_list(),
+            (Some("restore"), Some(name)) => self.trash_restore(name),
+            (Some("delete"), Some(name)) => self.trash_delete(name),
+            (Some("empty"), _) => self.trash_empty(),
+            _ => {
+                println!("Usage: /trash [list|restore <name>|delete <name>|empty]");
+                Ok(())
+            }
+        }
+    }
+
+    /// Entries of the trash as `(name, trashed at)`, newest first.
+    fn trash_entries(&self) -> Result<Vec<(String, u64)>, Box<dyn Error>> {
+        let mut entries = Vec::new();
+        if !self.tool_policy.trash_dir.exists() {
+            return Ok(entries);
+        }
+        for entry in fs::read_dir(&self.tool_policy.trash_dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            let at = trashed_name(&name).map_or(0, |(_, at)| at);
+            entries.push((name, at));
+        }
+        entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        Ok(entries)
+    }
+
+    fn trash_list(&self) -> Result<(), Box<dyn Error>> {
+        let entries = self.trash_entries()?;
+        if entries.is_empty() {
+            println!("(trash is empty)");
+            return Ok(());
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        println!("🗑️ Trash ({}):", self.tool_policy.trash_dir.display());
+        for (name, at) in entries {
+            let what = trashed_name(&name)
+                .and_then(|(original, _)| parse_log_file_name(original))
+                .map(|(id, branch)| format!("session {id}, branch {branch}"))
+                .unwrap_or_else(|| "file".into());
+            let days = now.saturating_sub(at) / 86_400;
+            println!("- {name}  ({what}, deleted {days} day(s) ago)");
+        }
+        Ok(())
+    }
+
+    /// Path of the trash entry `name`; only plain names, so nothing outside
+    /// the trash can be touched.
+    fn trash_entry(&self, name: &str) -> Option<PathBuf> {
+        if name.contains(['/', '\\']) || name == "." || name == ".." {
+            return None;
+        }
+        let path = self.tool_policy.trash_dir.join(name);
+        path.exists().then_some(path)
+    }
+
+    fn trash_restore(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        let Some(path) = self.trash_entry(name) else {
+            println!("❌ Not in the trash: {name}");
+            return Ok(());
+        };
+        let Some((id, branch)) = trashed_name(name).and_then(|(original, _)| parse_log_file_name(original)) else {
+            println!("⚠️ Not a session log; it is still at {}", path.display());
+            return Ok(());
+        };
+
+        let target = self.log_path(&id, &branch);
+        if target.exists() {
+            println!("⚠️ {} already exists; delete or rename that branch first.", target.display());
+            return Ok(());
+        }
+        fs::create_dir_all(&self.log_dir)?;
+        fs::rename(&path, &target)?;
+
+        if id == self.session.id {
+            println!("♻️ Restored branch '{branch}'. Use /branch switch {branch}");
+        } else {
+            println!("♻️ Restored session {id}, branch '{branch}'. Use /load {id}");
+        }
+        Ok(())
+    }
+
+    fn trash_delete(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        let Some(path) = self.trash_entry(name) else {
+            println!("❌ Not in the trash: {name}");
+            return Ok(());
+        };
+        if !ask_confirm(&format!("⚠️ Permanently delete '{name}'?")) {
+            println!("❎ Cancelled.");
+            return Ok(());
+        }
+        if path.is_dir() {
+            fs::remove_dir_all(&path)?;
+        } else {
+            fs::remove_file(&path)?;
+        }
+        println!("🔥 Deleted {name}");
+        Ok(())
+    }
+
+    fn trash_empty(&mut self) -> Result<(), Box<dyn Error>> {
+        let count = self.trash_entries()?.len();
+        if count == 0 {
+            println!("(trash is empty)");
+            return Ok(());
+        }
+        if !ask_confirm(&format!("⚠️ Permanently delete all {count} item(s) in the trash?")) {
+            println!("❎ Cancelled.");
+            return Ok(());
+        }
+        fs::remove_dir_all(&self.tool_policy.trash_dir)?;
+        println!("🔥 Trash emptied.");
+        Ok(())
+    }
+
+    /// Permanently delete trash entries older than `days` days (`trash_days`
+    /// in the config file). Returns how many were removed.
+    pub fn purge_trash(&self, days: u64) -> usize {
+        trash::purge(&self.tool_policy.trash_dir, days)
+    }
+
+    /// -------- Audit --------
+    pub fn handle_audit_command(&self, input: &str) -> Result<(), Box<dyn Error>> {
+        let parts: Vec<&str> = input.split_whitespace().collect();
+        let count = match (parts.get(1).copied(), parts.get(2)) {
+            (None | Some("tail"), None) => Some(20),
+            (Some("tail"), Some(n)) => n.parse().ok().filter(|&n: &usize| n > 0),
+            _ => None,
+        };
+        let Some(count) = count else {
+            println!("Usage: /audit tail [n]");
+            return Ok(());
+        };
+
+        let path = &self.tool_policy.audit_log;
+        let (entries, unreadable) = audit::tail(path, count)?;
+        if entries.is_empty() {
+            println!("(no tool runs recorded in {})", path.display());
+        } else {
+            println!("🧾 Last {} tool run(s) ({}):", entries.len(), path.display());
+            for entry in &entries {
+                println!("{}", audit_line(entry));
+            }
+        }
+        if unreadable > 0 {
+            println!("⚠️ {unreadable} unreadable line(s) skipped.");
+        }
+        Ok(())
+    }
+}
+
+/// One `/audit tail` line: when, which session, and what the tool did.
+fn audit_line(entry: &AuditEntry) -> String {
+    let time = entry.ts % 86_400;
+    let mut target = match (&entry.command, &entry.path) {
+        (Some(command), _) => {
+            let first = command.lines().next().unwrap_or("");
+            let short: String = first.chars().take(60).collect();
+            let cut = short.len() < first.len() || command.lines().nth(1).is_some();
+            format!(" `{short}{}`", if cut { "…" } else { "" })
+        }
+        (None, Some(path)) => format!(" {path}"),
+        (None, None) => String::new(),
+    };
+    if let Some(dst) = &entry.dst {
+        target.push_str(&format!(" → {dst}"));
+    }
+
+    let mut details = Vec::new();
+    if let Some(code) = entry.exit_code {
+        details.push(format!("exit {code}"));
+    }
+    match &entry.error {
+        Some(e) => details.push(format!("error: {e}")),
+        None => details.push(format!("{} bytes", entry.result_len)),
+    }
+    details.push(format!("{} ms", entry.duration_ms));
+
+    format!(
+        "- {} {:02}:{:02}:{:02}  {}  {} {}{}  ({})",
+        export::date_of(entry.ts),
+        time / 3_600,
+        time % 3_600 / 60,
+        time % 60,
+        entry.session,
+        if entry.ok { "✅" } else { "❌" },
+        entry.tool,
+        target,
+        details.join(", ")
+    )
 }
 
 /// Yes/No prompt
-fn ask_confirm(prompt: &str) -> bool {
+pub(crate) fn ask_confirm(prompt: &str) -> bool {
     use std::io::{stdin, stdout, Write};
 
     print!("{prompt} (y/n): ");
@@ -353,3 +1064,162 @@ fn ask_confirm(prompt: &str) -> bool {
 
     matches!(buf.trim().to_lowercase().as_str(), "y" | "yes")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MockBackend;
+
+    fn session() -> Session {
+        Session {
+            id: "1".into(),
+            branch: "main".into(),
+            created_at: 0,
+            messages: vec![],
+            summary: None,
+            vars: BTreeMap::new(),
+            system_prompt: None,
+            params: GenParams::default(),
+        }
+    }
+
+    fn message(role: &str, content: &str) -> Message {
+        Message { role: role.into(), content: content.into() }
+    }
+
+    #[test]
+    fn role_counts_counts_each_role() {
+        let mut s = session();
+        assert_eq!(s.role_counts().total(), 0);
+        for role in ["system", "user", "assistant", "user", "tool", "assistant", "user"] {
+            s.messages.push(message(role, "x"));
+        }
+        assert_eq!(s.role_counts(), RoleCounts { user: 3, assistant: 2, other: 2 });
+        assert_eq!(s.role_counts().total(), 7);
+    }
+
+    /// A fresh, empty directory under the system temp dir.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mycli-session-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A manager logging into a scratch directory whose backend always
+    /// answers "ok".
+    fn mock_manager(name: &str) -> (SessionManager, PathBuf) {
+        let dir = scratch_dir(name);
+        let mut manager = SessionManager::new(dir.join("logs"));
+        manager.backend = Box::new(MockBackend { reply: "ok".into() });
+        (manager, dir)
+    }
+
+    #[test]
+    fn prompt_file_sends_the_file_through_the_template() {
+        let (mut manager, dir) = mock_manager("prompt-file");
+        let file = dir.join("notes.txt");
+        fs::write(&file, "buy milk").unwrap();
+        let path = file.to_string_lossy().into_owned();
+        manager.prompt_file_template = Some("Review {{path}} for {{who}}:\n{{content}}".into());
+        manager.session.vars.insert("who".into(), "Ada".into());
+
+        manager.handle_prompt_file_command(&path).unwrap();
+
+        let sent: Vec<_> = manager.session.messages.iter().map(|m| (m.role.as_str(), m.content.as_str())).collect();
+        let prompt = format!("Review {path} for Ada:\nbuy milk");
+        assert_eq!(sent, [("user", prompt.as_str()), ("assistant", "ok")]);
+    }
+
+    #[test]
+    fn prompt_file_refuses_files_over_the_read_limit() {
+        let (mut manager, dir) = mock_manager("prompt-file-large");
+        let file = dir.join("big.txt");
+        fs::write(&file, "x".repeat(100)).unwrap();
+        manager.tool_policy.max_read_bytes = 10;
+
+        let err = manager.handle_prompt_file_command(&file.to_string_lossy()).unwrap_err();
+
+        assert!(err.to_string().contains("too large"), "{err}");
+        assert!(manager.session.messages.is_empty());
+        assert!(manager.handle_prompt_file_command("/no/such/file").is_err());
+    }
+
+    #[test]
+    fn expand_vars_fills_fixed_values_and_session_vars() {
+        let mut s = session();
+        s.vars.insert("lang".into(), "Rust".into());
+        s.vars.insert("path".into(), "shadowed".into());
+        let out = s.expand_vars("{{ path }} in {{lang}}: {{content}}", &[("path", "a.rs"), ("content", "{{lang}}")]);
+        // Fixed values win over variables and are not expanded again.
+        assert_eq!(out.unwrap(), "a.rs in Rust: {{lang}}");
+    }
+
+    #[test]
+    fn expand_vars_keeps_braces_that_are_not_placeholders() {
+        let s = session();
+        assert_eq!(s.expand_vars("fn f() {{ a + b }} {{", &[]).unwrap(), "fn f() {{ a + b }} {{");
+    }
+
+    #[test]
+    fn expand_vars_rejects_undefined_variables() {
+        let err = session().expand_vars("hi {{who}}", &[]).unwrap_err();
+        assert!(err.to_string().contains("undefined variable 'who'"), "{err}");
+    }
+
+    #[test]
+    fn log_file_names_round_trip() {
+        for (id, branch) in [("1", "main"), ("a_b", "fix_1"), ("100%", "x/y:z"), ("日本", "tab\there")] {
+            let name = log_file_name(id, branch);
+            assert_eq!(name.matches('_').count(), 1, "{name}");
+            assert!(!name.contains(['/', ':', '\t']), "{name}");
+            assert_eq!(parse_log_file_name(&name), Some((id.to_string(), branch.to_string())));
+        }
+    }
+
+    #[test]
+    fn parse_log_file_name_keeps_malformed_escapes() {
+        assert_eq!(parse_log_file_name("50%_%zz.json"), Some(("50%".into(), "%zz".into())));
+        assert_eq!(parse_log_file_name("1_main.txt"), None);
+        assert_eq!(parse_log_file_name("nounderscore.json"), None);
+    }
+
+    #[test]
+    fn save_to_logs_leaves_no_temp_file() {
+        let (mut manager, dir) = mock_manager("atomic-save");
+        manager.session.messages.push(message("user", "hi"));
+        manager.save_to_logs().unwrap();
+
+        let names: Vec<_> = fs::read_dir(dir.join("logs"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, [log_file_name(&manager.session.id, "main")]);
+        let saved = read_log(&manager.log_path(&manager.session.id, "main")).unwrap();
+        assert_eq!(saved.messages.len(), 1);
+    }
+
+    #[test]
+    fn load_session_skips_a_corrupt_log() {
+        let (mut manager, dir) = mock_manager("corrupt-load");
+        fs::create_dir_all(dir.join("logs")).unwrap();
+        fs::write(manager.log_path("42", "main"), "{\"id\": \"42\", \"messa").unwrap();
+        let before = manager.session.id.clone();
+
+        manager.load_session(Some("42")).unwrap();
+        assert_eq!(manager.session.id, before);
+        assert!(read_log(&manager.log_path("42", "main")).is_err());
+    }
+
+    #[test]
+    fn coalesce_merges_runs_of_the_same_role() {
+        let mut s = session();
+        for (role, content) in [("user", "a"), ("user", "b"), ("assistant", "c"), ("user", "d"), ("user", "e"), ("user", "f")] {
+            s.messages.push(message(role, content));
+        }
+        assert_eq!(s.coalesce(), 3);
+        let merged: Vec<_> = s.messages.iter().map(|m| (m.role.as_str(), m.content.as_str())).collect();
+        assert_eq!(merged, [("user", "a\nb"), ("assistant", "c"), ("user", "d\ne\nf")]);
+        assert_eq!(s.coalesce(), 0);
+    }
+}
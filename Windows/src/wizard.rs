@@ -0,0 +1,86 @@
+use crate::api_key::API_KEY_ENV;
+use crate::backend::PROVIDERS;
+use crate::config::{config_path, Config};
+use crate::session::DEFAULT_MODEL;
+use std::env;
+use std::io::{self, BufRead, Write};
+
+/// First run: no config file and no key in the environment.
+pub fn is_first_run() -> bool {
+    let has_config = config_path().is_some_and(|p| p.exists());
+    let has_env_key = env::var(API_KEY_ENV).is_ok_and(|k| !k.trim().is_empty());
+    !has_config && !has_env_key
+}
+
+/// Ask for provider, model and the env var holding the key.
+/// Empty answers take the default shown in brackets.
+pub fn run(input: &mut impl BufRead, out: &mut impl Write) -> io::Result<Config> {
+    writeln!(out, "👋 Looks like this is your first run. Let's set things up.")?;
+    writeln!(out, "   (Press Enter to accept the default in brackets.)\n")?;
+
+    let provider = loop {
+        let answer = ask(input, out, "Provider", PROVIDERS[0])?;
+        if PROVIDERS.contains(&answer.as_str()) {
+            break answer;
+        }
+        writeln!(out, "⚠️ Unknown provider '{answer}'. Available: {}", PROVIDERS.join(", "))?;
+    };
+    let model = ask(input, out, "Model", DEFAULT_MODEL)?;
+    let api_key_env = ask(input, out, "Environment variable holding your API key", API_KEY_ENV)?;
+
+    Ok(Config {
+        provider: Some(provider),
+        model: Some(model),
+        api_key_env: Some(api_key_env),
+        ..Default::default()
+    })
+}
+
+fn ask(input: &mut impl BufRead, out: &mut impl Write, question: &str, default: &str) -> io::Result<String> {
+    write!(out, "{question} [{default}]: ")?;
+    out.flush()?;
+
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "setup cancelled"));
+    }
+    let answer = line.trim();
+    Ok(if answer.is_empty() { default } else { answer }.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn run_with(answers: &str) -> (io::Result<Config>, String) {
+        let mut out = Vec::new();
+        let config = run(&mut Cursor::new(answers), &mut out);
+        (config, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn empty_answers_take_the_defaults() {
+        let (config, _) = run_with("\n\n\n");
+        let config = config.unwrap();
+        assert_eq!(config.provider.as_deref(), Some(PROVIDERS[0]));
+        assert_eq!(config.model.as_deref(), Some(DEFAULT_MODEL));
+        assert_eq!(config.api_key_env.as_deref(), Some(API_KEY_ENV));
+    }
+
+    #[test]
+    fn unknown_providers_are_asked_again() {
+        let (config, out) = run_with("gpt\n ollama \nqwen3:1.7b\nMY_KEY\n");
+        let config = config.unwrap();
+        assert!(out.contains("Unknown provider 'gpt'"), "{out}");
+        assert_eq!(config.provider.as_deref(), Some("ollama"));
+        assert_eq!(config.model.as_deref(), Some("qwen3:1.7b"));
+        assert_eq!(config.api_key_env.as_deref(), Some("MY_KEY"));
+    }
+
+    #[test]
+    fn end_of_input_cancels_setup() {
+        let (config, _) = run_with("mock\n");
+        assert_eq!(config.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
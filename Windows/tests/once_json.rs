@@ -0,0 +1,119 @@
+//! `mycli --once --json` as a script sees it: stdout must hold nothing but
+//! the JSON object, whatever the run prints along the way.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mycli-once-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir.canonicalize().unwrap()
+}
+
+/// An OpenAI-compatible server streaming one entry of `replies` per
+/// request; returns the base URL.
+fn fake_server(replies: Vec<&'static str>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for reply in replies {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            // Read the headers, then as much body as they announce.
+            while let Ok(n @ 1..) = stream.read(&mut buf) {
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let len = text[..end]
+                        .lines()
+                        .find_map(|l| l.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().to_string()))
+                        .and_then(|v| v.parse::<usize>().ok())
+                        .unwrap_or(0);
+                    if request.len() >= end + 4 + len {
+                        break;
+                    }
+                }
+            }
+            let delta = serde_json::json!({ "choices": [{ "delta": { "content": reply } }] });
+            let body = format!("data: {delta}\n\ndata: [DONE]\n\n");
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+    format!("http://{addr}/v1")
+}
+
+/// Run `mycli --json --mcp <extra>` in `dir/work` against a fake server
+/// streaming `replies`; returns the parsed stdout and the stderr text.
+fn run_once_json(dir: &Path, extra: &[&str], replies: Vec<&'static str>) -> (serde_json::Value, String) {
+    let base = fake_server(replies);
+    let config = dir.join(".config").join("rustai");
+    fs::create_dir_all(&config).unwrap();
+    fs::write(config.join("config.toml"), format!("provider = \"openai\"\nopenai_base_url = \"{base}\"\n")).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mycli"))
+        .args(["--json", "--mcp"])
+        .args(extra)
+        .current_dir(dir.join("work"))
+        .env("HOME", dir)
+        .env("RUSTAI_LOG_DIR", dir.join("logs"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"Look at a.txt").unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    assert!(output.status.success(), "{stderr}");
+    let value = serde_json::from_str(&stdout).unwrap_or_else(|e| panic!("{e} in {stdout:?}"));
+    (value, stderr)
+}
+
+#[test]
+fn once_json_mcp_prints_only_json_on_stdout() {
+    let dir = scratch_dir("json-mcp");
+    fs::create_dir_all(dir.join("work")).unwrap();
+    fs::write(dir.join("work").join("a.txt"), "hello").unwrap();
+
+    let (value, stderr) = run_once_json(
+        &dir,
+        &[],
+        vec![r#"<use_tool name="filesystem.read" params={"path": "a.txt"} />"#, "It says hello. Done."],
+    );
+
+    assert_eq!(value["response"], "It says hello. Done.");
+    assert_eq!(value["tool_calls"][0]["name"], "filesystem.read");
+    assert!(stderr.contains("📂 Read file 'a.txt'"), "{stderr}");
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn once_json_safe_mode_refusal_stays_off_stdout() {
+    let dir = scratch_dir("json-safe");
+    fs::create_dir_all(dir.join("work")).unwrap();
+    fs::write(dir.join("work").join("a.txt"), "hello").unwrap();
+
+    let (value, stderr) = run_once_json(
+        &dir,
+        &["--safe"],
+        vec![r#"<use_tool name="filesystem.delete" params={"path": "a.txt"} />"#, "Could not. Done."],
+    );
+
+    assert_eq!(value["tool_calls"][0]["name"], "filesystem.delete");
+    assert!(value["tool_calls"][0]["result"].as_str().unwrap().starts_with("error: "), "{value}");
+    assert!(stderr.contains("🔒 Refused filesystem.delete"), "{stderr}");
+    assert!(dir.join("work").join("a.txt").exists());
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -1,21 +1,209 @@
-use std::sync::mpsc::Sender;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures_util::future::join_all;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+use tokio::task::{AbortHandle, JoinHandle};
 
 use ratatui::widgets::ListState;
 use uuid::Uuid;
 use ratatui::layout::Rect;
 
+use crate::backend::{default_backend, LlmBackend};
+use crate::config::{usable_log_dir, Config, EmptyResponse, EMPTY_RESPONSE_MARKER};
+use crate::frontend::actions::{ToolCache, ToolPolicy, HISTORY_MAX_CHARS};
+use crate::mcp_client::McpClient;
+use crate::trash;
+use crate::tui::WrapCache;
+
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::error::Error;
 use std::fs;
 use std::fs::File;
-use std::path::Path;
-use serde_json;
+use std::path::{Path, PathBuf};
 
-/// Who sent the message.
+const TRIMMED_MARKER: &str = "[earlier conversation trimmed]";
+
+/// Sent inputs kept for Up / Down recall across restarts, as a JSON list
+/// in the log directory. Not named `*.json`, which `load_logs` would take
+/// for a branch file.
+const INPUT_HISTORY_FILE: &str = ".input_history";
+const INPUT_HISTORY_MAX: usize = 100;
+/// Characters per token for the status line's estimate.
+pub const CHARS_PER_TOKEN: usize = 4;
+
+/// Inputs saved by `App::record_input`; empty if the file is missing or bad.
+fn load_input_history(log_dir: &Path) -> Vec<String> {
+    fs::read_to_string(log_dir.join(INPUT_HISTORY_FILE))
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+/// Layout choices kept across restarts, as JSON next to the input history.
+const UI_PREFS_FILE: &str = ".ui_prefs";
+const SIDEBAR_DEFAULT_WIDTH: u16 = 25;
+const SIDEBAR_MIN_WIDTH: u16 = 15;
+const SIDEBAR_MAX_WIDTH: u16 = 60;
+/// Columns `<` / `>` take from or give to the sidebar.
+const SIDEBAR_STEP: u16 = 5;
+
+/// What `App::save_ui_prefs` writes; missing fields fall back to defaults.
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+struct UiPrefs {
+    sidebar_collapsed: bool,
+    sidebar_width: u16,
+}
+
+impl Default for UiPrefs {
+    fn default() -> Self {
+        Self { sidebar_collapsed: false, sidebar_width: SIDEBAR_DEFAULT_WIDTH }
+    }
+}
+
+/// Session indices in sidebar order: most recently updated first, and the
+/// later-created of two sessions updated in the same second.
+pub fn session_order(sessions: &[Session]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..sessions.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse((sessions[i].updated_at, sessions[i].created_at, i)));
+    order
+}
+
+/// Saved layout choices; the defaults if the file is missing or bad.
+fn load_ui_prefs(log_dir: &Path) -> UiPrefs {
+    let mut prefs: UiPrefs = fs::read_to_string(log_dir.join(UI_PREFS_FILE))
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default();
+    prefs.sidebar_width = prefs.sidebar_width.clamp(SIDEBAR_MIN_WIDTH, SIDEBAR_MAX_WIDTH);
+    prefs
+}
+
+/// Index of the first message to keep so that the messages from there on
+/// fit in `budget` characters (`lens` are the per-message sizes). The last
+/// two messages, the latest user/assistant pair, are always kept.
+fn budget_start(lens: &[usize], budget: usize) -> usize {
+    let mut start = lens.len();
+    let mut used = 0;
+    while start > 0 {
+        let len = lens[start - 1];
+        let must_keep = lens.len() - start < 2;
+        if !must_keep && used + len > budget {
+            break;
+        }
+        used += len;
+        start -= 1;
+    }
+    start
+}
+
+/// Escape characters that would be ambiguous or unsafe in a log file name
+/// (`%`, the `_` separator, path separators and control characters) as
+/// `%XX`, so any session title or branch name round-trips.
+fn encode_name_part(part: &str) -> String {
+    let mut out = String::with_capacity(part.len());
+    for c in part.chars() {
+        if matches!(c, '%' | '_' | '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') || c.is_control() {
+            let mut buf = [0; 4];
+            for b in c.encode_utf8(&mut buf).bytes() {
+                out.push_str(&format!("%{b:02X}"));
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Inverse of `encode_name_part`. Malformed escapes are kept literally.
+fn decode_name_part(part: &str) -> String {
+    let bytes = part.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let Some(b) = part.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Split the name of a per-branch log file, `<session>_<branch>.json` with
+/// both parts escaped, back into `(session, branch)`.
+pub(crate) fn parse_log_file_name(name: &str) -> Option<(String, String)> {
+    let stem = name.strip_suffix(".json")?;
+    let (title, branch) = stem.split_once('_')?;
+    Some((decode_name_part(title), decode_name_part(branch)))
+}
+
+/// Sessions are saved one file each, as `<log dir>/sessions/<id>.json`.
+const SESSIONS_DIR: &str = "sessions";
+/// Layout of a session file, saved as its `schema_version`. Files without
+/// the field count as version 0.
+pub const SESSION_SCHEMA_VERSION: u64 = 2;
+/// Where per-branch log files are moved once folded into session files.
+const LEGACY_DIR: &str = "legacy";
+
+/// A session as JSON for its file, stamped with `SESSION_SCHEMA_VERSION`.
+fn versioned_session(session: &Session) -> Result<Value, Box<dyn Error>> {
+    let mut value = serde_json::to_value(session)?;
+    value["schema_version"] = SESSION_SCHEMA_VERSION.into();
+    Ok(value)
+}
+
+/// `schema_version` of a saved session.
+fn schema_version(value: &Value) -> u64 {
+    value.get("schema_version").and_then(Value::as_u64).unwrap_or(0)
+}
+
+/// Bring a session file up to `SESSION_SCHEMA_VERSION`, one version at a
+/// time, so files saved by older builds keep loading as the layout changes.
+/// Files from a newer build must be turned away before this: read with
+/// missing fields and saved back, they would lose what this build does not
+/// know about.
+fn upgrade_session(mut value: Value) -> Result<Session, serde_json::Error> {
+    if schema_version(&value) < 1 {
+        // Hand-written files may leave out which branch was open.
+        if value.get("active_branch").is_none() {
+            let last = value["branches"].as_array().map_or(0, |b| b.len().saturating_sub(1));
+            value["active_branch"] = last.into();
+        }
+    }
+    // Version 2 added `Tool` messages; older files have none, so they load
+    // as they are.
+
+    serde_json::from_value(value)
+}
+
+/// Write `value` as pretty JSON next to `path` and rename it into place, so
+/// a crash mid-write never leaves a truncated log behind.
+fn write_json_atomic<T: Serialize>(path: &Path, value: &T) -> Result<(), Box<dyn Error>> {
+    let tmp = path.with_extension("json.tmp");
+    let file = File::create(&tmp)?;
+    serde_json::to_writer_pretty(&file, value)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Who sent the message.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum MessageFrom {
     User,
     Assistant,
+    /// The result of a tool the assistant called during an MCP run.
+    Tool,
 }
 
 /// Single message in a branch.
@@ -23,6 +211,85 @@ pub enum MessageFrom {
 pub struct Message {
     pub from: MessageFrom,
     pub content: String,
+    /// Source URLs/titles the provider cited for this reply, if any.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<String>,
+    /// Unix time (seconds) the message was created; `None` in logs written
+    /// before timestamps were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    /// Why the reply failed (network, API or tool loop error), shown
+    /// below the content in the error style.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Name of the tool that produced a `Tool` message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool: Option<String>,
+}
+
+/// How many of `messages` belong to the reply they start with: an
+/// assistant message, then any tool results and the answers after them.
+pub fn reply_len(messages: &[Message]) -> usize {
+    if messages.first().is_none_or(|m| m.from != MessageFrom::Assistant) {
+        return 0;
+    }
+    let mut len = 1;
+    while let Some(m) = messages.get(len) {
+        let follows_tool = messages[len - 1].from == MessageFrom::Tool;
+        match m.from {
+            MessageFrom::Tool => len += 1,
+            MessageFrom::Assistant if follows_tool => len += 1,
+            _ => break,
+        }
+    }
+    len
+}
+
+/// Whether `title` is still the "Session N" a new session gets.
+pub fn is_default_title(title: &str) -> bool {
+    title
+        .strip_prefix("Session ")
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Seconds since the Unix epoch, the format of every stored timestamp.
+pub fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// "just now", "5m ago", "2h ago", "3d ago" for the sidebar.
+pub fn relative_time(ts: u64, now: u64) -> String {
+    let secs = now.saturating_sub(ts);
+    match secs {
+        0..=59 => "just now".to_string(),
+        60..=3_599 => format!("{}m ago", secs / 60),
+        3_600..=86_399 => format!("{}h ago", secs / 3_600),
+        _ => format!("{}d ago", secs / 86_400),
+    }
+}
+
+impl Message {
+    /// New message stamped with the current time.
+    pub fn new(from: MessageFrom, content: impl Into<String>) -> Self {
+        Self {
+            from,
+            content: content.into(),
+            sources: Vec::new(),
+            timestamp: Some(now_secs()),
+            error: None,
+            tool: None,
+        }
+    }
+
+    /// The result of tool `name`.
+    pub fn tool(name: impl Into<String>, result: impl Into<String>) -> Self {
+        Self { tool: Some(name.into()), ..Self::new(MessageFrom::Tool, result) }
+    }
+
+    /// A `Tool` message as the text the model saw during the run.
+    pub fn tool_text(&self) -> String {
+        format!("[Tool: {}]\nresult: {}", self.tool.as_deref().unwrap_or("unknown"), self.content)
+    }
 }
 
 /// A single conversation branch.
@@ -31,6 +298,87 @@ pub struct Branch {
     pub id: usize,              // Unique branch identifier
     pub name: String,           // Branch display name ("main", "branch-1", ...)
     pub messages: Vec<Message>, // All messages in this branch
+    /// Id of the branch this one was forked from (`None` for roots).
+    #[serde(default)]
+    pub parent: Option<usize>,
+    /// Index of the edited message where the fork starts.
+    #[serde(default)]
+    pub fork_point: Option<usize>,
+    /// Copy of the session's system prompt in the per-branch log files
+    /// sessions were saved as before session files; read, never written.
+    #[serde(default, skip_serializing)]
+    pub system_prompt: Option<String>,
+    /// Copy of the session's summary in those files.
+    #[serde(default, skip_serializing)]
+    pub summary: Option<String>,
+    /// Copy of the session's `next_branch_id` in those files.
+    #[serde(default, skip_serializing)]
+    pub next_branch_id: usize,
+    /// When the branch was created and last got a message (epoch seconds,
+    /// like `created_at` in the CLI's logs). 0 until `migrate_legacy_logs`
+    /// fills in the file's modification time for logs saved without them.
+    #[serde(default)]
+    pub created_at: u64,
+    #[serde(default)]
+    pub updated_at: u64,
+}
+
+/// Number of messages per sender in a branch.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RoleCounts {
+    pub user: usize,
+    pub assistant: usize,
+    pub tool: usize,
+}
+
+impl RoleCounts {
+    pub fn total(&self) -> usize {
+        self.user + self.assistant + self.tool
+    }
+}
+
+impl Branch {
+    /// Merge runs of consecutive messages from the same sender into one,
+    /// joined with newlines; tool results stay apart. Returns how many
+    /// messages were folded away.
+    pub fn coalesce(&mut self) -> usize {
+        let before = self.messages.len();
+        let mut merged: Vec<Message> = Vec::with_capacity(before);
+        for m in self.messages.drain(..) {
+            match merged.last_mut() {
+                Some(prev) if prev.from == m.from && m.from != MessageFrom::Tool => {
+                    prev.content.push('\n');
+                    prev.content.push_str(&m.content);
+                    for source in m.sources {
+                        if !prev.sources.contains(&source) {
+                            prev.sources.push(source);
+                        }
+                    }
+                }
+                _ => merged.push(m),
+            }
+        }
+        self.messages = merged;
+        before - self.messages.len()
+    }
+
+    /// Protected branches are never bulk-deleted.
+    pub fn is_protected(&self) -> bool {
+        self.name == "main"
+    }
+
+    /// Count messages by sender.
+    pub fn role_counts(&self) -> RoleCounts {
+        let mut counts = RoleCounts::default();
+        for m in &self.messages {
+            match m.from {
+                MessageFrom::User => counts.user += 1,
+                MessageFrom::Assistant => counts.assistant += 1,
+                MessageFrom::Tool => counts.tool += 1,
+            }
+        }
+        counts
+    }
 }
 
 /// One chat session.
@@ -42,6 +390,222 @@ pub struct Session {
     // Branch system
     pub branches: Vec<Branch>,  // All branches created in this session
     pub active_branch: usize,   // Index of the currently selected branch
+
+    /// Sent as the first system message of every request (`/system`).
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    /// Model-written summary of the conversation (`S` or `/summarize`).
+    #[serde(default)]
+    pub summary: Option<String>,
+    /// Id for the next new branch. It only grows, so the id and the
+    /// `branch-N` name of a deleted branch are never handed out again.
+    #[serde(default)]
+    pub next_branch_id: usize,
+    /// Earliest `created_at` and latest `updated_at` of the branches; the
+    /// sidebar lists the most recently updated session first.
+    #[serde(default)]
+    pub created_at: u64,
+    #[serde(default)]
+    pub updated_at: u64,
+}
+
+impl Session {
+    /// Record that a message was added to branch `branch_idx` just now.
+    pub fn touch(&mut self, branch_idx: usize) {
+        let now = now_secs();
+        self.updated_at = now;
+        if let Some(branch) = self.branches.get_mut(branch_idx) {
+            branch.updated_at = now;
+        }
+    }
+
+    /// Give every branch that shares its id with an earlier one a fresh
+    /// id, so `parent` links keep resolving to the first holder, and move
+    /// `next_branch_id` past every id in use. Returns true if duplicate
+    /// ids were found.
+    pub fn normalize_branch_ids(&mut self) -> bool {
+        let highest = self.branches.iter().map(|b| b.id + 1).max().unwrap_or(0);
+        self.next_branch_id = self.next_branch_id.max(highest);
+
+        let mut seen = HashSet::new();
+        let mut had_duplicates = false;
+        for i in 0..self.branches.len() {
+            if !seen.insert(self.branches[i].id) {
+                had_duplicates = true;
+                self.branches[i].id = self.next_branch_id;
+                self.next_branch_id += 1;
+            }
+            let branch = &mut self.branches[i];
+            if branch.parent == Some(branch.id) {
+                branch.parent = None;
+            }
+        }
+        had_duplicates
+    }
+
+    /// Branch indices in tree order (depth first, children after their
+    /// parent) with the ASCII prefix that draws the tree, e.g. "├─ ".
+    pub fn branch_tree(&self) -> Vec<(usize, String)> {
+        let index_of: HashMap<usize, usize> =
+            self.branches.iter().enumerate().map(|(i, b)| (b.id, i)).collect();
+        let children = |parent: Option<usize>| -> Vec<usize> {
+            self.branches
+                .iter()
+                .enumerate()
+                .filter(|(_, b)| match parent {
+                    Some(p) => b.parent == Some(p),
+                    // Roots, and branches whose parent no longer exists.
+                    None => b.parent.is_none_or(|p| !index_of.contains_key(&p)),
+                })
+                .map(|(i, _)| i)
+                .collect()
+        };
+
+        let mut out = Vec::with_capacity(self.branches.len());
+        let mut visited = HashSet::new();
+        // (branch index, prefix for its own row, prefix for its children)
+        let mut stack: Vec<(usize, String, String)> = children(None)
+            .into_iter()
+            .rev()
+            .map(|i| (i, String::new(), String::new()))
+            .collect();
+
+        while let Some((i, own, below)) = stack.pop() {
+            if !visited.insert(i) {
+                continue;
+            }
+            out.push((i, own));
+            let kids = children(Some(self.branches[i].id));
+            let last = kids.len().saturating_sub(1);
+            for (k, child) in kids.into_iter().enumerate().rev() {
+                let (joint, cont) = if k == last { ("└─ ", "   ") } else { ("├─ ", "│  ") };
+                stack.push((child, format!("{below}{joint}"), format!("{below}{cont}")));
+            }
+        }
+
+        // A parent cycle hides its members from the walk; list them flat.
+        out.extend((0..self.branches.len()).filter(|i| !visited.contains(i)).map(|i| (i, String::new())));
+        out
+    }
+
+    /// Id and default `branch-N` name for a new branch, taken from
+    /// `next_branch_id`. Numbers whose name a (renamed or older) branch
+    /// already has are skipped.
+    pub fn new_branch_id(&mut self) -> (usize, String) {
+        loop {
+            let id = self.next_branch_id;
+            self.next_branch_id += 1;
+            let name = format!("branch-{id}");
+            if self.branches.iter().all(|b| b.name != name) {
+                return (id, name);
+            }
+        }
+    }
+
+    /// Remove message `msg_idx` of a branch, together with the reply right
+    /// after it when it is a user message (with the tool results and
+    /// answers of an MCP run). Forks taken further down keep pointing at the
+    /// same message. Returns how many were removed.
+    pub fn remove_message(&mut self, branch_idx: usize, msg_idx: usize) -> usize {
+        let branch = &mut self.branches[branch_idx];
+        if msg_idx >= branch.messages.len() {
+            return 0;
+        }
+        let mut end = msg_idx + 1;
+        if branch.messages[msg_idx].from == MessageFrom::User {
+            end += reply_len(&branch.messages[end..]);
+        }
+        branch.messages.drain(msg_idx..end);
+
+        let removed = end - msg_idx;
+        let id = branch.id;
+        for child in self.branches.iter_mut().filter(|b| b.parent == Some(id)) {
+            if let Some(point) = child.fork_point.as_mut().filter(|p| **p >= msg_idx) {
+                *point = point.saturating_sub(removed).max(msg_idx);
+            }
+        }
+        removed
+    }
+
+    /// Remove the branches at `indices`, skipping protected ones (`main`).
+    /// Keeps `active_branch` pointing at the same branch, or falls back to
+    /// the first one if it was removed. Returns the removed branches.
+    pub fn remove_branches(&mut self, indices: &BTreeSet<usize>) -> Vec<Branch> {
+        let doomed: Vec<usize> = indices
+            .iter()
+            .copied()
+            .filter(|&i| i < self.branches.len() && !self.branches[i].is_protected())
+            .collect();
+
+        // Never leave a session without branches.
+        if doomed.len() == self.branches.len() {
+            return vec![];
+        }
+
+        // Children of a removed branch move up to its parent.
+        for &i in &doomed {
+            let (id, parent) = (self.branches[i].id, self.branches[i].parent);
+            for branch in &mut self.branches {
+                if branch.parent == Some(id) {
+                    branch.parent = parent;
+                }
+            }
+        }
+
+        let active_removed = doomed.contains(&self.active_branch);
+        let shift = doomed.iter().filter(|&&i| i < self.active_branch).count();
+
+        let mut removed = Vec::with_capacity(doomed.len());
+        for &i in doomed.iter().rev() {
+            removed.push(self.branches.remove(i));
+        }
+        removed.reverse();
+
+        self.active_branch = if active_removed {
+            0
+        } else {
+            self.active_branch - shift
+        };
+        removed
+    }
+}
+
+/// State of the branch tree overlay (opened with `b`).
+#[derive(Debug, Default)]
+pub struct BranchOverlay {
+    pub cursor: usize,
+    /// Branch indices marked with space for a bulk action.
+    pub selected: BTreeSet<usize>,
+    /// Waiting for y/n before deleting `selected`.
+    pub confirm_delete: bool,
+}
+
+/// Two branches of the active session side by side (`C`), read-only.
+#[derive(Default)]
+pub struct CompareView {
+    /// Session the branches belong to; switching sessions closes the view.
+    pub session_idx: usize,
+    /// Branch shown in the left and in the right column.
+    pub branches: [usize; 2],
+    /// Column whose branch `[` / `]` change (0 left, 1 right).
+    pub focus: usize,
+    /// Wrapped lines of each column, kept apart from the main view's cache.
+    pub caches: [WrapCache; 2],
+}
+
+/// Full-text search over the active session (`/`, then `n` / `N`).
+#[derive(Debug, Default)]
+pub struct Search {
+    pub query: String,
+    /// Still typing the query.
+    pub editing: bool,
+    /// Session the matches belong to.
+    pub session_idx: usize,
+    /// (branch index, message index) of every matching message.
+    pub matches: Vec<(usize, usize)>,
+    pub current: usize,
+    /// Scroll the current match into view on the next draw.
+    pub jump: bool,
 }
 
 /// Current input mode of the TUI (similar to Vim).
@@ -58,6 +622,41 @@ pub enum InputMode {
 pub enum BackendEvent {
     AssistantChunk { session_idx: usize, branch_idx: usize, chunk: String },
     AssistantDone { session_idx: usize, branch_idx: usize },
+    /// A tool the reply being streamed called returned; the rest of the
+    /// reply goes into a new message.
+    ToolResult { session_idx: usize, branch_idx: usize, name: String, result: String },
+    /// Citations that came with the reply being streamed.
+    AssistantSources { session_idx: usize, branch_idx: usize, sources: Vec<String> },
+    /// A summary request finished; errors are already rendered as text.
+    SummaryDone { session_idx: usize, result: Result<String, String> },
+    /// A title for the session with this id came back from the model.
+    TitleDone { session_id: String, result: Result<String, String> },
+    /// The configured MCP servers that started, and why the others did not.
+    McpServersReady { servers: Vec<McpClient>, failures: Vec<String> },
+    /// The worker wants to run a shell command and waits until `reply` gets an answer.
+    ToolApprovalRequested { command: String, reply: oneshot::Sender<bool> },
+    /// A note for the status line, such as a rate-limited retry.
+    Status(String),
+    /// The reply being streamed failed; `AssistantDone` still follows.
+    Error { session_idx: usize, branch_idx: usize, message: String },
+}
+
+/// An assistant reply being streamed into a branch.
+pub struct ReplyStream {
+    pub session_idx: usize,
+    pub branch_idx: usize,
+    /// Index of the assistant message the chunks are appended to.
+    pub msg_idx: usize,
+    /// When the reply was requested, for the waiting indicator.
+    pub started: Instant,
+    /// The task producing the reply, so Esc can stop it.
+    pub task: Option<AbortHandle>,
+}
+
+/// A shell command waiting for the user to approve or reject it.
+pub struct ToolApproval {
+    pub command: String,
+    pub reply: oneshot::Sender<bool>,
 }
 
 /// Editing context for "fork branch by editing old message"
@@ -65,6 +664,9 @@ pub struct EditContext {
     pub session_idx: usize,     // Which session we are editing in
     pub branch_idx: usize,      // Which branch we are editing
     pub message_idx: usize,     // Message index where the fork begins
+    /// Overwrite the message where it is (`E`) instead of forking a
+    /// branch and asking for a new reply.
+    pub in_place: bool,
 }
 
 /// Global application state used by the TUI.
@@ -83,22 +685,73 @@ pub struct App {
     pub new_button_selected: bool,  
     /// Vertical scroll offset for the message area on the right.
     pub msg_scroll: usize,  
+    /// Keep the message view at the bottom while a reply streams in.
+    /// Cleared by scrolling up, restored by scrolling back to the end.
+    pub follow_bottom: bool,
     /// Screen area of the send button in the input panel (if drawn).
     pub send_button_area: Option<Rect>,
-    /// Sender used to send backend events (assistant chunks) from worker threads.
-    pub backend_tx: Option<Sender<BackendEvent>>,
-    /// (session_idx, message_idx) of the currently streaming assistant message.
-    pub streaming_assistant: Option<(usize, usize, usize)>,
+    /// Track of the message scrollbar (if drawn), for dragging.
+    pub msg_scrollbar_area: Option<Rect>,
+    /// Largest `msg_scroll` at the last draw.
+    pub msg_scroll_max: usize,
+    /// Message lines that fit on screen at the last draw.
+    pub msg_viewport_height: usize,
+    /// Wrapped message lines kept between frames.
+    pub wrap_cache: WrapCache,
+    /// The message scrollbar is being dragged with the left button.
+    pub dragging_scrollbar: bool,
+    /// The border between sidebar and chat is being dragged.
+    pub dragging_sidebar: bool,
+    /// Sender used to send backend events (assistant chunks) from worker tasks.
+    pub backend_tx: Option<UnboundedSender<BackendEvent>>,
+    /// Replies being streamed, at most one per branch.
+    pub streams: Vec<ReplyStream>,
     /// Whether the left session sidebar is collapsed.
     pub sidebar_collapsed: bool,
+    /// Columns the sidebar takes when expanded (`<` / `>`).
+    pub sidebar_width: u16,
+    /// Keep the session's system prompt in a fixed header above the messages (`p`).
+    pub pin_header: bool,
+    /// Show only the first line of the pinned header (`P`).
+    pub header_collapsed: bool,
+    /// Session whose summary is being written by a worker, if any.
+    pub summarizing: Option<usize>,
+    /// Ids of the sessions a title was asked for this run, so each is
+    /// named at most once.
+    pub titled: HashSet<String>,
     /// Editing context (None if not editing)
     pub edit_ctx: Option<EditContext>,
+    /// (session_idx, branch_idx, message_idx) picked with J / K or a click,
+    /// for editing in place (`E`) or deleting (`X`).
+    pub selected_msg: Option<(usize, usize, usize)>,
+    /// Scroll the selected message into view on the next draw.
+    pub reveal_selected: bool,
+    /// Waiting for y/n before deleting the selected message (`X`).
+    pub confirm_delete_message: bool,
+    /// Every visible message line with the index of its message, so any
+    /// message (not only the user's) can be clicked.
+    pub msg_line_hitboxes: Vec<(usize, Rect)>,
+    /// "… +N lines" markers on screen, clicked to expand their message.
+    pub collapse_hitboxes: Vec<(usize, Rect)>,
+    /// Messages of the branch on screen long enough to collapse, as of the
+    /// last frame.
+    pub long_msgs: Vec<usize>,
+    /// (session_idx, branch_idx, message_idx) of long messages shown in full.
+    pub expanded_msgs: HashSet<(usize, usize, usize)>,
     /// Hitboxes for user messages in the UI.
     pub user_msg_hitboxes: Vec<(usize, Rect)>,
     /// Which user message index is currently hovered.
     pub hovered_user_msg: Option<usize>,
     /// Vertical scroll offset for the input box (0 = bottom)
     pub input_scroll: usize,
+    /// Cursor in `input`, as a byte offset on a char boundary.
+    pub input_cursor: usize,
+    /// Previously sent inputs, oldest first (Up / Down in INSERT mode).
+    pub input_history: Vec<String>,
+    /// Entry of `input_history` shown in the input box while browsing.
+    pub history_pos: Option<usize>,
+    /// What was typed before browsing started, restored past the newest entry.
+    pub history_draft: String,
     // Screen area of the input box, used for mouse hit-testing and scrolling behavior.
     pub input_area: Option<Rect>, 
     // Clickable area for the sidebar toggle button (collapse / expand).
@@ -107,16 +760,55 @@ pub struct App {
     pub new_chat_area: Option<Rect>,
     pub session_hitboxes: Vec<(usize, Rect)>,
     pub edit_area: Option<(usize, Rect)>,
+    /// Restrictions for the MCP filesystem tools.
+    pub tool_policy: ToolPolicy,
+    /// External MCP servers whose tools the agent may call.
+    pub mcp_servers: Vec<Arc<McpClient>>,
+    /// Recent file tool results per session id, shared with the workers.
+    pub tool_caches: HashMap<String, Arc<Mutex<ToolCache>>>,
+    /// User preferences.
+    pub config: Config,
+    /// Model server used for new replies (cycled with `o`).
+    pub backend: Arc<dyn LlmBackend>,
+    /// Shell commands waiting for approval; the front one is shown as a modal.
+    pub pending_approvals: VecDeque<ToolApproval>,
+    /// Branch picker overlay, when open.
+    pub branch_overlay: Option<BranchOverlay>,
+    /// Scroll offset of the key bindings modal (`?`) while it is open.
+    pub keys_overlay: Option<usize>,
+    /// Side-by-side branch comparison, when open.
+    pub compare: Option<CompareView>,
+    /// Message search, while typing a query or stepping through matches.
+    pub search: Option<Search>,
+    /// New title being typed for the selected session (`r`).
+    pub renaming_session: Option<String>,
+    /// Waiting for y/n before deleting the selected session (`d`).
+    pub confirm_delete_session: bool,
+    /// Background worker tasks that may still be running.
+    pub workers: Vec<JoinHandle<()>>,
+    /// Short feedback shown next to the input title, with when it was set.
+    pub status: Option<(String, Instant)>,
+    /// System clipboard, opened on first copy. Kept alive because on some
+    /// platforms the copied text disappears with its owner.
+    pub clipboard: Option<arboard::Clipboard>,
 }
 
 impl App {
-    /// Create a new App.
-    pub fn new() -> Self {
+    /// Create a new App; sessions are loaded from `config.log_dir`, or from
+    /// the fallback directory if that cannot be written.
+    pub fn new(mut config: Config) -> Self {
         let mut list_state = ListState::default();
 
-        let mut sessions = Self::load_logs().unwrap_or_default();
+        let (log_dir, log_dir_warning) = usable_log_dir(&config.log_dir);
+        let (migrated, mut skipped) = match Self::migrate_legacy_logs(&log_dir) {
+            Ok(result) => result,
+            Err(e) => (0, vec![format!("old branch files ({e})")]),
+        };
+        let (mut sessions, unreadable) = Self::load_logs(&log_dir).unwrap_or_default();
+        skipped.extend(unreadable);
 
         if sessions.is_empty() {
+            let now = now_secs();
             sessions.push(Session {
                 id: Uuid::new_v4().to_string(),
                 title: "Session 1".to_string(),
@@ -124,47 +816,121 @@ impl App {
                     id: 0,
                     name: "main".to_string(),
                     messages: vec![],
+                    system_prompt: None,
+                    summary: None,
+                    next_branch_id: 1,
+                    parent: None,
+                    fork_point: None,
+                    created_at: now,
+                    updated_at: now,
                 }],
                 active_branch: 0,
+                system_prompt: None,
+                summary: None,
+                next_branch_id: 1,
+                created_at: now,
+                updated_at: now,
             });
         }
 
-        let active_idx = sessions.len() - 1;
-    
+        // Ids may collide after deletions or hand-edited logs.
+        let fixed: Vec<String> = sessions
+            .iter_mut()
+            .filter_map(|s| s.normalize_branch_ids().then(|| s.title.clone()))
+            .collect();
+
+        // Start on the most recently updated session, the top of the list.
+        let active_idx = session_order(&sessions)[0];
 
         // let sessions = vec![initial_session];
+        let prefs = load_ui_prefs(&log_dir);
+        let input_history = load_input_history(&log_dir);
+        let tool_policy = ToolPolicy::for_log_dir(&log_dir);
+        config.log_dir = log_dir;
 
-        list_state.select(Some(active_idx)); // Select the first (only) session.
+        list_state.select(Some(0));
 
         Self {
             sessions,
             list_state,
-            active_idx: active_idx,
+            active_idx,
             input: String::new(),
             // Start in Normal mode.
             input_mode: InputMode::Normal,
             new_button_selected: false,
             // Start at the top of the message list (no scrolling).
             msg_scroll: 0,
+            follow_bottom: true,
+            msg_scrollbar_area: None,
+            msg_scroll_max: 0,
+            msg_viewport_height: 1,
+            wrap_cache: WrapCache::default(),
+            dragging_scrollbar: false,
+            dragging_sidebar: false,
             send_button_area: None,
             backend_tx: None,
-            streaming_assistant: None,
-            sidebar_collapsed: false,
+            streams: Vec::new(),
+            sidebar_collapsed: prefs.sidebar_collapsed,
+            sidebar_width: prefs.sidebar_width,
+            pin_header: false,
+            header_collapsed: false,
+            summarizing: None,
+            titled: HashSet::new(),
             edit_ctx: None,
+            selected_msg: None,
+            reveal_selected: false,
+            confirm_delete_message: false,
+            msg_line_hitboxes: Vec::new(),
+            collapse_hitboxes: Vec::new(),
+            long_msgs: Vec::new(),
+            expanded_msgs: HashSet::new(),
             user_msg_hitboxes: Vec::new(),
             hovered_user_msg: None,
             input_scroll: 0,
+            input_cursor: 0,
+            input_history,
+            history_pos: None,
+            history_draft: String::new(),
             input_area: None, 
             toggle_sidebar_area: None,
             new_chat_area: None,
             session_hitboxes: Vec::new(),
             edit_area: None,
+            tool_policy,
+            mcp_servers: Vec::new(),
+            tool_caches: HashMap::new(),
+            config,
+            backend: default_backend(),
+            pending_approvals: VecDeque::new(),
+            branch_overlay: None,
+            keys_overlay: None,
+            compare: None,
+            search: None,
+            renaming_session: None,
+            confirm_delete_session: false,
+            workers: Vec::new(),
+            status: if let Some(warning) = log_dir_warning {
+                Some((warning, Instant::now()))
+            } else if !skipped.is_empty() {
+                Some((format!("⚠️ Skipped unreadable logs: {}", skipped.join(", ")), Instant::now()))
+            } else if migrated > 0 {
+                Some((
+                    format!("📦 Converted {migrated} session(s) to session files; old files are in {LEGACY_DIR}/"),
+                    Instant::now(),
+                ))
+            } else {
+                (!fixed.is_empty()).then(|| {
+                    (format!("⚠️ Renumbered duplicate branch ids in: {}", fixed.join(", ")), Instant::now())
+                })
+            },
+            clipboard: None,
         }
     }
     
     /// Create a new empty session and switch to it.
     pub fn new_session(&mut self) {
         let id = Uuid::new_v4().to_string();
+        let now = now_secs();
 
         self.sessions.push(Session {
             id,
@@ -173,13 +939,25 @@ impl App {
                 id: 0,
                 name: "main".into(),
                 messages: vec![],
+                system_prompt: None,
+                summary: None,
+                next_branch_id: 1,
+                parent: None,
+                fork_point: None,
+                created_at: now,
+                updated_at: now,
             }],
             active_branch: 0,
+            system_prompt: None,
+            summary: None,
+            next_branch_id: 1,
+            created_at: now,
+            updated_at: now,
         });
 
         // Set the new session as active.
         self.active_idx = self.sessions.len() - 1;
-        self.list_state.select(Some(self.active_idx));
+        self.select_active_in_list();
         self.msg_scroll = 0;
     }
 
@@ -189,31 +967,56 @@ impl App {
     }
 
 
-    /// Move selection to the previous session (if any).
+    /// Move selection to the session above in the sidebar (if any).
     pub fn prev_session(&mut self) {
-        if self.sessions.is_empty() {
-            return;
-        }
-        if self.active_idx > 0 {
-            self.active_idx -= 1;
-        }
+        let order = session_order(&self.sessions);
+        let pos = order.iter().position(|&i| i == self.active_idx).unwrap_or(0);
+        self.active_idx = order[pos.saturating_sub(1)];
         self.msg_scroll = 0;
-        self.list_state.select(Some(self.active_idx));
+        self.select_active_in_list();
     }
 
-    /// Move selection to the next session (if any).
+    /// Move selection to the session below in the sidebar (if any).
     pub fn next_session(&mut self) {
-        if self.sessions.is_empty() {
-            return;
-        }
-        if self.active_idx + 1 < self.sessions.len() {
-            self.active_idx += 1;
-        }
+        let order = session_order(&self.sessions);
+        let pos = order.iter().position(|&i| i == self.active_idx).unwrap_or(0);
+        self.active_idx = order[(pos + 1).min(order.len() - 1)];
         self.msg_scroll = 0;
-        self.list_state.select(Some(self.active_idx));
+        self.select_active_in_list();
+    }
+
+    /// Point the sidebar's highlight at the active session's row; rows
+    /// follow `session_order`, so they move as sessions get messages.
+    pub fn select_active_in_list(&mut self) {
+        let pos = session_order(&self.sessions).iter().position(|&i| i == self.active_idx);
+        self.list_state.select(pos);
     }
 
  
+    /// Whether any reply is being streamed.
+    pub fn is_streaming(&self) -> bool {
+        !self.streams.is_empty()
+    }
+
+    /// The reply being streamed into branch `branch_idx` of `session_idx`.
+    pub fn stream_for(&self, session_idx: usize, branch_idx: usize) -> Option<&ReplyStream> {
+        self.streams
+            .iter()
+            .find(|r| r.session_idx == session_idx && r.branch_idx == branch_idx)
+    }
+
+    /// Remember the task producing the reply of (session_idx, branch_idx)
+    /// so it can be stopped.
+    pub fn set_stream_task(&mut self, session_idx: usize, branch_idx: usize, task: AbortHandle) {
+        if let Some(stream) = self
+            .streams
+            .iter_mut()
+            .find(|r| r.session_idx == session_idx && r.branch_idx == branch_idx)
+        {
+            stream.task = Some(task);
+        }
+    }
+
     /// Append assistant chunk to branch message
     pub fn append_assistant_chunk(
         &mut self,
@@ -221,42 +1024,158 @@ impl App {
         branch_idx: usize,
         chunk: String,
     ) {
-        if let Some((s, b, msg_idx)) = self.streaming_assistant {
-            if s == session_idx && b == branch_idx {
-                if let Some(session) = self.sessions.get_mut(s) {
-                    if let Some(branch) = session.branches.get_mut(b) {
-                        if let Some(msg) = branch.messages.get_mut(msg_idx) {
-                            msg.content.push_str(&chunk);
-                        }
-                    }
+        let Some(msg_idx) = self.stream_for(session_idx, branch_idx).map(|r| r.msg_idx) else {
+            return;
+        };
+        if let Some(session) = self.sessions.get_mut(session_idx) {
+            if let Some(branch) = session.branches.get_mut(branch_idx) {
+                if let Some(msg) = branch.messages.get_mut(msg_idx) {
+                    msg.content.push_str(&chunk);
+                }
+            }
+        }
+    }
+
+    /// Add the result of a tool the streaming reply of (session_idx,
+    /// branch_idx) called, and stream the rest of the reply into a new
+    /// assistant message after it.
+    pub fn add_tool_result(&mut self, session_idx: usize, branch_idx: usize, name: String, result: String) {
+        let Some(stream) = self
+            .streams
+            .iter_mut()
+            .find(|r| r.session_idx == session_idx && r.branch_idx == branch_idx)
+        else {
+            return;
+        };
+        let messages = &mut self.sessions[session_idx].branches[branch_idx].messages;
+        messages.push(Message::tool(name, result));
+        messages.push(Message::new(MessageFrom::Assistant, String::new()));
+        stream.msg_idx = messages.len() - 1;
+        stream.started = Instant::now();
+    }
+
+    /// Record why the streaming reply of (session_idx, branch_idx) failed.
+    pub fn fail_streaming(&mut self, session_idx: usize, branch_idx: usize, message: String) {
+        let Some(msg_idx) = self.stream_for(session_idx, branch_idx).map(|r| r.msg_idx) else {
+            return;
+        };
+        if let Some(msg) = self.sessions[session_idx].branches[branch_idx].messages.get_mut(msg_idx) {
+            msg.error = Some(message);
+        }
+    }
+
+    /// Attach cited sources to the message being streamed, skipping ones it
+    /// already lists.
+    pub fn add_assistant_sources(&mut self, session_idx: usize, branch_idx: usize, sources: Vec<String>) {
+        let Some(msg_idx) = self.stream_for(session_idx, branch_idx).map(|r| r.msg_idx) else {
+            return;
+        };
+        if let Some(msg) = self.sessions[session_idx].branches[branch_idx].messages.get_mut(msg_idx) {
+            for source in sources {
+                if !msg.sources.contains(&source) {
+                    msg.sources.push(source);
                 }
             }
         }
     }
 
-    /// Start streaming assistant message in a specific branch
+    /// Start streaming assistant message in a specific branch. A reply
+    /// still streaming into the same branch is stopped.
     pub fn start_streaming_assistant(
         &mut self,
         session_idx: usize,
         branch_idx: usize,
     ) {
+        if let Some(pos) = self
+            .streams
+            .iter()
+            .position(|r| r.session_idx == session_idx && r.branch_idx == branch_idx)
+        {
+            if let Some(task) = self.streams.remove(pos).task {
+                task.abort();
+            }
+        }
+
         let session = &mut self.sessions[session_idx];
         let branch = &mut session.branches[branch_idx];
 
         let msg_idx = branch.messages.len();
-        branch.messages.push(Message {
-            from: MessageFrom::Assistant,
-            content: String::new(),
+        branch.messages.push(Message::new(MessageFrom::Assistant, String::new()));
+        session.touch(branch_idx);
+
+        self.streams.push(ReplyStream {
+            session_idx,
+            branch_idx,
+            msg_idx,
+            started: Instant::now(),
+            task: None,
         });
+        // A new message was just sent: show it and follow the reply.
+        self.follow_bottom = true;
+    }
+
+    /// Scroll the messages one screen up (PgUp) or down (PgDn). Reaching the
+    /// end resumes following the stream.
+    pub fn scroll_messages_page(&mut self, up: bool) {
+        let page = self.msg_viewport_height.max(1);
+        self.msg_scroll = if up {
+            self.msg_scroll.min(self.msg_scroll_max).saturating_sub(page)
+        } else {
+            (self.msg_scroll + page).min(self.msg_scroll_max)
+        };
+        self.follow_bottom = self.msg_scroll >= self.msg_scroll_max;
+    }
+
+    /// Jump to the first message line (`g` / Home).
+    pub fn scroll_messages_to_top(&mut self) {
+        self.msg_scroll = 0;
+        self.follow_bottom = self.msg_scroll_max == 0;
+    }
+
+    /// Jump to the end and follow the stream again (`G` / End).
+    pub fn scroll_messages_to_bottom(&mut self) {
+        self.msg_scroll = self.msg_scroll_max;
+        self.follow_bottom = true;
+    }
 
-        self.streaming_assistant = Some((session_idx, branch_idx, msg_idx));
+    /// Scroll the messages so the thumb of the message scrollbar sits at
+    /// screen row `y`. Dragging to the end resumes following the stream.
+    pub fn drag_scrollbar_to(&mut self, y: u16) {
+        let Some(track) = self.msg_scrollbar_area else {
+            return;
+        };
+        let row = y.clamp(track.y, track.y + track.height.saturating_sub(1)) - track.y;
+        let span = track.height.saturating_sub(1).max(1) as usize;
+        self.msg_scroll = (row as usize * self.msg_scroll_max + span / 2) / span;
+        self.follow_bottom = self.msg_scroll >= self.msg_scroll_max;
     }
 
     /// Mark streaming as finished for (session_idx, branch_idx).
+    /// A reply that is still blank at this point gets `config.on_empty`.
     pub fn finish_streaming(&mut self, session_idx: usize, branch_idx: usize) {
-        if let Some((s, b, _)) = self.streaming_assistant {
-            if s == session_idx && b == branch_idx {
-                self.streaming_assistant = None;
+        let Some(pos) = self
+            .streams
+            .iter()
+            .position(|r| r.session_idx == session_idx && r.branch_idx == branch_idx)
+        else {
+            return;
+        };
+        let msg_idx = self.streams.remove(pos).msg_idx;
+
+        let messages = &mut self.sessions[session_idx].branches[branch_idx].messages;
+        if messages.get(msg_idx).is_some_and(|m| m.content.trim().is_empty() && m.error.is_none()) {
+            // An MCP run that ended on a tool result has nothing more to say.
+            if msg_idx > 0 && messages[msg_idx - 1].from == MessageFrom::Tool {
+                messages.remove(msg_idx);
+                return;
+            }
+            match self.config.on_empty {
+                EmptyResponse::Drop => {
+                    messages.remove(msg_idx);
+                }
+                EmptyResponse::Retry | EmptyResponse::Marker => {
+                    messages[msg_idx].content = EMPTY_RESPONSE_MARKER.to_string();
+                }
             }
         }
     }
@@ -287,112 +1206,1493 @@ impl App {
         self.msg_scroll = 0;
     }
 
-    /// Current width of the left sidebar in columns.
-    pub fn sidebar_width(&self) -> u16 {
-        if self.sidebar_collapsed {
-            3
-        } else {
-            25
+    /// Copy session `idx` with all its branches under a fresh id and a
+    /// "(copy)" title, save the copy and switch to it. Returns its index.
+    pub fn duplicate_session(&mut self, idx: usize) -> Result<usize, Box<dyn Error>> {
+        if self.streams.iter().any(|r| r.session_idx == idx) {
+            return Err("wait for the current reply before copying".into());
         }
-    }
+        let mut copy = self.sessions[idx].clone();
+        // The sidebar lists sessions by title; like generated titles
+        // (`unique_title`), the copy gets one no other session has.
+        copy.title = (1..)
+            .map(|n| match n {
+                1 => format!("{} (copy)", copy.title),
+                n => format!("{} (copy {n})", copy.title),
+            })
+            .find(|title| self.sessions.iter().all(|s| &s.title != title))
+            .expect("unbounded range");
+        copy.id = Uuid::new_v4().to_string();
+        let now = now_secs();
+        copy.created_at = now;
+        copy.updated_at = now;
 
-    /// Toggle the visibility of the left sidebar.
-    pub fn toggle_sidebar(&mut self) {
-        self.sidebar_collapsed = !self.sidebar_collapsed;
+        self.sessions.push(copy);
+        let new_idx = self.sessions.len() - 1;
+        self.active_idx = new_idx;
+        self.select_active_in_list();
         self.msg_scroll = 0;
+        self.save_session(new_idx)?;
+        Ok(new_idx)
     }
 
-    /// Save current branch as a JSON file in /logs.
-    pub fn save_to_logs(&mut self) -> Result<(), Box<dyn Error>> {
-        // the saving address of history conversation 
-        let log_dir: &str = "logs";
-
-        let session = &mut self.sessions[self.active_idx];
-        let branch = &mut session.branches[session.active_branch];
+    /// Copy branch `branch_idx` of the active session into a new branch
+    /// under it, save the copy and switch to it. Returns its index.
+    pub fn duplicate_branch(&mut self, branch_idx: usize) -> Result<usize, Box<dyn Error>> {
+        let session_idx = self.active_idx;
+        if self.stream_for(session_idx, branch_idx).is_some() {
+            return Err("wait for the current reply before copying".into());
+        }
+        let session = &mut self.sessions[session_idx];
+        if branch_idx >= session.branches.len() {
+            return Err("no such branch".into());
+        }
+        let (id, name) = session.new_branch_id();
+        let source = &session.branches[branch_idx];
+        let now = now_secs();
+        let copy = Branch {
+            id,
+            name,
+            messages: source.messages.clone(),
+            system_prompt: source.system_prompt.clone(),
+            summary: source.summary.clone(),
+            next_branch_id: session.next_branch_id,
+            parent: Some(source.id),
+            fork_point: None,
+            created_at: now,
+            updated_at: now,
+        };
+        session.branches.push(copy);
+        let new_idx = session.branches.len() - 1;
+        session.active_branch = new_idx;
+        session.touch(new_idx);
+        self.msg_scroll = 0;
+        self.save_session(session_idx)?;
+        Ok(new_idx)
+    }
 
-        fs::create_dir_all(log_dir)?;
-        let path = Path::new(log_dir).join(format!("{}_{}.json", session.title, branch.name));
-        let file = File::create(&path)?;
-        serde_json::to_writer_pretty(file, branch)?;
-        // println!("💾 Saved: {}", path.display());
-        Ok(())
+    /// Path of the file a session is saved in.
+    fn session_path(log_dir: &Path, id: &str) -> PathBuf {
+        log_dir.join(SESSIONS_DIR).join(format!("{id}.json"))
     }
 
-    /// Build conversation history as a prompt string.
-    pub(crate) fn history_string(&mut self) -> String {
-        let session = &mut self.sessions[self.active_idx];
-        let branch = &mut session.branches[session.active_branch];
+    /// Rename the selected session to the pending `renaming_session` title
+    /// and save it.
+    pub fn commit_rename_session(&mut self) {
+        let Some(new_title) = self.renaming_session.take() else {
+            return;
+        };
+        let new_title = new_title.trim().to_string();
+        if new_title.is_empty() || new_title == self.sessions[self.active_idx].title {
+            return;
+        }
+        if self.sessions.iter().any(|s| s.title == new_title) {
+            self.set_status(format!("⚠️ A session named '{new_title}' already exists"));
+            return;
+        }
 
-        branch.messages
-            .iter()
-            .map(|m| format!("{}: {}", 
-                match m.from {
-                    MessageFrom::User => "User",
-                    MessageFrom::Assistant => "Assistant",
-                },
-            m.content))
-            .collect::<Vec<_>>()
-            .join("\n")
+        self.sessions[self.active_idx].title = new_title;
+        if let Err(e) = self.save_session(self.active_idx) {
+            self.set_status(format!("⚠️ Renamed, but saving failed: {e}"));
+        }
     }
 
-    /// Loads all sessions and branches from /logs.
-    pub fn load_logs() -> Result<Vec<Session>, Box<dyn std::error::Error>> {
-        let log_dir: &str = "logs";
-        let mut sessions_map: std::collections::HashMap<String, Vec<Branch>> = std::collections::HashMap::new();
+    /// Delete the selected session and its file.
+    pub fn delete_active_session(&mut self) {
+        self.confirm_delete_session = false;
 
-        // Make sure directory exists
-        if !Path::new(log_dir).exists() {
-            return Ok(vec![]);
+        if self.sessions.len() <= 1 {
+            self.set_status("⚠️ Can't delete the last session");
+            return;
+        }
+        // Session indices are baked into in-flight streams and edits.
+        if self.is_streaming() || self.edit_ctx.is_some() {
+            self.set_status("⚠️ Wait for the current reply before deleting");
+            return;
         }
 
-        // Iterate all JSON files
-        for entry in fs::read_dir(log_dir)? {
-            let entry = entry?;
-            let path = entry.path();
+        if let Err(e) = self.trash_session(&self.sessions[self.active_idx]) {
+            self.set_status(format!("⚠️ Not deleted, the trash is not writable: {e}"));
+            return;
+        }
+        let session = self.sessions.remove(self.active_idx);
+        let _ = fs::remove_file(Self::session_path(&self.config.log_dir, &session.id));
 
-            if path.extension().and_then(|s| s.to_str()) != Some("json") {
-                continue;
-            }
+        self.active_idx = session_order(&self.sessions)[0];
+        self.select_active_in_list();
+        self.branch_overlay = None;
+        self.msg_scroll = 0;
+        self.set_status(format!("🗑️ Moved '{}' to the trash", session.title));
+    }
 
-            // File name example: "Session 1_main.json"
-            let filename = path.file_stem().unwrap().to_string_lossy();
+    /// Write `session` as it is now into the trash, where it can be moved
+    /// back to `sessions/` by hand.
+    fn trash_session(&self, session: &Session) -> Result<PathBuf, Box<dyn Error>> {
+        let target = trash::trash_target(&self.tool_policy.trash_dir, &format!("{}.json", session.id))?;
+        write_json_atomic(&target, &versioned_session(session)?)?;
+        Ok(target)
+    }
 
-            // split into "Session 1" and "main"
-            let parts: Vec<&str> = filename.split('_').collect();
-            if parts.len() != 2 {
-                continue;
-            }
+    /// Erase trash entries older than `trash_days` from the config file.
+    pub fn purge_trash(&mut self) {
+        let Some(days) = self.config.trash_days else {
+            return;
+        };
+        let purged = trash::purge(&self.tool_policy.trash_dir, days);
+        if purged > 0 {
+            self.set_status(format!("🗑️ Purged {purged} trash item(s) older than {days} day(s)"));
+        }
+    }
 
-            let session_title = parts[0].to_string();
-            let branch_name = parts[1].to_string();
+    /// Start typing a search query.
+    pub fn start_search(&mut self) {
+        self.search = Some(Search {
+            editing: true,
+            session_idx: self.active_idx,
+            ..Default::default()
+        });
+    }
 
-            // Deserialize file into Branch
-            let file = File::open(&path)?;
-            let mut branch: Branch = serde_json::from_reader(file)?;
+    /// Find the typed query (case-insensitive) in every branch of the
+    /// active session and jump to the first match.
+    pub fn run_search(&mut self) {
+        let Some(search) = self.search.as_mut() else {
+            return;
+        };
+        search.editing = false;
+        search.session_idx = self.active_idx;
+        search.current = 0;
 
-            // Fix branch name if needed
-            branch.name = branch_name;
+        let needle = search.query.to_lowercase();
+        search.matches = if needle.is_empty() {
+            Vec::new()
+        } else {
+            self.sessions[self.active_idx]
+                .branches
+                .iter()
+                .enumerate()
+                .flat_map(|(b, branch)| {
+                    branch
+                        .messages
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, m)| m.content.to_lowercase().contains(&needle))
+                        .map(move |(i, _)| (b, i))
+                })
+                .collect()
+        };
 
-            // Insert into map
-            sessions_map
-                .entry(session_title)
-                .or_default()
-                .push(branch);
+        if search.matches.is_empty() {
+            let query = search.query.clone();
+            self.search = None;
+            self.set_status(format!("No matches for '{query}'"));
+        } else {
+            self.show_search_match();
         }
+    }
 
-        // Convert map into Vec<Session>
-        let mut sessions: Vec<Session> = vec![];
+    /// Whether there are matches in the active session to step through.
+    pub fn has_search_matches(&self) -> bool {
+        self.search
+            .as_ref()
+            .is_some_and(|s| !s.editing && !s.matches.is_empty() && s.session_idx == self.active_idx)
+    }
 
-        for (title, branches) in sessions_map {
-            let id = Uuid::new_v4().to_string();
-            sessions.push(Session {
-                id,
-                title,
-                branches: branches.clone(),
-                active_branch: branches.len()-1,
-            });
+    /// Move to the next (or previous) match, wrapping around.
+    pub fn step_search(&mut self, forward: bool) {
+        if !self.has_search_matches() {
+            return;
+        }
+        let Some(search) = self.search.as_mut() else {
+            return;
+        };
+        let n = search.matches.len();
+        search.current = if forward { (search.current + 1) % n } else { (search.current + n - 1) % n };
+        self.show_search_match();
+    }
+
+    /// Switch to the branch of the current match and scroll to it.
+    fn show_search_match(&mut self) {
+        let Some(search) = self.search.as_mut() else {
+            return;
+        };
+        let Some(&(branch_idx, _)) = search.matches.get(search.current) else {
+            return;
+        };
+        search.jump = true;
+        if let Some(session) = self.sessions.get_mut(search.session_idx) {
+            session.active_branch = branch_idx;
+        }
+        self.follow_bottom = false;
+    }
+
+    /// Message index of the current match, if it is in the branch on screen.
+    pub fn current_search_match(&self) -> Option<usize> {
+        let search = self.search.as_ref().filter(|s| s.session_idx == self.active_idx)?;
+        let &(branch_idx, msg_idx) = search.matches.get(search.current)?;
+        (branch_idx == self.sessions[self.active_idx].active_branch).then_some(msg_idx)
+    }
+
+    /// Text for the input title: the query being typed, or "3/12 matches".
+    pub fn search_indicator(&self) -> Option<String> {
+        let search = self.search.as_ref().filter(|s| s.session_idx == self.active_idx)?;
+        if search.editing {
+            Some(format!("/{}", search.query))
+        } else {
+            Some(format!("{}/{} matches", search.current + 1, search.matches.len()))
+        }
+    }
+
+    /// Open the branch picker with the cursor on the active branch.
+    pub fn open_branch_overlay(&mut self) {
+        self.branch_overlay = Some(BranchOverlay {
+            cursor: self.sessions[self.active_idx].active_branch,
+            ..Default::default()
+        });
+    }
+
+    /// Compare the active branch with the next one side by side; `[` / `]`
+    /// then pick either column's branch.
+    pub fn open_compare(&mut self) {
+        let session = &self.sessions[self.active_idx];
+        if session.branches.len() < 2 {
+            self.set_status("Only one branch in this session, nothing to compare");
+            return;
+        }
+        let left = session.active_branch;
+        let right = (left + 1) % session.branches.len();
+        self.compare = Some(CompareView {
+            session_idx: self.active_idx,
+            branches: [left, right],
+            ..Default::default()
+        });
+        self.selected_msg = None;
+        self.msg_scroll = 0;
+        self.follow_bottom = false;
+    }
+
+    /// Leave the comparison for the normal message view.
+    pub fn close_compare(&mut self) {
+        self.compare = None;
+        self.msg_scroll = 0;
+    }
+
+    /// Show the previous / next branch in the focused compare column.
+    pub fn cycle_compare_branch(&mut self, forward: bool) {
+        let count = self.sessions[self.active_idx].branches.len();
+        let Some(compare) = self.compare.as_mut() else {
+            return;
+        };
+        let branch = &mut compare.branches[compare.focus];
+        *branch = if forward { (*branch + 1) % count } else { (*branch + count - 1) % count };
+    }
+
+    /// Delete the branches selected in the overlay from the active session
+    /// and save it. Returns how many were removed.
+    pub fn delete_selected_branches(&mut self) -> usize {
+        let Some(overlay) = self.branch_overlay.as_mut() else {
+            return 0;
+        };
+        // Branch indices are baked into in-flight streams and edits.
+        if !self.streams.is_empty() || self.edit_ctx.is_some() {
+            overlay.confirm_delete = false;
+            return 0;
+        }
+        overlay.confirm_delete = false;
+
+        // The whole session goes to the trash first, deleted branches included.
+        let trashed = self.trash_session(&self.sessions[self.active_idx]);
+        let Some(overlay) = self.branch_overlay.as_mut() else {
+            return 0;
+        };
+        if let Err(e) = trashed {
+            self.set_status(format!("⚠️ Not deleted, the trash is not writable: {e}"));
+            return 0;
+        }
+        let session = &mut self.sessions[self.active_idx];
+        let removed = session.remove_branches(&overlay.selected);
+
+        overlay.selected.clear();
+        overlay.cursor = session.active_branch;
+        self.msg_scroll = 0;
+        if let Err(e) = self.save_session(self.active_idx) {
+            self.set_status(format!("⚠️ Could not save the remaining branches: {e}"));
+        }
+        removed.len()
+    }
+
+    /// Index of the selected message, if it is in the branch on screen.
+    pub fn selected_message(&self) -> Option<usize> {
+        let (s, b, m) = self.selected_msg?;
+        let session = &self.sessions[self.active_idx];
+        let on_screen = s == self.active_idx && b == session.active_branch;
+        (on_screen && m < session.branches[b].messages.len()).then_some(m)
+    }
+
+    /// Select the message above (or below) the selected one; with nothing
+    /// selected yet, start at the newest message.
+    pub fn select_message(&mut self, older: bool) {
+        let session = &self.sessions[self.active_idx];
+        let len = session.branches[session.active_branch].messages.len();
+        if len == 0 {
+            return;
+        }
+        let idx = match self.selected_message() {
+            None => len - 1,
+            Some(i) if older => i.saturating_sub(1),
+            Some(i) => (i + 1).min(len - 1),
+        };
+        self.selected_msg = Some((self.active_idx, session.active_branch, idx));
+        self.reveal_selected = true;
+        self.follow_bottom = false;
+    }
+
+    /// Whether long message `idx` of the branch on screen is shown in full.
+    pub fn is_expanded(&self, idx: usize) -> bool {
+        let branch_idx = self.active_session().active_branch;
+        self.expanded_msgs.contains(&(self.active_idx, branch_idx, idx))
+    }
+
+    /// Expand long message `idx` of the branch on screen, or collapse it
+    /// again.
+    pub fn toggle_expanded(&mut self, idx: usize) {
+        let key = (self.active_idx, self.active_session().active_branch, idx);
+        if !self.expanded_msgs.remove(&key) {
+            self.expanded_msgs.insert(key);
+        }
+        self.follow_bottom = false;
+    }
+
+    /// Expand or collapse the selected message if it is long, else the
+    /// newest long message.
+    pub fn toggle_collapse(&mut self) {
+        let target = self
+            .selected_message()
+            .filter(|idx| self.long_msgs.contains(idx))
+            .or_else(|| self.long_msgs.last().copied());
+        match target {
+            Some(idx) => {
+                self.toggle_expanded(idx);
+                self.reveal_selected = self.selected_message() == Some(idx);
+            }
+            None => self.set_status("No long messages in this branch"),
+        }
+    }
+
+    /// Select message `idx` of the branch on screen, or clear the selection
+    /// if it is the selected one.
+    pub fn toggle_message_selection(&mut self, idx: usize) {
+        self.selected_msg = if self.selected_message() == Some(idx) {
+            None
+        } else {
+            Some((self.active_idx, self.active_session().active_branch, idx))
+        };
+    }
+
+    /// Load message `message_idx` of the branch on screen into the input in
+    /// INSERT mode. Sending it forks a new branch, or with `in_place`
+    /// overwrites the message without asking for a reply.
+    pub fn start_edit(&mut self, message_idx: usize, in_place: bool) {
+        let session_idx = self.active_idx;
+        let branch_idx = self.sessions[session_idx].active_branch;
+        if in_place && self.stream_for(session_idx, branch_idx).is_some_and(|r| r.msg_idx == message_idx) {
+            self.set_status("⚠️ Wait for the reply to finish before editing it");
+            return;
+        }
+        let Some(msg) = self.sessions[session_idx].branches[branch_idx].messages.get(message_idx) else {
+            return;
+        };
+        self.set_input(msg.content.clone());
+        self.edit_ctx = Some(EditContext { session_idx, branch_idx, message_idx, in_place });
+        self.input_mode = InputMode::Insert;
+    }
+
+    /// Overwrite the message `ctx` points at with `text`, keeping the rest
+    /// of the branch, and save the branch.
+    pub fn edit_message_in_place(&mut self, ctx: EditContext, text: String) -> Result<(), Box<dyn Error>> {
+        let session = &mut self.sessions[ctx.session_idx];
+        let msg = session
+            .branches
+            .get_mut(ctx.branch_idx)
+            .and_then(|b| b.messages.get_mut(ctx.message_idx))
+            .ok_or("the message no longer exists")?;
+        msg.content = text;
+        session.touch(ctx.branch_idx);
+        self.save_session(ctx.session_idx)
+    }
+
+    /// Delete the selected message (and the reply to it) and save.
+    pub fn delete_selected_message(&mut self) {
+        self.confirm_delete_message = false;
+        // Message indices are baked into in-flight streams and edits.
+        if self.is_streaming() || self.edit_ctx.is_some() {
+            self.set_status("⚠️ Wait for the current reply before deleting");
+            return;
+        }
+        let Some(msg_idx) = self.selected_message() else {
+            return;
+        };
+
+        let session_idx = self.active_idx;
+        let session = &mut self.sessions[session_idx];
+        let branch_idx = session.active_branch;
+        let removed = session.remove_message(branch_idx, msg_idx);
+        session.touch(branch_idx);
+        self.selected_msg = None;
+        // Expanded messages are kept by index too.
+        self.expanded_msgs.retain(|&(s, b, _)| (s, b) != (session_idx, branch_idx));
+        // Matches point at message indices that just moved.
+        self.search = None;
+        match self.save_session(session_idx) {
+            Ok(()) => self.set_status(format!("🗑️ Deleted {removed} message(s)")),
+            Err(e) => self.set_status(format!("⚠️ Deleted {removed} message(s), but saving failed: {e}")),
+        }
+    }
+
+    /// Replace the input text and put the cursor at its end.
+    pub fn set_input(&mut self, text: impl Into<String>) {
+        self.input = text.into();
+        self.input_cursor = self.input.len();
+    }
+
+    /// Remember a sent input and save the most recent ones to disk.
+    pub fn record_input(&mut self, text: &str) {
+        self.history_pos = None;
+        if self.input_history.last().map(String::as_str) != Some(text) {
+            self.input_history.push(text.to_string());
+        }
+        let excess = self.input_history.len().saturating_sub(INPUT_HISTORY_MAX);
+        self.input_history.drain(..excess);
+        if fs::create_dir_all(&self.config.log_dir).is_ok() {
+            let _ = write_json_atomic(&self.config.log_dir.join(INPUT_HISTORY_FILE), &self.input_history);
+        }
+    }
+
+    /// Recall the previous input; only from the first line of the input box.
+    pub fn history_prev(&mut self) {
+        if self.input[..self.input_cursor].contains('\n') || self.input_history.is_empty() {
+            return;
+        }
+        let idx = match self.history_pos {
+            None => {
+                self.history_draft = self.input.clone();
+                self.input_history.len() - 1
+            }
+            Some(idx) => idx.saturating_sub(1),
+        };
+        self.history_pos = Some(idx);
+        self.set_input(self.input_history[idx].clone());
+    }
+
+    /// Step towards newer inputs, ending on the saved draft; only from the
+    /// last line of the input box.
+    pub fn history_next(&mut self) {
+        let Some(idx) = self.history_pos else {
+            return;
+        };
+        if self.input[self.input_cursor..].contains('\n') {
+            return;
+        }
+        if idx + 1 < self.input_history.len() {
+            self.history_pos = Some(idx + 1);
+            self.set_input(self.input_history[idx + 1].clone());
+        } else {
+            self.history_pos = None;
+            let draft = std::mem::take(&mut self.history_draft);
+            self.set_input(draft);
+        }
+    }
+
+    /// Empty the input box.
+    pub fn clear_input(&mut self) {
+        self.input.clear();
+        self.input_cursor = 0;
+    }
+
+    /// Insert `c` at the cursor and move past it.
+    pub fn insert_char(&mut self, c: char) {
+        self.input.insert(self.input_cursor, c);
+        self.input_cursor += c.len_utf8();
+    }
+
+    /// Insert `text` at the cursor and move past it.
+    pub fn insert_str(&mut self, text: &str) {
+        self.input.insert_str(self.input_cursor, text);
+        self.input_cursor += text.len();
+    }
+
+    /// Remove the char before the cursor.
+    pub fn backspace(&mut self) {
+        if let Some(c) = self.input[..self.input_cursor].chars().next_back() {
+            self.input_cursor -= c.len_utf8();
+            self.input.remove(self.input_cursor);
+        }
+    }
+
+    /// Remove the char under the cursor.
+    pub fn delete_char(&mut self) {
+        if self.input_cursor < self.input.len() {
+            self.input.remove(self.input_cursor);
+        }
+    }
+
+    pub fn cursor_left(&mut self) {
+        if let Some(c) = self.input[..self.input_cursor].chars().next_back() {
+            self.input_cursor -= c.len_utf8();
+        }
+    }
+
+    pub fn cursor_right(&mut self) {
+        if let Some(c) = self.input[self.input_cursor..].chars().next() {
+            self.input_cursor += c.len_utf8();
+        }
+    }
+
+    /// Start of the current input line.
+    pub fn cursor_home(&mut self) {
+        self.input_cursor = self.input[..self.input_cursor].rfind('\n').map_or(0, |i| i + 1);
+    }
+
+    /// End of the current input line.
+    pub fn cursor_end(&mut self) {
+        self.input_cursor += self.input[self.input_cursor..]
+            .find('\n')
+            .unwrap_or(self.input.len() - self.input_cursor);
+    }
+
+    /// Show a short status message for a few seconds.
+    pub fn set_status(&mut self, msg: impl Into<String>) {
+        self.status = Some((msg.into(), Instant::now()));
+    }
+
+    /// Take the MCP servers that finished starting and report the result.
+    pub fn add_mcp_servers(&mut self, servers: Vec<McpClient>, failures: Vec<String>) {
+        let started: Vec<String> = servers
+            .iter()
+            .map(|s| format!("{} ({} tools)", s.name, s.tools.len()))
+            .collect();
+        self.mcp_servers.extend(servers.into_iter().map(Arc::new));
+        match (started.is_empty(), failures.is_empty()) {
+            (_, false) => self.set_status(format!("⚠️ {}", failures.join("; "))),
+            (false, true) => self.set_status(format!("🔌 MCP: {}", started.join(", "))),
+            (true, true) => {}
+        }
+    }
+
+    /// Rough token count of what the active branch sends: its messages,
+    /// system prompt and summary, at `CHARS_PER_TOKEN`.
+    pub fn token_estimate(&self) -> usize {
+        let session = self.active_session();
+        let chars: usize = session
+            .branches
+            .get(session.active_branch)
+            .map_or(0, |b| b.messages.iter().map(|m| m.content.chars().count()).sum())
+            + session.system_prompt.as_deref().map_or(0, |p| p.chars().count())
+            + session.summary.as_deref().map_or(0, |s| s.chars().count());
+        chars.div_ceil(CHARS_PER_TOKEN)
+    }
+
+    /// Tokens the status line measures the conversation against: the
+    /// configured limit for the current model, else the history budget.
+    pub fn context_limit(&self) -> usize {
+        let label = self.backend.label();
+        let model = label.split_once(':').map_or(label.as_str(), |(_, model)| model);
+        self.config
+            .context_limits
+            .get(model)
+            .copied()
+            .or(self.config.context_limit)
+            .unwrap_or(HISTORY_MAX_CHARS / CHARS_PER_TOKEN)
+    }
+
+    /// The status message, if it is still fresh.
+    pub fn current_status(&self) -> Option<&str> {
+        const STATUS_TTL: Duration = Duration::from_secs(3);
+        self.status
+            .as_ref()
+            .filter(|(_, at)| at.elapsed() < STATUS_TTL)
+            .map(|(msg, _)| msg.as_str())
+    }
+
+    /// Run `job` as a background task and keep its handle. The returned
+    /// handle stops the task.
+    pub fn spawn_worker(&mut self, job: impl Future<Output = ()> + Send + 'static) -> AbortHandle {
+        self.prune_workers();
+        let handle = tokio::spawn(job);
+        let abort = handle.abort_handle();
+        self.workers.push(handle);
+        abort
+    }
+
+    /// Drop the workers that have finished.
+    pub fn prune_workers(&mut self) {
+        self.workers.retain(|h| !h.is_finished());
+    }
+
+    /// Wait up to `timeout` for outstanding workers.
+    /// Returns how many are still running afterwards.
+    pub async fn join_workers(&mut self, timeout: Duration) -> usize {
+        // Workers waiting on an approval get a rejection once the reply
+        // sender is dropped.
+        self.pending_approvals.clear();
+
+        let _ = tokio::time::timeout(timeout, join_all(self.workers.iter_mut())).await;
+        self.prune_workers();
+        self.workers.len()
+    }
+
+    /// Stop every reply being streamed; what arrived so far is kept.
+    pub fn cancel_streaming(&mut self) {
+        let streaming: Vec<(usize, usize)> = self.streams.iter().map(|r| (r.session_idx, r.branch_idx)).collect();
+        for (s, b) in streaming {
+            if let Some(task) = self.streams.iter_mut().find(|r| (r.session_idx, r.branch_idx) == (s, b)).and_then(|r| r.task.take()) {
+                task.abort();
+            }
+            self.fail_streaming(s, b, "Stopped by user".to_string());
+            self.finish_streaming(s, b);
+            self.autosave_session(s);
+        }
+    }
+
+    /// Answer the approval modal currently shown (if any).
+    pub fn answer_tool_approval(&mut self, approved: bool) {
+        if let Some(req) = self.pending_approvals.pop_front() {
+            // The worker may be gone already; nothing to do then.
+            let _ = req.reply.send(approved);
+        }
+    }
+
+    /// Current width of the left sidebar in columns.
+    pub fn sidebar_width(&self) -> u16 {
+        if self.sidebar_collapsed {
+            3
+        } else {
+            self.sidebar_width
+        }
+    }
+
+    /// Toggle the visibility of the left sidebar.
+    pub fn toggle_sidebar(&mut self) {
+        self.sidebar_collapsed = !self.sidebar_collapsed;
+        self.msg_scroll = 0;
+        self.save_ui_prefs();
+    }
+
+    /// Widen (`wider`) or narrow the sidebar by one step, expanding it
+    /// first if it is collapsed.
+    pub fn resize_sidebar(&mut self, wider: bool) {
+        if self.sidebar_collapsed {
+            self.sidebar_collapsed = false;
+        } else if wider {
+            self.set_sidebar_width(self.sidebar_width + SIDEBAR_STEP);
+        } else {
+            self.set_sidebar_width(self.sidebar_width.saturating_sub(SIDEBAR_STEP));
+        }
+        self.save_ui_prefs();
+    }
+
+    /// Set the expanded sidebar width, kept within the allowed range.
+    /// Not saved; the caller decides when the change is final.
+    pub fn set_sidebar_width(&mut self, width: u16) {
+        self.sidebar_width = width.clamp(SIDEBAR_MIN_WIDTH, SIDEBAR_MAX_WIDTH);
+    }
+
+    /// Write the sidebar layout to disk so the next start restores it.
+    pub fn save_ui_prefs(&self) {
+        let prefs = UiPrefs { sidebar_collapsed: self.sidebar_collapsed, sidebar_width: self.sidebar_width };
+        if fs::create_dir_all(&self.config.log_dir).is_ok() {
+            let _ = write_json_atomic(&self.config.log_dir.join(UI_PREFS_FILE), &prefs);
+        }
+    }
+
+    /// Set or clear the active session's system prompt and save the session
+    /// file, which holds the prompt once for all of its branches.
+    pub fn set_system_prompt(&mut self, prompt: Option<String>) -> Result<(), Box<dyn Error>> {
+        self.sessions[self.active_idx].system_prompt = prompt;
+        self.save_session(self.active_idx)
+    }
+
+    /// Store a session's summary and save it.
+    pub fn set_summary(&mut self, session_idx: usize, summary: Option<String>) -> Result<(), Box<dyn Error>> {
+        self.sessions[session_idx].summary = summary;
+        self.save_session(session_idx)
+    }
+
+    /// `title`, or `title (2)`, `title (3)`, ... when another session than
+    /// `session_idx` already has it.
+    fn unique_title(&self, session_idx: usize, title: &str) -> String {
+        let taken = |t: &str| self.sessions.iter().enumerate().any(|(i, s)| i != session_idx && s.title == t);
+        let mut candidate = title.to_string();
+        let mut n = 2;
+        while taken(&candidate) {
+            candidate = format!("{title} ({n})");
+            n += 1;
+        }
+        candidate
+    }
+
+    /// A title worker finished: rename the session unless the user named
+    /// it meanwhile. A failure only costs the title, so it is not reported.
+    pub fn finish_title(&mut self, session_id: &str, result: Result<String, String>) {
+        let Ok(title) = result else {
+            return;
+        };
+        let Some(session_idx) = self.sessions.iter().position(|s| s.id == session_id) else {
+            return;
+        };
+        if !is_default_title(&self.sessions[session_idx].title) {
+            return;
+        }
+        let title = self.unique_title(session_idx, &title);
+        self.sessions[session_idx].title = title.clone();
+        match self.save_session(session_idx) {
+            Ok(()) => self.set_status(format!("🏷️ Named the session '{title}'")),
+            Err(e) => self.set_status(format!("⚠️ Named the session '{title}', but saving failed: {e}")),
+        }
+    }
+
+    /// A summary worker finished: store the result or report the failure.
+    pub fn finish_summary(&mut self, session_idx: usize, result: Result<String, String>) {
+        self.summarizing = None;
+        if session_idx >= self.sessions.len() {
+            return;
+        }
+        match result {
+            Ok(summary) => match self.set_summary(session_idx, Some(summary)) {
+                Ok(()) => self.set_status("🧩 Summary updated"),
+                Err(e) => self.set_status(format!("⚠️ Summary saved in memory only: {e}")),
+            },
+            Err(e) => self.set_status(format!("⚠️ Summary failed: {e}")),
+        }
+    }
+
+    /// Write a session to `logs/exports/<title>.md`: the active branch, or
+    /// every branch when `all_branches` is set. Message text is copied
+    /// verbatim so code fences survive. Returns the written path.
+    pub fn export_markdown(&self, session_idx: usize, all_branches: bool) -> Result<PathBuf, Box<dyn Error>> {
+        let session = &self.sessions[session_idx];
+        let mut out = format!("# {}\n\n", session.title);
+        if let Some(summary) = &session.summary {
+            out.push_str("> **Summary:** ");
+            out.push_str(&summary.replace('\n', "\n> "));
+            out.push_str("\n\n");
+        }
+        if let Some(prompt) = &session.system_prompt {
+            out.push_str("> **System prompt:** ");
+            out.push_str(&prompt.replace('\n', "\n> "));
+            out.push_str("\n\n");
+        }
+
+        let branches: Vec<&Branch> = if all_branches {
+            session.branches.iter().collect()
+        } else {
+            session.branches.get(session.active_branch).into_iter().collect()
+        };
+        for branch in branches {
+            out.push_str(&format!("## Branch `{}`\n\n", branch.name));
+            for m in &branch.messages {
+                let label = match m.from {
+                    MessageFrom::User => "You",
+                    MessageFrom::Assistant => "AI",
+                    MessageFrom::Tool => {
+                        // Tool output is raw text; fence it so it cannot break the page.
+                        let fence = if m.content.contains("```") { "~~~~" } else { "```" };
+                        let name = m.tool.as_deref().unwrap_or("tool");
+                        out.push_str(&format!("🔧 `{name}`\n\n{fence}\n{}\n{fence}\n\n", m.content.trim_end()));
+                        continue;
+                    }
+                };
+                out.push_str(&format!("**{label}:**\n\n{}\n\n", m.content.trim_end()));
+                if let Some(error) = &m.error {
+                    out.push_str(&format!("> ⚠️ {}\n\n", error.replace('\n', "\n> ")));
+                }
+                if !m.sources.is_empty() {
+                    out.push_str("Sources:\n");
+                    for (i, source) in m.sources.iter().enumerate() {
+                        out.push_str(&format!("[{}] {}\n", i + 1, source));
+                    }
+                    out.push('\n');
+                }
+            }
+        }
+
+        let dir = self.config.log_dir.join("exports");
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}.md", encode_name_part(&session.title)));
+        fs::write(&path, out)?;
+        Ok(path)
+    }
+
+    /// Save a session after a change the user did not ask to save, showing
+    /// the error in the status line instead of losing it.
+    pub fn autosave_session(&mut self, session_idx: usize) {
+        if let Err(e) = self.save_session(session_idx) {
+            self.set_status(format!("⚠️ Not saved to {}: {e}", self.config.log_dir.display()));
+        }
+    }
+
+    /// Save the active session.
+    pub fn save_to_logs(&mut self) -> Result<(), Box<dyn Error>> {
+        self.save_session(self.active_idx)
+    }
+
+    /// Save a session with all its branches as `sessions/<id>.json` in the
+    /// log directory.
+    pub fn save_session(&self, session_idx: usize) -> Result<(), Box<dyn Error>> {
+        let session = &self.sessions[session_idx];
+        fs::create_dir_all(self.config.log_dir.join(SESSIONS_DIR))?;
+        write_json_atomic(&Self::session_path(&self.config.log_dir, &session.id), &versioned_session(session)?)
+    }
+
+    /// Conversation of a branch as chat messages (`user` / `assistant`),
+    /// keeping only the most recent whole messages that fit in `max_chars`.
+    /// Older turns are dropped from the front and replaced by a trim marker.
+    pub(crate) fn history_messages_for(&self, session_idx: usize, branch_idx: usize, max_chars: usize) -> Vec<Value> {
+        let messages = &self.sessions[session_idx].branches[branch_idx].messages;
+        let texts: Vec<String> = messages
+            .iter()
+            .map(|m| if m.from == MessageFrom::Tool { m.tool_text() } else { m.content.clone() })
+            .collect();
+        let lens: Vec<usize> = texts.iter().map(|t| t.chars().count()).collect();
+        let start = budget_start(&lens, max_chars);
+
+        let mut out = Vec::with_capacity(messages.len() - start + 1);
+        if start > 0 {
+            out.push(json!({ "role": "system", "content": TRIMMED_MARKER }));
         }
+        out.extend(messages[start..].iter().zip(&texts[start..]).map(|(m, text)| {
+            // Tool results go back the way the MCP loop falls back to,
+            // since `tool` messages need native tool call ids.
+            let role = match m.from {
+                MessageFrom::User => "user",
+                MessageFrom::Assistant => "assistant",
+                MessageFrom::Tool => "system",
+            };
+            json!({ "role": role, "content": text })
+        }));
+        out
+    }
+
+    /// Conversation of a branch as one prompt string, keeping only the most
+    /// recent whole messages that fit in `max_chars` (for summaries).
+    pub(crate) fn history_string_for(&self, session_idx: usize, branch_idx: usize, max_chars: usize) -> String {
+        let branch = &self.sessions[session_idx].branches[branch_idx];
+
+        let lines: Vec<String> = branch.messages
+            .iter()
+            .map(|m| match m.from {
+                MessageFrom::Tool => m.tool_text(),
+                MessageFrom::User => format!("User: {}", m.content),
+                MessageFrom::Assistant => format!("Assistant: {}", m.content),
+            })
+            .collect();
+
+        let lens: Vec<usize> = lines.iter().map(|l| l.chars().count() + 1).collect();
+        let mut start = budget_start(&lens, max_chars);
+        let mut out = Vec::new();
+        if start > 0 {
+            out.push(TRIMMED_MARKER.to_string());
+            start = budget_start(&lens, max_chars.saturating_sub(TRIMMED_MARKER.len() + 1));
+        }
+        out.extend(lines[start..].iter().cloned());
+        out.join("\n")
+    }
+
+    /// Loads every session file in `log_dir/sessions`, upgrading older
+    /// layouts, along with the names of files that could not be read or
+    /// parsed, or were saved by a newer version, and were skipped.
+    pub fn load_logs(log_dir: &Path) -> Result<(Vec<Session>, Vec<String>), Box<dyn std::error::Error>> {
+        let mut sessions = Vec::new();
+        let mut skipped = Vec::new();
+        let dir = log_dir.join(SESSIONS_DIR);
+        if !dir.exists() {
+            return Ok((sessions, skipped));
+        }
+
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|s| s.to_str()) != Some("json") {
+                continue;
+            }
+            // A corrupt or too new file only loses itself.
+            let name = format!("{SESSIONS_DIR}/{}", path.file_name().unwrap_or_default().to_string_lossy());
+            let Some(value) = fs::read_to_string(&path)
+                .ok()
+                .and_then(|text| serde_json::from_str::<Value>(&text).ok())
+            else {
+                skipped.push(name);
+                continue;
+            };
+            let version = schema_version(&value);
+            if version > SESSION_SCHEMA_VERSION {
+                skipped.push(format!("{name} (saved by a newer version, schema {version})"));
+                continue;
+            }
+            match upgrade_session(value) {
+                Ok(session) if !session.branches.is_empty() => {
+                    let mut session = session;
+                    session.active_branch = session.active_branch.min(session.branches.len() - 1);
+                    sessions.push(session);
+                }
+                _ => skipped.push(name),
+            }
+        }
+        Ok((sessions, skipped))
+    }
+
+    /// Fold the per-branch log files (`<title>_<branch>.json`) sessions were
+    /// saved as before into session files, then move them to `legacy/`.
+    /// Returns how many sessions were converted and the files that could
+    /// not be read, which stay where they are.
+    ///
+    /// Safe to run again after a failure: a converted session keeps an id
+    /// derived from its title, and files whose session file already exists
+    /// are only moved to `legacy/`.
+    pub fn migrate_legacy_logs(log_dir: &Path) -> Result<(usize, Vec<String>), Box<dyn std::error::Error>> {
+        let mut sessions_map: std::collections::HashMap<String, (Vec<Branch>, Vec<PathBuf>)> =
+            std::collections::HashMap::new();
+        let mut skipped = Vec::new();
+
+        if !log_dir.exists() {
+            return Ok((0, skipped));
+        }
+
+        // Iterate all JSON files
+        for entry in fs::read_dir(log_dir)? {
+            let entry = entry?;
+            let path = entry.path();
+
+            if path.extension().and_then(|s| s.to_str()) != Some("json") {
+                continue;
+            }
+
+            // File name example: "Session 1_main.json"; '_' inside a
+            // title or branch name is escaped as "%5F".
+            let filename = path.file_name().unwrap().to_string_lossy();
+            let Some((session_title, branch_name)) = parse_log_file_name(&filename) else {
+                continue;
+            };
+
+            // Deserialize file into Branch; a corrupt file only loses itself.
+            let parsed = File::open(&path)
+                .map_err(Box::<dyn Error>::from)
+                .and_then(|file| Ok(serde_json::from_reader::<_, Branch>(file)?));
+            let mut branch = match parsed {
+                Ok(branch) => branch,
+                Err(_) => {
+                    skipped.push(filename.into_owned());
+                    continue;
+                }
+            };
+
+            // Fix branch name if needed
+            branch.name = branch_name;
+
+            // Logs from before timestamps were saved: use the file's age.
+            if branch.created_at == 0 || branch.updated_at == 0 {
+                let mtime = entry
+                    .metadata()
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |d| d.as_secs());
+                if branch.created_at == 0 {
+                    branch.created_at = mtime;
+                }
+                if branch.updated_at == 0 {
+                    branch.updated_at = mtime;
+                }
+            }
+
+            // Insert into map
+            let (branches, files) = sessions_map.entry(session_title).or_default();
+            branches.push(branch);
+            files.push(path);
+        }
+
+        let legacy = log_dir.join(LEGACY_DIR);
+        let mut converted = 0;
+        for (title, (mut branches, files)) in sessions_map {
+            // A session file is written whole before any of its old files
+            // move, so finding it means an earlier run got this far.
+            let id = legacy_session_id(&title);
+            let path = Self::session_path(log_dir, &id);
+            if !path.exists() {
+                // read_dir order is arbitrary; keep branches in saved-id order.
+                branches.sort_by(|a, b| (a.id, &a.name).cmp(&(b.id, &b.name)));
+                let session = Self::legacy_session(id, title, branches);
+                fs::create_dir_all(log_dir.join(SESSIONS_DIR))?;
+                write_json_atomic(&path, &versioned_session(&session)?)?;
+                converted += 1;
+            }
+
+            fs::create_dir_all(&legacy)?;
+            for file in files {
+                if let Some(name) = file.file_name() {
+                    fs::rename(&file, legacy.join(name))?;
+                }
+            }
+        }
+        Ok((converted, skipped))
+    }
+
+    /// A session made of the branches of one legacy title.
+    fn legacy_session(id: String, title: String, branches: Vec<Branch>) -> Session {
+        Session {
+            id,
+            title,
+            active_branch: branches.len() - 1,
+            system_prompt: branches.iter().find_map(|b| b.system_prompt.clone()),
+            summary: branches.iter().find_map(|b| b.summary.clone()),
+            next_branch_id: branches.iter().map(|b| b.next_branch_id.max(b.id + 1)).max().unwrap_or(0),
+            created_at: branches.iter().map(|b| b.created_at).min().unwrap_or(0),
+            updated_at: branches.iter().map(|b| b.updated_at).max().unwrap_or(0),
+            branches,
+        }
+    }
+}
+
+/// Session id for the legacy logs of `title`: the same on every run, so a
+/// retried migration finds what it wrote before (64-bit FNV-1a).
+fn legacy_session_id(title: &str) -> String {
+    let hash = title
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |h, b| (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3));
+    format!("legacy-{hash:016x}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of its own under the system temp dir.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rustai-test-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn branch(id: usize, name: &str, text: &str) -> Branch {
+        Branch {
+            id,
+            name: name.to_string(),
+            messages: vec![Message::new(MessageFrom::User, text)],
+            parent: None,
+            fork_point: None,
+            system_prompt: None,
+            summary: None,
+            next_branch_id: 0,
+            created_at: 1,
+            updated_at: 2,
+        }
+    }
+
+    fn write_legacy(dir: &Path, file: &str, branch: &Branch) {
+        fs::write(dir.join(file), serde_json::to_string(branch).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn role_counts_counts_each_sender() {
+        let mut b = branch(0, "main", "hi");
+        b.messages.push(Message::new(MessageFrom::Assistant, "hello"));
+        b.messages.push(Message::new(MessageFrom::Tool, "result"));
+        b.messages.push(Message::new(MessageFrom::User, "thanks"));
+        let counts = b.role_counts();
+        assert_eq!((counts.user, counts.assistant, counts.tool), (2, 1, 1));
+        assert_eq!(counts.total(), 4);
+    }
+
+    fn child(id: usize, name: &str, parent: usize) -> Branch {
+        Branch { parent: Some(parent), ..branch(id, name, name) }
+    }
 
-        Ok(sessions)
+    fn session_of(branches: Vec<Branch>) -> Session {
+        App::legacy_session("s1".into(), "Test".into(), branches)
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn remove_branches_keeps_main_and_reparents_children() {
+        let mut session = session_of(vec![branch(0, "main", "m"), child(1, "branch-1", 0), child(2, "branch-2", 1)]);
+        assert_eq!(session.active_branch, 2);
+
+        let removed = session.remove_branches(&BTreeSet::from([0, 1]));
+
+        assert_eq!(removed.iter().map(|b| b.name.as_str()).collect::<Vec<_>>(), ["branch-1"]);
+        assert_eq!(session.branches.iter().map(|b| b.name.as_str()).collect::<Vec<_>>(), ["main", "branch-2"]);
+        assert_eq!(session.branches[1].parent, Some(0));
+        assert_eq!(session.active_branch, 1);
+    }
+
+    #[test]
+    fn remove_branches_falls_back_to_the_first_branch() {
+        let mut session = session_of(vec![branch(0, "main", "m"), child(1, "branch-1", 0)]);
+        session.remove_branches(&BTreeSet::from([1, 7]));
+        assert_eq!((session.branches.len(), session.active_branch), (1, 0));
+    }
+
+    #[test]
+    fn remove_branches_never_empties_a_session() {
+        let mut session = session_of(vec![branch(0, "a", "a"), branch(1, "b", "b")]);
+        assert!(session.remove_branches(&BTreeSet::from([0, 1])).is_empty());
+        assert_eq!(session.branches.len(), 2);
+    }
+
+    #[test]
+    fn delete_selected_branches_trashes_the_session_first() {
+        let dir = scratch_dir("bulk-delete");
+        let mut app = App::new(Config { log_dir: dir.clone(), ..Config::default() });
+        let idx = app.active_idx;
+        app.sessions[idx].branches = vec![branch(0, "main", "m"), child(1, "branch-1", 0), child(2, "branch-2", 0)];
+        app.open_branch_overlay();
+        if let Some(overlay) = app.branch_overlay.as_mut() {
+            overlay.selected = BTreeSet::from([0, 2]);
+            overlay.confirm_delete = true;
+        }
+
+        assert_eq!(app.delete_selected_branches(), 1);
+
+        let session = &app.sessions[idx];
+        assert_eq!(session.branches.iter().map(|b| b.name.as_str()).collect::<Vec<_>>(), ["main", "branch-1"]);
+        let trashed: Vec<_> = fs::read_dir(&app.tool_policy.trash_dir).unwrap().flatten().collect();
+        assert_eq!(trashed.len(), 1);
+        let saved = fs::read_to_string(App::session_path(&dir, &session.id)).unwrap();
+        assert!(!saved.contains("branch-2"));
+    }
+
+    #[test]
+    fn budget_start_keeps_the_newest_messages_that_fit() {
+        assert_eq!(budget_start(&[], 10), 0);
+        assert_eq!(budget_start(&[3, 3, 3], 10), 0);
+        assert_eq!(budget_start(&[5, 3, 3, 3], 10), 1);
+        // The latest pair goes out even when it alone is over budget.
+        assert_eq!(budget_start(&[1, 50, 50], 10), 1);
+    }
+
+    /// An app whose active session has one branch holding `messages`.
+    fn app_with_messages(name: &str, messages: Vec<Message>) -> App {
+        let mut app = App::new(Config { log_dir: scratch_dir(name), ..Config::default() });
+        let idx = app.active_idx;
+        app.sessions[idx].branches = vec![Branch { messages, ..branch(0, "main", "") }];
+        app.sessions[idx].active_branch = 0;
+        app
+    }
+
+    #[test]
+    fn history_messages_for_trims_from_the_front() {
+        let app = app_with_messages(
+            "history-trim",
+            vec![
+                Message::new(MessageFrom::User, "old ".repeat(10)),
+                Message::new(MessageFrom::Assistant, "older ".repeat(10)),
+                Message::new(MessageFrom::User, "new"),
+                Message::new(MessageFrom::Assistant, "newer"),
+            ],
+        );
+
+        let messages = app.history_messages_for(app.active_idx, 0, 20);
+
+        assert_eq!(
+            messages,
+            [
+                json!({"role": "system", "content": TRIMMED_MARKER}),
+                json!({"role": "user", "content": "new"}),
+                json!({"role": "assistant", "content": "newer"}),
+            ]
+        );
+        assert_eq!(app.history_messages_for(app.active_idx, 0, 1000).len(), 4);
+    }
+
+    #[test]
+    fn history_string_for_fits_the_marker_in_the_budget() {
+        let app = app_with_messages(
+            "history-string",
+            vec![
+                Message::new(MessageFrom::User, "a".repeat(30)),
+                Message::new(MessageFrom::Assistant, "b".repeat(30)),
+                Message::new(MessageFrom::User, "hi"),
+                Message::new(MessageFrom::Assistant, "yo"),
+            ],
+        );
+
+        let text = app.history_string_for(app.active_idx, 0, 60);
+
+        assert_eq!(text, format!("{TRIMMED_MARKER}\nUser: hi\nAssistant: yo"));
+        assert!(text.chars().count() <= 60);
+    }
+
+    #[tokio::test]
+    async fn join_workers_waits_for_finished_work_only() {
+        let mut app = App::new(Config { log_dir: scratch_dir("workers"), ..Config::default() });
+        app.spawn_worker(async {});
+        app.spawn_worker(tokio::time::sleep(Duration::from_millis(20)));
+        assert_eq!(app.join_workers(Duration::from_secs(5)).await, 0);
+        assert!(app.workers.is_empty());
+
+        let stuck = app.spawn_worker(std::future::pending());
+        assert_eq!(app.join_workers(Duration::from_millis(20)).await, 1);
+        stuck.abort();
+    }
+
+    #[tokio::test]
+    async fn spawn_worker_prunes_finished_workers() {
+        let mut app = App::new(Config { log_dir: scratch_dir("prune"), ..Config::default() });
+        app.spawn_worker(async {});
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        app.spawn_worker(std::future::pending());
+
+        assert_eq!(app.workers.len(), 1);
+        app.workers[0].abort();
+    }
+
+    /// Stream an assistant reply of `text` into the active branch of an
+    /// app set to `on_empty`, and finish it.
+    fn finish_reply(name: &str, on_empty: EmptyResponse, before: Vec<Message>, text: &str) -> Vec<Message> {
+        let mut app = app_with_messages(name, before);
+        app.config.on_empty = on_empty;
+        let idx = app.active_idx;
+        app.start_streaming_assistant(idx, 0);
+        app.sessions[idx].branches[0].messages.last_mut().unwrap().content.push_str(text);
+        app.finish_streaming(idx, 0);
+        app.sessions[idx].branches[0].messages.clone()
+    }
+
+    #[test]
+    fn finish_streaming_applies_on_empty() {
+        let asked = || vec![Message::new(MessageFrom::User, "hi")];
+
+        let marked = finish_reply("empty-marker", EmptyResponse::Marker, asked(), " \n");
+        assert_eq!(marked.last().map(|m| m.content.as_str()), Some(EMPTY_RESPONSE_MARKER));
+
+        assert_eq!(finish_reply("empty-drop", EmptyResponse::Drop, asked(), "").len(), 1);
+
+        let kept = finish_reply("empty-kept", EmptyResponse::Drop, asked(), "hello");
+        assert_eq!(kept.last().map(|m| m.content.as_str()), Some("hello"));
+    }
+
+    #[test]
+    fn finish_streaming_drops_an_empty_reply_after_a_tool_result() {
+        let before = vec![Message::new(MessageFrom::User, "hi"), Message::new(MessageFrom::Tool, "done")];
+        let messages = finish_reply("empty-after-tool", EmptyResponse::Marker, before, "");
+        assert_eq!(messages.last().map(|m| m.from), Some(MessageFrom::Tool));
+    }
+
+    #[test]
+    fn normalize_branch_ids_gives_duplicates_fresh_ids() {
+        let mut session = session_of(vec![branch(0, "main", "m"), child(1, "a", 0), child(1, "b", 1), child(0, "c", 0)]);
+        session.next_branch_id = 0;
+
+        assert!(session.normalize_branch_ids());
+
+        let ids: Vec<usize> = session.branches.iter().map(|b| b.id).collect();
+        assert_eq!(ids, [0, 1, 2, 3]);
+        assert_eq!(session.next_branch_id, 4);
+        // Links keep pointing at the first holder of an id.
+        assert_eq!(session.branches[2].parent, Some(1));
+        assert_eq!(session.branches[3].parent, Some(0));
+    }
+
+    #[test]
+    fn normalize_branch_ids_leaves_unique_ids_alone() {
+        let mut session = session_of(vec![branch(0, "main", "m"), child(5, "a", 0)]);
+        assert!(!session.normalize_branch_ids());
+        assert_eq!(session.branches[1].id, 5);
+        assert!(session.next_branch_id >= 6);
+    }
+
+    #[test]
+    fn loading_a_session_with_duplicate_ids_renumbers_and_warns() {
+        let dir = scratch_dir("duplicate-ids");
+        let session = session_of(vec![branch(0, "main", "m"), branch(0, "other", "o")]);
+        fs::create_dir_all(dir.join(SESSIONS_DIR)).unwrap();
+        write_json_atomic(&App::session_path(&dir, &session.id), &versioned_session(&session).unwrap()).unwrap();
+
+        let app = App::new(Config { log_dir: dir, ..Config::default() });
+
+        let loaded = app.sessions.iter().find(|s| s.id == "s1").unwrap();
+        assert_ne!(loaded.branches[0].id, loaded.branches[1].id);
+        let status = app.status.as_ref().map(|(text, _)| text.as_str()).unwrap_or("");
+        assert!(status.contains("Renumbered duplicate branch ids in: Test"), "{status}");
+    }
+
+    #[test]
+    fn migrate_legacy_logs_folds_branches_into_one_session() {
+        let dir = scratch_dir("migrate");
+        write_legacy(&dir, "Notes_main.json", &branch(0, "main", "hello"));
+        write_legacy(&dir, "Notes_branch-1.json", &branch(1, "branch-1", "fork"));
+
+        let (converted, skipped) = App::migrate_legacy_logs(&dir).unwrap();
+        assert_eq!((converted, skipped.len()), (1, 0));
+        assert!(dir.join(LEGACY_DIR).join("Notes_main.json").exists());
+        assert!(!dir.join("Notes_main.json").exists());
+
+        let (sessions, _) = App::load_logs(&dir).unwrap();
+        assert_eq!(sessions.len(), 1);
+        let names: Vec<&str> = sessions[0].branches.iter().map(|b| b.name.as_str()).collect();
+        assert_eq!(names, ["main", "branch-1"]);
+        assert_eq!(sessions[0].title, "Notes");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn migrate_legacy_logs_retry_does_not_duplicate_sessions() {
+        let dir = scratch_dir("migrate-retry");
+        write_legacy(&dir, "Notes_main.json", &branch(0, "main", "hello"));
+        write_legacy(&dir, "Notes_branch-1.json", &branch(1, "branch-1", "fork"));
+        App::migrate_legacy_logs(&dir).unwrap();
+
+        // As if the last run failed after writing the session file but
+        // before moving this file.
+        fs::rename(dir.join(LEGACY_DIR).join("Notes_branch-1.json"), dir.join("Notes_branch-1.json")).unwrap();
+        let (converted, _) = App::migrate_legacy_logs(&dir).unwrap();
+        assert_eq!(converted, 0);
+        assert!(dir.join(LEGACY_DIR).join("Notes_branch-1.json").exists());
+
+        let (sessions, _) = App::load_logs(&dir).unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].branches.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn migrate_legacy_logs_leaves_unreadable_files() {
+        let dir = scratch_dir("migrate-corrupt");
+        fs::write(dir.join("Broken_main.json"), "{ not json").unwrap();
+
+        let (converted, skipped) = App::migrate_legacy_logs(&dir).unwrap();
+        assert_eq!(converted, 0);
+        assert_eq!(skipped, ["Broken_main.json"]);
+        assert!(dir.join("Broken_main.json").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn legacy_session_id_is_stable_per_title() {
+        assert_eq!(legacy_session_id("Notes"), legacy_session_id("Notes"));
+        assert_ne!(legacy_session_id("Notes"), legacy_session_id("Notes 2"));
+    }
+
+    #[test]
+    fn log_file_names_round_trip() {
+        for (title, branch) in [("Notes", "main"), ("a_b", "branch_1"), ("100%", "x/y:z"), ("日本", "tab\there")] {
+            let name = format!("{}_{}.json", encode_name_part(title), encode_name_part(branch));
+            assert_eq!(parse_log_file_name(&name), Some((title.to_string(), branch.to_string())));
+        }
+        assert_eq!(parse_log_file_name("50%_%zz.json"), Some(("50%".into(), "%zz".into())));
+        assert_eq!(parse_log_file_name("Notes.json"), None);
+    }
+
+    #[test]
+    fn load_logs_skips_corrupt_session_files() {
+        let dir = scratch_dir("corrupt-sessions");
+        let good = session_of(vec![branch(0, "main", "hi")]);
+        fs::create_dir_all(dir.join(SESSIONS_DIR)).unwrap();
+        write_json_atomic(&App::session_path(&dir, &good.id), &versioned_session(&good).unwrap()).unwrap();
+        fs::write(dir.join(SESSIONS_DIR).join("broken.json"), "{\"id\": ").unwrap();
+        fs::write(dir.join(SESSIONS_DIR).join("notes.txt"), "not a session").unwrap();
+
+        let (sessions, skipped) = App::load_logs(&dir).unwrap();
+        assert_eq!(sessions.iter().map(|s| s.id.as_str()).collect::<Vec<_>>(), [good.id.as_str()]);
+        assert_eq!(skipped, [format!("{SESSIONS_DIR}/broken.json")]);
+        assert!(!dir.join(SESSIONS_DIR).join(format!("{}.json.tmp", good.id)).exists());
+    }
+
+    #[test]
+    fn coalesce_merges_same_sender_runs_but_not_tool_results() {
+        let mut b = branch(0, "main", "a");
+        let reply = |text: &str, source: &str| {
+            let mut m = Message::new(MessageFrom::Assistant, text);
+            m.sources = vec![source.to_string()];
+            m
+        };
+        b.messages.push(Message::new(MessageFrom::User, "b"));
+        b.messages.push(reply("c", "x"));
+        b.messages.push(reply("d", "x"));
+        b.messages.push(Message::new(MessageFrom::Tool, "t1"));
+        b.messages.push(Message::new(MessageFrom::Tool, "t2"));
+
+        assert_eq!(b.coalesce(), 2);
+        let merged: Vec<_> = b.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(merged, ["a\nb", "c\nd", "t1", "t2"]);
+        assert_eq!(b.messages[1].sources, ["x"]);
+    }
+
+    #[test]
+    fn load_logs_clamps_active_branch_and_skips_sessions_without_branches() {
+        let dir = scratch_dir("clamp-active");
+        fs::create_dir_all(dir.join(SESSIONS_DIR)).unwrap();
+        let mut stale = versioned_session(&session_of(vec![branch(0, "main", "m"), child(1, "branch-1", 0)])).unwrap();
+        stale["active_branch"] = 7.into();
+        fs::write(dir.join(SESSIONS_DIR).join("stale.json"), stale.to_string()).unwrap();
+        let mut empty = stale.clone();
+        empty["id"] = "s2".into();
+        empty["branches"] = Value::Array(Vec::new());
+        empty["active_branch"] = 0.into();
+        fs::write(dir.join(SESSIONS_DIR).join("empty.json"), empty.to_string()).unwrap();
+
+        let (sessions, skipped) = App::load_logs(&dir).unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].active_branch, 1);
+        assert_eq!(skipped, [format!("{SESSIONS_DIR}/empty.json")]);
+    }
+
+    #[test]
+    fn duplicate_session_copies_under_a_free_title_and_saves() {
+        let mut app = app_with_messages("dup-session", vec![Message::new(MessageFrom::User, "hi")]);
+        let original = app.active_idx;
+        let title = app.sessions[original].title.clone();
+
+        let first = app.duplicate_session(original).unwrap();
+        let second = app.duplicate_session(original).unwrap();
+
+        assert_eq!(app.active_idx, second);
+        assert_eq!(app.sessions[first].title, format!("{title} (copy)"));
+        assert_eq!(app.sessions[second].title, format!("{title} (copy 2)"));
+        assert_ne!(app.sessions[first].id, app.sessions[original].id);
+        assert_eq!(app.sessions[first].branches[0].messages[0].content, "hi");
+        for idx in [first, second] {
+            assert!(App::session_path(&app.config.log_dir, &app.sessions[idx].id).exists());
+        }
+    }
+
+    #[test]
+    fn duplicate_branch_copies_under_the_source() {
+        let mut app = app_with_messages("dup-branch", vec![Message::new(MessageFrom::User, "hi")]);
+        let idx = app.active_idx;
+
+        let copy = app.duplicate_branch(0).unwrap();
+
+        let session = &app.sessions[idx];
+        assert_eq!(session.active_branch, copy);
+        assert_eq!(session.branches[copy].parent, Some(session.branches[0].id));
+        assert_ne!(session.branches[copy].id, session.branches[0].id);
+        assert_eq!(session.branches[copy].messages[0].content, "hi");
+        let (saved, _) = App::load_logs(&app.config.log_dir).unwrap();
+        assert_eq!(saved.iter().find(|s| s.id == session.id).map(|s| s.branches.len()), Some(2));
+        assert!(app.duplicate_branch(9).is_err());
+    }
+
+    #[test]
+    fn new_branch_id_never_hands_out_an_id_or_name_again() {
+        let mut session = session_of(vec![branch(0, "main", "m"), child(1, "branch-1", 0), child(3, "branch-3", 0)]);
+        assert_eq!(session.next_branch_id, 4);
+        session.remove_branches(&BTreeSet::from([1]));
+        session.branches[1].name = "branch-5".into();
+
+        assert_eq!(session.new_branch_id(), (4, "branch-4".to_string()));
+        assert_eq!(session.new_branch_id(), (6, "branch-6".to_string()));
+    }
+
+    #[test]
+    fn next_branch_id_survives_a_save() {
+        let dir = scratch_dir("next-branch-id");
+        let mut session = session_of(vec![branch(0, "main", "m")]);
+        session.next_branch_id = 7;
+        fs::create_dir_all(dir.join(SESSIONS_DIR)).unwrap();
+        write_json_atomic(&App::session_path(&dir, &session.id), &versioned_session(&session).unwrap()).unwrap();
+
+        let (sessions, _) = App::load_logs(&dir).unwrap();
+        assert_eq!(sessions[0].next_branch_id, 7);
+    }
+}
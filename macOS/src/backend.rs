@@ -0,0 +1,207 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::future::BoxFuture;
+use serde_json::Value;
+
+use crate::config::{Config, OllamaConfig, OpenAiConfig};
+use crate::error::AppError;
+use crate::frontend::actions::{
+    call_chat_api_stream, call_ollama_chat_stream, ApiClient, ChatEndpoint, ChatReply, DASHSCOPE_BASE_URL,
+};
+use crate::frontend::api_key;
+
+const DEFAULT_DASHSCOPE_MODEL: &str = "qwen-plus";
+
+/// Callback handed each piece of a streamed reply.
+pub type OnChunk<'a> = &'a mut (dyn FnMut(&str) + Send);
+
+/// A model server that answers chat messages. The send paths only talk to
+/// this trait, so a new provider is one more impl.
+pub trait LlmBackend: Send + Sync {
+    /// Stream one chat completion, handing each piece of text to `on_chunk`.
+    fn stream<'a>(
+        &'a self,
+        api: &'a ApiClient,
+        messages: &'a [Value],
+        on_chunk: OnChunk<'a>,
+    ) -> BoxFuture<'a, Result<ChatReply, AppError>>;
+
+    /// Short label for the input title, e.g. "ollama:qwen3:1.7b".
+    fn label(&self) -> String;
+
+    /// The backend `o` switches to from this one: DashScope with its
+    /// default model unless overridden.
+    fn next(&self, _config: &Config) -> Arc<dyn LlmBackend> {
+        Arc::new(DashScopeBackend::default())
+    }
+}
+
+/// The backend new sessions start with.
+pub fn default_backend() -> Arc<dyn LlmBackend> {
+    Arc::new(DashScopeBackend::default())
+}
+
+/// DashScope's OpenAI-compatible chat endpoint.
+pub struct DashScopeBackend {
+    pub model: String,
+}
+
+impl Default for DashScopeBackend {
+    fn default() -> Self {
+        Self { model: DEFAULT_DASHSCOPE_MODEL.to_string() }
+    }
+}
+
+impl LlmBackend for DashScopeBackend {
+    fn stream<'a>(
+        &'a self,
+        api: &'a ApiClient,
+        messages: &'a [Value],
+        on_chunk: OnChunk<'a>,
+    ) -> BoxFuture<'a, Result<ChatReply, AppError>> {
+        Box::pin(async move {
+            let endpoint = ChatEndpoint {
+                provider: "DashScope",
+                base_url: DASHSCOPE_BASE_URL,
+                api_key: Some(api_key::resolve(None)?),
+            };
+            call_chat_api_stream(api, &endpoint, &self.model, messages, on_chunk).await
+        })
+    }
+
+    fn label(&self) -> String {
+        format!("dashscope:{}", self.model)
+    }
+
+    fn next(&self, config: &Config) -> Arc<dyn LlmBackend> {
+        Arc::new(OllamaBackend::new(&config.ollama))
+    }
+}
+
+/// Any OpenAI-compatible server from `Config::openai`.
+pub struct OpenAiCompatBackend {
+    pub base_url: String,
+    pub api_key: Option<String>,
+    pub model: String,
+}
+
+impl OpenAiCompatBackend {
+    pub fn new(config: &OpenAiConfig) -> Self {
+        Self {
+            base_url: config.base_url.clone(),
+            api_key: config.api_key.clone(),
+            model: config.model.clone(),
+        }
+    }
+}
+
+impl LlmBackend for OpenAiCompatBackend {
+    fn stream<'a>(
+        &'a self,
+        api: &'a ApiClient,
+        messages: &'a [Value],
+        on_chunk: OnChunk<'a>,
+    ) -> BoxFuture<'a, Result<ChatReply, AppError>> {
+        Box::pin(async move {
+            let endpoint = ChatEndpoint {
+                provider: "OpenAI-compatible server",
+                base_url: &self.base_url,
+                api_key: self.api_key.clone(),
+            };
+            call_chat_api_stream(api, &endpoint, &self.model, messages, on_chunk).await
+        })
+    }
+
+    fn label(&self) -> String {
+        format!("openai:{}", self.model)
+    }
+}
+
+/// Ollama server from `Config::ollama`.
+pub struct OllamaBackend {
+    pub url: String,
+    pub model: String,
+}
+
+impl OllamaBackend {
+    pub fn new(config: &OllamaConfig) -> Self {
+        Self { url: config.url.clone(), model: config.model.clone() }
+    }
+}
+
+impl LlmBackend for OllamaBackend {
+    fn stream<'a>(
+        &'a self,
+        api: &'a ApiClient,
+        messages: &'a [Value],
+        on_chunk: OnChunk<'a>,
+    ) -> BoxFuture<'a, Result<ChatReply, AppError>> {
+        Box::pin(call_ollama_chat_stream(api, &self.url, &self.model, messages, on_chunk))
+    }
+
+    fn label(&self) -> String {
+        format!("ollama:{}", self.model)
+    }
+
+    fn next(&self, config: &Config) -> Arc<dyn LlmBackend> {
+        match &config.openai {
+            Some(openai) => Arc::new(OpenAiCompatBackend::new(openai)),
+            None => Arc::new(DashScopeBackend::default()),
+        }
+    }
+}
+
+/// Canned replies without any network (`--mock`), for trying the streaming
+/// pipeline and the UI offline.
+pub struct MockBackend {
+    pub reply: String,
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        Self { reply: "This is a canned reply from the mock backend.".to_string() }
+    }
+}
+
+impl LlmBackend for MockBackend {
+    fn stream<'a>(
+        &'a self,
+        _api: &'a ApiClient,
+        _messages: &'a [Value],
+        on_chunk: OnChunk<'a>,
+    ) -> BoxFuture<'a, Result<ChatReply, AppError>> {
+        Box::pin(async move {
+            // Word by word, so the reply streams in like a real one.
+            for piece in self.reply.split_inclusive(' ') {
+                on_chunk(piece);
+                tokio::time::sleep(Duration::from_millis(30)).await;
+            }
+            Ok(ChatReply { text: self.reply.clone(), sources: Vec::new() })
+        })
+    }
+
+    fn label(&self) -> String {
+        "mock".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_cycles_through_the_configured_backends() {
+        let mut config = Config::default();
+        let dashscope = default_backend();
+        let ollama = dashscope.next(&config);
+        assert_eq!(dashscope.label(), "dashscope:qwen-plus");
+        assert_eq!(ollama.label(), format!("ollama:{}", config.ollama.model));
+        assert_eq!(ollama.next(&config).label(), "dashscope:qwen-plus");
+
+        config.openai = Some(OpenAiConfig { base_url: "http://localhost:1234/v1".into(), api_key: None, model: "local".into() });
+        let openai = ollama.next(&config);
+        assert_eq!(openai.label(), "openai:local");
+        assert_eq!(openai.next(&config).label(), "dashscope:qwen-plus");
+    }
+}
@@ -0,0 +1,598 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::error::AppError;
+use crate::frontend::api_key::config_path;
+use crate::frontend::actions::{normalize_escaped_content, Shell};
+use crate::frontend::keymap::{KeyMap, KeySpec};
+use crate::mcp_client::McpServerConfig;
+
+/// User-tunable behaviour of the TUI.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Stay in INSERT mode after sending a message (otherwise drop to NORMAL).
+    pub stay_in_insert_after_send: bool,
+    /// Render Markdown in assistant messages (otherwise show raw text).
+    pub render_markdown: bool,
+    /// Color fenced code blocks; turn off for low-color terminals.
+    pub highlight_code: bool,
+    /// Show line numbers in a gutter left of code blocks.
+    pub code_line_numbers: bool,
+    /// Scrollbars on the message area and the session list.
+    pub scrollbars: bool,
+    /// Token estimate and input length under the input box (`t`).
+    pub status_bar: bool,
+    /// Messages longer than this many lines are shown collapsed; 0 shows
+    /// every message in full.
+    pub collapse_after: usize,
+    /// Tokens a conversation may use before the status line warns, for
+    /// every model; `None` uses what the history sent to the model is
+    /// trimmed to.
+    pub context_limit: Option<usize>,
+    /// The same per model name (`[context_limits]`), taking precedence.
+    pub context_limits: HashMap<String, usize>,
+    /// Blank lines between messages.
+    pub density: Density,
+    /// What to do when the model answers with only whitespace.
+    pub on_empty: EmptyResponse,
+    /// Timeouts and retry policy for API requests.
+    pub http: HttpConfig,
+    /// Sampling settings; unset ones are left out of requests.
+    pub params: GenParams,
+    /// Summarize a branch automatically every this many user/assistant
+    /// pairs; `None` leaves it to `S` / `/summarize`.
+    pub summarize_after: Option<usize>,
+    /// Name sessions still called "Session N" after their first reply.
+    pub auto_title: bool,
+    /// Server and model used when the Ollama backend is selected.
+    pub ollama: OllamaConfig,
+    /// OpenAI-compatible server offered by `o`, when configured.
+    pub openai: Option<OpenAiConfig>,
+    /// How long a shell.run command may take before it is killed.
+    pub shell_timeout: Duration,
+    /// Shell shell.run commands run in.
+    pub shell: Shell,
+    /// Directories the agent's file tools may use; `None` keeps the
+    /// working directory.
+    pub allowed_roots: Option<Vec<PathBuf>>,
+    /// Copy files to `logs/backups/` before the agent overwrites them.
+    pub backup_on_write: bool,
+    /// Model calls one agent run may make before it is stopped.
+    pub max_agent_steps: usize,
+    /// Refuse file changes and shell commands in `/mcp` runs, like `--safe`.
+    pub safe_mode: bool,
+    /// Erase trash entries older than this many days at startup.
+    pub trash_days: Option<u64>,
+    /// NORMAL-mode keys, from the defaults and `[keys]`.
+    pub keymap: KeyMap,
+    /// External MCP servers to start (`[[mcp_servers]]`).
+    pub mcp_servers: Vec<McpServerConfig>,
+    /// Where sessions, input history, exports, backups and the trash are
+    /// kept: `RUSTAI_LOG_DIR`, or `logs` in the working directory.
+    pub log_dir: PathBuf,
+}
+
+/// Where sessions are saved unless `RUSTAI_LOG_DIR` names another place.
+pub const DEFAULT_LOG_DIR: &str = "logs";
+
+/// The log directory asked for: `RUSTAI_LOG_DIR`, or `logs`.
+pub fn requested_log_dir() -> PathBuf {
+    env::var_os("RUSTAI_LOG_DIR")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_LOG_DIR))
+}
+
+/// `wanted`, created if missing. When it cannot be written, a directory
+/// under the system temp dir is used instead, and the second value is a
+/// warning to show once.
+pub fn usable_log_dir(wanted: &Path) -> (PathBuf, Option<String>) {
+    let Err(e) = check_writable(wanted) else {
+        return (wanted.to_path_buf(), None);
+    };
+    let fallback = env::temp_dir().join("rustai-logs");
+    let warning = match check_writable(&fallback) {
+        Ok(()) => format!(
+            "⚠️ Saving to {}: cannot write to {} ({e}); set RUSTAI_LOG_DIR to choose",
+            fallback.display(),
+            wanted.display()
+        ),
+        Err(e2) => format!(
+            "⚠️ Cannot write to {} ({e}) or {} ({e2}); sessions will not be saved",
+            wanted.display(),
+            fallback.display()
+        ),
+    };
+    (fallback, Some(warning))
+}
+
+/// Create `dir` and write and remove a probe file in it.
+fn check_writable(dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let probe = dir.join(".write_test");
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)
+}
+
+const DEFAULT_OLLAMA_URL: &str = "http://localhost:11434";
+const DEFAULT_OLLAMA_MODEL: &str = "qwen3:1.7b";
+
+/// Where the Ollama backend runs and which model it serves. Set from the
+/// config file (`ollama_url`, `ollama_model`), then `OLLAMA_HOST` /
+/// `OLLAMA_MODEL`, then `--ollama-url` / `--ollama-model`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OllamaConfig {
+    pub url: String,
+    pub model: String,
+}
+
+impl Default for OllamaConfig {
+    fn default() -> Self {
+        Self {
+            url: DEFAULT_OLLAMA_URL.to_string(),
+            model: DEFAULT_OLLAMA_MODEL.to_string(),
+        }
+    }
+}
+
+impl OllamaConfig {
+    /// Set the server from a host or URL ("gpu-box:11434" gets `http://`).
+    /// Blank values are ignored.
+    pub fn set_url(&mut self, host: &str) {
+        let host = host.trim().trim_end_matches('/');
+        if host.is_empty() {
+            return;
+        }
+        self.url = if host.starts_with("http://") || host.starts_with("https://") {
+            host.to_string()
+        } else {
+            format!("http://{host}")
+        };
+    }
+
+    /// Set the model name; blank values are ignored.
+    pub fn set_model(&mut self, model: &str) {
+        if !model.trim().is_empty() {
+            self.model = model.trim().to_string();
+        }
+    }
+
+    /// Apply `--ollama-url <url>` / `--ollama-model <name>` from `args`.
+    pub fn apply_args(&mut self, args: &[String]) {
+        for pair in args.windows(2) {
+            match pair[0].as_str() {
+                "--ollama-url" => self.set_url(&pair[1]),
+                "--ollama-model" => self.set_model(&pair[1]),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Any server speaking OpenAI's `/v1/chat/completions` (LM Studio, vLLM,
+/// llama.cpp server, ...), from `openai_base_url`, `openai_api_key` and
+/// `openai_model` (else the shared `model`) in the config file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenAiConfig {
+    /// e.g. `http://localhost:1234/v1`.
+    pub base_url: String,
+    /// Bearer token, if the server wants one.
+    pub api_key: Option<String>,
+    pub model: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmptyResponse {
+    /// Ask again once, then fall back to the marker.
+    Retry,
+    /// Keep the turn, showing `EMPTY_RESPONSE_MARKER`.
+    #[default]
+    Marker,
+    /// Remove the empty assistant message.
+    Drop,
+}
+
+pub const EMPTY_RESPONSE_MARKER: &str = "[empty response]";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Density {
+    Compact,
+    #[default]
+    Normal,
+    Relaxed,
+}
+
+impl Density {
+    /// Spacer lines drawn after each message.
+    pub fn spacer_lines(self) -> usize {
+        match self {
+            Density::Compact => 0,
+            Density::Normal => 1,
+            Density::Relaxed => 2,
+        }
+    }
+}
+
+/// Optional generation settings, only sent when set.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GenParams {
+    /// Sequences at which the model should stop generating.
+    pub stop: Vec<String>,
+    /// Sampling temperature, 0.0 to 2.0.
+    pub temperature: Option<f32>,
+    /// Nucleus sampling cutoff, above 0.0 and at most 1.0.
+    pub top_p: Option<f32>,
+    /// Upper bound on generated tokens.
+    pub max_tokens: Option<u32>,
+}
+
+impl GenParams {
+    /// Add the set values to an OpenAI-compatible (DashScope) request body.
+    pub fn apply(&self, body: &mut Value) {
+        if !self.stop.is_empty() {
+            body["stop"] = json!(self.stop);
+        }
+        if let Some(t) = self.temperature {
+            body["temperature"] = json!(t);
+        }
+        if let Some(p) = self.top_p {
+            body["top_p"] = json!(p);
+        }
+        if let Some(n) = self.max_tokens {
+            body["max_tokens"] = json!(n);
+        }
+    }
+
+    /// Same for Ollama, which nests them under `options` and calls the
+    /// token limit `num_predict`.
+    pub fn apply_ollama(&self, body: &mut Value) {
+        let mut options = json!({});
+        if !self.stop.is_empty() {
+            options["stop"] = json!(self.stop);
+        }
+        if let Some(t) = self.temperature {
+            options["temperature"] = json!(t);
+        }
+        if let Some(p) = self.top_p {
+            options["top_p"] = json!(p);
+        }
+        if let Some(n) = self.max_tokens {
+            options["num_predict"] = json!(n);
+        }
+        if options.as_object().is_some_and(|o| !o.is_empty()) {
+            body["options"] = options;
+        }
+    }
+
+    /// Set one setting from user input; `off` unsets it (for `stop`,
+    /// clears every sequence). Out-of-range values are rejected with a
+    /// message saying why.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let value = value.trim();
+        let off = value == "off";
+        match name {
+            "stop" if off => self.stop.clear(),
+            "stop" => {
+                // `"\n\n"` and `\n\n` both mean two newlines.
+                let quoted = value.len() >= 2 && value.starts_with('"') && value.ends_with('"');
+                let seq = normalize_escaped_content(if quoted { &value[1..value.len() - 1] } else { value });
+                if seq.is_empty() {
+                    return Err("stop sequence must not be empty".into());
+                }
+                if !self.stop.contains(&seq) {
+                    self.stop.push(seq);
+                }
+            }
+            "temperature" => {
+                self.temperature = if off { None } else { Some(parse_in_range(value, 0.0, 2.0, name)?) };
+            }
+            "top_p" => {
+                let p = if off { None } else { Some(parse_in_range(value, 0.0, 1.0, name)?) };
+                if p == Some(0.0) {
+                    return Err("top_p must be greater than 0".into());
+                }
+                self.top_p = p;
+            }
+            "max_tokens" => {
+                self.max_tokens = if off {
+                    None
+                } else {
+                    match value.parse::<u32>() {
+                        Ok(n) if n > 0 => Some(n),
+                        _ => return Err(format!("max_tokens must be a positive whole number, got '{value}'")),
+                    }
+                };
+            }
+            _ => return Err(format!("unknown setting '{name}' (temperature, top_p, max_tokens, stop)")),
+        }
+        Ok(())
+    }
+}
+
+fn parse_in_range(value: &str, min: f32, max: f32, name: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(v) if (min..=max).contains(&v) => Ok(v),
+        _ => Err(format!("{name} must be a number between {min} and {max}, got '{value}'")),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    pub connect_timeout: Duration,
+    /// Maximum wait for any single read/write on the connection.
+    pub read_timeout: Duration,
+    /// Extra attempts after the first one for transient failures.
+    pub max_retries: u32,
+    /// Delay before the first retry; doubled after each attempt.
+    pub initial_backoff: Duration,
+    /// Longest wait a `Retry-After` header can ask for before a retry.
+    pub max_retry_after: Duration,
+    /// Sent with every request (`[headers]` in the config file).
+    pub headers: HashMap<String, String>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(60),
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_retry_after: Duration::from_secs(60),
+            headers: HashMap::new(),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            stay_in_insert_after_send: true,
+            render_markdown: true,
+            highlight_code: true,
+            code_line_numbers: false,
+            scrollbars: true,
+            status_bar: true,
+            collapse_after: 60,
+            context_limit: None,
+            context_limits: HashMap::new(),
+            density: Density::default(),
+            on_empty: EmptyResponse::default(),
+            http: HttpConfig::default(),
+            params: GenParams::default(),
+            summarize_after: None,
+            auto_title: true,
+            ollama: OllamaConfig::default(),
+            openai: None,
+            shell_timeout: Duration::from_secs(30),
+            shell: Shell::default(),
+            allowed_roots: None,
+            backup_on_write: true,
+            max_agent_steps: 10,
+            safe_mode: false,
+            trash_days: None,
+            keymap: KeyMap::default(),
+            mcp_servers: Vec::new(),
+            log_dir: PathBuf::from(DEFAULT_LOG_DIR),
+        }
+    }
+}
+
+/// The keys of `~/.config/rustai/config.toml` (shared with the CLI) the TUI uses.
+#[derive(Deserialize, Default)]
+struct FileSettings {
+    stay_in_insert_after_send: Option<bool>,
+    empty_response: Option<EmptyResponse>,
+    code_line_numbers: Option<bool>,
+    scrollbars: Option<bool>,
+    status_bar: Option<bool>,
+    collapse_after: Option<usize>,
+    context_limit: Option<usize>,
+    /// Model name -> tokens, e.g. `"qwen3:1.7b" = 8192`.
+    context_limits: Option<HashMap<String, usize>>,
+    density: Option<Density>,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    max_tokens: Option<u32>,
+    /// Stop sequences, e.g. `stop = ["\n\n"]`.
+    stop: Option<Vec<String>>,
+    summarize_after: Option<usize>,
+    auto_title: Option<bool>,
+    ollama_url: Option<String>,
+    ollama_model: Option<String>,
+    openai_base_url: Option<String>,
+    openai_api_key: Option<String>,
+    openai_model: Option<String>,
+    model: Option<String>,
+    shell_timeout_secs: Option<u64>,
+    shell: Option<Shell>,
+    allowed_roots: Option<Vec<PathBuf>>,
+    backup_on_write: Option<bool>,
+    max_agent_steps: Option<usize>,
+    safe_mode: Option<bool>,
+    trash_days: Option<u64>,
+    /// Action name -> key(s), e.g. `quit = "Q"`.
+    keys: Option<HashMap<String, KeySpec>>,
+    mcp_servers: Option<Vec<McpServerConfig>>,
+    /// Header name -> value, sent with every API request.
+    headers: Option<HashMap<String, String>>,
+}
+
+/// The settings in `path`; none if it cannot be read, an error if it is
+/// not valid TOML.
+fn read_settings(path: &Path) -> Result<FileSettings, AppError> {
+    let Ok(text) = fs::read_to_string(path) else {
+        return Ok(FileSettings::default());
+    };
+    toml::from_str(&text).map_err(|e| AppError::Config(format!("invalid config file {}: {e}", path.display())))
+}
+
+impl Config {
+    /// Defaults, overridden by the shared config file when it can be read.
+    /// A file that is not valid TOML is an error rather than ignored, since
+    /// falling back to the defaults would also drop `safe_mode` and
+    /// `allowed_roots`.
+    pub fn load() -> Result<Self, AppError> {
+        let file = match config_path() {
+            Some(path) => read_settings(&path)?,
+            None => FileSettings::default(),
+        };
+        Ok(Self::from_settings(file))
+    }
+
+    /// Defaults with the keys set in `file` applied.
+    fn from_settings(file: FileSettings) -> Self {
+        let mut config = Self::default();
+        if let Some(on) = file.stay_in_insert_after_send {
+            config.stay_in_insert_after_send = on;
+        }
+        if let Some(mode) = file.empty_response {
+            config.on_empty = mode;
+        }
+        if let Some(on) = file.code_line_numbers {
+            config.code_line_numbers = on;
+        }
+        if let Some(on) = file.scrollbars {
+            config.scrollbars = on;
+        }
+        if let Some(on) = file.status_bar {
+            config.status_bar = on;
+        }
+        if let Some(lines) = file.collapse_after {
+            config.collapse_after = lines;
+        }
+        config.context_limit = file.context_limit;
+        config.context_limits = file.context_limits.unwrap_or_default();
+        if let Some(density) = file.density {
+            config.density = density;
+        }
+        config.summarize_after = file.summarize_after;
+        if let Some(on) = file.auto_title {
+            config.auto_title = on;
+        }
+        config.allowed_roots = file.allowed_roots;
+        if let Some(on) = file.backup_on_write {
+            config.backup_on_write = on;
+        }
+        if let Some(steps) = file.max_agent_steps {
+            config.max_agent_steps = steps;
+        }
+        if let Some(on) = file.safe_mode {
+            config.safe_mode = on;
+        }
+        if file.trash_days.is_some() {
+            config.trash_days = file.trash_days;
+        }
+        if let Some(keys) = &file.keys {
+            config.keymap.apply(keys);
+        }
+        config.mcp_servers = file.mcp_servers.unwrap_or_default();
+        config.http.headers = file.headers.unwrap_or_default();
+        if let Some(secs) = file.shell_timeout_secs {
+            config.shell_timeout = Duration::from_secs(secs);
+        }
+        if let Some(shell) = file.shell {
+            config.shell = shell;
+        }
+        if let Some(url) = &file.ollama_url {
+            config.ollama.set_url(url);
+        }
+        if let Some(model) = &file.ollama_model {
+            config.ollama.set_model(model);
+        }
+        config.log_dir = requested_log_dir();
+        if let Ok(host) = env::var("OLLAMA_HOST") {
+            config.ollama.set_url(&host);
+        }
+        if let Ok(model) = env::var("OLLAMA_MODEL") {
+            config.ollama.set_model(&model);
+        }
+        if let Some(base_url) = file.openai_base_url.filter(|u| !u.trim().is_empty()) {
+            config.openai = Some(OpenAiConfig {
+                base_url: base_url.trim().to_string(),
+                api_key: file.openai_api_key.filter(|k| !k.trim().is_empty()),
+                model: file.openai_model.or(file.model).unwrap_or_else(|| "default".to_string()),
+            });
+        }
+        // Out-of-range values in the file are ignored, like unreadable ones.
+        let numbers = [
+            ("temperature", file.temperature.map(|v| v.to_string())),
+            ("top_p", file.top_p.map(|v| v.to_string())),
+            ("max_tokens", file.max_tokens.map(|v| v.to_string())),
+        ];
+        for (name, value) in numbers {
+            if let Some(value) = value {
+                let _ = config.params.set(name, &value);
+            }
+        }
+        config.params.stop = file.stop.unwrap_or_default().into_iter().filter(|s| !s.is_empty()).collect();
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_toml(text: &str) -> Config {
+        Config::from_settings(toml::from_str(text).unwrap())
+    }
+
+    #[test]
+    fn read_settings_rejects_invalid_toml_but_not_a_missing_file() {
+        let dir = env::temp_dir().join(format!("rustai-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(&path, "safe_mode = tru").unwrap();
+
+        let err = read_settings(&path).err().expect("invalid TOML is an error");
+        assert!(err.to_string().contains("invalid config file"), "{err}");
+
+        fs::write(&path, "safe_mode = true").unwrap();
+        assert_eq!(read_settings(&path).unwrap().safe_mode, Some(true));
+        assert!(read_settings(&dir.join("missing.toml")).unwrap().safe_mode.is_none());
+    }
+
+    #[test]
+    fn empty_response_is_read_from_the_file() {
+        assert_eq!(from_toml("").on_empty, EmptyResponse::Marker);
+        assert_eq!(from_toml("empty_response = \"drop\"").on_empty, EmptyResponse::Drop);
+        assert_eq!(from_toml("empty_response = \"retry\"").on_empty, EmptyResponse::Retry);
+    }
+
+    #[test]
+    fn stay_in_insert_after_send_defaults_to_on() {
+        assert!(Config::default().stay_in_insert_after_send);
+        assert!(from_toml("").stay_in_insert_after_send);
+    }
+
+    #[test]
+    fn stay_in_insert_after_send_can_be_turned_off() {
+        assert!(!from_toml("stay_in_insert_after_send = false").stay_in_insert_after_send);
+        assert!(from_toml("stay_in_insert_after_send = true").stay_in_insert_after_send);
+    }
+
+    #[test]
+    fn density_sets_the_spacer_lines() {
+        assert_eq!(from_toml("").density, Density::Normal);
+        let spacers = |text| from_toml(text).density.spacer_lines();
+        assert_eq!(spacers("density = \"compact\""), 0);
+        assert_eq!(spacers("density = \"normal\""), 1);
+        assert_eq!(spacers("density = \"relaxed\""), 2);
+        assert!(toml::from_str::<FileSettings>("density = \"tight\"").is_err());
+    }
+
+    #[test]
+    fn allowed_roots_are_read_from_the_file() {
+        assert_eq!(from_toml("").allowed_roots, None);
+        let roots = from_toml("allowed_roots = [\"/srv/a\", \"b\"]").allowed_roots;
+        assert_eq!(roots, Some(vec![PathBuf::from("/srv/a"), PathBuf::from("b")]));
+    }
+}
@@ -1,21 +1,183 @@
-use std::thread;
+use std::thread::{self, JoinHandle};
 
 use anyhow::Result;
-use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
 
-use crate::app::{App, BackendEvent, Message, MessageFrom, EditContext, Branch};
+use crate::app::{is_default_title, now_secs, App, BackendEvent, Message, MessageFrom, EditContext, Branch, InputMode};
 
-use reqwest::blocking::{Client};
+use bytes::Bytes;
+use futures_util::stream::{BoxStream, StreamExt};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, NoProxy, Proxy, RequestBuilder, Response, StatusCode, Url};
 use std::error::Error;
 
 use std::fs;
+use std::io::Read;
+use std::process::{Command, Stdio};
 
 use regex::Regex;
+use serde::Deserialize;
 use serde_json::{json, Value};
-use crate::frontend::api_key::DASHSCOPE_API_KEY;
-
-use std::path::PathBuf;
+use crate::audit::{self, AuditEntry};
+use crate::backend::{default_backend, LlmBackend};
+use crate::config::{EmptyResponse, GenParams, HttpConfig, DEFAULT_LOG_DIR};
+use crate::error::AppError;
+use crate::frontend::keymap::{KeyMap, INSERT_KEYS};
+use crate::mcp_client::{prompt_lines, McpClient};
+use crate::trash::move_to_trash;
+
+use std::path::{Component, Path, PathBuf};
+use std::collections::HashMap;
 use std::env;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Restrictions applied to filesystem tools.
+#[derive(Debug, Clone)]
+pub struct ToolPolicy {
+    /// Directory that filesystem.move / filesystem.delete may not escape.
+    pub workspace_root: PathBuf,
+    /// Directories filesystem.read / write / list may touch.
+    pub allowed_roots: Vec<PathBuf>,
+    /// Copy files into `backup_dir` before filesystem.write overwrites them.
+    pub backup_on_write: bool,
+    pub backup_dir: PathBuf,
+    /// Model calls one agent run may make before it is stopped.
+    pub max_agent_steps: usize,
+    /// Move deleted files into `trash_dir` instead of unlinking them.
+    pub safe_delete: bool,
+    pub trash_dir: PathBuf,
+    /// Run shell.run commands without asking the user first.
+    pub auto_approve_shell: bool,
+    /// How long shell.run may take before the command is killed.
+    pub shell_timeout: Duration,
+    /// Interpreter shell.run commands are handed to.
+    pub shell: Shell,
+    /// Refuse every tool that changes files or runs commands (`--safe`).
+    pub safe_mode: bool,
+    /// JSON-lines record of every built-in tool run.
+    pub audit_log: PathBuf,
+}
+
+impl ToolPolicy {
+    /// The defaults, with the trash and backups inside `log_dir`.
+    pub fn for_log_dir(log_dir: &Path) -> Self {
+        Self {
+            trash_dir: log_dir.join(".trash"),
+            backup_dir: log_dir.join("backups"),
+            audit_log: log_dir.join(AUDIT_LOG_FILE),
+            ..Self::default()
+        }
+    }
+}
+
+impl Default for ToolPolicy {
+    fn default() -> Self {
+        Self {
+            workspace_root: env::current_dir().unwrap_or_else(|_| PathBuf::from(".")),
+            allowed_roots: vec![env::current_dir().unwrap_or_else(|_| PathBuf::from("."))],
+            safe_delete: true,
+            trash_dir: PathBuf::from(DEFAULT_LOG_DIR).join(".trash"),
+            backup_on_write: true,
+            backup_dir: PathBuf::from(DEFAULT_LOG_DIR).join("backups"),
+            max_agent_steps: 10,
+            auto_approve_shell: false,
+            shell_timeout: Duration::from_secs(30),
+            shell: Shell::default(),
+            safe_mode: false,
+            audit_log: PathBuf::from(DEFAULT_LOG_DIR).join(AUDIT_LOG_FILE),
+        }
+    }
+}
+
+/// Name of the audit log inside the log directory. It is not a session
+/// file, so deleting sessions leaves it alone.
+const AUDIT_LOG_FILE: &str = "audit.jsonl";
+
+/// What a tool refused in safe mode returns to the model.
+const SAFE_MODE_REFUSAL: &str = "tool disabled in safe mode";
+
+/// Built-in tools that change files or run commands.
+fn is_mutating(name: &str) -> bool {
+    matches!(
+        name,
+        "filesystem.write" | "filesystem.move" | "filesystem.delete" | "filesystem.restore" | "shell.run"
+    )
+}
+
+/// Tool lines of the agent prompt that safe mode leaves out.
+const WRITE_TOOLS_PROMPT: &str = "\
+    - filesystem.write - write text into a file. Example: <use_tool name=\"filesystem.write\" params={\"path\": \"output.txt\", \"content\": \"Hello\"} />\n\
+    - filesystem.move - move or rename a file. Example: <use_tool name=\"filesystem.move\" params={\"src\": \"old.txt\", \"dst\": \"new.txt\"} />\n\
+    - filesystem.delete - delete a file. Example: <use_tool name=\"filesystem.delete\" params={\"path\": \"old.txt\"} />\n\
+    - filesystem.restore - put back a backup made by filesystem.write. Example: <use_tool name=\"filesystem.restore\" params={\"src\": \"logs/backups/notes.txt.1700000000000\", \"dst\": \"notes.txt\"} />\n\
+    - shell.run - run shell commands. Example: <use_tool name=\"shell.run\" params={\"content\": \"mkdir Playground\"} />\n";
+
+/// Told to the model instead of `WRITE_TOOLS_PROMPT` in safe mode.
+const SAFE_MODE_PROMPT: &str = "Safe mode is on: only the tools above are available. Writing, moving, \
+    deleting or restoring files, running shell commands and tools of other servers are disabled, so do not try them.\n";
+
+/// Interpreter for shell.run: `sh`, `bash` or `zsh`, or `cmd`, `powershell`
+/// or `pwsh` on Windows (`shell` in the config file).
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Shell {
+    Cmd,
+    Powershell,
+    Pwsh,
+    Sh,
+    Bash,
+    Zsh,
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        if cfg!(target_os = "windows") {
+            Shell::Cmd
+        } else {
+            Shell::Sh
+        }
+    }
+}
+
+impl Shell {
+    pub fn name(self) -> &'static str {
+        match self {
+            Shell::Cmd => "cmd",
+            Shell::Powershell => "powershell",
+            Shell::Pwsh => "pwsh",
+            Shell::Sh => "sh",
+            Shell::Bash => "bash",
+            Shell::Zsh => "zsh",
+        }
+    }
+
+    /// Program and arguments that run `command`.
+    fn command(self, command: &str) -> Command {
+        let mut cmd = Command::new(self.name());
+        match self {
+            Shell::Cmd => cmd.args(["/C", command]),
+            Shell::Powershell | Shell::Pwsh => cmd.args(["-NoProfile", "-Command", command]),
+            Shell::Sh | Shell::Bash | Shell::Zsh => cmd.args(["-c", command]),
+        };
+        cmd
+    }
+
+    /// Line for the system prompt telling the model which syntax to use.
+    fn prompt_note(self) -> String {
+        match self {
+            Shell::Cmd => "Shell commands are executed via `cmd /C \"command\"`: write cmd syntax.".to_string(),
+            Shell::Powershell | Shell::Pwsh => format!(
+                "Shell commands are executed via `{} -NoProfile -Command \"command\"`: write PowerShell syntax.",
+                self.name()
+            ),
+            Shell::Sh | Shell::Bash | Shell::Zsh => {
+                format!("Shell commands are executed via `{0} -c \"command\"`: write POSIX shell syntax.", self.name())
+            }
+        }
+    }
+}
 
 
 
@@ -28,17 +190,18 @@ pub fn show_help_message(app: &mut App) -> Result<()> {
     app.start_streaming_assistant(session_idx, branch_idx);
 
     // 3) Clear the input box
-    app.input.clear();
+    app.clear_input();
     app.input_scroll = 0; 
 
+    let help = help_text(&app.config.keymap);
     if let Some(tx_main) = app.backend_tx.clone() {
-        let tx_thread = tx_main.clone(); // clone for thread
+        let tx_thread = tx_main.clone(); // clone for the task
 
-        thread::spawn(move || {
+        let task = app.spawn_worker(async move {
             let tx_for_loop = tx_thread.clone();
             let tx_for_done = tx_thread.clone();
            
-            let _ = stream_help_message(session_idx, branch_idx, tx_for_loop);
+            let _ = stream_help_message(help, session_idx, branch_idx, tx_for_loop).await;
 
             // send final done event
             let _ = tx_for_done.send(BackendEvent::AssistantDone {
@@ -47,90 +210,888 @@ pub fn show_help_message(app: &mut App) -> Result<()> {
             });
             
         });
+        app.set_stream_task(session_idx, branch_idx, task);
     }
 
     Ok(())
 }
 
-pub fn stream_help_message(
+pub async fn stream_help_message(
+    help_text: String,
     session_idx: usize,
     branch_idx: usize,
-    tx: Sender<BackendEvent>,
+    tx: UnboundedSender<BackendEvent>,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    // stream like LLM output
+    UiStreamer::default().stream(&help_text, session_idx, branch_idx, &tx).await?;
+
+    Ok(())
+}
+
+/// The help text, listing the NORMAL-mode keys currently bound in `keymap`.
+pub fn help_text(keymap: &KeyMap) -> String {
+    let mut normal = String::new();
+    for (help, keys) in keymap.entries() {
+        normal.push_str(&format!("  {:<10} {}\n", KeyMap::keys_label(keys), help));
+    }
+    let mut insert = String::new();
+    for (keys, help) in INSERT_KEYS {
+        insert.push_str(&format!("  {keys:<10} {help}\n"));
+    }
 
-    let help_text = r#"
+    format!(r#"
 📖 MyCLI Help
 
 NORMAL MODE
-  q          Quit
-  n          New session
-  j / k      Next / previous session
-  ↑ / ↓      Move session selection
-  [ / ]      Previous / next branch
-  TAB        Toggle new-session button
-  s          Toggle sidebar
-  e          Edit last user message
-  i          Enter insert mode
-
+{normal}
 INSERT MODE
-  Enter      Send message
-  Esc        Back to normal mode
-
+{insert}
 TIPS
   • Editing a message forks a new branch
+  • Send /system <text> to set this session's system prompt
+    (/system clear removes it, /system shows it)
+  • Send /set temperature 0.2 (or top_p, max_tokens; "off" resets)
+  • Send /set stop "\\n\\n" to end replies at a stop sequence
+    (/set stop off removes them)
+  • Send /coalesce to merge adjacent messages from the same sender
+  • Send /ab <prompt> to ask the current backend and the next one (o)
+    at once, each answer in its own branch named after the model
+  • Send /duplicate (or /duplicate branch) to copy this session
+    (or branch) and keep experimenting on the copy
+  • Send /summarize to summarize the branch (same as S); set
+    summarize_after = 20 in the config file to do it every 20 exchanges
+  • New sessions are named by the model after the first reply; set
+    auto_title = false in the config file to keep "Session N"
+  • Send /export md (or /export md all) to save the session as Markdown
+  • Start with --no-highlight to show code blocks without colors
+  • Start with --ollama-url <url> / --ollama-model <name> (or set
+    OLLAMA_HOST / OLLAMA_MODEL) to choose the Ollama server and model
+  • Agent file tools stay inside the working directory; start with
+    --allow-root <dir> to allow another one
+  • Start with --safe (or set safe_mode = true) to let /mcp only read
+    and list files; [SAFE] in the input title shows it is on
+  • Set density = "compact" / "normal" / "relaxed" in
+    ~/.config/rustai/config.toml for 0 / 1 / 2 blank lines between messages
+  • Rebind the NORMAL MODE keys under [keys] in the same file, e.g.
+    quit = "Q" or next_session = ["j", "Down"] ([] unbinds)
+
+"#)
+}
 
-"#;
 
-    // stream like LLM output
-    stream_string_into_ui(help_text, session_idx, branch_idx, &tx)?;
 
-    Ok(())
+/// History sent to the model is trimmed to roughly this many characters.
+pub const HISTORY_MAX_CHARS: usize = 24_000;
+/// How much of the first message a title is asked for on.
+const TITLE_PROMPT_MAX_CHARS: usize = 2_000;
+/// Titles are a few words; this keeps the request cheap.
+const TITLE_MAX_TOKENS: u32 = 24;
+/// Longest title kept, in characters, so the sidebar stays readable.
+const TITLE_MAX_CHARS: usize = 60;
+
+/// DashScope's OpenAI-compatible API.
+pub const DASHSCOPE_BASE_URL: &str = "https://dashscope.aliyuncs.com/compatible-mode/v1";
+
+/// An OpenAI-compatible server: `POST {base_url}/chat/completions`.
+pub struct ChatEndpoint<'a> {
+    /// Name used in error messages, e.g. "DashScope".
+    pub provider: &'static str,
+    /// Everything before `/chat/completions`.
+    pub base_url: &'a str,
+    /// Sent as a bearer token; local servers often need none.
+    pub api_key: Option<String>,
+}
+
+/// Build the shared client with the configured timeouts, headers and the
+/// proxy from the environment.
+pub fn http_client(cfg: &HttpConfig) -> Result<Client, AppError> {
+    let mut builder = Client::builder()
+        .connect_timeout(cfg.connect_timeout)
+        .timeout(cfg.read_timeout)
+        .default_headers(default_headers(&cfg.headers)?);
+    for proxy in env_proxies()? {
+        builder = builder.proxy(proxy);
+    }
+    builder.build().map_err(|e| AppError::Config(format!("cannot set up the HTTP client: {e}")))
+}
+
+/// `[headers]` from the config file, checked for names and values HTTP allows.
+fn default_headers(headers: &HashMap<String, String>) -> Result<HeaderMap, AppError> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        let header = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| AppError::Config(format!("invalid header name in [headers]: `{name}`")))?;
+        let mut value = HeaderValue::from_str(value)
+            .map_err(|_| AppError::Config(format!("invalid value for header `{name}` in [headers]")))?;
+        // Gateway keys stay out of debug output.
+        value.set_sensitive(true);
+        map.insert(header, value);
+    }
+    Ok(map)
+}
+
+/// Proxies named by `HTTPS_PROXY`, `HTTP_PROXY` and `ALL_PROXY` (or their
+/// lower-case forms); hosts in `NO_PROXY` bypass them.
+fn env_proxies() -> Result<Vec<Proxy>, AppError> {
+    let mut proxies = Vec::new();
+    for (var, make) in [
+        ("HTTPS_PROXY", Proxy::https::<Url> as fn(Url) -> reqwest::Result<Proxy>),
+        ("HTTP_PROXY", Proxy::http::<Url>),
+        ("ALL_PROXY", Proxy::all::<Url>),
+    ] {
+        let lower = var.to_lowercase();
+        let Some((var, value)) = [var, lower.as_str()]
+            .into_iter()
+            .find_map(|name| env::var(name).ok().filter(|v| !v.trim().is_empty()).map(|v| (name, v)))
+        else {
+            continue;
+        };
+        let proxy = make(proxy_url(var, value.trim())?).map_err(|e| AppError::Config(format!("{var}: {e}")))?;
+        proxies.push(proxy.no_proxy(NoProxy::from_env()));
+    }
+    Ok(proxies)
+}
+
+/// `value` as an `http://` or `https://` proxy URL. The value itself is
+/// left out of errors, since it may carry a password.
+fn proxy_url(var: &str, value: &str) -> Result<Url, AppError> {
+    let bad = |why: String| {
+        AppError::Config(format!("{var} is not a usable proxy URL ({why}); expected e.g. http://proxy.example.com:8080"))
+    };
+    let url = Url::parse(value).map_err(|e| bad(e.to_string()))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(bad(format!("unsupported scheme `{}`", url.scheme())));
+    }
+    if url.host_str().is_none() {
+        return Err(bad("no host".to_string()));
+    }
+    Ok(url)
+}
+
+
+/// HTTP client plus the retry settings every API call needs.
+#[derive(Clone)]
+pub struct ApiClient {
+    pub client: Client,
+    pub http: HttpConfig,
+    /// Ask again once when a completion comes back blank.
+    pub retry_empty: bool,
+    /// Server and model that answer chat requests.
+    pub backend: Arc<dyn LlmBackend>,
+    /// Sampling settings added to every request.
+    pub params: GenParams,
+    /// Where to report rate-limited retries, if anyone is listening.
+    pub status_tx: Option<UnboundedSender<BackendEvent>>,
+}
+
+impl ApiClient {
+    pub fn new(http: HttpConfig) -> Self {
+        Self {
+            // Checked at startup, so this only falls back if the
+            // environment changed since.
+            client: http_client(&http).unwrap_or_else(|_| Client::new()),
+            http,
+            retry_empty: false,
+            backend: default_backend(),
+            params: GenParams::default(),
+            status_tx: None,
+        }
+    }
+
+    /// Send the request built by `make`, retrying transient failures
+    /// (connection errors, timeouts, HTTP 429/500/502/503) with exponential
+    /// backoff. A `Retry-After` header on a 429 or 503 sets the wait instead,
+    /// up to `max_retry_after`. `target` names the server in the final error.
+    async fn send_with_retry(&self, target: &str, make: impl Fn() -> RequestBuilder) -> Result<Response, AppError> {
+        let mut backoff = self.http.initial_backoff;
+        let mut attempt = 0;
+
+        loop {
+            let retries_left = attempt < self.http.max_retries;
+            let wait = match make().send().await {
+                Ok(resp) if retries_left && is_transient_status(resp.status()) => {
+                    let wait = retry_after(&resp).map_or(backoff, |w| w.min(self.http.max_retry_after));
+                    if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+                        if let Some(tx) = &self.status_tx {
+                            let secs = wait.as_millis().div_ceil(1000);
+                            let _ = tx.send(BackendEvent::Status(format!("⏳ Rate limited, retrying in {secs}s")));
+                        }
+                    }
+                    wait
+                }
+                Ok(resp) => return Ok(resp),
+                Err(e) if retries_left && (e.is_connect() || e.is_timeout() || e.is_request()) => backoff,
+                Err(source) => {
+                    return Err(AppError::Http {
+                        context: format!("request to {target} failed after {} attempt(s)", attempt + 1),
+                        source,
+                    })
+                }
+            };
+
+            attempt += 1;
+            tokio::time::sleep(wait).await;
+            backoff *= 2;
+        }
+    }
+}
+
+fn is_transient_status(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 429 | 500 | 502 | 503)
+}
+
+/// The wait a 429 or 503 response asks for in `Retry-After`: seconds, or
+/// an HTTP date.
+fn retry_after(resp: &Response) -> Option<Duration> {
+    let value = resp.headers().get(reqwest::header::RETRY_AFTER)?.to_str().ok()?;
+    let now = now_secs();
+    parse_retry_after(resp.status().as_u16(), value, now)
+}
+
+/// `retry_after` for a response `status` and header `value`, with `now` in
+/// seconds since the epoch.
+fn parse_retry_after(status: u16, value: &str, now: u64) -> Option<Duration> {
+    if !matches!(status, 429 | 503) {
+        return None;
+    }
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = parse_http_date(value)?;
+    Some(Duration::from_secs(at.saturating_sub(now)))
+}
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Seconds since the epoch of an HTTP date, "Sun, 06 Nov 1994 08:49:37 GMT".
+/// The header comes from the server, so out-of-range fields are rejected
+/// before any arithmetic rather than left to overflow.
+fn parse_http_date(value: &str) -> Option<u64> {
+    let mut parts = value.split_whitespace().skip(1);
+    let day: u64 = parts.next()?.parse().ok()?;
+    let month_name = parts.next()?;
+    let month = MONTHS.iter().position(|m| *m == month_name)? as u64 + 1;
+    let year: u64 = parts.next()?.parse().ok()?;
+    let mut time = parts.next()?.split(':').map(|p| p.parse::<u64>().ok());
+    let (h, m, s) = (time.next()??, time.next()??, time.next()??);
+    // 60 allows a leap second.
+    if !(1..=31).contains(&day) || !(1970..=9999).contains(&year) || h > 23 || m > 59 || s > 60 {
+        return None;
+    }
+
+    // Days from the civil date (Howard Hinnant's algorithm).
+    let (y, mo) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * mo + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = (era * 146_097 + doe).checked_sub(719_468)?;
+    Some(days * 86_400 + h * 3600 + m * 60 + s)
+}
+
+/// A response body split into lines as the bytes arrive.
+struct BodyLines {
+    body: BoxStream<'static, reqwest::Result<Bytes>>,
+    buf: Vec<u8>,
+}
+
+impl BodyLines {
+    fn new(resp: Response) -> Self {
+        Self { body: resp.bytes_stream().boxed(), buf: Vec::new() }
+    }
+
+    /// The next line without its line ending; `None` once the body ends.
+    async fn next_line(&mut self) -> Result<Option<String>, AppError> {
+        loop {
+            if let Some(end) = self.buf.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                return Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()));
+            }
+            match self.body.next().await {
+                Some(chunk) => self.buf.extend_from_slice(&chunk?),
+                None if self.buf.is_empty() => return Ok(None),
+                None => return Ok(Some(String::from_utf8_lossy(&std::mem::take(&mut self.buf)).into_owned())),
+            }
+        }
+    }
+}
+
+/// Text of a completion plus the sources the provider cited, if any.
+#[derive(Debug, Default)]
+pub struct ChatReply {
+    pub text: String,
+    pub sources: Vec<String>,
 }
 
+/// Citations carried by one response event: a top-level `citations` list
+/// of URLs, or DashScope's `search_info.search_results` (web search).
+fn extract_sources(event: &Value) -> Vec<String> {
+    let mut out = Vec::new();
+    if let Some(urls) = event["citations"].as_array() {
+        out.extend(urls.iter().filter_map(|u| u.as_str()).map(str::to_string));
+    }
+    if let Some(results) = event["search_info"]["search_results"].as_array() {
+        out.extend(results.iter().filter_map(|r| {
+            r["url"].as_str().or_else(|| r["title"].as_str()).map(str::to_string)
+        }));
+    }
+    out
+}
 
+/// Cuts a reply at the first stop sequence, for servers that ignore
+/// `stop`. The end of a chunk that could begin a stop sequence is held back
+/// until the next chunk shows whether it does, so a sequence split across
+/// chunks is still caught.
+pub struct StopFilter<'a> {
+    stops: Vec<&'a str>,
+    pending: String,
+    done: bool,
+}
 
-pub fn call_chat_api(
-    client: &Client,
+impl<'a> StopFilter<'a> {
+    pub fn new(stops: &'a [String]) -> Self {
+        Self {
+            stops: stops.iter().map(String::as_str).filter(|s| !s.is_empty()).collect(),
+            pending: String::new(),
+            done: false,
+        }
+    }
+
+    /// Feed the next chunk; returns the text that can be shown now.
+    pub fn push(&mut self, chunk: &str) -> String {
+        if self.done {
+            return String::new();
+        }
+        if self.stops.is_empty() {
+            return chunk.to_string();
+        }
+        self.pending.push_str(chunk);
+        if let Some(cut) = self.stops.iter().filter_map(|s| self.pending.find(s)).min() {
+            self.done = true;
+            self.pending.truncate(cut);
+            return std::mem::take(&mut self.pending);
+        }
+        let held = self.stops.iter().map(|s| partial_match(&self.pending, s)).max().unwrap_or(0);
+        let rest = self.pending.split_off(self.pending.len() - held);
+        std::mem::replace(&mut self.pending, rest)
+    }
+
+    /// Whether a stop sequence was seen; later text is dropped.
+    pub fn done(&self) -> bool {
+        self.done
+    }
+
+    /// Text still held back when the reply ends without a stop sequence.
+    pub fn finish(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Length of the longest end of `text` that is the start of `stop`.
+fn partial_match(text: &str, stop: &str) -> usize {
+    (1..stop.len())
+        .rev()
+        .find(|&n| stop.is_char_boundary(n) && text.ends_with(&stop[..n]))
+        .unwrap_or(0)
+}
+
+/// Streaming chat completion against an OpenAI-compatible `endpoint`: sets
+/// `"stream": true`, parses the SSE `data:` lines and hands each
+/// `choices[0].delta.content` piece to `on_chunk`. The reply ends at the
+/// first stop sequence even if the server ignores `stop`. Returns the full
+/// concatenated answer and any cited sources.
+pub async fn call_chat_api_stream(
+    api: &ApiClient,
+    endpoint: &ChatEndpoint<'_>,
     model: &str,
     messages: &[Value],
-) -> Result<String, Box<dyn Error>> {
+    mut on_chunk: impl FnMut(&str) + Send,
+) -> Result<ChatReply, AppError> {
+    let url = format!("{}/chat/completions", endpoint.base_url.trim_end_matches('/'));
+    let mut request_body = json!({
+        "model": model,
+        "messages": messages,
+        "stream": true,
+    });
+    api.params.apply(&mut request_body);
+
+    let resp = api.send_with_retry(endpoint.provider, || {
+        let mut request = api.client.post(&url).header("Content-Type", "application/json");
+        if let Some(key) = &endpoint.api_key {
+            request = request.header("Authorization", format!("Bearer {key}"));
+        }
+        request.json(&request_body)
+    })
+    .await?;
 
-    let api_key = DASHSCOPE_API_KEY;
+    let status = resp.status();
+    if !status.is_success() {
+        return Err(api_error(endpoint.provider, status, &resp.text().await?));
+    }
 
-    let url = "https://dashscope.aliyuncs.com/compatible-mode/v1/chat/completions";
+    let mut answer = String::new();
+    let mut sources = Vec::new();
+    let mut stop = StopFilter::new(&api.params.stop);
+    let mut lines = BodyLines::new(resp);
+    while let Some(line) = lines.next_line().await? {
+        let Some(data) = line.strip_prefix("data:") else {
+            continue;
+        };
+        let data = data.trim();
+        if data == "[DONE]" {
+            break;
+        }
+        let Ok(event) = serde_json::from_str::<Value>(data) else {
+            continue;
+        };
+        if event.get("error").is_some() {
+            return Err(api_error(endpoint.provider, status, data));
+        }
+        if let Some(delta) = event["choices"][0]["delta"]["content"].as_str() {
+            let text = stop.push(delta);
+            if !text.is_empty() {
+                answer.push_str(&text);
+                on_chunk(&text);
+            }
+        }
+        for source in extract_sources(&event) {
+            if !sources.contains(&source) {
+                sources.push(source);
+            }
+        }
+        if stop.done() {
+            break;
+        }
+    }
+    let rest = stop.finish();
+    if !rest.is_empty() {
+        answer.push_str(&rest);
+        on_chunk(&rest);
+    }
 
-    let resp = client
-        .post(url)
-        .header("Authorization", format!("Bearer {}", api_key))
-        .header("Content-Type", "application/json")
-        .json(&json!({
-            "model": model,
-            "messages": messages,
-        }))
-        .send()?;
+    Ok(ChatReply { text: answer, sources })
+}
 
-    let status = resp.status();
-    let body: Value = resp.json()?;
+/// Streaming chat against a local Ollama server (`/api/chat`), which sends
+/// one JSON object per line. Returns the full concatenated answer; Ollama
+/// does not cite sources.
+pub async fn call_ollama_chat_stream(
+    api: &ApiClient,
+    url: &str,
+    model: &str,
+    messages: &[Value],
+    mut on_chunk: impl FnMut(&str) + Send,
+) -> Result<ChatReply, AppError> {
+    let endpoint = format!("{}/api/chat", url.trim_end_matches('/'));
+    let mut request_body = json!({
+        "model": model,
+        "messages": messages,
+        "stream": true,
+    });
+    api.params.apply_ollama(&mut request_body);
+
+    let resp = api
+        .send_with_retry("Ollama", || api.client.post(&endpoint).json(&request_body))
+        .await
+        .map_err(|e| match e {
+            AppError::Http { context, source } => AppError::Http {
+                context: format!("{context} (is `ollama serve` running at {url}?)"),
+                source,
+            },
+            other => other,
+        })?;
 
+    let status = resp.status();
+    let ollama_error = |message: &str| AppError::Api {
+        provider: "Ollama",
+        status: status.as_u16(),
+        message: message.to_string(),
+    };
     if !status.is_success() {
-        let msg = body
-            .get("error")
-            .and_then(|e| e.get("message"))
-            .and_then(|m| m.as_str())
-            .unwrap_or("unknown error from API");
-        return Err(format!("DashScope API error ({status}): {msg}").into());
+        let raw = resp.text().await?;
+        let body: Value = serde_json::from_str(&raw).unwrap_or(Value::Null);
+        return Err(ollama_error(body["error"].as_str().unwrap_or("unknown error from API")));
+    }
+
+    let mut answer = String::new();
+    let mut stop = StopFilter::new(&api.params.stop);
+    let mut lines = BodyLines::new(resp);
+    while let Some(line) = lines.next_line().await? {
+        let Ok(event) = serde_json::from_str::<Value>(&line) else {
+            continue;
+        };
+        if let Some(err) = event["error"].as_str() {
+            return Err(ollama_error(err));
+        }
+        if let Some(delta) = event["message"]["content"].as_str() {
+            let text = stop.push(delta);
+            if !text.is_empty() {
+                answer.push_str(&text);
+                on_chunk(&text);
+            }
+        }
+        if stop.done() || event["done"].as_bool() == Some(true) {
+            break;
+        }
+    }
+    let rest = stop.finish();
+    if !rest.is_empty() {
+        answer.push_str(&rest);
+        on_chunk(&rest);
+    }
+
+    Ok(ChatReply { text: answer, sources: Vec::new() })
+}
+
+/// Stream one chat completion from the backend selected in `api`.
+async fn call_backend_stream(
+    api: &ApiClient,
+    messages: &[Value],
+    mut on_chunk: impl FnMut(&str) + Send,
+) -> Result<ChatReply, AppError> {
+    api.backend.stream(api, messages, &mut on_chunk).await
+}
+
+/// Role of tool results sent back to the model, and the one used once the
+/// server has rejected it.
+const TOOL_ROLE: &str = "tool";
+const TOOL_ROLE_FALLBACK: &str = "system";
+
+fn uses_role(messages: &[Value], role: &str) -> bool {
+    messages.iter().any(|m| m["role"] == role)
+}
+
+/// Change every `from` message in `messages` to `to`.
+fn set_role(messages: &mut [Value], from: &str, to: &str) {
+    for m in messages.iter_mut().filter(|m| m["role"] == from) {
+        m["role"] = json!(to);
     }
+}
+
+/// Turn an error body into a readable error using its `error.message` field.
+fn api_error(provider: &'static str, status: StatusCode, raw: &str) -> AppError {
+    let body: Value = serde_json::from_str(raw).unwrap_or(Value::Null);
+    let msg = body
+        .get("error")
+        .and_then(|e| e.get("message"))
+        .and_then(|m| m.as_str())
+        .unwrap_or("unknown error from API");
+    AppError::Api { provider, status: status.as_u16(), message: msg.to_string() }
+}
+
+
+/// Copy the raw text of the active branch's last assistant message to the
+/// clipboard, or to `logs/clipboard.txt` when no clipboard is available.
+pub fn copy_last_assistant_message(app: &mut App) {
+    let session = &app.sessions[app.active_idx];
+    let Some(text) = session.branches[session.active_branch]
+        .messages
+        .iter()
+        .rev()
+        .find(|m| matches!(m.from, MessageFrom::Assistant) && !m.content.is_empty())
+        .map(|m| m.content.clone())
+    else {
+        app.set_status("Nothing to copy yet");
+        return;
+    };
+
+    if app.clipboard.is_none() {
+        app.clipboard = arboard::Clipboard::new().ok();
+    }
+    let copied = app
+        .clipboard
+        .as_mut()
+        .is_some_and(|c| c.set_text(text.clone()).is_ok());
+    if copied {
+        app.set_status("📋 Copied last reply");
+        return;
+    }
+
+    let path = app.config.log_dir.join("clipboard.txt");
+    let saved = fs::create_dir_all(&app.config.log_dir).and_then(|_| fs::write(&path, &text));
+    app.set_status(match saved {
+        Ok(()) => format!("📋 No clipboard; saved to {}", path.display()),
+        Err(e) => format!("⚠️ Copy failed: {e}"),
+    });
+}
+
+/// Send whatever is in the input box, used by both Enter and the send button.
+/// Forks a branch if an edit is pending, then applies the post-send input mode.
+pub fn submit_input(app: &mut App) -> Result<()> {
+    let msg = app.input.trim().to_string();
+    if msg.is_empty() {
+        return Ok(());
+    }
+
+    // Clear input first.
+    app.clear_input();
+    app.record_input(&msg);
+
+    if msg == "/system" || msg.starts_with("/system ") {
+        handle_system_command(app, msg["/system".len()..].trim());
+    } else if let Some(setting) = msg.strip_prefix("/set ") {
+        handle_set_command(app, setting.trim());
+    } else if msg == "/coalesce" {
+        coalesce_active_branch(app);
+    } else if msg == "/ab" || msg.starts_with("/ab ") {
+        start_ab_generate(app, msg["/ab".len()..].trim())?;
+    } else if msg == "/duplicate" {
+        duplicate_session(app);
+    } else if msg == "/duplicate branch" {
+        duplicate_branch(app, app.active_session().active_branch);
+    } else if msg == "/summarize" {
+        start_summary(app, app.active_idx);
+    } else if msg == "/export md" || msg == "/export md all" {
+        let result = app.export_markdown(app.active_idx, msg.ends_with(" all"));
+        match result {
+            Ok(path) => app.set_status(format!("📝 Exported to {}", path.display())),
+            Err(e) => app.set_status(format!("⚠️ Export failed: {e}")),
+        }
+    } else if let Some(ctx) = app.edit_ctx.take() {
+        if ctx.in_place {
+            // Overwrite the message where it is; no new reply.
+            match app.edit_message_in_place(ctx, msg) {
+                Ok(()) => app.set_status("✏️ Message updated"),
+                Err(e) => app.set_status(format!("⚠️ Could not update the message: {e}")),
+            }
+        } else {
+            // We are editing an existing user message.
+            // This will fork a new branch and overwrite that message there.
+            fork_and_send_from_edit(app, ctx, msg)?;
+        }
+    } else {
+        // Normal case: send a brand new user message on the active branch.
+        send_user_message_with_streaming(app, msg)?;
+    }
+
+    app.input_mode = if app.config.stay_in_insert_after_send {
+        InputMode::Insert
+    } else {
+        InputMode::Normal
+    };
+    Ok(())
+}
+
+/// `/system <text>` sets the session's system prompt, `/system clear`
+/// removes it and a bare `/system` shows it in the status line.
+fn handle_system_command(app: &mut App, arg: &str) {
+    let prompt = match arg {
+        "" => {
+            let status = match &app.active_session().system_prompt {
+                Some(prompt) => format!("System prompt: {prompt}"),
+                None => "No system prompt set (/system <text>)".to_string(),
+            };
+            app.set_status(status);
+            return;
+        }
+        "clear" => None,
+        text => Some(text.to_string()),
+    };
+
+    let cleared = prompt.is_none();
+    match app.set_system_prompt(prompt) {
+        Ok(()) if cleared => app.set_status("System prompt cleared"),
+        Ok(()) => app.set_status("System prompt set"),
+        Err(e) => app.set_status(format!("⚠️ Could not save system prompt: {e}")),
+    }
+}
+
+/// `/coalesce`: merge adjacent same-sender messages in the active branch.
+fn coalesce_active_branch(app: &mut App) {
+    // Message indices are baked into an in-flight stream or edit.
+    if app.is_streaming() || app.edit_ctx.is_some() {
+        app.set_status("⚠️ Wait for the current reply before coalescing");
+        return;
+    }
+    let session = &mut app.sessions[app.active_idx];
+    let merged = session.branches[session.active_branch].coalesce();
+    if merged == 0 {
+        app.set_status("No adjacent same-sender messages");
+        return;
+    }
+    match app.save_to_logs() {
+        Ok(()) => app.set_status(format!("Merged {merged} message(s)")),
+        Err(e) => app.set_status(format!("⚠️ Merged {merged}, but saving failed: {e}")),
+    }
+}
+
+/// `D` / `/duplicate`: copy the active session and switch to the copy.
+pub fn duplicate_session(app: &mut App) {
+    match app.duplicate_session(app.active_idx) {
+        Ok(idx) => {
+            let title = app.sessions[idx].title.clone();
+            app.set_status(format!("📄 Duplicated as '{title}'"));
+        }
+        Err(e) => app.set_status(format!("⚠️ Could not duplicate the session: {e}")),
+    }
+}
+
+/// `c` in the branch tree / `/duplicate branch`: copy a branch of the
+/// active session and switch to the copy.
+pub fn duplicate_branch(app: &mut App, branch_idx: usize) {
+    match app.duplicate_branch(branch_idx) {
+        Ok(idx) => {
+            let name = app.active_session().branches[idx].name.clone();
+            app.set_status(format!("📄 Duplicated branch as '{name}'"));
+        }
+        Err(e) => app.set_status(format!("⚠️ Could not duplicate the branch: {e}")),
+    }
+}
+
+/// Summarize the active branch of `session_idx` on a worker task; the
+/// result comes back as `BackendEvent::SummaryDone`.
+pub fn start_summary(app: &mut App, session_idx: usize) {
+    if app.summarizing.is_some() {
+        app.set_status("A summary is already being written");
+        return;
+    }
+    let branch_idx = app.sessions[session_idx].active_branch;
+    if app.sessions[session_idx].branches[branch_idx].messages.is_empty() {
+        app.set_status("Nothing to summarize yet");
+        return;
+    }
+    let Some(tx) = app.backend_tx.clone() else {
+        return;
+    };
+
+    let history = app.history_string_for(session_idx, branch_idx, HISTORY_MAX_CHARS);
+    let api = api_client(app);
+
+    app.summarizing = Some(session_idx);
+    app.set_status("🧩 Summarizing...");
+    app.spawn_worker(async move {
+        let result = summarize_history(&api, &history).await.map_err(|e| e.to_string());
+        let _ = tx.send(BackendEvent::SummaryDone { session_idx, result });
+    });
+}
+
+/// Start a summary when a branch reaches every `summarize_after` pairs,
+/// the way the CLI summarizes long sessions.
+pub fn maybe_auto_summarize(app: &mut App, session_idx: usize, branch_idx: usize) {
+    let Some(every) = app.config.summarize_after.filter(|&n| n > 0) else {
+        return;
+    };
+    let Some(branch) = app.sessions.get(session_idx).and_then(|s| s.branches.get(branch_idx)) else {
+        return;
+    };
+    let len = branch.messages.len();
+    if len > 0 && len % 2 == 0 && (len / 2) % every == 0 {
+        start_summary(app, session_idx);
+    }
+}
+
+/// After a reply, ask the model for a title for the session if it is still
+/// called "Session N" (`auto_title`, on by default). Each session is tried
+/// once per run; the result comes back as `BackendEvent::TitleDone`.
+pub fn maybe_auto_title(app: &mut App, session_idx: usize, branch_idx: usize) {
+    if !app.config.auto_title {
+        return;
+    }
+    let Some(session) = app.sessions.get(session_idx) else {
+        return;
+    };
+    if !is_default_title(&session.title) || app.titled.contains(&session.id) {
+        return;
+    }
+    let Some(branch) = session.branches.get(branch_idx) else {
+        return;
+    };
+    // Only once there is a real answer to go on.
+    let answered = branch
+        .messages
+        .last()
+        .is_some_and(|m| matches!(m.from, MessageFrom::Assistant) && m.error.is_none() && !m.content.trim().is_empty());
+    if !answered {
+        return;
+    }
+    let Some(first) = branch.messages.iter().find(|m| matches!(m.from, MessageFrom::User)) else {
+        return;
+    };
+    let Some(tx) = app.backend_tx.clone() else {
+        return;
+    };
+
+    let prompt: String = first.content.chars().take(TITLE_PROMPT_MAX_CHARS).collect();
+    let session_id = session.id.clone();
+    let mut api = api_client(app);
+    api.params.max_tokens = Some(TITLE_MAX_TOKENS);
+    app.titled.insert(session_id.clone());
+    app.spawn_worker(async move {
+        let result = title_conversation(&api, &prompt).await.map_err(|e| e.to_string());
+        let _ = tx.send(BackendEvent::TitleDone { session_id, result });
+    });
+}
+
+/// Ask the model for a 3-6 word title for a conversation that starts with
+/// `prompt`.
+async fn title_conversation(api: &ApiClient, prompt: &str) -> Result<String, Box<dyn Error>> {
+    let messages = vec![
+        json!({
+            "role": "system",
+            "content": "You name chat conversations. Reply with a title of 3-6 words and nothing else: no quotes, no trailing period.",
+        }),
+        json!({
+            "role": "user",
+            "content": format!("The conversation starts with:\n\n{prompt}\n\nTitle:"),
+        }),
+    ];
+    let reply = call_backend_stream(api, &messages, |_| {}).await?;
+    // Models still add quotes, a "Title:" label or a second line.
+    let line = reply.text.lines().map(str::trim).find(|l| !l.is_empty()).unwrap_or("");
+    let line = line.strip_prefix("Title:").unwrap_or(line);
+    let title: String = line
+        .trim()
+        .trim_matches(|c: char| matches!(c, '"' | '\'' | '*' | '`' | '.'))
+        .trim()
+        .chars()
+        .take(TITLE_MAX_CHARS)
+        .collect();
+    if title.is_empty() {
+        return Err("the model returned an empty title".into());
+    }
+    Ok(title.trim_end().to_string())
+}
 
-    Ok(body["choices"][0]["message"]["content"]
-        .as_str()
-        .unwrap_or("")
-        .to_string())
+/// Ask the model for a short summary of `history`.
+async fn summarize_history(api: &ApiClient, history: &str) -> Result<String, Box<dyn Error>> {
+    let messages = vec![
+        json!({
+            "role": "system",
+            "content": "You are a helpful assistant. Write a concise summary (2-6 sentences).",
+        }),
+        json!({
+            "role": "user",
+            "content": format!(
+                "Here is the conversation history:\n\n{}\n\nPlease summarize it.",
+                history
+            ),
+        }),
+    ];
+    let reply = call_backend_stream(api, &messages, |_| {}).await?;
+    let summary = reply.text.trim();
+    if summary.is_empty() {
+        return Err("the model returned an empty summary".into());
+    }
+    Ok(summary.to_string())
 }
 
+/// `/set <temperature|top_p|max_tokens|stop> <value|off>` for this run.
+fn handle_set_command(app: &mut App, setting: &str) {
+    let Some((name, value)) = setting.split_once(' ') else {
+        app.set_status("Usage: /set <temperature|top_p|max_tokens|stop> <value|off>");
+        return;
+    };
+    match app.config.params.set(name, value) {
+        Ok(()) if name == "stop" => app.set_status(format!("stop = {:?}", app.config.params.stop)),
+        Ok(()) => app.set_status(format!("{name} = {}", value.trim())),
+        Err(e) => app.set_status(format!("⚠️ {e}")),
+    }
+}
 
 /// Send a user message on the active branch and start background streaming.
 pub fn send_user_message_with_streaming(app: &mut App, text: String) -> Result<()> {
-    let prompt = text.clone();
     let session_idx = app.active_idx;
     let branch_idx = app.sessions[session_idx].active_branch;
 
@@ -138,114 +1099,174 @@ pub fn send_user_message_with_streaming(app: &mut App, text: String) -> Result<(
     {
         let session = &mut app.sessions[session_idx];
         let branch = &mut session.branches[branch_idx];
-        branch.messages.push(Message {
-            from: MessageFrom::User,
-            content: text,
-        });
+        branch.messages.push(Message::new(MessageFrom::User, text));
+        session.touch(branch_idx);
     }
 
-    // 2) Create empty assistant message for streaming output
-    app.start_streaming_assistant(session_idx, branch_idx);
-
-    // 3) Clear UI input
-    app.input.clear();
+    // 2) Clear UI input
+    app.clear_input();
     app.input_scroll = 0;
 
-    // 4) precompute history BEFORE thread
-    let initial_history = app.history_string();
-
-    // 5) Clone channel
-    if let Some(tx_main) = app.backend_tx.clone() {
-        let tx_thread = tx_main.clone(); // clone for thread
-
-        thread::spawn(move || {
-            let tx_for_loop = tx_thread.clone();
-            let tx_for_done = tx_thread.clone();
-
-            if let Err(e) = run_mcp_loop(prompt, initial_history, session_idx, branch_idx, tx_for_loop) {
-                eprintln!("MCP error: {e}");
-            }
-
-            // send final done event
-            let _ = tx_for_done.send(BackendEvent::AssistantDone {
-                session_idx,
-                branch_idx,
-            });
-        });
-    }
+    // 3) Stream the reply on a worker
+    start_streaming_on_branch(app, session_idx, branch_idx)
+}
 
-    Ok(())
+/// HTTP client for a request from the UI, set up with the active backend
+/// and the current sampling settings.
+fn api_client(app: &App) -> ApiClient {
+    let mut api = ApiClient::new(app.config.http.clone());
+    api.retry_empty = app.config.on_empty == EmptyResponse::Retry;
+    api.backend = app.backend.clone();
+    api.params = app.config.params.clone();
+    api.status_tx = app.backend_tx.clone();
+    api
 }
 
-fn run_mcp_loop(
-    user_prompt: String,
-    mut history: String,
+#[allow(clippy::too_many_arguments)]
+async fn run_mcp_loop(
+    system_prompt: Option<String>,
+    mut history: Vec<Value>,
     session_idx: usize,
     branch_idx: usize,
-    tx: Sender<BackendEvent>,
+    session_id: String,
+    api: &ApiClient,
+    policy: &ToolPolicy,
+    servers: &[Arc<McpClient>],
+    cache: Arc<Mutex<ToolCache>>,
+    tx: UnboundedSender<BackendEvent>,
 ) -> Result<(), Box<dyn Error>> {
-    let client = Client::new();
-
-    let system_mcp_prompt = format!(
+    let system_mcp_prompt =
         "You are an AI assistant with access to MCP tools.\n\
         Available tools:\n\
-        - filesystem.read  - read file content. Example: <use_tool name=\"filesystem.read\" params={{\"path\": \"src/main.rs\"}} />\n\
-        - filesystem.write - write text into a file. Example: <use_tool name=\"filesystem.write\" params={{\"path\": \"output.txt\", \"content\": \"Hello\"}} />\n\
-        - shell.run - run shell commands. Example: <use_tool name=\"shell.run\" params={{\"content\": \"mkdir Playground\"}} />\n\
-        On macOS/Linux, shell commands are executed via `sh -c \"command\"`.\n\
-        On Windows, they run via `cmd /C \"command\"`.\n\
+        - filesystem.read  - read file content. Example: <use_tool name=\"filesystem.read\" params={\"path\": \"src/main.rs\"} />\n\
+          For big files add \"start_line\" / \"end_line\" (1-based, inclusive) to read a part: params={\"path\": \"src/main.rs\", \"start_line\": 1, \"end_line\": 100}\n\
+        - filesystem.list - list a directory (defaults to \".\"). Example: <use_tool name=\"filesystem.list\" params={\"path\": \"src\"} />\n\
+        {write_tools}{servers}{shell}\n\
         When using a tool, use EXACTLY this XML-style syntax.\n\
         You can add some explaining information after a tool call, but take care of format for readability.\n\
         You can use **only one <use_tool> command per message.** If you need to use multiple tools, call them one by one — wait for the tool's result before issuing the next <use_tool>.\n\
         You can use **only one <use_tool> command per message.** \n\
         You can use **only one <use_tool> command per message.** "
-
-    );
-
-
-    loop {
-        // --- Build messages for DashScope ---
-        let messages = vec![
-            serde_json::json!({
-                "role": "system",
-                "content": system_mcp_prompt,
-            }),
-            serde_json::json!({
-                "role": "user",
-                "content": format!(
-                    "User initial prompt:\n{}\n\nConversation so far:\n{}\n\n\
-                    Continue reasoning or issue next tool call if needed.",
-                    user_prompt, history
-                ),
-            }),
-        ];
-
-        // --- Call DashScope (non-stream) ---
-        let answer = call_chat_api(&client, "qwen-plus", &messages)?;
-
-        // --- stream chunks to UI ---
-        stream_string_into_ui(&answer, session_idx, branch_idx, &tx)?;
-
-        // --- append answer into history ---
-        history.push_str("\nAssistant: ");
-        history.push_str(&answer);
-
-
-        // --- detect tool call ---
-        if let Some(tool_call) = parse_tool_use(&answer) {
-            let tool_result = execute_mcp(&tool_call)?;
-
-            // stream tool result too
-            stream_string_into_ui(
-                &format!("\n[Tool: {}]\nresult: {}\n", tool_call.name, tool_result),
+            .replace("{write_tools}", if policy.safe_mode { SAFE_MODE_PROMPT } else { WRITE_TOOLS_PROMPT })
+            .replace("{servers}", &if policy.safe_mode { String::new() } else { prompt_lines(servers.iter().map(|s| s.as_ref())) })
+            .replace("{shell}", &if policy.safe_mode { String::new() } else { policy.shell.prompt_note() });
+
+    let mut steps = 0;
+    let mut last_call: Option<ToolCall> = None;
+    let mut tool_role = TOOL_ROLE;
+    'agent: loop {
+        if steps == policy.max_agent_steps {
+            let note = format!("tool call limit reached after {steps} steps");
+            stop_agent(&note, session_idx, branch_idx, &tx).await?;
+            break;
+        }
+        steps += 1;
+
+        // --- Build messages for the model; the session's own prompt goes first ---
+        let mut messages: Vec<Value> = system_prompt
+            .iter()
+            .map(|p| json!({ "role": "system", "content": p }))
+            .collect();
+        messages.push(json!({ "role": "system", "content": system_mcp_prompt }));
+        messages.extend(history.iter().cloned());
+
+        // --- Call the backend, streaming chunks to the UI as they arrive ---
+        let send_chunk = |chunk: &str| {
+            let _ = tx.send(BackendEvent::AssistantChunk {
                 session_idx,
                 branch_idx,
-                &tx,
-            )?;
+                chunk: chunk.to_string(),
+            });
+        };
+        let mut reply = match call_backend_stream(api, &messages, send_chunk).await {
+            // Servers that only take `tool` messages answering native tool
+            // calls reject ours; resend the results as `system` from now on.
+            Err(AppError::Api { status: 400, .. }) if tool_role == TOOL_ROLE && uses_role(&messages, TOOL_ROLE) => {
+                tool_role = TOOL_ROLE_FALLBACK;
+                set_role(&mut history, TOOL_ROLE, TOOL_ROLE_FALLBACK);
+                set_role(&mut messages, TOOL_ROLE, TOOL_ROLE_FALLBACK);
+                call_backend_stream(api, &messages, send_chunk).await?
+            }
+            other => other?,
+        };
+        if reply.text.trim().is_empty() && api.retry_empty {
+            reply = call_backend_stream(api, &messages, send_chunk).await?;
+        }
+        if !reply.sources.is_empty() {
+            let _ = tx.send(BackendEvent::AssistantSources {
+                session_idx,
+                branch_idx,
+                sources: std::mem::take(&mut reply.sources),
+            });
+        }
+        let answer = reply.text;
 
-            // append to history for next round
-            history.push_str("\nTool result: ");
-            history.push_str(&tool_result);
+        // --- append answer into history ---
+        history.push(json!({ "role": "assistant", "content": answer }));
+
+        // --- detect tool calls (a message may contain several) ---
+        let tool_calls = parse_tool_uses(&answer);
+        if !tool_calls.is_empty() {
+            for tool_call in &tool_calls {
+                // The same call twice in a row means the model is stuck.
+                if last_call.as_ref() == Some(tool_call) {
+                    let note = format!("{} repeated with the same arguments", tool_call.name);
+                    stop_agent(&note, session_idx, branch_idx, &tx).await?;
+                    break 'agent;
+                }
+                last_call = Some(tool_call.clone());
+
+                let server = servers
+                    .iter()
+                    .find_map(|s| s.owns(&tool_call.name).map(|tool| (s.clone(), tool.to_string())));
+                let tool_result = if policy.safe_mode && server.is_some() {
+                    // Server tools may change anything; safe mode cannot tell.
+                    format!("error: {SAFE_MODE_REFUSAL}")
+                } else if let Some((server, tool)) = server {
+                    // Tools of configured servers come first; the built-in
+                    // ones below are the fallback. The call blocks on the pipe.
+                    let arguments = tool_call.params.clone();
+                    if let Ok(mut cache) = cache.lock() {
+                        cache.invalidate(tool_call);
+                    }
+                    tokio::task::spawn_blocking(move || {
+                        server.call_tool(&tool, arguments).unwrap_or_else(|e| format!("error: {e}"))
+                    })
+                    .await
+                    .unwrap_or_else(|e| format!("error: {e}"))
+                } else if tool_call.name == "shell.run"
+                    && !policy.safe_mode
+                    && !policy.auto_approve_shell
+                    && !request_shell_approval(tool_call, &tx).await
+                {
+                    "user rejected command".to_string()
+                } else {
+                    // File and shell tools block, so they get a thread of their own.
+                    // A failing tool must not abort the rest of the batch.
+                    let (call, policy, cache) = (tool_call.clone(), policy.clone(), cache.clone());
+                    let session_id = session_id.clone();
+                    tokio::task::spawn_blocking(move || {
+                        let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+                        execute_cached(&call, &policy, &mut cache, &session_id).unwrap_or_else(|e| format!("error: {e}"))
+                    })
+                    .await
+                    .unwrap_or_else(|e| format!("error: {e}"))
+                };
+
+                // Shown as a message of its own; the next answer starts a new one.
+                tx.send(BackendEvent::ToolResult {
+                    session_idx,
+                    branch_idx,
+                    name: tool_call.name.clone(),
+                    result: tool_result.clone(),
+                })?;
+
+                // append to history for next round
+                history.push(json!({
+                    "role": tool_role,
+                    "content": format!("[Tool: {}]\nresult: {}", tool_call.name, tool_result),
+                }));
+            }
         } else {
             // no more tools
             break;
@@ -259,70 +1280,263 @@ fn run_mcp_loop(
     Ok(())
 }
 
+/// Error text for the chat, with a hint for auth and network failures.
+fn error_message(e: &(dyn Error + 'static)) -> String {
+    match AppError::hint_for(e) {
+        Some(hint) => format!("Error: {e}\n💡 {hint}"),
+        None => format!("Error: {e}"),
+    }
+}
 
-fn stream_string_into_ui(
-    s: &str,
-    session_idx: usize,
-    branch_idx: usize,
-    tx: &Sender<BackendEvent>,
-) -> Result<(), Box<dyn Error>> {
-    let max_chunk = 12;
-    let delay = std::time::Duration::from_millis(10);
+/// End an agent run early, with a note in the reply saying why.
+async fn stop_agent(reason: &str, session_idx: usize, branch_idx: usize, tx: &UnboundedSender<BackendEvent>) -> Result<(), Box<dyn Error>> {
+    UiStreamer::default().stream(&format!("\n🛑 Agent stopped: {reason}.\n"), session_idx, branch_idx, tx).await
+}
 
-    let mut buf = String::new();
+/// Ask the UI to confirm a shell command and wait for the answer.
+/// Anything other than an explicit approval counts as a rejection.
+async fn request_shell_approval(tool_call: &ToolCall, tx: &UnboundedSender<BackendEvent>) -> bool {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let request = BackendEvent::ToolApprovalRequested {
+        command: tool_call.content.clone().unwrap_or_default(),
+        reply: reply_tx,
+    };
+    if tx.send(request).is_err() {
+        return false;
+    }
+    reply_rx.await.unwrap_or(false)
+}
 
-    for c in s.chars() {
-        buf.push(c);
+/// Replays a finished string into the UI in small chunks so it looks streamed.
+pub struct UiStreamer {
+    /// Maximum bytes per chunk (a newline always ends a chunk).
+    pub max_chunk: usize,
+    /// Pause after each chunk; zero disables sleeping entirely.
+    pub delay: Duration,
+}
+
+impl Default for UiStreamer {
+    fn default() -> Self {
+        Self {
+            max_chunk: 12,
+            delay: Duration::from_millis(10),
+        }
+    }
+}
+
+impl UiStreamer {
+    pub async fn stream(
+        &self,
+        s: &str,
+        session_idx: usize,
+        branch_idx: usize,
+        tx: &UnboundedSender<BackendEvent>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut buf = String::new();
+
+        for c in s.chars() {
+            buf.push(c);
+
+            if buf.len() >= self.max_chunk || c == '\n' {
+                tx.send(BackendEvent::AssistantChunk {
+                    session_idx,
+                    branch_idx,
+                    chunk: buf.clone(),
+                })?;
+                buf.clear();
+
+                // give UI time to animate
+                if !self.delay.is_zero() {
+                    tokio::time::sleep(self.delay).await;
+                }
+            }
+        }
 
-        if buf.len() >= max_chunk || c == '\n' {
+        if !buf.is_empty() {
             tx.send(BackendEvent::AssistantChunk {
                 session_idx,
                 branch_idx,
-                chunk: buf.clone(),
+                chunk: buf,
             })?;
-            buf.clear();
-
-            // give UI time to animate
-            std::thread::sleep(delay);
         }
-    }
 
-    if !buf.is_empty() {
-        tx.send(BackendEvent::AssistantChunk {
-            session_idx,
-            branch_idx,
-            chunk: buf,
-        })?;
+        Ok(())
     }
-
-    Ok(())
 }
 
 
 /// Struct for parsed tool info
-#[derive(Debug)]
+#[derive(Debug, Default, Clone, PartialEq)]
 struct ToolCall {
     name: String,
     path: Option<String>,
     content: Option<String>,
+    /// Destination for filesystem.move.
+    dst: Option<String>,
+    /// 1-based inclusive line range for filesystem.read.
+    start_line: Option<usize>,
+    end_line: Option<usize>,
+    /// The whole `params` object, passed on as the arguments of MCP
+    /// server tools.
+    params: Value,
+}
+
+impl ToolCall {
+    /// Build a call from its JSON `params`. Line numbers may be given as
+    /// numbers or numeric strings.
+    fn from_params(name: String, params: &Value) -> Self {
+        let field = |key: &str| params.get(key).and_then(|v| v.as_str()).map(|s| s.to_string());
+        let line = |key: &str| {
+            params.get(key).and_then(|v| match v {
+                Value::Number(n) => n.as_u64().map(|n| n as usize),
+                Value::String(s) => s.trim().parse().ok(),
+                _ => None,
+            })
+        };
+        ToolCall {
+            name,
+            path: field("path").or_else(|| field("src")),
+            content: field("content"),
+            dst: field("dst"),
+            start_line: line("start_line"),
+            end_line: line("end_line"),
+            params: params.clone(),
+        }
+    }
+}
+
+/// Cut `content` down to the 1-based inclusive `start..=end` lines, clamped
+/// to the file. Returns the slice and a header such as "lines 10-40 of 2000".
+fn slice_lines(content: &str, start: Option<usize>, end: Option<usize>) -> (String, String) {
+    let lines: Vec<&str> = content.lines().collect();
+    let total = lines.len();
+    let want_start = start.unwrap_or(1);
+    let want_end = end.unwrap_or(total);
+    let first = want_start.max(1);
+    let last = want_end.min(total);
+
+    if total == 0 || first > last {
+        return (String::new(), format!("no lines in range {want_start}-{want_end} (file has {total} lines)"));
+    }
+    let mut header = format!("lines {first}-{last} of {total}");
+    if first != want_start || last != want_end {
+        header.push_str(&format!(", clamped from {want_start}-{want_end}"));
+    }
+    (lines[first - 1..last].join("\n"), header)
+}
+
+/// Parse every MCP-style tool command in `output`, in order of appearance.
+/// Falls back to the single-call parser when the full pattern finds nothing.
+fn parse_tool_uses(output: &str) -> Vec<ToolCall> {
+    let calls: Vec<ToolCall> = tool_tags(output)
+        .into_iter()
+        .map(|(name, params)| ToolCall::from_params(name, &params))
+        .collect();
+
+    if calls.is_empty() {
+        parse_tool_use(output).into_iter().collect()
+    } else {
+        calls
+    }
+}
+
+/// Name and JSON params of every `<use_tool name="..." params={...}` in
+/// `output`. The params are cut out by brace matching, so quotes, braces
+/// and newlines inside string values do not end them early, and a missing
+/// `/>` does not matter. Unparseable params are skipped.
+fn tool_tags(output: &str) -> Vec<(String, Value)> {
+    let Ok(re_tag) = Regex::new(r#"<use_tool\s+name="([^"]+)"\s+params="#) else {
+        return Vec::new();
+    };
+    re_tag
+        .captures_iter(output)
+        .filter_map(|caps| {
+            let name = caps.get(1)?.as_str().to_string();
+            let rest = output[caps.get(0)?.end()..].trim_start();
+            let params = balanced_json(rest)?;
+            let value = serde_json::from_str::<Value>(params)
+                .or_else(|_| serde_json::from_str::<Value>(&escape_controls_in_strings(params)))
+                .ok()?;
+            Some((name, value))
+        })
+        .collect()
+}
+
+/// The balanced `{...}` object at the start of `s`, ignoring braces inside
+/// JSON strings. `None` if `s` does not start with `{` or never closes.
+fn balanced_json(s: &str) -> Option<&str> {
+    if !s.starts_with('{') {
+        return None;
+    }
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&s[..=i]);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Models often put raw newlines and tabs inside JSON strings; escape them
+/// so the params still parse.
+fn escape_controls_in_strings(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in s.chars() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                '\n' => {
+                    out.push_str("\\n");
+                    continue;
+                }
+                '\r' => {
+                    out.push_str("\\r");
+                    continue;
+                }
+                '\t' => {
+                    out.push_str("\\t");
+                    continue;
+                }
+                _ => {}
+            }
+        } else if c == '"' {
+            in_string = true;
+        }
+        out.push(c);
+    }
+    out
 }
 
 /// Parse MCP-style tool command from model output
 fn parse_tool_use(output: &str) -> Option<ToolCall> {
-    // First try to capture the whole params JSON object (dot matches newlines with (?s))
-    let re_full = Regex::new(r#"(?s)<use_tool\s+name="([^"]+)"\s+params=(\{.*?\})\s*/?>"#).ok()?;
-    if let Some(caps) = re_full.captures(output) {
-        let name = caps.get(1)?.as_str().to_string();
-        let params_str = caps.get(2)?.as_str();
-
-        // Try parsing params as JSON
-        if let Ok(json_val) = serde_json::from_str::<Value>(params_str) {
-            let path = json_val.get("path").and_then(|v| v.as_str()).map(|s| s.to_string());
-            let content = json_val.get("content").and_then(|v| v.as_str()).map(|s| s.to_string());
-            return Some(ToolCall { name, path, content });
-        }
-        // If parsing failed, continue to fallback regexes below
+    // First try the brace-matched params JSON object
+    if let Some((name, params)) = tool_tags(output).into_iter().next() {
+        return Some(ToolCall::from_params(name, &params));
     }
+    // If parsing failed, continue to fallback regexes below
 
     // Fallback: simple read command
     let re_read = Regex::new(
@@ -333,7 +1547,7 @@ fn parse_tool_use(output: &str) -> Option<ToolCall> {
         return Some(ToolCall {
             name: "filesystem.read".into(),
             path: Some(caps[1].to_string()),
-            content: None,
+            ..Default::default()
         });
     }
 
@@ -347,6 +1561,7 @@ fn parse_tool_use(output: &str) -> Option<ToolCall> {
             name: "filesystem.write".into(),
             path: Some(caps[1].to_string()),
             content: Some(caps[2].to_string()),
+            ..Default::default()
         });
     }
 
@@ -358,35 +1573,254 @@ fn parse_tool_use(output: &str) -> Option<ToolCall> {
     if let Some(caps) = re_shell.captures(output) {
         return Some(ToolCall {
             name: "shell.run".into(),
-            path: None,
             content: Some(caps[1].to_string()),
+            ..Default::default()
         });
     }
 
     None
 }
 
-/// Simulate MCP tools (filesystem.read, filesystem.write, shell.run)
-fn execute_mcp(tool: &ToolCall) -> Result<String, Box<dyn Error>> {
+/// Most results `ToolCache` keeps, and their total size in bytes.
+const TOOL_CACHE_ENTRIES: usize = 32;
+const TOOL_CACHE_BYTES: usize = 1024 * 1024;
+
+/// Tool name, path, content and line range: what makes two calls identical.
+type CacheKey = (String, Option<String>, Option<String>, Option<usize>, Option<usize>);
+
+/// Results of earlier filesystem.read / filesystem.list calls in a session,
+/// so a repeated call is answered from memory while the file or directory
+/// is unchanged.
+#[derive(Debug, Default)]
+pub struct ToolCache {
+    /// Least recently used first.
+    entries: Vec<CachedResult>,
+}
+
+#[derive(Debug)]
+struct CachedResult {
+    key: CacheKey,
+    /// Modification time and size of the path when the result was made.
+    /// A directory's time changes when entries come or go, not when a file
+    /// in it grows, so sizes in a listing can lag behind edits made outside
+    /// the agent.
+    stamp: (SystemTime, u64),
+    result: String,
+}
+
+impl ToolCache {
+    fn key(tool: &ToolCall) -> CacheKey {
+        (tool.name.clone(), tool.path.clone(), tool.content.clone(), tool.start_line, tool.end_line)
+    }
+
+    /// Current modification time and size of the path `tool` reads or lists.
+    fn stamp(tool: &ToolCall, policy: &ToolPolicy) -> Option<(SystemTime, u64)> {
+        let raw = expand_tilde(tool.path.as_deref().unwrap_or("."));
+        let meta = fs::metadata(sandbox_path(&policy.allowed_roots, &raw).ok()?).ok()?;
+        Some((meta.modified().ok()?, meta.len()))
+    }
+
+    /// The earlier result of an identical call, if its path is unchanged.
+    fn get(&mut self, tool: &ToolCall, policy: &ToolPolicy) -> Option<String> {
+        let key = Self::key(tool);
+        let pos = self.entries.iter().position(|e| e.key == key)?;
+        let entry = self.entries.remove(pos);
+        if Self::stamp(tool, policy) != Some(entry.stamp) {
+            return None;
+        }
+        let result = entry.result.clone();
+        self.entries.push(entry);
+        Some(result)
+    }
+
+    fn put(&mut self, tool: &ToolCall, policy: &ToolPolicy, result: &str) {
+        let Some(stamp) = Self::stamp(tool, policy) else {
+            return;
+        };
+        let key = Self::key(tool);
+        self.entries.retain(|e| e.key != key);
+        self.entries.push(CachedResult { key, stamp, result: result.to_string() });
+        while self.entries.len() > TOOL_CACHE_ENTRIES
+            || self.entries.iter().map(|e| e.result.len()).sum::<usize>() > TOOL_CACHE_BYTES
+        {
+            self.entries.remove(0);
+        }
+    }
+
+    /// Forget what `tool` may change: the files a file tool touches and
+    /// every listing, or everything for shell commands and server tools.
+    fn invalidate(&mut self, tool: &ToolCall) {
+        match tool.name.as_str() {
+            "filesystem.read" | "filesystem.list" => {}
+            "filesystem.write" | "filesystem.move" | "filesystem.delete" | "filesystem.restore" => {
+                let touched = [tool.path.as_ref(), tool.dst.as_ref()];
+                self.entries
+                    .retain(|e| e.key.0 == "filesystem.read" && !touched.contains(&e.key.1.as_ref()));
+            }
+            _ => self.entries.clear(),
+        }
+    }
+}
+
+/// `execute_mcp`, with a repeated read or listing answered from `cache`
+/// (marked "(cached)") and other tools clearing what they may change.
+fn execute_cached(
+    tool: &ToolCall,
+    policy: &ToolPolicy,
+    cache: &mut ToolCache,
+    session_id: &str,
+) -> Result<String, Box<dyn Error>> {
+    if !matches!(tool.name.as_str(), "filesystem.read" | "filesystem.list") {
+        cache.invalidate(tool);
+        return execute_mcp(tool, policy, session_id);
+    }
+    if let Some(result) = cache.get(tool, policy) {
+        return Ok(format!("(cached) {result}"));
+    }
+    let result = execute_mcp(tool, policy, session_id)?;
+    cache.put(tool, policy, &result);
+    Ok(result)
+}
+
+/// Run a built-in tool and append what happened to the audit log.
+fn execute_mcp(tool: &ToolCall, policy: &ToolPolicy, session_id: &str) -> Result<String, Box<dyn Error>> {
+    let started = Instant::now();
+    let mut exit_code = None;
+    let result = run_tool(tool, policy, &mut exit_code);
+
+    let shell = tool.name == "shell.run";
+    audit::append(
+        &policy.audit_log,
+        &AuditEntry {
+            ts: now_secs(),
+            session: session_id.to_string(),
+            tool: tool.name.clone(),
+            path: if shell { None } else { tool.path.clone() },
+            dst: tool.dst.clone(),
+            command: if shell { tool.content.clone() } else { None },
+            // Sandbox refusals come back as results for the model to read.
+            ok: result.as_ref().is_ok_and(|r| !r.starts_with("Blocked ")),
+            result_len: result.as_ref().map_or(0, |r| r.len()),
+            exit_code,
+            error: result.as_ref().err().map(|e| e.to_string()),
+            duration_ms: started.elapsed().as_millis() as u64,
+        },
+    );
+    result
+}
+
+/// Simulate MCP tools (filesystem.read, filesystem.list, filesystem.write, filesystem.move,
+/// filesystem.delete, filesystem.restore, shell.run). A shell command's exit
+/// code is left in `exit_code`.
+fn run_tool(tool: &ToolCall, policy: &ToolPolicy, exit_code: &mut Option<i32>) -> Result<String, Box<dyn Error>> {
+    if policy.safe_mode && is_mutating(&tool.name) {
+        return Err(SAFE_MODE_REFUSAL.into());
+    }
     match tool.name.as_str() {
         "filesystem.read" => {
             let raw_path = tool.path.as_ref().ok_or("Missing path for filesystem.read")?;
-            let path = expand_tilde(raw_path);
+            let path = match sandbox_path(&policy.allowed_roots, &expand_tilde(raw_path)) {
+                Ok(p) => p,
+                Err(e) => return Ok(format!("Blocked filesystem.read: {e}")),
+            };
             let content = fs::read_to_string(&path)?;
             // println!("📂 Read file '{}': {} bytes", path, content.len());
-            Ok(format!("Read file '{}' ({} bytes). Content:\n{}", path.display(), content.len(), content))
+            if tool.start_line.is_none() && tool.end_line.is_none() {
+                return Ok(format!("Read file '{}' ({} bytes). Content:\n{}", path.display(), content.len(), content));
+            }
+            let (content, header) = slice_lines(&content, tool.start_line, tool.end_line);
+            Ok(format!("Read file '{}', {}. Content:\n{}", path.display(), header, content))
+        }
+
+        "filesystem.list" => {
+            let raw = expand_tilde(tool.path.as_deref().unwrap_or("."));
+            match sandbox_path(&policy.allowed_roots, &raw) {
+                Ok(path) => Ok(list_dir(&path)),
+                Err(e) => Ok(format!("Blocked filesystem.list: {e}")),
+            }
         }
 
         "filesystem.write" => {
             let raw_path = tool.path.as_ref().ok_or("Missing path for filesystem.write")?;
-            let path = expand_tilde(raw_path);
+            let path = match sandbox_path(&policy.allowed_roots, &expand_tilde(raw_path)) {
+                Ok(p) => p,
+                Err(e) => return Ok(format!("Blocked filesystem.write: {e}")),
+            };
 
             let data_raw = tool.content.as_ref().ok_or("Missing content for filesystem.write")?;
             let data = normalize_escaped_content(data_raw);
 
+            let backup = back_up_before_write(&path, policy)?;
             fs::write(&path, &data)?;
             // println!("💾 Wrote {} bytes to '{}'", data.len(), path);
-            Ok(format!("Wrote {} bytes to '{}'.", data.len(), path.display()))
+            Ok(format!("Wrote {} bytes to '{}'.{}", data.len(), path.display(), backup_note(backup.as_ref())))
+        }
+
+        "filesystem.restore" => {
+            let backup_raw = tool.path.as_ref().ok_or("Missing src (backup file) for filesystem.restore")?;
+            let dst_raw = tool.dst.as_ref().ok_or("Missing dst for filesystem.restore")?;
+
+            // Backups are named relative to the working directory, like
+            // the paths in filesystem.write results.
+            let backup_abs = env::current_dir()?.join(expand_tilde(backup_raw));
+            let backup = match sandbox_path(std::slice::from_ref(&policy.backup_dir), &backup_abs) {
+                Ok(p) => p,
+                Err(e) => return Ok(format!("Blocked filesystem.restore: {e}")),
+            };
+            let dst = match sandbox_path(&policy.allowed_roots, &expand_tilde(dst_raw)) {
+                Ok(p) => p,
+                Err(e) => return Ok(format!("Blocked filesystem.restore: {e}")),
+            };
+            if !backup.is_file() {
+                return Ok(format!("No backup at '{}'.", backup.display()));
+            }
+
+            let current = back_up_before_write(&dst, policy)?;
+            fs::copy(&backup, &dst)?;
+            Ok(format!("Restored '{}' from '{}'.{}", dst_raw, backup.display(), backup_note(current.as_ref())))
+        }
+
+        "filesystem.move" => {
+            let src_raw = tool.path.as_ref().ok_or("Missing src for filesystem.move")?;
+            let dst_raw = tool.dst.as_ref().ok_or("Missing dst for filesystem.move")?;
+
+            let (src, dst) = match (
+                confine_to_root(&policy.workspace_root, &expand_tilde(src_raw)),
+                confine_to_root(&policy.workspace_root, &expand_tilde(dst_raw)),
+            ) {
+                (Ok(src), Ok(dst)) => (src, dst),
+                (Err(e), _) | (_, Err(e)) => return Ok(format!("Blocked filesystem.move: {e}")),
+            };
+
+            if dst.exists() {
+                return Ok(format!("Blocked filesystem.move: '{}' already exists.", dst.display()));
+            }
+            if let Some(parent) = dst.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(&src, &dst)?;
+            Ok(format!("Moved '{}' to '{}'.", src.display(), dst.display()))
+        }
+
+        "filesystem.delete" => {
+            let raw = tool.path.as_ref().ok_or("Missing path for filesystem.delete")?;
+            let path = match confine_to_root(&policy.workspace_root, &expand_tilde(raw)) {
+                Ok(p) => p,
+                Err(e) => return Ok(format!("Blocked filesystem.delete: {e}")),
+            };
+
+            if policy.safe_delete {
+                let trashed = move_to_trash(&path, &policy.trash_dir)?;
+                Ok(format!("Deleted '{}' (moved to '{}').", path.display(), trashed.display()))
+            } else {
+                if path.is_dir() {
+                    // Only empty directories are removed without the trash safety net.
+                    fs::remove_dir(&path)?;
+                } else {
+                    fs::remove_file(&path)?;
+                }
+                Ok(format!("Deleted '{}'.", path.display()))
+            }
         }
 
         "shell.run" => {
@@ -396,36 +1830,80 @@ fn execute_mcp(tool: &ToolCall) -> Result<String, Box<dyn Error>> {
                 .ok_or("Missing 'content' for shell.run (expected shell command)")?;
             // println!("🖥️ Running shell command: `{}`", command_raw);
 
-            #[cfg(target_os = "windows")]
-            let output = std::process::Command::new("cmd")
-                .args(&["/C", command_raw])
-                .output()?;
-
-            #[cfg(not(target_os = "windows"))]
-            let output = std::process::Command::new("sh")
-                .args(&["-c", command_raw])
-                .output()?;
-
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            let stderr = String::from_utf8_lossy(&output.stderr);
-
-            // println!("📤 Command output:\n{}", stdout);
-            // if !stderr.is_empty() {
-            //     println!("⚠️ Command error output:\n{}", stderr);
-            // }
-
-            Ok(format!(
-                "Command `{}` executed.\nSTDOUT:\n{}\nSTDERR:\n{}",
-                command_raw, stdout, stderr
-            ))
+            let (report, code) = run_shell(command_raw, policy.shell, policy.shell_timeout)?;
+            *exit_code = code;
+            Ok(report)
         }
 
         _ => Err(format!("Unknown MCP tool: {}", tool.name).into()),
     }
 }
 
+/// Run `command` through `shell`, killing it once `timeout` passes. The
+/// output pipes are drained on their own threads so a chatty command cannot
+/// stall on a full pipe. Returns the report for the model and the exit code,
+/// if the command exited by itself.
+fn run_shell(command: &str, shell: Shell, timeout: Duration) -> Result<(String, Option<i32>), Box<dyn Error>> {
+    let spawned = shell.command(command).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn();
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(format!(
+                "shell '{}' is not installed or not on PATH; change `shell` in ~/.config/rustai/config.toml",
+                shell.name()
+            )
+            .into())
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let stdout = drain_pipe(child.stdout.take());
+    let stderr = drain_pipe(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            // The readers may still be held open by grandchildren; leave them.
+            let report = format!("Command `{command}` timed out after {}s and was killed.", timeout.as_secs());
+            return Ok((report, None));
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    let exit = match status.code() {
+        Some(code) => format!("exit code {code}"),
+        None => "no exit code (killed by a signal)".to_string(),
+    };
+    let report = format!(
+        "Command `{}` ({}) finished with {}.\nSTDOUT:\n{}\nSTDERR:\n{}",
+        command,
+        shell.name(),
+        exit,
+        stdout,
+        stderr
+    );
+    Ok((report, status.code()))
+}
+
+/// Read a child's pipe to the end on a background thread.
+fn drain_pipe(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<String> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
+    })
+}
+
 /// Loosely decode escaped sequences and handle real newlines safely
-fn normalize_escaped_content(s: &str) -> String {
+pub(crate) fn normalize_escaped_content(s: &str) -> String {
     let mut out = String::new();
     let mut chars = s.chars().peekable();
 
@@ -448,6 +1926,143 @@ fn normalize_escaped_content(s: &str) -> String {
     out
 }
 
+/// Sorted listing of `path`: directories get a trailing slash, files their size.
+/// Errors are returned as text so the agent loop can carry on.
+fn list_dir(path: &Path) -> String {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) => return format!("Cannot list '{}': {e}", path.display()),
+    };
+
+    let mut lines: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            match entry.metadata() {
+                Ok(meta) if meta.is_dir() => format!("{name}/"),
+                Ok(meta) => format!("{name} ({} bytes)", meta.len()),
+                Err(_) => name,
+            }
+        })
+        .collect();
+    lines.sort();
+
+    if lines.is_empty() {
+        return format!("'{}' is empty.", path.display());
+    }
+    format!("Listing of '{}':\n{}", path.display(), lines.join("\n"))
+}
+
+/// Resolve `raw` for the file tools and require it to lie
+/// under one of `roots` (relative paths start at the first root). Uses the
+/// same resolution as `confine_to_root`, so `..` and symlinks cannot escape.
+fn sandbox_path(roots: &[PathBuf], raw: &Path) -> Result<PathBuf, AppError> {
+    let roots: Vec<PathBuf> = roots.iter().filter_map(|r| r.canonicalize().ok()).collect();
+    let base = roots
+        .first()
+        .ok_or_else(|| AppError::Sandbox("no accessible sandbox root".into()))?;
+    let resolved = resolve_under(base, raw);
+    if roots.iter().any(|root| resolved.starts_with(root)) {
+        Ok(resolved)
+    } else {
+        Err(AppError::Sandbox(format!("path outside sandbox: '{}'", raw.display())))
+    }
+}
+
+/// Join `raw` onto `root` unless it is absolute, resolve `..` lexically and
+/// canonicalize the deepest existing ancestor.
+fn resolve_under(root: &Path, raw: &Path) -> PathBuf {
+    let joined = if raw.is_absolute() {
+        raw.to_path_buf()
+    } else {
+        root.join(raw)
+    };
+
+    let mut normalized = PathBuf::new();
+    for comp in joined.components() {
+        match comp {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::CurDir => {}
+            other => normalized.push(other),
+        }
+    }
+
+    // Canonicalize the part that exists, then re-attach the rest.
+    let mut existing = normalized.as_path();
+    let mut rest = Vec::new();
+    while !existing.exists() {
+        match (existing.file_name(), existing.parent()) {
+            (Some(name), Some(parent)) => {
+                rest.push(name.to_os_string());
+                existing = parent;
+            }
+            _ => break,
+        }
+    }
+    let mut resolved = existing.canonicalize().unwrap_or_else(|_| existing.to_path_buf());
+    for part in rest.iter().rev() {
+        resolved.push(part);
+    }
+    resolved
+}
+
+/// Resolve `raw` against `root` and make sure the result stays inside it.
+/// `..` is resolved lexically and the deepest existing ancestor is
+/// canonicalized, so symlinks pointing outside the root are caught too.
+fn confine_to_root(root: &Path, raw: &Path) -> Result<PathBuf, AppError> {
+    let root = root.canonicalize().map_err(|e| {
+        AppError::Sandbox(format!("workspace root '{}' is not accessible: {e}", root.display()))
+    })?;
+    let resolved = resolve_under(&root, raw);
+
+    if resolved == root {
+        return Err(AppError::Sandbox("refusing to operate on the workspace root itself".into()));
+    }
+    if !resolved.starts_with(&root) {
+        return Err(AppError::Sandbox(format!(
+            "'{}' is outside the workspace root '{}'",
+            raw.display(),
+            root.display()
+        )));
+    }
+    Ok(resolved)
+}
+
+/// Copy `path` into `backup_dir` with a millisecond timestamp suffix;
+/// returns the copy's location.
+fn back_up(path: &Path, backup_dir: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    fs::create_dir_all(backup_dir)?;
+    let ts = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let name = path
+        .file_name()
+        .ok_or("cannot back up a path without a file name")?
+        .to_string_lossy();
+    let target = backup_dir.join(format!("{name}.{ts}"));
+    fs::copy(path, &target)?;
+    Ok(target)
+}
+
+/// Back up `path` if it is an existing file and backups are on. A failed
+/// backup is an error, so the caller never overwrites without one.
+fn back_up_before_write(path: &Path, policy: &ToolPolicy) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    if !policy.backup_on_write || !path.is_file() {
+        return Ok(None);
+    }
+    back_up(path, &policy.backup_dir)
+        .map(Some)
+        .map_err(|e| format!("could not back up '{}' before writing, nothing was written: {e}", path.display()).into())
+}
+
+/// Suffix for a write result saying where the old contents went.
+fn backup_note(backup: Option<&PathBuf>) -> String {
+    match backup {
+        Some(b) => format!(" Previous version saved to '{}' (filesystem.restore brings it back).", b.display()),
+        None => String::new(),
+    }
+}
+
 fn expand_tilde(path: &str) -> PathBuf {
     if let Some(stripped) = path.strip_prefix("~/") {
         if let Ok(home) = env::var("HOME") {
@@ -457,6 +2072,62 @@ fn expand_tilde(path: &str) -> PathBuf {
     PathBuf::from(path)
 }
 
+/// `/ab <prompt>`: ask the selected backend and the one `o` switches to
+/// the same question at once. Each answer streams into its own branch,
+/// forked from the end of the active one and named after the backend; a
+/// backend that fails only marks the reply in its branch.
+fn start_ab_generate(app: &mut App, prompt: &str) -> Result<()> {
+    if prompt.is_empty() {
+        app.set_status("Usage: /ab <prompt>");
+        return Ok(());
+    }
+    let session_idx = app.active_idx;
+    let parent_idx = app.sessions[session_idx].active_branch;
+    if app.stream_for(session_idx, parent_idx).is_some() {
+        app.set_status("⚠️ Wait for the current reply before comparing");
+        return Ok(());
+    }
+
+    let backends = [app.backend.clone(), app.backend.next(&app.config)];
+    let mut labels = Vec::new();
+    let mut first_branch = None;
+    for backend in backends {
+        let label = backend.label();
+        let session = &mut app.sessions[session_idx];
+        let (id, name) = session.new_branch_id();
+        let parent = &session.branches[parent_idx];
+        let mut messages = parent.messages.clone();
+        let fork_point = messages.len();
+        messages.push(Message::new(MessageFrom::User, prompt.to_string()));
+        session.branches.push(Branch {
+            id,
+            name: format!("{name} {label}"),
+            messages,
+            system_prompt: session.system_prompt.clone(),
+            summary: session.summary.clone(),
+            next_branch_id: session.next_branch_id,
+            parent: Some(parent.id),
+            fork_point: Some(fork_point),
+            created_at: now_secs(),
+            updated_at: 0,
+        });
+        let branch_idx = session.branches.len() - 1;
+        session.touch(branch_idx);
+        first_branch.get_or_insert(branch_idx);
+        labels.push(label);
+
+        start_streaming_with(app, session_idx, branch_idx, backend)?;
+    }
+
+    // Show the first answer; `[` / `]` or `b` reach the other.
+    if let Some(branch_idx) = first_branch {
+        app.sessions[session_idx].active_branch = branch_idx;
+    }
+    app.msg_scroll = 0;
+    app.set_status(format!("A/B: {} vs {}", labels[0], labels[1]));
+    Ok(())
+}
+
 /// Create a new branch starting from the edit point,
 /// then send the edited user message on that new branch.
 pub fn fork_and_send_from_edit(app: &mut App, ctx: EditContext, text: String) -> Result<()> {
@@ -464,6 +2135,7 @@ pub fn fork_and_send_from_edit(app: &mut App, ctx: EditContext, text: String) ->
         session_idx,
         branch_idx,
         message_idx,
+        ..
     } = ctx;
 
     // 1) Take a snapshot of the old branch so it is preserved.
@@ -475,52 +2147,121 @@ pub fn fork_and_send_from_edit(app: &mut App, ctx: EditContext, text: String) ->
 
     // 2) Overwrite the edited user message in the cloned branch.
     if let Some(msg) = new_messages.get_mut(message_idx) {
-        msg.content = text.clone();
+        *msg = Message::new(MessageFrom::User, text);
     }
 
     // 3) Drop everything after the edited message (old assistant reply, etc.).
     new_messages.truncate(message_idx + 1);
 
     // 4) Create a new branch with this updated message list.
+    //    Ids and names must stay unique even after branches were deleted.
     let new_branch_idx = session.branches.len();
+    let (id, name) = session.new_branch_id();
     session.branches.push(Branch {
-        id: new_branch_idx,
-        name: format!("branch-{new_branch_idx}"),
+        id,
+        name,
         messages: new_messages,
+        system_prompt: session.system_prompt.clone(),
+        summary: session.summary.clone(),
+        next_branch_id: session.next_branch_id,
+        parent: Some(session.branches[branch_idx].id),
+        fork_point: Some(message_idx),
+        created_at: now_secs(),
+        updated_at: 0,
     });
+    // The fork is the session's newest activity.
+    session.touch(new_branch_idx);
 
     // 5) Switch to the new branch so the UI shows the edited version.
     session.active_branch = new_branch_idx;
 
     // 6) Start streaming a fresh assistant reply on this new branch.
-    start_streaming_on_branch(app, session_idx, new_branch_idx, text)?;
+    start_streaming_on_branch(app, session_idx, new_branch_idx)?;
 
     Ok(())
 }
 
+/// Start the configured MCP servers on a worker; the handshakes can take a
+/// while, so the servers join `App::mcp_servers` through an event.
+pub fn start_mcp_servers(app: &mut App) {
+    let (Some(tx), false) = (app.backend_tx.clone(), app.config.mcp_servers.is_empty()) else {
+        return;
+    };
+    let configs = app.config.mcp_servers.clone();
+    app.spawn_worker(async move {
+        let started = tokio::task::spawn_blocking(move || {
+            let mut servers = Vec::new();
+            let mut failures = Vec::new();
+            for config in &configs {
+                match McpClient::connect(config) {
+                    Ok(client) => servers.push(client),
+                    Err(e) => failures.push(format!("MCP server '{}' not available: {e}", config.name)),
+                }
+            }
+            (servers, failures)
+        })
+        .await;
+        if let Ok((servers, failures)) = started {
+            let _ = tx.send(BackendEvent::McpServersReady { servers, failures });
+        }
+    });
+}
+
 /// Start streaming an assistant reply on a specific session/branch.
-fn start_streaming_on_branch(
+fn start_streaming_on_branch(app: &mut App, session_idx: usize, branch_idx: usize) -> Result<()> {
+    let backend = app.backend.clone();
+    start_streaming_with(app, session_idx, branch_idx, backend)
+}
+
+/// Same, with the reply coming from `backend` instead of the selected one.
+fn start_streaming_with(
     app: &mut App,
     session_idx: usize,
     branch_idx: usize,
-    prompt: String,
+    backend: Arc<dyn LlmBackend>,
 ) -> Result<()> {
+    // Precompute history BEFORE the task; it ends with the user's message.
+    let initial_history = app.history_messages_for(session_idx, branch_idx, HISTORY_MAX_CHARS);
+
     // Create empty assistant message in this branch
     app.start_streaming_assistant(session_idx, branch_idx);
 
-    // 4) precompute history BEFORE thread
-    let initial_history = app.history_string();
+    let policy = app.tool_policy.clone();
+    let servers = app.mcp_servers.clone();
+    let cache = app.tool_caches.entry(app.sessions[session_idx].id.clone()).or_default().clone();
+    let mut api = api_client(app);
+    api.backend = backend;
+    let system_prompt = app.sessions[session_idx].system_prompt.clone();
+    let session_id = app.sessions[session_idx].id.clone();
 
     // 5) Clone channel
     if let Some(tx_main) = app.backend_tx.clone() {
-        let tx_thread = tx_main.clone(); // clone for thread
+        let tx_thread = tx_main.clone(); // clone for the task
 
-        thread::spawn(move || {
+        let task = app.spawn_worker(async move {
             let tx_for_loop = tx_thread.clone();
             let tx_for_done = tx_thread.clone();
 
-            if let Err(e) = run_mcp_loop(prompt, initial_history, session_idx, branch_idx, tx_for_loop) {
-                eprintln!("MCP error: {e}");
+            let result = run_mcp_loop(
+                system_prompt,
+                initial_history,
+                session_idx,
+                branch_idx,
+                session_id,
+                &api,
+                &policy,
+                &servers,
+                cache,
+                tx_for_loop,
+            )
+            .await;
+            if let Err(e) = result {
+                // Show the failure in the chat; stderr is hidden behind the TUI.
+                let _ = tx_for_done.send(BackendEvent::Error {
+                    session_idx,
+                    branch_idx,
+                    message: error_message(e.as_ref()),
+                });
             }
 
             // send final done event
@@ -529,7 +2270,591 @@ fn start_streaming_on_branch(
                 branch_idx,
             });
         });
+        app.set_stream_task(session_idx, branch_idx, task);
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{MockBackend, OnChunk};
+    use crate::config::Config;
+    use futures_util::future::BoxFuture;
+
+    /// A fresh, empty directory under the system temp dir.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rustai-actions-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    /// A policy whose workspace, sandbox, trash and backups all live in `dir`.
+    fn policy_in(dir: &Path) -> ToolPolicy {
+        let work = dir.join("work");
+        fs::create_dir_all(&work).unwrap();
+        ToolPolicy {
+            workspace_root: work.clone(),
+            allowed_roots: vec![work],
+            ..ToolPolicy::for_log_dir(&dir.join("logs"))
+        }
+    }
+
+    fn call(name: &str, params: Value) -> ToolCall {
+        ToolCall::from_params(name.into(), &params)
+    }
+
+    fn run(tool: &ToolCall, policy: &ToolPolicy) -> Result<String, Box<dyn Error>> {
+        run_tool(tool, policy, &mut None)
+    }
+
+    /// An app with no saved sessions, logging into a scratch directory.
+    fn test_app(name: &str, config: Config) -> App {
+        App::new(Config { log_dir: scratch_dir(name), ..config })
+    }
+
+    #[test]
+    fn submit_input_keeps_or_leaves_insert_mode_per_config() {
+        for (stay, mode) in [(true, InputMode::Insert), (false, InputMode::Normal)] {
+            let mut app = test_app("stay-insert", Config { stay_in_insert_after_send: stay, ..Config::default() });
+            app.input_mode = InputMode::Insert;
+            app.input = "/system be brief".into();
+
+            submit_input(&mut app).unwrap();
+
+            assert_eq!(app.input_mode, mode);
+            assert!(app.input.is_empty());
+        }
+    }
+
+    /// Answer one connection per entry of `responses` (raw HTTP) on a local
+    /// port; returns the base URL.
+    fn fake_server(responses: Vec<String>) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                // Read the headers, then as much body as they announce.
+                while let Ok(n @ 1..) = stream.read(&mut buf) {
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request);
+                    if let Some(end) = text.find("\r\n\r\n") {
+                        let len = text[..end]
+                            .lines()
+                            .find_map(|l| l.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().to_string()))
+                            .and_then(|v| v.parse::<usize>().ok())
+                            .unwrap_or(0);
+                        if request.len() >= end + 4 + len {
+                            break;
+                        }
+                    }
+                }
+                std::io::Write::write_all(&mut stream, response.as_bytes()).unwrap();
+            }
+        });
+        format!("http://{addr}/v1")
+    }
+
+    fn http_response(status: &str, extra_headers: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {status}\r\n{extra_headers}Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    fn quick_api() -> ApiClient {
+        ApiClient::new(HttpConfig { initial_backoff: Duration::from_millis(1), ..HttpConfig::default() })
+    }
+
+    #[tokio::test]
+    async fn send_with_retry_waits_out_a_rate_limit() {
+        let base = fake_server(vec![
+            http_response("429 Too Many Requests", "Retry-After: 0\r\n", "{}"),
+            http_response("503 Service Unavailable", "", "{}"),
+            http_response("200 OK", "", "{}"),
+        ]);
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let api = ApiClient { status_tx: Some(tx), ..quick_api() };
+
+        let resp = api.send_with_retry("Test", || api.client.get(&base)).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        match rx.try_recv() {
+            Ok(BackendEvent::Status(text)) => assert!(text.contains("Rate limited"), "{text}"),
+            other => panic!("expected a status event, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn send_with_retry_does_not_retry_client_errors() {
+        // A second request would find nobody listening.
+        let base = fake_server(vec![http_response("400 Bad Request", "", "{}")]);
+        let api = quick_api();
+        let resp = api.send_with_retry("Test", || api.client.get(&base)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn send_with_retry_reports_attempts_when_the_server_is_down() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let api = ApiClient { http: HttpConfig { max_retries: 2, ..quick_api().http }, ..quick_api() };
+        let err = api
+            .send_with_retry("Test", || api.client.get(format!("http://127.0.0.1:{port}/")))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("failed after 3 attempt(s)"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn ui_streamer_chunks_without_a_delay() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let streamer = UiStreamer { max_chunk: 4, delay: Duration::ZERO };
+        let started = Instant::now();
+
+        streamer.stream("abcdefg\nhi", 1, 2, &tx).await.unwrap();
+
+        let mut chunks = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            match event {
+                BackendEvent::AssistantChunk { session_idx: 1, branch_idx: 2, chunk } => chunks.push(chunk),
+                other => panic!("unexpected event {other:?}"),
+            }
+        }
+        assert_eq!(chunks, ["abcd", "efg\n", "hi"]);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn parse_tool_uses_returns_every_call_in_order() {
+        let output = r#"First I read, then I list.
+<use_tool name="filesystem.read" params={"path": "a.txt"} />
+and
+<use_tool name="filesystem.list" params={"path": "src"} />
+<use_tool name="shell.run" params={"content": "echo {done}"} />"#;
+        let calls = parse_tool_uses(output);
+        let names: Vec<_> = calls.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["filesystem.read", "filesystem.list", "shell.run"]);
+        assert_eq!(calls[1].path.as_deref(), Some("src"));
+        assert_eq!(calls[2].content.as_deref(), Some("echo {done}"));
+    }
+
+    #[test]
+    fn parse_tool_uses_skips_calls_with_broken_params() {
+        let output = r#"<use_tool name="filesystem.read" params={"path": } />
+<use_tool name="filesystem.list" params={"path": "."} />"#;
+        let calls = parse_tool_uses(output);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "filesystem.list");
+        assert!(parse_tool_uses("no tools here").is_empty());
+    }
+
+    #[test]
+    fn confine_to_root_keeps_paths_inside_the_root() {
+        let dir = scratch_dir("confine");
+        assert_eq!(confine_to_root(&dir, Path::new("a/../b.txt")).unwrap(), dir.join("b.txt"));
+        assert!(confine_to_root(&dir, Path::new("../outside.txt")).is_err());
+        assert!(confine_to_root(&dir, Path::new("/etc/passwd")).is_err());
+        assert!(confine_to_root(&dir, Path::new(".")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn confine_to_root_follows_symlinks_out_of_the_root() {
+        let dir = scratch_dir("confine-symlink");
+        let root = dir.join("root");
+        fs::create_dir_all(&root).unwrap();
+        std::os::unix::fs::symlink(&dir, root.join("link")).unwrap();
+        assert!(confine_to_root(&root, Path::new("link/secret.txt")).is_err());
+    }
+
+    #[test]
+    fn delete_moves_the_file_to_the_trash() {
+        let dir = scratch_dir("delete");
+        let policy = policy_in(&dir);
+        fs::write(policy.workspace_root.join("old.txt"), "bye").unwrap();
+
+        let result = run(&call("filesystem.delete", json!({"path": "old.txt"})), &policy).unwrap();
+
+        assert!(result.starts_with("Deleted "), "{result}");
+        let trashed: Vec<_> = fs::read_dir(&policy.trash_dir).unwrap().flatten().collect();
+        assert_eq!(trashed.len(), 1);
+        assert_eq!(fs::read_to_string(trashed[0].path()).unwrap(), "bye");
+    }
+
+    #[test]
+    fn move_never_overwrites_the_destination() {
+        let dir = scratch_dir("move");
+        let policy = policy_in(&dir);
+        let work = &policy.workspace_root;
+        fs::write(work.join("a.txt"), "a").unwrap();
+        fs::write(work.join("b.txt"), "b").unwrap();
+
+        let result = run(&call("filesystem.move", json!({"src": "a.txt", "dst": "b.txt"})), &policy).unwrap();
+        assert!(result.contains("already exists"), "{result}");
+        assert_eq!(fs::read_to_string(work.join("b.txt")).unwrap(), "b");
+    }
+
+    #[test]
+    fn parse_http_date_reads_imf_fixdate() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784_111_777));
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(parse_http_date("Thu, 29 Feb 2024 12:00:00 GMT"), Some(1_709_208_000));
+    }
+
+    #[test]
+    fn parse_http_date_rejects_out_of_range_fields() {
+        // Each of these used to underflow or overflow.
+        assert_eq!(parse_http_date("Mon, 01 Jan 0 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Mon, 00 Jan 2024 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Mon, 01 Jan 2024 99999999999999999:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Mon, 99999999999999999 Jan 2024 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Mon, 01 Jan 18446744073709551615 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Mon, 01 Jan 2024 24:00:00 GMT"), None);
+    }
+
+    #[test]
+    fn parse_http_date_rejects_garbage() {
+        assert_eq!(parse_http_date(""), None);
+        assert_eq!(parse_http_date("soon"), None);
+        assert_eq!(parse_http_date("Mon, 01 Foo 2024 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Mon, 01 Jan 2024"), None);
+        assert_eq!(parse_http_date("Mon, 01 Jan 2024 10:xx:00 GMT"), None);
+    }
+
+    #[test]
+    fn retry_after_takes_seconds_or_a_date() {
+        let now = 1_445_412_480; // Wed, 21 Oct 2015 07:28:00 GMT
+        assert_eq!(parse_retry_after(429, " 120 ", now), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(503, "Wed, 21 Oct 2015 07:29:30 GMT", now), Some(Duration::from_secs(90)));
+        // A date in the past means "now".
+        assert_eq!(parse_retry_after(429, "Wed, 21 Oct 2015 07:00:00 GMT", now), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn retry_after_ignores_other_statuses_and_bad_values() {
+        assert_eq!(parse_retry_after(500, "5", 0), None);
+        assert_eq!(parse_retry_after(200, "5", 0), None);
+        assert_eq!(parse_retry_after(429, "later", 0), None);
+        assert_eq!(parse_retry_after(429, "-5", 0), None);
+    }
+
+    /// A 200 response streaming `events` as SSE `data:` lines.
+    fn sse_response(events: &[Value]) -> String {
+        let body: String = events.iter().map(|e| format!("data: {e}\n\n")).collect();
+        http_response("200 OK", "", &format!("{body}data: [DONE]\n\n"))
+    }
+
+    fn delta(text: &str) -> Value {
+        json!({"choices": [{"delta": {"content": text}}]})
+    }
+
+    #[test]
+    fn extract_sources_reads_citations_and_search_results() {
+        let event = json!({
+            "citations": ["https://a.example"],
+            "search_info": {"search_results": [{"url": "https://b.example"}, {"title": "Untitled"}, {}]},
+        });
+        assert_eq!(extract_sources(&event), ["https://a.example", "https://b.example", "Untitled"]);
+        assert!(extract_sources(&delta("hi")).is_empty());
+    }
+
+    #[tokio::test]
+    async fn call_chat_api_stream_collects_each_source_once() {
+        let base = fake_server(vec![sse_response(&[
+            json!({"choices": [{"delta": {"content": "See "}}], "citations": ["https://a.example"]}),
+            json!({"choices": [{"delta": {"content": "docs."}}], "citations": ["https://a.example", "https://b.example"]}),
+        ])]);
+        let endpoint = ChatEndpoint { provider: "Test", base_url: &base, api_key: None };
+
+        let reply = call_chat_api_stream(&quick_api(), &endpoint, "m", &[], |_| {}).await.unwrap();
+
+        assert_eq!(reply.text, "See docs.");
+        assert_eq!(reply.sources, ["https://a.example", "https://b.example"]);
+    }
+
+    #[test]
+    fn sandbox_path_accepts_every_allowed_root() {
+        let dir = scratch_dir("sandbox-roots");
+        let (work, extra) = (dir.join("work"), dir.join("extra"));
+        fs::create_dir_all(&work).unwrap();
+        fs::create_dir_all(&extra).unwrap();
+        let roots = [work.clone(), extra.clone()];
+
+        assert_eq!(sandbox_path(&roots, Path::new("a.txt")).unwrap(), work.join("a.txt"));
+        assert_eq!(sandbox_path(&roots, &extra.join("b.txt")).unwrap(), extra.join("b.txt"));
+        assert_eq!(sandbox_path(&roots, Path::new(".")).unwrap(), work);
+    }
+
+    #[test]
+    fn sandbox_path_rejects_paths_outside_the_roots() {
+        let dir = scratch_dir("sandbox-escape");
+        let work = dir.join("work");
+        fs::create_dir_all(&work).unwrap();
+        let roots = [work.clone()];
+
+        assert!(sandbox_path(&roots, Path::new("../secret.txt")).is_err());
+        assert!(sandbox_path(&roots, &dir.join("secret.txt")).is_err());
+        assert!(sandbox_path(&[dir.join("missing")], Path::new("a.txt")).is_err());
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&dir, work.join("link")).unwrap();
+            assert!(sandbox_path(&roots, Path::new("link/secret.txt")).is_err());
+        }
+    }
+
+    #[test]
+    fn file_tools_answer_blocked_outside_the_sandbox() {
+        let dir = scratch_dir("sandbox-tools");
+        let policy = policy_in(&dir);
+        fs::write(dir.join("secret.txt"), "no").unwrap();
+
+        let read = run(&call("filesystem.read", json!({"path": "../secret.txt"})), &policy).unwrap();
+        assert!(read.starts_with("Blocked filesystem.read: path outside sandbox"), "{read}");
+        let list = run(&call("filesystem.list", json!({"path": ".."})), &policy).unwrap();
+        assert!(list.starts_with("Blocked filesystem.list"), "{list}");
+        let write = run(&call("filesystem.write", json!({"path": "../out.txt", "content": "x"})), &policy).unwrap();
+        assert!(write.starts_with("Blocked filesystem.write"), "{write}");
+        assert!(!dir.join("out.txt").exists());
+    }
+
+    /// Answers with `replies` in order, then with nothing.
+    struct ScriptedBackend {
+        replies: Mutex<Vec<&'static str>>,
+    }
+
+    impl LlmBackend for ScriptedBackend {
+        fn stream<'a>(
+            &'a self,
+            _api: &'a ApiClient,
+            _messages: &'a [Value],
+            on_chunk: OnChunk<'a>,
+        ) -> BoxFuture<'a, Result<ChatReply, AppError>> {
+            Box::pin(async move {
+                let mut replies = self.replies.lock().unwrap();
+                let text = if replies.is_empty() { String::new() } else { replies.remove(0).to_string() };
+                on_chunk(&text);
+                Ok(ChatReply { text, sources: Vec::new() })
+            })
+        }
+
+        fn label(&self) -> String {
+            "scripted".to_string()
+        }
+    }
+
+    /// Run the agent loop with `backend` on `policy`; returns the names of
+    /// the tools that ran and everything streamed into the reply.
+    async fn run_agent(backend: Arc<dyn LlmBackend>, policy: &ToolPolicy) -> (Vec<String>, String) {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let api = ApiClient { backend, ..quick_api() };
+        let history = vec![json!({"role": "user", "content": "go"})];
+        let cache = Arc::new(Mutex::new(ToolCache::default()));
+        run_mcp_loop(None, history, 0, 0, "s1".into(), &api, policy, &[], cache, tx).await.unwrap();
+
+        let (mut tools, mut text) = (Vec::new(), String::new());
+        while let Ok(event) = rx.try_recv() {
+            match event {
+                BackendEvent::ToolResult { name, .. } => tools.push(name),
+                BackendEvent::AssistantChunk { chunk, .. } => text.push_str(&chunk),
+                _ => {}
+            }
+        }
+        (tools, text)
+    }
+
+    #[tokio::test]
+    async fn agent_stops_when_a_tool_call_repeats() {
+        let dir = scratch_dir("agent-repeat");
+        let policy = policy_in(&dir);
+        let reply = r#"<use_tool name="filesystem.list" params={"path": "."} />"#;
+
+        let (tools, text) = run_agent(Arc::new(MockBackend { reply: reply.into() }), &policy).await;
+
+        assert_eq!(tools, ["filesystem.list"]);
+        assert!(text.contains("Agent stopped: filesystem.list repeated with the same arguments"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn agent_stops_at_the_step_limit() {
+        let dir = scratch_dir("agent-steps");
+        let policy = ToolPolicy { max_agent_steps: 2, ..policy_in(&dir) };
+        let replies = vec![
+            r#"<use_tool name="filesystem.list" params={"path": "."} />"#,
+            r#"<use_tool name="filesystem.read" params={"path": "a.txt"} />"#,
+            r#"<use_tool name="filesystem.read" params={"path": "b.txt"} />"#,
+        ];
+
+        let (tools, text) = run_agent(Arc::new(ScriptedBackend { replies: Mutex::new(replies) }), &policy).await;
+
+        assert_eq!(tools, ["filesystem.list", "filesystem.read"]);
+        assert!(text.contains("Agent stopped: tool call limit reached after 2 steps"), "{text}");
+    }
+
+    #[test]
+    fn balanced_json_ignores_braces_and_quotes_in_strings() {
+        assert_eq!(balanced_json(r#"{"a": "}{\"}"} />"#), Some(r#"{"a": "}{\"}"}"#));
+        assert_eq!(balanced_json(r#"{"a": {"b": 1}} tail}"#), Some(r#"{"a": {"b": 1}}"#));
+        assert_eq!(balanced_json(r#"{"a": "never closed"#), None);
+        assert_eq!(balanced_json(r#" {"a": 1}"#), None);
+    }
+
+    #[test]
+    fn escape_controls_in_strings_only_touches_string_values() {
+        assert_eq!(escape_controls_in_strings("{\n\"a\": \"x\ty\nz\"\n}"), "{\n\"a\": \"x\\ty\\nz\"\n}");
+        assert_eq!(escape_controls_in_strings(r#"{"a": "q\"\n"}"#), r#"{"a": "q\"\n"}"#);
+    }
+
+    #[test]
+    fn parse_tool_uses_accepts_raw_newlines_and_a_missing_close() {
+        let output = "<use_tool name=\"filesystem.write\" params={\"path\": \"a.rs\", \"content\": \"fn main() {\n\tprintln!(\\\"}\\\");\n}\"}";
+        let calls = parse_tool_uses(output);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].path.as_deref(), Some("a.rs"));
+        assert_eq!(calls[0].content.as_deref(), Some("fn main() {\n\tprintln!(\"}\");\n}"));
+    }
+
+    #[test]
+    fn parse_tool_uses_falls_back_to_the_simple_patterns() {
+        // `\q` is not a JSON escape, so only the shell.run pattern matches.
+        let calls = parse_tool_uses(r#"<use_tool name="shell.run" params={"content": "grep \q x"} />"#);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "shell.run");
+        assert_eq!(calls[0].content.as_deref(), Some(r"grep \q x"));
+    }
+
+    fn stop_list(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn stop_filter_cuts_a_stop_split_across_chunks() {
+        let stops = stop_list(&["END"]);
+        let mut filter = StopFilter::new(&stops);
+        assert_eq!(filter.push("one E"), "one ");
+        assert_eq!(filter.push("N"), "");
+        assert!(!filter.done());
+        assert_eq!(filter.push("D two"), "");
+        assert!(filter.done());
+        assert_eq!(filter.push("three"), "");
+        assert_eq!(filter.finish(), "");
+    }
+
+    #[test]
+    fn stop_filter_releases_held_text_that_was_no_stop() {
+        let stops = stop_list(&["END", "\n\n"]);
+        let mut filter = StopFilter::new(&stops);
+        assert_eq!(filter.push("EN"), "");
+        assert_eq!(filter.push("TRY\n"), "ENTRY");
+        assert_eq!(filter.finish(), "\n");
+
+        let none = stop_list(&[""]);
+        let mut filter = StopFilter::new(&none);
+        assert_eq!(filter.push("END"), "END");
+    }
+
+    #[test]
+    fn stop_filter_cuts_at_the_earliest_stop() {
+        let stops = stop_list(&["b", "a"]);
+        let mut filter = StopFilter::new(&stops);
+        assert_eq!(filter.push("xxab"), "xx");
+        assert!(filter.done());
+    }
+
+    #[test]
+    fn partial_match_respects_char_boundaries() {
+        assert_eq!(partial_match("abc", "cde"), 1);
+        assert_eq!(partial_match("abcd", "cde"), 2);
+        assert_eq!(partial_match("abc", "xyz"), 0);
+        assert_eq!(partial_match("ab", "ab"), 0);
+        assert_eq!(partial_match("x\u{e9}", "\u{e9}t"), 2);
+    }
+
+    #[tokio::test]
+    async fn call_chat_api_stream_stops_on_a_split_stop_sequence() {
+        let base = fake_server(vec![sse_response(&[delta("one E"), delta("ND two")])]);
+        let endpoint = ChatEndpoint { provider: "Test", base_url: &base, api_key: None };
+        let mut api = quick_api();
+        api.params.stop = stop_list(&["END"]);
+        let mut shown = String::new();
+
+        let reply = call_chat_api_stream(&api, &endpoint, "m", &[], |c| shown.push_str(c)).await.unwrap();
+
+        assert_eq!(reply.text, "one ");
+        assert_eq!(shown, "one ");
+    }
+
+    #[test]
+    fn only_writes_moves_deletes_restores_and_shell_are_mutating() {
+        for name in ["filesystem.write", "filesystem.move", "filesystem.delete", "filesystem.restore", "shell.run"] {
+            assert!(is_mutating(name), "{name}");
+        }
+        for name in ["filesystem.read", "filesystem.list", "docs.search"] {
+            assert!(!is_mutating(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn safe_mode_refuses_mutating_tools_but_still_reads() {
+        let dir = scratch_dir("safe-mode-tools");
+        let policy = ToolPolicy { safe_mode: true, ..policy_in(&dir) };
+        fs::write(policy.workspace_root.join("a.txt"), "hello").unwrap();
+
+        let write = run(&call("filesystem.write", json!({"path": "b.txt", "content": "x"})), &policy).unwrap_err();
+        assert_eq!(write.to_string(), SAFE_MODE_REFUSAL);
+        assert!(!policy.workspace_root.join("b.txt").exists());
+        let shell = run(&call("shell.run", json!({"content": "echo hi"})), &policy).unwrap_err();
+        assert_eq!(shell.to_string(), SAFE_MODE_REFUSAL);
+        let read = run(&call("filesystem.read", json!({"path": "a.txt"})), &policy).unwrap();
+        assert!(read.ends_with("hello"), "{read}");
+    }
+
+    /// Answers with `replies` in turn and keeps the messages of each call.
+    struct RecordingBackend {
+        replies: Mutex<Vec<&'static str>>,
+        seen: Arc<Mutex<Vec<Vec<Value>>>>,
+    }
+
+    impl LlmBackend for RecordingBackend {
+        fn stream<'a>(
+            &'a self,
+            _api: &'a ApiClient,
+            messages: &'a [Value],
+            _on_chunk: OnChunk<'a>,
+        ) -> BoxFuture<'a, Result<ChatReply, AppError>> {
+            Box::pin(async move {
+                self.seen.lock().unwrap().push(messages.to_vec());
+                let mut replies = self.replies.lock().unwrap();
+                let text = if replies.is_empty() { String::new() } else { replies.remove(0).to_string() };
+                Ok(ChatReply { text, sources: Vec::new() })
+            })
+        }
+
+        fn label(&self) -> String {
+            "recording".to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn safe_mode_agent_is_not_offered_and_cannot_use_write_tools() {
+        let dir = scratch_dir("safe-mode-agent");
+        let policy = ToolPolicy { safe_mode: true, ..policy_in(&dir) };
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let replies = vec![r#"<use_tool name="filesystem.write" params={"path": "b.txt", "content": "x"} />"#, "Done."];
+        let backend = RecordingBackend { replies: Mutex::new(replies), seen: seen.clone() };
+
+        let (tools, _) = run_agent(Arc::new(backend), &policy).await;
+
+        let seen = seen.lock().unwrap();
+        let prompt = seen[0][0]["content"].as_str().unwrap();
+        assert!(prompt.contains(SAFE_MODE_PROMPT), "{prompt}");
+        assert!(!prompt.contains("filesystem.write"), "{prompt}");
+        assert_eq!(tools, ["filesystem.write"]);
+        assert_eq!(seen[1].last().unwrap()["content"], format!("[Tool: filesystem.write]\nresult: error: {SAFE_MODE_REFUSAL}"));
+        assert!(!dir.join("work/b.txt").exists());
+    }
+}
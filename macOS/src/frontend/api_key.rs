@@ -1,3 +1,52 @@
-pub const DASHSCOPE_API_KEY: &str = "sk-619560edb9664aa5884397e62ac8b712";
-// backup api key if needed
-// pub const DASHSCOPE_API_KEY: &str = "sk-7ae122c42ceb4240aaa7ad34df284362";
\ No newline at end of file
+use serde::Deserialize;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+const API_KEY_ENV: &str = "DASHSCOPE_API_KEY";
+
+/// The subset of `~/.config/rustai/config.toml` that holds credentials.
+#[derive(Deserialize, Default)]
+struct KeyFile {
+    /// Name of the environment variable holding the key.
+    api_key_env: Option<String>,
+    dashscope_api_key: Option<String>,
+}
+
+/// Location of the optional config file (`~/.config/rustai/config.toml`).
+pub fn config_path() -> Option<PathBuf> {
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))?;
+    Some(PathBuf::from(home).join(".config").join("rustai").join("config.toml"))
+}
+
+/// Find the DashScope API key: an explicit value first, then the
+/// `DASHSCOPE_API_KEY` environment variable, then the config file
+/// (the variable named by `api_key_env`, or `dashscope_api_key`).
+pub fn resolve(explicit: Option<&str>) -> Result<String, Box<dyn Error>> {
+    if let Some(key) = explicit.map(str::trim).filter(|k| !k.is_empty()) {
+        return Ok(key.to_string());
+    }
+
+    if let Ok(key) = env::var(API_KEY_ENV) {
+        if !key.trim().is_empty() {
+            return Ok(key.trim().to_string());
+        }
+    }
+
+    if let Some(path) = config_path() {
+        if let Ok(text) = fs::read_to_string(&path) {
+            let file: KeyFile = toml::from_str(&text)
+                .map_err(|e| format!("invalid config file {}: {e}", path.display()))?;
+            let referenced = file.api_key_env.as_deref().and_then(|var| env::var(var).ok());
+            if let Some(key) = referenced.filter(|k| !k.trim().is_empty()) {
+                return Ok(key.trim().to_string());
+            }
+            if let Some(key) = file.dashscope_api_key.filter(|k| !k.trim().is_empty()) {
+                return Ok(key.trim().to_string());
+            }
+        }
+    }
+
+    Err(format!("no API key found; set {API_KEY_ENV}").into())
+}
@@ -1,102 +1,108 @@
 use anyhow::Result;
-use crossterm::event::KeyCode;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 
 use crate::frontend::actions;
-use crate::app::{App, InputMode, MessageFrom, EditContext};
+use crate::frontend::keymap::Action;
+use crate::app::{App, InputMode, MessageFrom};
 
 /// Handle a single key event.
 /// Returns Ok(true) if the app should exit, Ok(false) otherwise.
-pub fn handle_key_event(code: KeyCode, app: &mut App) -> Result<bool> {
-    match app.input_mode {
-        InputMode::Normal => {
-            match code {
-                // Quit in normal mode.
-                KeyCode::Char('q') => {
-                    // Tell caller to exit the loop.
-                    return Ok(true);
-                }
-
-                // New session in normal mode.
-                KeyCode::Char('n') => {
-                    app.new_session();
-                }
+pub fn handle_key_event(key: KeyEvent, app: &mut App) -> Result<bool> {
+    let code = key.code;
+    let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
 
-                // Move selection up/down using j/k.
-                KeyCode::Char('j') => {
-                    app.next_session();
-                }
-                KeyCode::Char('k') => {
-                    app.prev_session();
-                }
-
-                // Also allow arrow keys in normal mode.
-                KeyCode::Up => app.prev_session(),
-                KeyCode::Down => app.next_session(),
+    // A pending shell-command approval captures all keys until answered.
+    if !app.pending_approvals.is_empty() {
+        match code {
+            KeyCode::Char('y') | KeyCode::Char('Y') => app.answer_tool_approval(true),
+            KeyCode::Char('n') | KeyCode::Char('N') | KeyCode::Esc => app.answer_tool_approval(false),
+            _ => {}
+        }
+        return Ok(false);
+    }
 
-                // Enter insert mode.
-                KeyCode::Char('i') => {
-                    app.input_mode = InputMode::Insert;
-                }
+    // Typing a new session title.
+    if let Some(title) = app.renaming_session.as_mut() {
+        match code {
+            KeyCode::Enter => app.commit_rename_session(),
+            KeyCode::Esc => app.renaming_session = None,
+            KeyCode::Backspace => {
+                title.pop();
+            }
+            KeyCode::Char(c) => title.push(c),
+            _ => {}
+        }
+        return Ok(false);
+    }
 
-                // TAB toggles between the button and the list.
-                KeyCode::Tab => {
-                    app.new_button_selected = !app.new_button_selected;
-                }
+    if app.confirm_delete_session {
+        match code {
+            KeyCode::Char('y') | KeyCode::Char('Y') => app.delete_active_session(),
+            _ => app.confirm_delete_session = false,
+        }
+        return Ok(false);
+    }
 
-                KeyCode::Char('[') => { app.prev_branch(); }
-                KeyCode::Char(']') => { app.next_branch(); }
+    if app.confirm_delete_message {
+        match code {
+            KeyCode::Char('y') | KeyCode::Char('Y') => app.delete_selected_message(),
+            _ => app.confirm_delete_message = false,
+        }
+        return Ok(false);
+    }
 
-                KeyCode::Enter => {
-                    if app.new_button_selected {
-                        // Pressing Enter on the button creates a new session.
-                        app.new_session();
-                    } else {
-                        // Do nothing for now when pressing Enter on the list.
-                    }
-                }
+    // The branch overlay captures all keys while open.
+    if app.branch_overlay.is_some() {
+        handle_branch_overlay_key(code, app);
+        return Ok(false);
+    }
 
-                KeyCode::Char('s') => {
-                    app.toggle_sidebar();
-                }
+    // So does the branch comparison.
+    if app.compare.is_some() {
+        handle_compare_key(code, app);
+        return Ok(false);
+    }
 
-                KeyCode::Char('h') => {
-                    let _ = actions::show_help_message(app);
-                }
+    // So does the key list.
+    if let Some(scroll) = app.keys_overlay {
+        if code == KeyCode::Esc || app.config.keymap.actions_for(code).contains(&Action::KeyHelp) {
+            app.keys_overlay = None;
+        } else {
+            app.keys_overlay = Some(match code {
+                KeyCode::Char('j') | KeyCode::Down => scroll + 1,
+                KeyCode::Char('k') | KeyCode::Up => scroll.saturating_sub(1),
+                KeyCode::PageDown => scroll + 10,
+                KeyCode::PageUp => scroll.saturating_sub(10),
+                _ => scroll,
+            });
+        }
+        return Ok(false);
+    }
 
-                KeyCode::Char('e') => {
-                    // Get the active session and branch
-                    let session_idx = app.active_idx;
-                    let session = &app.sessions[session_idx];
-                    let branch_idx = session.active_branch;
-                    let branch = &session.branches[branch_idx];
-
-                    // Find the most recent user message
-                    if let Some((msg_idx, last_user)) = branch
-                        .messages
-                        .iter()
-                        .enumerate()
-                        .rev()
-                        .find(|(_, m)| matches!(m.from, MessageFrom::User))
-                    {
-                        // Load the message content into the input box
-                        app.input.clear();
-                        app.input.push_str(&last_user.content);
-
-                        // Save edit context: editing will fork a new branch
-                        app.edit_ctx = Some(EditContext {
-                            session_idx,
-                            branch_idx,
-                            message_idx: msg_idx,
-                        });
-
-                        // Switch to INSERT mode so the user can modify the message
-                        app.input_mode = InputMode::Insert;
-                    }
-                }
+    // Typing a search query.
+    if let Some(search) = app.search.as_mut().filter(|s| s.editing) {
+        match code {
+            KeyCode::Enter => app.run_search(),
+            KeyCode::Esc => app.search = None,
+            KeyCode::Backspace => {
+                search.query.pop();
+            }
+            KeyCode::Char(c) => search.query.push(c),
+            _ => {}
+        }
+        return Ok(false);
+    }
 
-                _ => {
-                    // Ignore other keys in normal mode.
-                }
+    match app.input_mode {
+        InputMode::Normal => {
+            // Pressing Enter on the button creates a new session.
+            // Enter on the list does nothing for now.
+            if code == KeyCode::Enter && app.new_button_selected {
+                app.new_session();
+                return Ok(false);
+            }
+            if let Some(action) = pick_action(&app.config.keymap.actions_for(code), app) {
+                return run_action(action, app);
             }
         }
 
@@ -107,39 +113,36 @@ pub fn handle_key_event(code: KeyCode, app: &mut App) -> Result<bool> {
                     app.input_mode = InputMode::Normal;
                 }
 
-                // Handle text input.
+                // Cursor movement; Ctrl+A / Ctrl+E as in a shell.
+                KeyCode::Left => app.cursor_left(),
+                KeyCode::Right => app.cursor_right(),
+                KeyCode::Home => app.cursor_home(),
+                KeyCode::End => app.cursor_end(),
+                KeyCode::Char('a') if ctrl => app.cursor_home(),
+                KeyCode::Char('e') if ctrl => app.cursor_end(),
+
+                // Handle text input at the cursor.
                 KeyCode::Char(c) => {
-                    app.input.push(c);
+                    app.insert_char(c);
                 }
 
                 KeyCode::Backspace => {
-                    app.input.pop();
+                    app.backspace();
+                }
+
+                KeyCode::Delete => {
+                    app.delete_char();
                 }
                 
 
-                // On Enter: send the user message and call Ollama for a response.
+                // On Enter: send the user message (or the pending edit).
                 KeyCode::Enter => {
-                    let msg = app.input.trim().to_string();
-                    if msg.is_empty() {
-                        return Ok(false);
-                    }
-
-                    // Clear input first.
-                    app.input.clear();
-
-                    if let Some(ctx) = app.edit_ctx.take() {
-                        // We are editing an existing user message.
-                        // This will fork a new branch and overwrite that message there.
-                        actions::fork_and_send_from_edit(app, ctx, msg)?;
-                    } else {
-                        // Normal case: send a brand new user message on the active branch.
-                        actions::send_user_message_with_streaming(app, msg)?;
-                    }
+                    actions::submit_input(app)?;
                 }
 
-                // Allow arrow keys in insert mode too.
-                KeyCode::Up => app.prev_session(),
-                KeyCode::Down => app.next_session(),
+                // Recall sent inputs; sessions are switched in NORMAL mode.
+                KeyCode::Up => app.history_prev(),
+                KeyCode::Down => app.history_next(),
 
                 _ => {}
             }
@@ -147,4 +150,246 @@ pub fn handle_key_event(code: KeyCode, app: &mut App) -> Result<bool> {
     }
 
     Ok(false)
-}
\ No newline at end of file
+}
+
+/// The action a NORMAL-mode key triggers. A key can be bound to several:
+/// the ones that only apply in some state (`n` while there are search
+/// matches, Esc while searching or streaming) win when they apply.
+fn pick_action(actions: &[Action], app: &App) -> Option<Action> {
+    let applies = |action: Action| match action {
+        Action::NextMatch => Some(app.has_search_matches()),
+        Action::ClearSearch => Some(app.search.is_some()),
+        Action::StopReply => Some(app.is_streaming()),
+        Action::ClearSelection => Some(app.selected_message().is_some()),
+        _ => None,
+    };
+    actions
+        .iter()
+        .copied()
+        .find(|&a| applies(a) == Some(true))
+        .or_else(|| actions.iter().copied().find(|&a| applies(a).is_none()))
+}
+
+/// Carry out a NORMAL-mode action. Returns Ok(true) if the app should exit.
+fn run_action(action: Action, app: &mut App) -> Result<bool> {
+    match action {
+        // Tell caller to exit the loop.
+        Action::Quit => return Ok(true),
+
+        // Search messages; while there are matches n / N step through them.
+        Action::Search => app.start_search(),
+        Action::NextMatch => app.step_search(true),
+        Action::PrevMatch => app.step_search(false),
+        Action::ClearSearch => app.search = None,
+        Action::StopReply => app.cancel_streaming(),
+
+        Action::NewSession => app.new_session(),
+        Action::NextSession => app.next_session(),
+        Action::PrevSession => app.prev_session(),
+
+        // Scroll the messages a screen at a time, or to either end.
+        Action::PageUp => app.scroll_messages_page(true),
+        Action::PageDown => app.scroll_messages_page(false),
+        Action::ScrollTop => app.scroll_messages_to_top(),
+        Action::ScrollBottom => app.scroll_messages_to_bottom(),
+
+        Action::InsertMode => app.input_mode = InputMode::Insert,
+
+        // Toggles between the button and the list.
+        Action::ToggleNewButton => app.new_button_selected = !app.new_button_selected,
+
+        Action::PrevBranch => app.prev_branch(),
+        Action::NextBranch => app.next_branch(),
+        Action::BranchTree => app.open_branch_overlay(),
+        Action::CompareBranches => app.open_compare(),
+
+        // Rename / delete the selected session.
+        Action::RenameSession => {
+            app.renaming_session = Some(app.sessions[app.active_idx].title.clone());
+        }
+        Action::DeleteSession => app.confirm_delete_session = true,
+        Action::DuplicateSession => actions::duplicate_session(app),
+
+        Action::ToggleSidebar => app.toggle_sidebar(),
+        Action::WidenSidebar => app.resize_sidebar(true),
+        Action::NarrowSidebar => app.resize_sidebar(false),
+        Action::ToggleLineNumbers => app.config.code_line_numbers = !app.config.code_line_numbers,
+        Action::ToggleScrollbars => app.config.scrollbars = !app.config.scrollbars,
+        Action::ToggleStatusBar => app.config.status_bar = !app.config.status_bar,
+        Action::ToggleCollapse => app.toggle_collapse(),
+        Action::ToggleMarkdown => app.config.render_markdown = !app.config.render_markdown,
+        Action::CopyReply => actions::copy_last_assistant_message(app),
+
+        // Pin the system prompt above the messages.
+        Action::PinHeader => {
+            app.pin_header = !app.pin_header;
+            if app.pin_header && app.active_session().system_prompt.is_none() {
+                app.set_status("No system prompt to pin (/system <text>)");
+            }
+        }
+        Action::CollapseHeader => app.header_collapsed = !app.header_collapsed,
+
+        // Summarize the active branch into the header.
+        Action::Summarize => actions::start_summary(app, app.active_idx),
+
+        // Cycle DashScope -> Ollama -> OpenAI-compatible.
+        Action::SwitchBackend => {
+            app.backend = app.backend.next(&app.config);
+            app.set_status(format!("Backend: {}", app.backend.label()));
+        }
+
+        Action::Help => {
+            let _ = actions::show_help_message(app);
+        }
+        Action::KeyHelp => app.keys_overlay = Some(0),
+
+        Action::EditLast => edit_last_user_message(app),
+
+        // Work on one message of the branch without forking it.
+        Action::SelectPrevMessage => app.select_message(true),
+        Action::SelectNextMessage => app.select_message(false),
+        Action::ClearSelection => app.selected_msg = None,
+        Action::EditInPlace => match app.selected_message() {
+            Some(idx) => app.start_edit(idx, true),
+            None => app.set_status("Select a message first (J / K)"),
+        },
+        Action::DeleteMessage => {
+            if app.selected_message().is_some() {
+                app.confirm_delete_message = true;
+            } else {
+                app.set_status("Select a message first (J / K)");
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Load the most recent user message into the input; sending it forks a
+/// new branch.
+fn edit_last_user_message(app: &mut App) {
+    // Get the active session and branch
+    let session = app.active_session();
+    let branch = &session.branches[session.active_branch];
+
+    // Find the most recent user message
+    if let Some(msg_idx) = branch.messages.iter().rposition(|m| matches!(m.from, MessageFrom::User)) {
+        // Load it into the input box; editing will fork a new branch
+        app.start_edit(msg_idx, false);
+    }
+}
+
+/// Pastes larger than this are cut, so wrapping the input stays cheap.
+const MAX_PASTE_BYTES: usize = 256 * 1024;
+
+/// Insert bracketed-paste text at the cursor in one go, keeping newlines.
+pub fn handle_paste(text: String, app: &mut App) {
+    if !app.pending_approvals.is_empty()
+        || app.branch_overlay.is_some()
+        || app.keys_overlay.is_some()
+        || app.compare.is_some()
+    {
+        return;
+    }
+
+    let mut text = text.replace("\r\n", "\n").replace('\r', "\n");
+    if text.len() > MAX_PASTE_BYTES {
+        let mut cut = MAX_PASTE_BYTES;
+        while !text.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        text.truncate(cut);
+        app.set_status(format!("✂️ Paste cut to {} KiB", MAX_PASTE_BYTES / 1024));
+    }
+
+    app.insert_str(&text);
+    app.input_mode = InputMode::Insert;
+}
+
+/// Keys for the branch overlay: j/k move, space toggles selection,
+/// Enter switches, c copies the branch under the cursor, d deletes the
+/// selection (after y/n), Esc closes.
+fn handle_branch_overlay_key(code: KeyCode, app: &mut App) {
+    // The cursor walks the branches in the order the tree draws them.
+    let order: Vec<usize> = app.sessions[app.active_idx]
+        .branch_tree()
+        .into_iter()
+        .map(|(idx, _)| idx)
+        .collect();
+    let Some(overlay) = app.branch_overlay.as_mut() else {
+        return;
+    };
+
+    if overlay.confirm_delete {
+        match code {
+            KeyCode::Char('y') | KeyCode::Char('Y') => {
+                app.delete_selected_branches();
+            }
+            _ => overlay.confirm_delete = false,
+        }
+        return;
+    }
+
+    match code {
+        KeyCode::Esc | KeyCode::Char('q') | KeyCode::Char('b') => {
+            app.branch_overlay = None;
+        }
+        KeyCode::Char('j') | KeyCode::Down => {
+            let pos = order.iter().position(|&i| i == overlay.cursor).unwrap_or(0);
+            if let Some(&next) = order.get(pos + 1) {
+                overlay.cursor = next;
+            }
+        }
+        KeyCode::Char('k') | KeyCode::Up => {
+            let pos = order.iter().position(|&i| i == overlay.cursor).unwrap_or(0);
+            if let Some(&prev) = pos.checked_sub(1).and_then(|p| order.get(p)) {
+                overlay.cursor = prev;
+            }
+        }
+        KeyCode::Char(' ') => {
+            if overlay.selected.contains(&overlay.cursor) {
+                overlay.selected.remove(&overlay.cursor);
+            } else {
+                overlay.selected.insert(overlay.cursor);
+            }
+        }
+        KeyCode::Char('d') if !overlay.selected.is_empty() => {
+            overlay.confirm_delete = true;
+        }
+        KeyCode::Char('c') => {
+            let cursor = overlay.cursor;
+            app.branch_overlay = None;
+            actions::duplicate_branch(app, cursor);
+        }
+        KeyCode::Enter => {
+            let cursor = overlay.cursor;
+            app.sessions[app.active_idx].active_branch = cursor;
+            app.msg_scroll = 0;
+            app.branch_overlay = None;
+        }
+        _ => {}
+    }
+}
+
+/// Keys for the branch comparison: Tab / ← / → pick the column, [ / ]
+/// change its branch, j/k and the page keys scroll both columns, Esc
+/// closes.
+fn handle_compare_key(code: KeyCode, app: &mut App) {
+    let Some(compare) = app.compare.as_mut() else {
+        return;
+    };
+    match code {
+        KeyCode::Esc | KeyCode::Char('q') | KeyCode::Char('C') => app.close_compare(),
+        KeyCode::Tab => compare.focus = 1 - compare.focus,
+        KeyCode::Left | KeyCode::Char('h') => compare.focus = 0,
+        KeyCode::Right | KeyCode::Char('l') => compare.focus = 1,
+        KeyCode::Char('[') => app.cycle_compare_branch(false),
+        KeyCode::Char(']') => app.cycle_compare_branch(true),
+        KeyCode::Char('j') | KeyCode::Down => app.msg_scroll = (app.msg_scroll + 1).min(app.msg_scroll_max),
+        KeyCode::Char('k') | KeyCode::Up => app.msg_scroll = app.msg_scroll.saturating_sub(1),
+        KeyCode::PageDown | KeyCode::Char(' ') => app.scroll_messages_page(false),
+        KeyCode::PageUp => app.scroll_messages_page(true),
+        KeyCode::Char('g') | KeyCode::Home => app.scroll_messages_to_top(),
+        KeyCode::Char('G') | KeyCode::End => app.scroll_messages_to_bottom(),
+        _ => {}
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+
+use crossterm::event::KeyCode;
+use serde::Deserialize;
+
+/// NORMAL-mode commands. Each can be bound to other keys under `[keys]` in
+/// the config file; INSERT mode keeps its fixed text-editing keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    Quit,
+    NewSession,
+    Search,
+    NextMatch,
+    PrevMatch,
+    ClearSearch,
+    StopReply,
+    ClearSelection,
+    RenameSession,
+    DeleteSession,
+    DuplicateSession,
+    NextSession,
+    PrevSession,
+    PageUp,
+    PageDown,
+    ScrollTop,
+    ScrollBottom,
+    PrevBranch,
+    NextBranch,
+    BranchTree,
+    CompareBranches,
+    ToggleNewButton,
+    ToggleSidebar,
+    WidenSidebar,
+    NarrowSidebar,
+    ToggleMarkdown,
+    ToggleLineNumbers,
+    ToggleScrollbars,
+    ToggleStatusBar,
+    ToggleCollapse,
+    CopyReply,
+    PinHeader,
+    CollapseHeader,
+    Summarize,
+    SwitchBackend,
+    EditLast,
+    SelectPrevMessage,
+    SelectNextMessage,
+    EditInPlace,
+    DeleteMessage,
+    Help,
+    KeyHelp,
+    InsertMode,
+}
+
+/// Every action with its config name, help text and default keys, in the
+/// order the help lists them.
+const ACTIONS: &[(Action, &str, &str, &[&str])] = &[
+    (Action::Quit, "quit", "Quit", &["q"]),
+    (Action::NewSession, "new_session", "New session", &["n"]),
+    (Action::Search, "search", "Search messages", &["/"]),
+    (Action::NextMatch, "next_match", "Next search match", &["n"]),
+    (Action::PrevMatch, "prev_match", "Previous search match", &["N"]),
+    (Action::ClearSearch, "clear_search", "Clear the search", &["Esc"]),
+    (Action::StopReply, "stop_reply", "Stop the reply being streamed", &["Esc"]),
+    (Action::ClearSelection, "clear_selection", "Clear the message selection", &["Esc"]),
+    (Action::RenameSession, "rename_session", "Rename selected session", &["r"]),
+    (Action::DeleteSession, "delete_session", "Delete selected session", &["d"]),
+    (Action::DuplicateSession, "duplicate_session", "Duplicate selected session", &["D"]),
+    (Action::NextSession, "next_session", "Next session", &["j", "Down"]),
+    (Action::PrevSession, "prev_session", "Previous session", &["k", "Up"]),
+    (Action::PageUp, "page_up", "Scroll messages a screen up", &["PageUp"]),
+    (Action::PageDown, "page_down", "Scroll messages a screen down", &["PageDown"]),
+    (Action::ScrollTop, "scroll_top", "Jump to top of messages", &["g", "Home"]),
+    (Action::ScrollBottom, "scroll_bottom", "Jump to bottom of messages", &["G", "End"]),
+    (Action::PrevBranch, "prev_branch", "Previous branch", &["["]),
+    (Action::NextBranch, "next_branch", "Next branch", &["]"]),
+    (Action::BranchTree, "branch_tree", "Branch tree (enter switch, space select, c copy, d delete)", &["b"]),
+    (Action::CompareBranches, "compare_branches", "Compare two branches side by side", &["C"]),
+    (Action::ToggleNewButton, "toggle_new_button", "Toggle new-session button", &["Tab"]),
+    (Action::ToggleSidebar, "toggle_sidebar", "Toggle sidebar", &["s"]),
+    (Action::WidenSidebar, "widen_sidebar", "Widen sidebar", &[">"]),
+    (Action::NarrowSidebar, "narrow_sidebar", "Narrow sidebar", &["<"]),
+    (Action::ToggleMarkdown, "toggle_markdown", "Toggle Markdown rendering", &["m"]),
+    (Action::ToggleLineNumbers, "toggle_line_numbers", "Toggle code block line numbers", &["l"]),
+    (Action::ToggleScrollbars, "toggle_scrollbars", "Toggle scrollbars", &["v"]),
+    (Action::ToggleStatusBar, "toggle_status_bar", "Toggle the token / input length line", &["t"]),
+    (Action::ToggleCollapse, "toggle_collapse", "Expand / collapse the selected (or newest) long message", &["z"]),
+    (Action::CopyReply, "copy_reply", "Copy last reply to clipboard", &["y"]),
+    (Action::PinHeader, "pin_header", "Pin system prompt above messages", &["p"]),
+    (Action::CollapseHeader, "collapse_header", "Collapse / expand the pinned header", &["P"]),
+    (Action::Summarize, "summarize", "Summarize the branch into the header", &["S"]),
+    (Action::SwitchBackend, "switch_backend", "Switch backend (DashScope / Ollama / OpenAI-compatible)", &["o"]),
+    (Action::EditLast, "edit_last", "Edit last user message", &["e"]),
+    (Action::SelectPrevMessage, "select_prev_message", "Select previous message", &["K"]),
+    (Action::SelectNextMessage, "select_next_message", "Select next message", &["J"]),
+    (Action::EditInPlace, "edit_in_place", "Edit selected message in place (no new reply)", &["E"]),
+    (Action::DeleteMessage, "delete_message", "Delete selected message and its reply", &["X"]),
+    (Action::Help, "help", "Show help", &["h"]),
+    (Action::KeyHelp, "key_help", "Show / hide this key list", &["?"]),
+    (Action::InsertMode, "insert_mode", "Enter insert mode", &["i"]),
+];
+
+/// INSERT-mode keys, which cannot be rebound, with their help text.
+pub const INSERT_KEYS: &[(&str, &str)] = &[
+    ("Enter", "Send message"),
+    ("← / →", "Move cursor"),
+    ("↑ / ↓", "Recall earlier / later sent inputs"),
+    ("Home / End", "Start / end of line (also Ctrl+A / Ctrl+E)"),
+    ("Esc", "Back to normal mode"),
+];
+
+/// One key or several for an action under `[keys]`:
+/// `quit = "Q"`, `next_session = ["j", "Down"]`; `[]` unbinds it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum KeySpec {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Which keys trigger which NORMAL-mode action.
+#[derive(Debug, Clone)]
+pub struct KeyMap {
+    /// Same order as `ACTIONS`.
+    bindings: Vec<(Action, Vec<KeyCode>)>,
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        let bindings = ACTIONS
+            .iter()
+            .map(|(action, _, _, keys)| (*action, keys.iter().filter_map(|k| parse_key(k)).collect()))
+            .collect();
+        Self { bindings }
+    }
+}
+
+impl KeyMap {
+    /// Replace the keys of the actions named in `overrides`. Unknown action
+    /// names and unreadable keys are ignored, like other bad config values.
+    pub fn apply(&mut self, overrides: &HashMap<String, KeySpec>) {
+        for (name, spec) in overrides {
+            let Some(action) = ACTIONS.iter().find(|(_, n, _, _)| n == name).map(|(a, ..)| *a) else {
+                continue;
+            };
+            let keys: Vec<KeyCode> = match spec {
+                KeySpec::One(key) => parse_key(key).into_iter().collect(),
+                KeySpec::Many(keys) => keys.iter().filter_map(|k| parse_key(k)).collect(),
+            };
+            if let Some((_, bound)) = self.bindings.iter_mut().find(|(a, _)| *a == action) {
+                *bound = keys;
+            }
+        }
+    }
+
+    /// Actions bound to `code`, in `ACTIONS` order.
+    pub fn actions_for(&self, code: KeyCode) -> Vec<Action> {
+        self.bindings
+            .iter()
+            .filter(|(_, keys)| keys.contains(&code))
+            .map(|(action, _)| *action)
+            .collect()
+    }
+
+    /// Keys currently bound to `action`.
+    pub fn keys(&self, action: Action) -> &[KeyCode] {
+        self.bindings
+            .iter()
+            .find(|(a, _)| *a == action)
+            .map(|(_, keys)| keys.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Every action with its help text and current keys, for the help.
+    pub fn entries(&self) -> impl Iterator<Item = (&'static str, &[KeyCode])> {
+        self.bindings.iter().map(|(action, keys)| {
+            let help = ACTIONS.iter().find(|(a, ..)| a == action).map(|(_, _, help, _)| *help).unwrap_or("");
+            (help, keys.as_slice())
+        })
+    }
+
+    /// "j / Down" for the help; "(unbound)" when the action has no key.
+    pub fn keys_label(keys: &[KeyCode]) -> String {
+        if keys.is_empty() {
+            return "(unbound)".to_string();
+        }
+        keys.iter().map(|k| key_name(*k)).collect::<Vec<_>>().join(" / ")
+    }
+}
+
+/// A key as written in the config file: a single character, a name such as
+/// `Up`, `PageDown`, `Esc`, `Tab`, `Space`, or `F1`..`F12`.
+pub fn parse_key(text: &str) -> Option<KeyCode> {
+    let mut chars = text.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(KeyCode::Char(c));
+    }
+    let code = match text.to_ascii_lowercase().as_str() {
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "pageup" | "pgup" => KeyCode::PageUp,
+        "pagedown" | "pgdn" => KeyCode::PageDown,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "tab" => KeyCode::Tab,
+        "enter" => KeyCode::Enter,
+        "esc" | "escape" => KeyCode::Esc,
+        "backspace" => KeyCode::Backspace,
+        "delete" | "del" => KeyCode::Delete,
+        "space" => KeyCode::Char(' '),
+        other => {
+            let n: u8 = other.strip_prefix('f')?.parse().ok()?;
+            return (1..=12).contains(&n).then_some(KeyCode::F(n));
+        }
+    };
+    Some(code)
+}
+
+/// How `parse_key` would spell `code`.
+pub fn key_name(code: KeyCode) -> String {
+    match code {
+        KeyCode::Char(' ') => "Space".to_string(),
+        KeyCode::Char(c) => c.to_string(),
+        KeyCode::PageUp => "PgUp".to_string(),
+        KeyCode::PageDown => "PgDn".to_string(),
+        KeyCode::F(n) => format!("F{n}"),
+        other => format!("{other:?}"),
+    }
+}
@@ -1,4 +1,5 @@
 pub mod keyboard;
+pub mod keymap;
 pub mod actions;
 pub mod mouse;
 pub mod api_key;
\ No newline at end of file
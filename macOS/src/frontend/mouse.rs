@@ -1,10 +1,10 @@
 use anyhow::Result;
 use crossterm::{
-    event::{MouseEvent, MouseEventKind, MouseButton},
+    event::{KeyModifiers, MouseEvent, MouseEventKind, MouseButton},
 };
 use ratatui::layout::Rect;
 
-use crate::app::{App, EditContext, InputMode};
+use crate::app::App;
 use crate::frontend::actions;
 
 // const SESSION_LIST_ROW_START: u16 = 4;
@@ -14,6 +14,16 @@ use crate::frontend::actions;
 
 /// Handle mouse events such as clicking and scrolling.
 pub fn handle_mouse_event(me: MouseEvent, app: &mut App) -> Result<()> {
+    // The key list is modal: the wheel scrolls it, everything else waits.
+    if let Some(scroll) = app.keys_overlay {
+        app.keys_overlay = Some(match me.kind {
+            MouseEventKind::ScrollDown => scroll + 1,
+            MouseEventKind::ScrollUp => scroll.saturating_sub(1),
+            _ => scroll,
+        });
+        return Ok(());
+    }
+
     match me.kind {
         MouseEventKind::Moved => {
             // Mouse hover detection
@@ -41,6 +51,36 @@ pub fn handle_mouse_event(me: MouseEvent, app: &mut App) -> Result<()> {
 
             app.hovered_user_msg = hovered;
         }
+        // Press on the message scrollbar starts a drag and jumps there.
+        MouseEventKind::Down(MouseButton::Left) => {
+            if let Some(track) = app.msg_scrollbar_area {
+                if point_in_rect(me.column, me.row, track) {
+                    app.dragging_scrollbar = true;
+                    app.drag_scrollbar_to(me.row);
+                    return Ok(());
+                }
+            }
+            // Press on the border between sidebar and chat (either panel's
+            // edge column) starts a resize.
+            let border = app.sidebar_width();
+            if !app.sidebar_collapsed && (me.column + 1 == border || me.column == border) {
+                app.dragging_sidebar = true;
+            }
+        }
+        MouseEventKind::Drag(MouseButton::Left) if app.dragging_scrollbar => {
+            app.drag_scrollbar_to(me.row);
+        }
+        MouseEventKind::Drag(MouseButton::Left) if app.dragging_sidebar => {
+            app.set_sidebar_width(me.column + 1);
+        }
+        MouseEventKind::Up(MouseButton::Left) if app.dragging_scrollbar => {
+            app.dragging_scrollbar = false;
+        }
+        // Release ends the resize without counting as a click.
+        MouseEventKind::Up(MouseButton::Left) if app.dragging_sidebar => {
+            app.dragging_sidebar = false;
+            app.save_ui_prefs();
+        }
         // LEFT CLICK
         MouseEventKind::Up(MouseButton::Left) => {
             let x = me.column;
@@ -73,7 +113,7 @@ pub fn handle_mouse_event(me: MouseEvent, app: &mut App) -> Result<()> {
                     .find(|(_, r)| point_in_rect(x, y, *r))
                 {
                     app.active_idx = *idx;
-                    app.list_state.select(Some(app.active_idx));
+                    app.select_active_in_list();
                     app.new_button_selected = false;
                 }
 
@@ -85,41 +125,31 @@ pub fn handle_mouse_event(me: MouseEvent, app: &mut App) -> Result<()> {
             if let Some(area) = app.send_button_area {
                 if point_in_rect(x, y, area) {
                     // Click is inside the send button.
-                    let msg = app.input.trim().to_string();
-                    if !msg.is_empty() {
-                        // Let the actions module handle sending + streaming.
-                        actions::send_user_message_with_streaming(app, msg)?;
-                    }
+                    // Let the actions module handle sending + streaming.
+                    actions::submit_input(app)?;
                     return Ok(());
                 }
             }
 
             // 2) Check if the click is on a user message line (= edit / fork).
+            //    With Alt held the message is edited in place instead.
             if let Some((msg_idx, r)) = app.edit_area {
                 if point_in_rect(x, y, r) {
-                    let session_idx = app.active_idx;
-                    let branch_idx = app.sessions[session_idx].active_branch;
-
-                    // Fetch the original user message.
-                    let msg = &app.sessions[session_idx]
-                        .branches[branch_idx]
-                        .messages[msg_idx];
-
-                    // Pre-fill the input box with the message content.
-                    app.input = msg.content.clone();
-                    app.input_mode = InputMode::Insert;
-
-                    // Store edit context so that pressing Enter will fork a new branch
-                    // starting from this message.
-                    app.edit_ctx = Some(EditContext {
-                        session_idx,
-                        branch_idx,
-                        message_idx: msg_idx,
-                    });
-
+                    app.start_edit(msg_idx, me.modifiers.contains(KeyModifiers::ALT));
                     return Ok(());
                 }
             }
+
+            // 3) A click on a "… +N lines" marker expands its message.
+            if let Some(&(msg_idx, _)) = app.collapse_hitboxes.iter().find(|(_, r)| point_in_rect(x, y, *r)) {
+                app.toggle_expanded(msg_idx);
+                return Ok(());
+            }
+
+            // 4) A click on any message selects it (or clears the selection).
+            if let Some(&(msg_idx, _)) = app.msg_line_hitboxes.iter().find(|(_, r)| point_in_rect(x, y, *r)) {
+                app.toggle_message_selection(msg_idx);
+            }
         }
 
         // SCROLL UP
@@ -129,23 +159,22 @@ pub fn handle_mouse_event(me: MouseEvent, app: &mut App) -> Result<()> {
             let sidebar_width = app.sidebar_width();
 
             if x < sidebar_width {
-                // Scroll the session list: move active index up.
-                if app.active_idx > 0 {
-                    app.active_idx -= 1;
-                    app.list_state.select(Some(app.active_idx));
-                }
+                // Scroll the session list: select the session above.
+                app.prev_session();
             } else if let Some(area) = app.input_area {
                 // If mouse is inside the input area, scroll the input box.
                 if y >= area.y && y < area.y + area.height {
                     // Move view further up in the input (offset from bottom).
                     app.input_scroll = app.input_scroll.saturating_add(1);
-                } else if app.msg_scroll > 0 {
-                    // Otherwise scroll the message area up.
-                    app.msg_scroll -= 1;
+                } else {
+                    // Otherwise scroll the message area up, and stop following the stream.
+                    app.msg_scroll = app.msg_scroll.saturating_sub(1);
+                    app.follow_bottom = false;
                 }
-            } else if app.msg_scroll > 0 {
+            } else {
                 // Fallback: if we don't yet have an input rect, just scroll messages.
-                app.msg_scroll -= 1;
+                app.msg_scroll = app.msg_scroll.saturating_sub(1);
+                app.follow_bottom = false;
             }
         }
 
@@ -156,11 +185,8 @@ pub fn handle_mouse_event(me: MouseEvent, app: &mut App) -> Result<()> {
             let sidebar_width = app.sidebar_width();
 
             if x < sidebar_width {
-                // Scroll the session list: move active index down.
-                if app.active_idx + 1 < app.sessions.len() {
-                    app.active_idx += 1;
-                    app.list_state.select(Some(app.active_idx));
-                }
+                // Scroll the session list: select the session below.
+                app.next_session();
             } else if let Some(area) = app.input_area {
                 if y >= area.y && y < area.y + area.height {
                     // Scroll input back towards the bottom.
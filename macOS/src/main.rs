@@ -1,33 +1,49 @@
 mod app;
+#[path = "../../shared/audit.rs"]
+mod audit;
+mod backend;
+mod config;
+mod error;
+mod markdown;
+#[path = "../../shared/mcp_client.rs"]
+mod mcp_client;
+#[path = "../../shared/trash.rs"]
+mod trash;
 mod tui;
 mod frontend;
 
 use std::{
     io::{stdout, Stdout},
     time::Duration,
-    sync::mpsc,
-    env, 
-    process::Command
+    sync::Arc,
 };
+#[cfg(target_os = "macos")]
+use std::{env, process::Command};
 
 use anyhow::Result;
 use crossterm::{
-    event::{self, Event, EnableMouseCapture, DisableMouseCapture},
+    event::{Event, EventStream, EnableBracketedPaste, DisableBracketedPaste, EnableMouseCapture, DisableMouseCapture},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use frontend::keyboard::handle_key_event;
+use futures_util::StreamExt;
+use tokio::sync::mpsc;
+
+use frontend::actions::{http_client, maybe_auto_summarize, maybe_auto_title, start_mcp_servers};
+use frontend::keyboard::{handle_key_event, handle_paste};
 use frontend::mouse::handle_mouse_event;
 use ratatui::{backend::CrosstermBackend, Terminal};
 
-use crate::app::{App, BackendEvent};
+use crate::app::{App, BackendEvent, ToolApproval};
+use crate::backend::MockBackend;
+use crate::config::Config;
 use crate::tui::ui as draw_ui;
 
 /// Initialize terminal in raw mode and enter an alternate screen.
 fn setup_terminal() -> Result<Terminal<CrosstermBackend<Stdout>>> {
     enable_raw_mode()?;
     let mut out = stdout();
-    execute!(out, EnterAlternateScreen, EnableMouseCapture)?;
+    execute!(out, EnterAlternateScreen, EnableMouseCapture, EnableBracketedPaste)?;
     let backend = CrosstermBackend::new(out);
     let terminal = Terminal::new(backend)?;
     Ok(terminal)
@@ -36,7 +52,7 @@ fn setup_terminal() -> Result<Terminal<CrosstermBackend<Stdout>>> {
 /// Restore terminal back to normal mode.
 fn restore_terminal(mut terminal: Terminal<CrosstermBackend<Stdout>>) -> Result<()> {
     disable_raw_mode()?;
-    execute!(terminal.backend_mut(), LeaveAlternateScreen, DisableMouseCapture)?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen, DisableMouseCapture, DisableBracketedPaste)?;
     terminal.show_cursor()?;
     Ok(())
 }
@@ -51,60 +67,146 @@ fn main() -> Result<()> {
         }
     }
 
-    run_tui()
+    // Replies, summaries and tools run as tasks on this runtime; the UI
+    // loop below is one of them.
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run_tui())
 }
 
-fn run_tui() -> Result<()> {
+async fn run_tui() -> Result<()> {
+    // A broken config file would quietly drop every setting in it.
+    let config = Config::load()?;
+    // A bad proxy or header would send every request the wrong way; say so
+    // before the TUI takes over the screen.
+    http_client(&config.http)?;
     let mut terminal = setup_terminal()?;
 
-    let mut app = App::new();
+    let mut app = App::new(config);
+    let args: Vec<String> = std::env::args().collect();
+    app.config.highlight_code = !args.iter().any(|a| a == "--no-highlight");
+    app.config.ollama.apply_args(&args);
+    // `--mock` answers with canned text, no network needed.
+    if args.iter().any(|a| a == "--mock") {
+        app.backend = Arc::new(MockBackend::default());
+    }
+    app.tool_policy.shell_timeout = app.config.shell_timeout;
+    app.tool_policy.shell = app.config.shell;
+    app.tool_policy.backup_on_write = app.config.backup_on_write;
+    app.tool_policy.max_agent_steps = app.config.max_agent_steps;
+    // `--safe` keeps the agent read-only for this run.
+    app.tool_policy.safe_mode = app.config.safe_mode || args.iter().any(|a| a == "--safe");
+    if let Some(roots) = app.config.allowed_roots.clone() {
+        app.tool_policy.allowed_roots = roots;
+    }
+    // `--allow-root <dir>` (repeatable) widens the file tools' sandbox.
+    for pair in args.windows(2).filter(|pair| pair[0] == "--allow-root") {
+        app.tool_policy.allowed_roots.push(pair[1].clone().into());
+    }
     
     // Create a channel for backend events (assistant streaming).
-    let (tx, rx) = mpsc::channel::<BackendEvent>();
+    let (tx, mut rx) = mpsc::unbounded_channel::<BackendEvent>();
     app.backend_tx = Some(tx);
+    start_mcp_servers(&mut app);
+    app.purge_trash();
 
-    loop {
-        // 0) Drain backend events before drawing
-        while let Ok(event) = rx.try_recv() {
-            match event {
-                BackendEvent::AssistantChunk { session_idx, branch_idx, chunk } => {
-                    app.append_assistant_chunk(session_idx, branch_idx, chunk);
-                }
-                BackendEvent::AssistantDone { session_idx, branch_idx, } => {
-                    app.save_to_logs().ok();
-                    app.finish_streaming(session_idx, branch_idx);
-                }
+    let mut events = EventStream::new();
+    // Redraw now and then even when idle, for the spinner and status timeouts.
+    let mut tick = tokio::time::interval(Duration::from_millis(50));
 
-            }
-        }
+    loop {
+        app.prune_workers();
 
         // 1) Draw the UI based on current state.
         terminal.draw(|f| draw_ui(f, &mut app))?;
 
-        // 2) Handle input events (non-blocking poll).
-        if event::poll(Duration::from_millis(50))? {
-            match event::read()? {
-                Event::Key(key) => {
-                    // Delegate key handling to keyboard::handle_key_event.
-                    // If it returns true, we should exit the loop.
-                    if handle_key_event(key.code, &mut app)? {
+        // 2) Wait for whichever comes first: a backend event, input, or the tick.
+        tokio::select! {
+            Some(event) = rx.recv() => {
+                handle_backend_event(event, &mut app);
+                // Take the rest of a burst of chunks before drawing again.
+                while let Ok(event) = rx.try_recv() {
+                    handle_backend_event(event, &mut app);
+                }
+            }
+            event = events.next() => match event {
+                Some(Ok(event)) => {
+                    if handle_terminal_event(event, &mut app)? {
                         break;
                     }
                 }
-                Event::Mouse(m) => {
-                    handle_mouse_event(m, &mut app)?;
-                }
-                _ => {
-                    // Ignore other events (e.g. Resize) for now.
-                }
-            }
+                Some(Err(e)) => return Err(e.into()),
+                None => break,
+            },
+            _ = tick.tick() => {}
         }
     }
     // After breaking out of the loop, restore the terminal and exit cleanly.
     restore_terminal(terminal)?;
+
+    // Give in-flight workers a moment to finish before exiting.
+    if app.join_workers(Duration::from_secs(2)).await > 0 {
+        eprintln!("⚠️ Exiting with background requests still running.");
+    }
     Ok(())
 }
 
+/// Apply one event from a worker task to the app state.
+fn handle_backend_event(event: BackendEvent, app: &mut App) {
+    match event {
+        BackendEvent::AssistantChunk { session_idx, branch_idx, chunk } => {
+            app.append_assistant_chunk(session_idx, branch_idx, chunk);
+        }
+        BackendEvent::AssistantDone { session_idx, branch_idx, } => {
+            app.finish_streaming(session_idx, branch_idx);
+            app.autosave_session(session_idx);
+            maybe_auto_summarize(app, session_idx, branch_idx);
+            maybe_auto_title(app, session_idx, branch_idx);
+        }
+        BackendEvent::SummaryDone { session_idx, result } => {
+            app.finish_summary(session_idx, result);
+        }
+        BackendEvent::TitleDone { session_id, result } => {
+            app.finish_title(&session_id, result);
+        }
+        BackendEvent::ToolResult { session_idx, branch_idx, name, result } => {
+            app.add_tool_result(session_idx, branch_idx, name, result);
+        }
+        BackendEvent::AssistantSources { session_idx, branch_idx, sources } => {
+            app.add_assistant_sources(session_idx, branch_idx, sources);
+        }
+        BackendEvent::McpServersReady { servers, failures } => {
+            app.add_mcp_servers(servers, failures);
+        }
+        BackendEvent::ToolApprovalRequested { command, reply } => {
+            app.pending_approvals.push_back(ToolApproval { command, reply });
+        }
+        BackendEvent::Status(message) => app.set_status(message),
+        BackendEvent::Error { session_idx, branch_idx, message } => {
+            app.fail_streaming(session_idx, branch_idx, message);
+        }
+    }
+}
+
+/// Handle one terminal event. Returns true when the app should exit.
+fn handle_terminal_event(event: Event, app: &mut App) -> Result<bool> {
+    match event {
+        // Delegate key handling to keyboard::handle_key_event.
+        // If it returns true, we should exit the loop.
+        Event::Key(key) => return handle_key_event(key, app),
+        Event::Mouse(m) => {
+            handle_mouse_event(m, app)?;
+        }
+        // Pasted text arrives in one piece; its newlines never send.
+        Event::Paste(text) => handle_paste(text, app),
+        _ => {
+            // Ignore other events (e.g. Resize) for now.
+        }
+    }
+    Ok(false)
+}
+
 #[cfg(target_os = "macos")]
 fn pop_out_terminal() -> Result<()> {
     // Find the current executable path.
@@ -117,12 +219,14 @@ fn pop_out_terminal() -> Result<()> {
     let script = format!(
         r#"tell application "Terminal"
     activate
-    do script "cd '{}'; export MYCLI_POPPED=1; '{}'"
+    do script "cd '{}'; export MYCLI_POPPED=1; '{}'{}"
     set number of columns of front window to 120
     set number of rows of front window to 40
 end tell"#,
         cwd.display(),
-        exe.display()
+        exe.display(),
+        // Forward our own flags (e.g. --no-highlight) to the new window.
+        env::args().skip(1).map(|a| format!(" '{a}'")).collect::<String>()
     );
 
     Command::new("osascript")
@@ -0,0 +1,394 @@
+use ratatui::{
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+};
+use std::ops::Range;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// Cells given to an emoji cluster joined with ZWJ (or forced to emoji
+/// presentation with VS16). Terminals draw these as one wide glyph, while
+/// unicode-width sums the parts or reports 0.
+const EMOJI_CLUSTER_WIDTH: usize = 2;
+
+/// Display width of one grapheme cluster.
+pub fn grapheme_width(g: &str) -> usize {
+    if g.contains(['\u{200D}', '\u{FE0F}']) {
+        EMOJI_CLUSTER_WIDTH
+    } else {
+        UnicodeWidthStr::width(g)
+    }
+}
+
+/// Display width of `s`, measured per grapheme cluster.
+pub fn display_width(s: &str) -> usize {
+    s.graphemes(true).map(grapheme_width).sum()
+}
+
+fn is_space(g: &str) -> bool {
+    g.chars().all(char::is_whitespace)
+}
+
+/// Break grapheme clusters into lines of at most `first` display columns
+/// for the first line and `rest` for the others, returned as index ranges.
+/// Lines end after the last space that fits; only a word longer than a
+/// whole line is split. Every line holds at least one grapheme, so a glyph
+/// wider than the line still makes progress. With `drop_spaces`, the spaces
+/// at a break belong to no line, as in prose; otherwise they stay at the
+/// end of the line before it, as the input box and code need.
+pub fn wrap_ranges(graphemes: &[&str], first: usize, rest: usize, drop_spaces: bool) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    let mut width = first;
+    while start < graphemes.len() {
+        let mut used = 0;
+        let mut end = start;
+        // A break needs a word before it, so indentation alone never
+        // makes up a line.
+        let mut after_space = None;
+        let mut seen_word = false;
+        while end < graphemes.len() {
+            let w = grapheme_width(graphemes[end]);
+            if used + w > width && end > start {
+                break;
+            }
+            if !is_space(graphemes[end]) {
+                seen_word = true;
+            } else if seen_word {
+                after_space = Some(end + 1);
+            }
+            used += w;
+            end += 1;
+        }
+
+        let mut next = end;
+        if end < graphemes.len() && !is_space(graphemes[end]) {
+            // Mid-word: go back to the last space, unless the word fills
+            // the line. After only indentation, move the word down if it
+            // fits on a line of its own.
+            let indent_end = start + graphemes[start..end].iter().take_while(|g| is_space(g)).count();
+            let word_width: usize = graphemes[indent_end..]
+                .iter()
+                .take_while(|g| !is_space(g))
+                .map(|g| grapheme_width(g))
+                .sum();
+            let cut = after_space.or((indent_end > start && word_width <= rest).then_some(indent_end));
+            if let Some(cut) = cut {
+                end = cut;
+                next = cut;
+            }
+        }
+        if drop_spaces && next < graphemes.len() {
+            while end > start + 1 && is_space(graphemes[end - 1]) {
+                end -= 1;
+            }
+            while next < graphemes.len() && is_space(graphemes[next]) {
+                next += 1;
+            }
+        }
+
+        ranges.push(start..end);
+        start = next;
+        width = rest;
+    }
+    ranges
+}
+
+/// Style one line of Markdown: headings, bullet lists and inline
+/// `**bold**`, `*italic*` / `_italic_` and `` `code` ``.
+pub fn markdown_line(seg: &str) -> Vec<Span<'static>> {
+    let trimmed = seg.trim_start();
+    let indent = &seg[..seg.len() - trimmed.len()];
+
+    // Headings: "# Title" .. "###### Title"
+    let hashes = trimmed.chars().take_while(|&c| c == '#').count();
+    if (1..=6).contains(&hashes) && trimmed[hashes..].starts_with(' ') {
+        let mut style = Style::default().add_modifier(Modifier::BOLD);
+        if hashes == 1 {
+            style = style.add_modifier(Modifier::UNDERLINED);
+        }
+        let mut spans = Vec::new();
+        inline(trimmed[hashes..].trim(), style, &mut spans);
+        return spans;
+    }
+
+    // Bullets: "- item", "* item", "+ item"
+    for marker in ["- ", "* ", "+ "] {
+        if let Some(item) = trimmed.strip_prefix(marker) {
+            let mut spans = vec![Span::raw(format!("{indent}• "))];
+            inline(item, Style::default(), &mut spans);
+            return spans;
+        }
+    }
+
+    let mut spans = Vec::new();
+    inline(seg, Style::default(), &mut spans);
+    spans
+}
+
+/// Split inline emphasis and code spans out of `text`. Markers without a
+/// matching closing marker are kept as literal text.
+fn inline(text: &str, base: Style, out: &mut Vec<Span<'static>>) {
+    let mut plain = String::new();
+    let mut prev: Option<char> = None;
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        if let Some((marker, style)) = marker_at(rest, prev, base) {
+            let body = &rest[marker.len()..];
+            if let Some(end) = body.find(marker) {
+                let inner = &body[..end];
+                if !inner.is_empty() && !inner.starts_with(' ') {
+                    if !plain.is_empty() {
+                        out.push(Span::styled(std::mem::take(&mut plain), base));
+                    }
+                    if marker == "`" {
+                        out.push(Span::styled(inner.to_string(), style));
+                    } else {
+                        inline(inner, style, out);
+                    }
+                    rest = &body[end + marker.len()..];
+                    prev = marker.chars().last();
+                    continue;
+                }
+            }
+        }
+
+        plain.push(c);
+        prev = Some(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    if !plain.is_empty() {
+        out.push(Span::styled(plain, base));
+    }
+}
+
+/// The inline marker starting `rest`, if any, and the style it applies.
+fn marker_at(rest: &str, prev: Option<char>, base: Style) -> Option<(&'static str, Style)> {
+    if rest.starts_with("**") {
+        Some(("**", base.add_modifier(Modifier::BOLD)))
+    } else if rest.starts_with('`') {
+        Some(("`", Style::default().fg(Color::Yellow)))
+    } else if rest.starts_with('*') {
+        Some(("*", base.add_modifier(Modifier::ITALIC)))
+    } else if rest.starts_with('_') && !prev.is_some_and(|p| p.is_alphanumeric()) {
+        // Only at a word boundary, so snake_case stays untouched.
+        Some(("_", base.add_modifier(Modifier::ITALIC)))
+    } else {
+        None
+    }
+}
+
+/// Styled grapheme clusters of `spans`, in order.
+fn styled_graphemes<'a>(spans: &'a [Span<'static>]) -> Vec<(&'a str, Style)> {
+    spans
+        .iter()
+        .flat_map(|s| s.content.graphemes(true).map(move |g| (g, s.style)))
+        .collect()
+}
+
+/// Append `cells` to `line`, one span per run of the same style.
+fn push_styled(line: &mut Vec<Span<'static>>, cells: &[(&str, Style)]) {
+    for &(g, style) in cells {
+        match line.last_mut() {
+            Some(last) if last.style == style => last.content.to_mut().push_str(g),
+            _ => line.push(Span::styled(g.to_string(), style)),
+        }
+    }
+}
+
+/// Wrap styled spans to `width` display columns at word boundaries. The
+/// first line starts with `prefix`; continuation lines are indented by
+/// `indent` spaces. Styles are carried across the break.
+pub fn wrap_spans(prefix: &str, spans: Vec<Span<'static>>, width: usize, indent: usize) -> Vec<Line<'static>> {
+    let limit = width.max(indent + EMOJI_CLUSTER_WIDTH);
+    // Whole grapheme clusters, so emoji sequences are never split.
+    let cells = styled_graphemes(&spans);
+    let graphemes: Vec<&str> = cells.iter().map(|(g, _)| *g).collect();
+    let first = limit.saturating_sub(display_width(prefix));
+
+    let mut lines = Vec::new();
+    for (i, range) in wrap_ranges(&graphemes, first, limit - indent, true).into_iter().enumerate() {
+        let lead = if i == 0 { prefix.to_string() } else { " ".repeat(indent) };
+        let mut line = vec![Span::raw(lead)];
+        push_styled(&mut line, &cells[range]);
+        lines.push(Line::from(line));
+    }
+    if lines.is_empty() {
+        lines.push(Line::from(prefix.to_string()));
+    }
+    lines
+}
+
+/// Background of fenced code blocks.
+pub const CODE_BG: Color = Color::Rgb(35, 35, 35);
+
+const RUST_KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+    "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move",
+    "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait", "true",
+    "type", "unsafe", "use", "where", "while",
+];
+
+const PYTHON_KEYWORDS: &[&str] = &[
+    "and", "as", "class", "def", "elif", "else", "False", "for", "from", "if", "import", "in",
+    "is", "lambda", "None", "not", "or", "pass", "raise", "return", "True", "try", "except",
+    "while", "with", "yield",
+];
+
+const JS_KEYWORDS: &[&str] = &[
+    "async", "await", "break", "case", "class", "const", "else", "export", "false", "for",
+    "function", "if", "import", "let", "new", "null", "return", "switch", "this", "true",
+    "undefined", "var", "while",
+];
+
+/// If `seg` is a code fence (```lang), the language tag (possibly empty).
+pub fn fence_lang(seg: &str) -> Option<&str> {
+    seg.trim_start().strip_prefix("```").map(str::trim)
+}
+
+/// Color one line of code: keywords, strings and comments for the languages
+/// we know; other languages only get strings and comments.
+pub fn highlight_code(line: &str, lang: &str) -> Vec<Span<'static>> {
+    let base = Style::default().bg(CODE_BG);
+    let (keywords, comment): (&[&str], &str) = match lang {
+        "rust" | "rs" => (RUST_KEYWORDS, "//"),
+        "python" | "py" => (PYTHON_KEYWORDS, "#"),
+        "js" | "javascript" | "ts" | "typescript" => (JS_KEYWORDS, "//"),
+        "sh" | "bash" | "shell" | "toml" | "yaml" => (&[], "#"),
+        _ => (&[], "//"),
+    };
+
+    let mut spans = Vec::new();
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        let len = if rest.starts_with(comment) {
+            spans.push(Span::styled(rest.to_string(), base.fg(Color::Gray).add_modifier(Modifier::ITALIC)));
+            break;
+        } else if c == '"' || (c == '\'' && lang != "rust" && lang != "rs") {
+            // (single quotes are lifetimes in Rust)
+            // Up to the closing quote, skipping escaped ones.
+            let mut escaped = false;
+            let end = rest[1..]
+                .char_indices()
+                .find(|&(_, ch)| {
+                    let close = ch == c && !escaped;
+                    escaped = ch == '\\' && !escaped;
+                    close
+                })
+                .map(|(i, _)| i + 2)
+                .unwrap_or(rest.len());
+            spans.push(Span::styled(rest[..end].to_string(), base.fg(Color::Green)));
+            end
+        } else if c.is_alphanumeric() || c == '_' {
+            let end = rest
+                .find(|ch: char| !(ch.is_alphanumeric() || ch == '_'))
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            let style = if keywords.contains(&word) {
+                base.fg(Color::Magenta).add_modifier(Modifier::BOLD)
+            } else if c.is_ascii_digit() {
+                base.fg(Color::Cyan)
+            } else {
+                base
+            };
+            spans.push(Span::styled(word.to_string(), style));
+            end
+        } else {
+            spans.push(Span::styled(c.to_string(), base));
+            c.len_utf8()
+        };
+        rest = &rest[len..];
+    }
+    spans
+}
+
+/// Wrap a code line to `width` columns without a prefix. Breaks after the
+/// last whitespace that fits so identifiers are not split when avoidable,
+/// keeps indentation, and pads every line to the full width so the block
+/// background is even.
+pub fn wrap_code(spans: Vec<Span<'static>>, width: usize, pad: Style) -> Vec<Line<'static>> {
+    let cells = styled_graphemes(&spans);
+    let graphemes: Vec<&str> = cells.iter().map(|(g, _)| *g).collect();
+    let width = width.max(1);
+
+    let mut ranges = wrap_ranges(&graphemes, width, width, false);
+    if ranges.is_empty() {
+        ranges.push(0..0);
+    }
+    ranges
+        .into_iter()
+        .map(|range| {
+            let used: usize = graphemes[range.clone()].iter().map(|g| grapheme_width(g)).sum();
+            let mut line = Vec::new();
+            push_styled(&mut line, &cells[range]);
+            if used < width {
+                line.push(Span::styled(" ".repeat(width - used), pad));
+            }
+            Line::from(line)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(lines: &[Line<'static>]) -> Vec<String> {
+        lines.iter().map(|l| l.spans.iter().map(|s| s.content.as_ref()).collect()).collect()
+    }
+
+    #[test]
+    fn emoji_clusters_count_as_one_wide_glyph() {
+        assert_eq!(display_width("abc"), 3);
+        assert_eq!(display_width("日本"), 4);
+        assert_eq!(grapheme_width("👨\u{200D}👩\u{200D}👧"), 2);
+        assert_eq!(grapheme_width("❤\u{FE0F}"), 2);
+        assert_eq!(display_width("hi 👨\u{200D}👩\u{200D}👧!"), 6);
+    }
+
+    #[test]
+    fn wrap_spans_never_splits_an_emoji_cluster() {
+        let family = "👨\u{200D}👩\u{200D}👧";
+        let text = family.repeat(3);
+        let lines = wrap_spans("> ", vec![Span::raw(text)], 6, 2);
+        assert_eq!(texts(&lines), [format!("> {}", family.repeat(2)), format!("  {family}")]);
+        assert!(texts(&lines).iter().all(|t| display_width(t) <= 6));
+    }
+
+    /// The lines `wrap_ranges` makes of `text`.
+    fn wrapped(text: &str, first: usize, rest: usize, drop_spaces: bool) -> Vec<String> {
+        let graphemes: Vec<&str> = text.graphemes(true).collect();
+        wrap_ranges(&graphemes, first, rest, drop_spaces).into_iter().map(|r| graphemes[r].concat()).collect()
+    }
+
+    #[test]
+    fn wrap_ranges_breaks_after_the_last_space_that_fits() {
+        assert_eq!(wrapped("hello world foo", 11, 11, true), ["hello world", "foo"]);
+        assert_eq!(wrapped("ab  cd", 3, 3, true), ["ab", "cd"]);
+        assert_eq!(wrapped("hello world foo", 8, 8, false), ["hello ", "world ", "foo"]);
+        assert!(wrapped("", 3, 3, true).is_empty());
+    }
+
+    #[test]
+    fn wrap_ranges_splits_only_words_longer_than_a_line() {
+        assert_eq!(
+            wrapped("see https://example.com/a/very/long/path ok", 12, 12, true),
+            ["see", "https://exam", "ple.com/a/ve", "ry/long/path", "ok"]
+        );
+        assert_eq!(wrapped("日本語テキスト", 5, 5, true), ["日本", "語テ", "キス", "ト"]);
+    }
+
+    #[test]
+    fn wrap_ranges_moves_a_word_off_a_line_of_indentation() {
+        assert_eq!(wrapped("  abcdef", 6, 6, false), ["  ", "abcdef"]);
+        assert_eq!(wrapped("  ab cd", 4, 6, false), ["  ab", " cd"]);
+    }
+
+    #[test]
+    fn wrap_code_pads_every_line_to_the_width() {
+        let lines = wrap_code(vec![Span::raw("let x = 1;")], 8, Style::default());
+        assert_eq!(texts(&lines), ["let x = ", "1;      "]);
+    }
+}
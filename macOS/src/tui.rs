@@ -2,13 +2,25 @@ use ratatui::{
     layout::{Alignment, Constraint, Direction, Layout, Rect, Margin},
     style::{Modifier, Style, Color},
     text::{Span, Line},
-    widgets::{Block, Borders, BorderType, List, ListItem, Paragraph},
+    widgets::{
+        Block, Borders, BorderType, Clear, List, ListItem, Paragraph, Scrollbar, ScrollbarOrientation,
+        ScrollbarState, Wrap,
+    },
     Frame,
 };
 
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+
+use unicode_segmentation::UnicodeSegmentation;
 use unicode_width::UnicodeWidthStr;
 
-use crate::app::{App, MessageFrom, InputMode};
+use crate::markdown::{
+    display_width, fence_lang, highlight_code, markdown_line, wrap_code, wrap_ranges, wrap_spans, CODE_BG,
+};
+use crate::app::{now_secs, relative_time, reply_len, session_order, App, BranchOverlay, Message, MessageFrom, InputMode, Session};
+use crate::frontend::keymap::{Action, KeyMap, INSERT_KEYS};
 
 /// Draw the whole UI based on the current App state.
 pub fn ui(f: &mut Frame, app: &mut App) {
@@ -37,19 +49,7 @@ pub fn ui(f: &mut Frame, app: &mut App) {
 
     let mut input_lines = 1usize;
     if input_inner_width > 0 && !app.input.is_empty() {
-        let text = app.input.replace("\r\n", "\n");
-        input_lines = text
-            .split('\n')
-            .map(|line| {
-                let len = line.chars().count();
-                if len == 0 {
-                    1
-                } else {
-                    // number of wrapped lines for this logical line
-                    (len - 1) / input_inner_width + 1
-                }
-            })
-            .sum();
+        input_lines = input_rows(&app.input, input_inner_width).len();
     }
     
     let input_height =
@@ -144,12 +144,25 @@ pub fn ui(f: &mut Frame, app: &mut App) {
             );
         f.render_widget(new_chat_widget, header_chunks[1]);
 
-        let items: Vec<ListItem> = app
-            .sessions
+        // Most recently updated first, each with how long ago that was.
+        let order = session_order(&app.sessions);
+        app.select_active_in_list();
+        let now = now_secs();
+        let items: Vec<ListItem> = order
             .iter()
-            .map(|s| {
-                let label = format!("{}", s.title);
-                ListItem::new(Span::raw(label))
+            .map(|&i| (i, &app.sessions[i]))
+            .map(|(i, s)| match &app.renaming_session {
+                Some(title) if i == app.active_idx => ListItem::new(Span::styled(
+                    format!("{title}▏"),
+                    Style::default().add_modifier(Modifier::UNDERLINED),
+                )),
+                _ => ListItem::new(Line::from(vec![
+                    Span::raw(s.title.clone()),
+                    Span::styled(
+                        format!(" {}", relative_time(s.updated_at, now)),
+                        Style::default().fg(Color::DarkGray),
+                    ),
+                ])),
             })
             .collect();
 
@@ -164,20 +177,34 @@ pub fn ui(f: &mut Frame, app: &mut App) {
 
         f.render_stateful_widget(sessions_list, left_chunks[1], &mut app.list_state);
 
+        // Thin scrollbar on the list's right border once it overflows.
+        let list_rows = left_chunks[1].height.saturating_sub(1) as usize;
+        if app.config.scrollbars && app.sessions.len() > list_rows {
+            let track = Rect {
+                y: left_chunks[1].y,
+                height: list_rows as u16,
+                ..left_chunks[1]
+            };
+            let mut state = ScrollbarState::new(app.sessions.len().saturating_sub(list_rows))
+                .position(app.list_state.offset())
+                .viewport_content_length(list_rows);
+            f.render_stateful_widget(thin_scrollbar(), track, &mut state);
+        }
+
         app.session_hitboxes.clear();
 
         let list_outer = left_chunks[1];
         let list_inner = list_outer.inner(Margin { vertical: 1, horizontal: 1 }); // exclude borders
 
-        for (i, s) in app.sessions.iter().enumerate() {
+        for (row, &i) in order.iter().enumerate() {
             // Must match exactly what you show in the list
-            let label = format!("{}", s.title);
+            let label = app.sessions[i].title.clone();
 
             let w = UnicodeWidthStr::width(label.as_str()) as u16;
             let w = w.min(list_inner.width.max(1));
 
             // Each list item is 1 row tall
-            let y = list_inner.y + i as u16;
+            let y = list_inner.y + row as u16;
 
             // Only create a hitbox if it fits inside the visible list area
             if y < list_inner.y + list_inner.height {
@@ -194,198 +221,42 @@ pub fn ui(f: &mut Frame, app: &mut App) {
             Constraint::Length(input_height)])   // Input auto height
         .split(right_panel);
 
-    // Messages area with scroll support.
-    let msg_area = right_chunks[0];
-    let viewport_height = msg_area.height.saturating_sub(2).max(1) as usize;
-    let inner_width = msg_area.width.saturating_sub(2) as usize;
-
-    // 1) Build logical lines and capture session title using an immutable borrow to `app`. 
-    let (session_title, logical_lines): (String, Vec<(Option<usize>, Line)>) = {
-        let active = app.active_session();
-        let branch = &active.branches[active.active_branch];
-
-        let mut lines: Vec<(Option<usize>, Line)> = Vec::new();
-
-        for (idx, m) in branch.messages.iter().enumerate() {
-            match m.from {
-                MessageFrom::Assistant => {
-                    // AI on the left
-                    let prefix = "AI: ";
-                    let raw = m.content.replace("\r\n", "\n");
-
-                    for (i, seg) in raw.split('\n').enumerate() {
-                        // first visual line uses "AI: ", following lines are indented
-                        let mut current = if i == 0 {
-                            format!("{prefix}{seg}")
-                        } else {
-                            format!("{:width$}{}", "", seg, width = prefix.len())
-                        };
-
-                        while current.chars().count() > inner_width {
-                            // take one screen-width slice
-                            let mut taken = String::new();
-                            let mut count = 0;
-                            for ch in current.chars() {
-                                if count == inner_width {
-                                    break;
-                                }
-                                taken.push(ch);
-                                count += 1;
-                            }
-
-                            lines.push((None, Line::from(taken)));
-
-                            // remaining part
-                            current = current.chars().skip(count).collect();
-                            // indent 1ped lines
-                            current = format!("{:width$}{}", "", current, width = prefix.len());
-                        }
-
-                        lines.push((None, Line::from(current)));
-                    }
-                }
-
-                MessageFrom::User => {
-                    // User on the right: we build left-aligned text first,
-                    // then pad with spaces on the left so that it ends near the right edge.
-                    let prefix = "You: ";
-                    let raw = m.content.replace("\r\n", "\n");
-                    let mut first_line = true;
-
-                    for (i, seg) in raw.split('\n').enumerate() {
-                        let mut current = if i == 0 {
-                            format!("{prefix}{seg}")
-                        } else {
-                            format!("{:width$}{}", "", seg, width = prefix.len())
-                        };
-
-                        while current.chars().count() > inner_width {
-                            let mut taken = String::new();
-                            let mut count = 0;
-                            for ch in current.chars() {
-                                if count == inner_width {
-                                    break;
-                                }
-                                taken.push(ch);
-                                count += 1;
-                            }
-
-                            // Only the very first visual line of this user message
-                            // is tagged with Some(idx) for hitbox detection.
-                            let owner = Some(idx);
-
-                            let visual = if first_line {
-                                // right-align the first visual line
-                                let len = taken.chars().count();
-                                let padding = inner_width.saturating_sub(len);
-                                format!("{}{}", " ".repeat(padding), taken)
-                            } else {
-                                taken
-                            };
-                            
-                            first_line = false;
-                            lines.push((owner, Line::from(visual)));
-
-                            current = current.chars().skip(count).collect();
-                            current = format!("{:width$}{}", "", current, width = prefix.len());
-                        }
-
-                        // last fragment 
-                        let owner = Some(idx);
-
-                        let visual = if first_line {
-                            let len = current.chars().count();
-                            let padding = inner_width.saturating_sub(len);
-                            format!("{}{}", " ".repeat(padding), current)
-                        } else {
-                            current
-                        };
-
-                        first_line = false;
-                        lines.push((owner, Line::from(visual)));
-                    }
-                }
-            }
-
-            // spacer line after each message
-            lines.push((None, Line::from("")));
-        }
-
-        (active.title.clone(), lines)
-    };
-
-    // 2) mutate `app.user_msg_hitboxes`.
-    app.user_msg_hitboxes.clear();
-
-    // Clamp scroll offset so we never scroll beyond the end.
-    let total_lines = logical_lines.len();
-    let max_scroll = total_lines.saturating_sub(viewport_height);
-    app.msg_scroll = app.msg_scroll.min(max_scroll);
-
-    let scroll = app.msg_scroll;
-
-    // Take the visible window of lines and record hitboxes for user messages.
-    let mut visible_lines: Vec<Line> = Vec::new();
-
-    for (line_i, (owner, line)) in logical_lines.into_iter().enumerate() {
-        if line_i < scroll || line_i >= scroll + viewport_height {
-            continue;
-        }
-
-        // Compute terminal y coordinate for this logical line.
-        let screen_y = msg_area.y + 1 + (line_i - scroll) as u16;
-
-        // If this line belongs to a user message, record a hitbox so the mouse handler can detect hover/click.
-        if let Some(msg_idx) = owner {
-            let rect = Rect {
-                x: msg_area.x,
-                y: screen_y,
-                width: msg_area.width,
-                height: 1,
-            };
-            
-            if let Some(pos) = app.user_msg_hitboxes.iter().position(|(i, _)| *i == msg_idx) {
-                app.user_msg_hitboxes[pos] = (msg_idx, rect);
-            } else {
-                app.user_msg_hitboxes.push((msg_idx, rect));
-            }
-        }
-
-        visible_lines.push(line);
+    // Pinned system prompt and the session summary, drawn above the
+    // scrolling messages.
+    let width = right_chunks[0].width.saturating_sub(2) as usize;
+    let active = app.active_session();
+    let prompt = active.system_prompt.as_deref().filter(|_| app.pin_header);
+    let mut header = Vec::new();
+    let mut parts = Vec::new();
+    if let Some(prompt) = prompt {
+        header.extend(pinned_header_lines(prompt, width, app.header_collapsed));
+        parts.push("System");
+    }
+    if let Some(summary) = &active.summary {
+        header.extend(pinned_header_lines(summary, width, app.header_collapsed));
+        parts.push("Summary");
+    }
+    let header_height = pinned_header_height(&header, right_chunks[0].height);
+    let [header_area, msg_area] = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(header_height), Constraint::Min(0)])
+        .areas(right_chunks[0]);
+    if header_height > 0 {
+        let hint = if app.header_collapsed { "P expand" } else { "P collapse" };
+        let title = format!("{} ({hint})", parts.join(" + "));
+        let header_widget = Paragraph::new(header)
+            .block(Block::default().borders(Borders::TOP | Borders::RIGHT).title(title));
+        f.render_widget(header_widget, header_area);
     }
 
-    // Render the messages paragraph.
-    let messages_widget = Paragraph::new(visible_lines)
-        .block(
-            Block::default()
-                .borders(Borders::TOP | Borders::RIGHT)
-                .title(session_title),
-        );
-    
-    f.render_widget(messages_widget, msg_area);
-
-    // If a user message is hovered, render a small "edit" label on the right side of that message line.
-    app.edit_area = None;
-    if let Some(msg_idx) = app.hovered_user_msg {
-        if let Some((_, rect)) = app
-            .user_msg_hitboxes
-            .iter()
-            .find(|(idx, _)| *idx == msg_idx)
-        {
-            let edit_width = 6;
-            let edit_rect = Rect {
-                x: rect.x + rect.width.saturating_sub(edit_width + 1) + 1,
-                y: rect.y + 1,
-                width: edit_width,
-                height: 1,
-            };
-            
-            // Store edit rect 
-            app.edit_area = Some((msg_idx, edit_rect));
-
-            let edit_widget = Paragraph::new("\u{1F4DD}").alignment(Alignment::Center);
-            f.render_widget(edit_widget, edit_rect);
-        }
+    // Messages, or two branches side by side while comparing them.
+    if app.compare.as_ref().is_some_and(|c| c.session_idx != app.active_idx) {
+        app.compare = None;
+    }
+    if app.compare.is_some() {
+        draw_compare(f, app, msg_area);
+    } else {
+        draw_messages(f, app, msg_area);
     }
 
     // ===== Bottom input area (input + send button) =====
@@ -398,7 +269,28 @@ pub fn ui(f: &mut Frame, app: &mut App) {
         InputMode::Normal => "[NORMAL]",
         InputMode::Insert => "[INSERT]",
     };
-    let input_title = format!("Input {}", mode_label);
+    let backend_label = app.backend.label();
+    // File changes and shell commands are refused while this shows.
+    let safe_label = if app.tool_policy.safe_mode { " [SAFE]" } else { "" };
+    let mut input_title = match app.current_status() {
+        Some(status) => format!("Input {} [{}]{} — {}", mode_label, backend_label, safe_label, status),
+        None => format!("Input {} [{}]{}", mode_label, backend_label, safe_label),
+    };
+    if let Some(search) = app.search_indicator() {
+        input_title.push_str(&format!(" · {search}"));
+    }
+    if app.compare.is_some() {
+        input_title.push_str(" · comparing (Tab column, [ ] branch, Esc close)");
+    }
+    if let Some(idx) = app.selected_message() {
+        let keymap = &app.config.keymap;
+        input_title.push_str(&format!(
+            " · message {} selected ({} edit, {} delete)",
+            idx + 1,
+            KeyMap::keys_label(keymap.keys(Action::EditInPlace)),
+            KeyMap::keys_label(keymap.keys(Action::DeleteMessage)),
+        ));
+    }
 
     // reset the send button area every frame.
     app.send_button_area = None;
@@ -411,40 +303,15 @@ pub fn ui(f: &mut Frame, app: &mut App) {
         .saturating_sub(2)                      // borders
         .saturating_sub(reserved_for_button) as usize;
 
-    let mut input_visual_lines: Vec<Line> = Vec::new();
-
-    if input_inner_width == 0 || app.input.is_empty() {
-        input_visual_lines.push(Line::from(app.input.as_str()));
+    let input_row_ranges = if input_inner_width == 0 || app.input.is_empty() {
+        std::iter::once(0..app.input.len()).collect()
     } else {
-        let raw = app.input.replace("\r\n", "\n");
-
-        for seg in raw.split('\n') {
-            let mut current = seg.to_string();
-
-            if current.is_empty() {
-                input_visual_lines.push(Line::from(""));
-                continue;
-            }
-
-            while current.chars().count() > input_inner_width {
-                let mut taken = String::new();
-                let mut count = 0;
-
-                for ch in current.chars() {
-                    if count == input_inner_width {
-                        break;
-                    }
-                    taken.push(ch);
-                    count += 1;
-                }
-
-                input_visual_lines.push(Line::from(taken));
-                current = current.chars().skip(count).collect();
-            }
-
-            input_visual_lines.push(Line::from(current));
-        }
-    }
+        input_rows(&app.input, input_inner_width)
+    };
+    let input_visual_lines: Vec<Line> = input_row_ranges
+        .iter()
+        .map(|range| Line::from(&app.input[range.clone()]))
+        .collect();
 
     // 2) Always show the last N lines so the cursor area stays visible.
     let total_lines = input_visual_lines.len().max(1);
@@ -458,9 +325,7 @@ pub fn ui(f: &mut Frame, app: &mut App) {
     }
 
     let offset_from_bottom = app.input_scroll;
-    let start = total_lines
-        .saturating_sub(visible_lines + offset_from_bottom)
-        .max(0);
+    let start = total_lines.saturating_sub(visible_lines + offset_from_bottom);
 
     let visible_input: Vec<Line> = input_visual_lines
         .into_iter()
@@ -469,10 +334,13 @@ pub fn ui(f: &mut Frame, app: &mut App) {
         .collect();
 
     // draw border/title
-    let input_block = Block::default()
+    let mut input_block = Block::default()
         .borders(Borders::BOTTOM | Borders::RIGHT)
         .border_type(BorderType::Rounded)
         .title(input_title);
+    if app.config.status_bar {
+        input_block = input_block.title_bottom(context_line(app));
+    }
 
     f.render_widget(input_block, input_area);
 
@@ -514,6 +382,14 @@ pub fn ui(f: &mut Frame, app: &mut App) {
     };
 
     f.render_widget(input_text, content_area);
+
+    // Terminal cursor at the edit position (INSERT mode only).
+    if app.input_mode == InputMode::Insert && input_inner_width > 0 {
+        let (row, col) = cursor_visual_pos(&app.input, &input_row_ranges, app.input_cursor);
+        if row >= start && row < start + visible_lines {
+            f.set_cursor_position((content_area.x + col as u16, content_area.y + (row - start) as u16));
+        }
+    }
  
     // send botton
     let hit_h: u16 = 3;
@@ -549,4 +425,958 @@ pub fn ui(f: &mut Frame, app: &mut App) {
         );
 
     f.render_widget(send_button, render_rect);
-}
\ No newline at end of file
+
+    // ===== Overlay: branch picker =====
+    if let Some(overlay) = &app.branch_overlay {
+        draw_branch_overlay(f, &app.sessions[app.active_idx], overlay);
+    }
+
+    // ===== Modal: key bindings =====
+    if let Some(scroll) = app.keys_overlay {
+        let scroll = draw_keys_overlay(f, &app.config.keymap, scroll);
+        app.keys_overlay = Some(scroll);
+    }
+
+    // ===== Modal: delete session? =====
+    if app.confirm_delete_session {
+        let title = &app.sessions[app.active_idx].title;
+        draw_confirm_modal(f, "Delete session?", &format!("Delete '{title}' and its saved branches?"));
+    }
+
+    // ===== Modal: delete message? =====
+    if app.confirm_delete_message {
+        let with_reply = app.selected_message().is_some_and(|idx| {
+            let session = app.active_session();
+            let messages = &session.branches[session.active_branch].messages;
+            messages[idx].from == MessageFrom::User && reply_len(&messages[idx + 1..]) > 0
+        });
+        let question = if with_reply { "Delete this message and the reply to it?" } else { "Delete this message?" };
+        draw_confirm_modal(f, "Delete message?", question);
+    }
+
+    // ===== Modal: shell command approval =====
+    if let Some(req) = app.pending_approvals.front() {
+        draw_approval_modal(f, &req.command);
+    }
+}
+
+/// Messages of the active branch with scroll support, recording the
+/// hitboxes the mouse handler uses.
+fn draw_messages(f: &mut Frame, app: &mut App, msg_area: Rect) {
+    let viewport_height = msg_area.height.saturating_sub(2).max(1) as usize;
+    let inner_width = msg_area.width.saturating_sub(2) as usize;
+
+    // 1) Build logical lines and capture session title using an immutable borrow to `app`. 
+    let search_match = app.current_search_match();
+    let selected = app.selected_message();
+    let mut wrap_cache = std::mem::take(&mut app.wrap_cache);
+    let keys = app.config.keymap.keys(Action::ToggleCollapse);
+    let expand_hint = if keys.is_empty() {
+        "click to expand".to_string()
+    } else {
+        format!("press {} to expand", KeyMap::keys_label(keys))
+    };
+    let (session_title, counts, mut logical_lines, line_msgs, match_range, selected_range, long_msgs, markers) = {
+        let active = app.active_session();
+        let branch = &active.branches[active.active_branch];
+        let settings = WrapSettings {
+            width: inner_width,
+            markdown: app.config.render_markdown,
+            highlight: app.config.highlight_code,
+            line_numbers: app.config.code_line_numbers,
+        };
+        wrap_cache.select((app.active_idx, active.active_branch));
+        let spacer_lines = app.config.density.spacer_lines();
+        // The reply on screen that has been requested but not started yet.
+        let waiting_for = app
+            .stream_for(app.active_idx, active.active_branch)
+            .map(|r| (r.msg_idx, r.started));
+
+        let mut lines: Vec<(Option<usize>, Line)> = Vec::new();
+        // Message each line belongs to (`None` for spacers).
+        let mut line_msgs: Vec<Option<usize>> = Vec::new();
+        let mut match_range = None;
+        let mut selected_range = None;
+        // Messages over `collapse_after` lines, and the line of each
+        // "… +N lines" marker with its message.
+        let mut long_msgs = Vec::new();
+        let mut markers: Vec<(usize, usize)> = Vec::new();
+
+        for (idx, m) in branch.messages.iter().enumerate() {
+            let msg_start = lines.len();
+            // Spinner and elapsed time until the first chunk arrives.
+            if let Some((_, started)) = waiting_for.filter(|&(i, _)| i == idx && m.content.is_empty() && m.error.is_none()) {
+                let elapsed = started.elapsed();
+                let frame = SPINNER_FRAMES[(elapsed.as_millis() / 100) as usize % SPINNER_FRAMES.len()];
+                lines.push((None, Line::from(vec![
+                    Span::raw("AI: "),
+                    Span::styled(
+                        format!("{frame} waiting for reply… {}s", elapsed.as_secs()),
+                        Style::default().fg(Color::DarkGray),
+                    ),
+                ])));
+            } else {
+                let wrapped = wrap_cache.lines(idx, m, settings);
+                // The reply being streamed stays open so it can be followed;
+                // tool results are folded sooner.
+                let collapse_after = match m.from {
+                    MessageFrom::Tool => app.config.collapse_after.min(TOOL_COLLAPSE_AFTER),
+                    _ => app.config.collapse_after,
+                };
+                let long = collapse_after > 0
+                    && wrapped.len() > collapse_after
+                    && waiting_for.is_none_or(|(i, _)| i != idx);
+                if long {
+                    long_msgs.push(idx);
+                }
+                // The current search match is always shown in full.
+                if long && !app.is_expanded(idx) && search_match != Some(idx) {
+                    let shown = COLLAPSED_PREVIEW_LINES.min(collapse_after);
+                    lines.extend_from_slice(&wrapped[..shown]);
+                    markers.push((lines.len(), idx));
+                    lines.push((None, collapsed_marker(wrapped.len() - shown, &expand_hint)));
+                } else {
+                    lines.extend_from_slice(wrapped);
+                }
+            }
+
+            if search_match == Some(idx) {
+                match_range = Some((msg_start, lines.len()));
+            }
+            if selected == Some(idx) {
+                selected_range = Some((msg_start, lines.len()));
+            }
+            line_msgs.resize(lines.len(), Some(idx));
+
+            // spacer lines after each message
+            for _ in 0..spacer_lines {
+                lines.push((None, Line::from("")));
+            }
+            line_msgs.resize(lines.len(), None);
+        }
+
+        (active.title.clone(), branch.role_counts(), lines, line_msgs, match_range, selected_range, long_msgs, markers)
+    };
+    app.wrap_cache = wrap_cache;
+    app.long_msgs = long_msgs;
+
+    // Shade the selected message (J / K or a click).
+    if let Some((start, end)) = selected_range {
+        let style = Style::default().bg(Color::Rgb(40, 40, 60));
+        for (_, line) in &mut logical_lines[start..end] {
+            line.style = line.style.patch(style);
+        }
+    }
+
+    // Highlight the lines of the current search match that contain the
+    // query (all of its lines if wrapping split the query).
+    if let (Some((start, end)), Some(search)) = (match_range, app.search.as_ref()) {
+        let needle = search.query.to_lowercase();
+        let hit = |line: &Line| line.to_string().to_lowercase().contains(&needle);
+        let any_hit = logical_lines[start..end].iter().any(|(_, line)| hit(line));
+        let style = Style::default().bg(Color::Rgb(80, 70, 0));
+        for (_, line) in &mut logical_lines[start..end] {
+            if !any_hit || hit(line) {
+                line.style = line.style.patch(style);
+            }
+        }
+    }
+
+    // 2) mutate `app.user_msg_hitboxes`.
+    app.user_msg_hitboxes.clear();
+    app.msg_line_hitboxes.clear();
+    app.collapse_hitboxes.clear();
+
+    // Clamp scroll offset so we never scroll beyond the end.
+    let total_lines = logical_lines.len();
+    let max_scroll = total_lines.saturating_sub(viewport_height);
+    app.msg_scroll_max = max_scroll;
+    app.msg_viewport_height = viewport_height;
+    if let (Some((start, _)), Some(search)) = (match_range, app.search.as_mut()) {
+        if search.jump {
+            search.jump = false;
+            app.msg_scroll = start;
+        }
+    }
+    if let (Some((start, end)), true) = (selected_range, app.reveal_selected) {
+        if start < app.msg_scroll {
+            app.msg_scroll = start;
+        } else if end > app.msg_scroll + viewport_height {
+            app.msg_scroll = end.saturating_sub(viewport_height).min(start);
+        }
+    }
+    app.reveal_selected = false;
+    app.msg_scroll = app.msg_scroll.min(max_scroll);
+
+    // Stick to the bottom while a reply streams, unless the user scrolled up;
+    // scrolling back down to the end resumes following.
+    if app.msg_scroll == max_scroll {
+        app.follow_bottom = true;
+    }
+    if app.follow_bottom && app.is_streaming() {
+        app.msg_scroll = max_scroll;
+    }
+
+    let scroll = app.msg_scroll;
+
+    // Take the visible window of lines and record hitboxes for user messages.
+    let mut visible_lines: Vec<Line> = Vec::new();
+
+    for (line_i, (owner, line)) in logical_lines.into_iter().enumerate() {
+        if line_i < scroll || line_i >= scroll + viewport_height {
+            continue;
+        }
+
+        // Compute terminal y coordinate for this logical line.
+        let screen_y = msg_area.y + 1 + (line_i - scroll) as u16;
+        let row = Rect { x: msg_area.x, y: screen_y, width: msg_area.width, height: 1 };
+        if let Some(msg_idx) = line_msgs[line_i] {
+            app.msg_line_hitboxes.push((msg_idx, row));
+        }
+        if let Some(&(_, msg_idx)) = markers.iter().find(|(marker_line, _)| *marker_line == line_i) {
+            app.collapse_hitboxes.push((msg_idx, row));
+        }
+
+        // If this line belongs to a user message, record a hitbox so the mouse handler can detect hover/click.
+        if let Some(msg_idx) = owner {
+            if let Some(pos) = app.user_msg_hitboxes.iter().position(|(i, _)| *i == msg_idx) {
+                app.user_msg_hitboxes[pos] = (msg_idx, row);
+            } else {
+                app.user_msg_hitboxes.push((msg_idx, row));
+            }
+        }
+
+        visible_lines.push(line);
+    }
+
+    // Render the messages paragraph.
+    let messages_widget = Paragraph::new(visible_lines)
+        .block(
+            Block::default()
+                .borders(Borders::TOP | Borders::RIGHT)
+                .title(session_title)
+                .title_top(
+                    Line::from(format!(
+                        " {} user · {} AI · {} total ",
+                        counts.user,
+                        counts.assistant,
+                        counts.total()
+                    ))
+                    .right_aligned(),
+                ),
+        );
+    
+    f.render_widget(messages_widget, msg_area);
+
+    // Scrollbar on the right border, below the title row.
+    app.msg_scrollbar_area = None;
+    if app.config.scrollbars && max_scroll > 0 {
+        let track = Rect {
+            y: msg_area.y + 1,
+            height: msg_area.height.saturating_sub(1),
+            ..msg_area
+        };
+        let mut state = ScrollbarState::new(max_scroll)
+            .position(scroll)
+            .viewport_content_length(viewport_height);
+        f.render_stateful_widget(thin_scrollbar(), track, &mut state);
+        app.msg_scrollbar_area = Some(Rect {
+            x: track.x + track.width.saturating_sub(1),
+            width: 1,
+            ..track
+        });
+    }
+
+    // If a user message is hovered, render a small "edit" label on the right side of that message line.
+    app.edit_area = None;
+    if let Some(msg_idx) = app.hovered_user_msg {
+        if let Some((_, rect)) = app
+            .user_msg_hitboxes
+            .iter()
+            .find(|(idx, _)| *idx == msg_idx)
+        {
+            let edit_width = 6;
+            let edit_rect = Rect {
+                x: rect.x + rect.width.saturating_sub(edit_width + 1) + 1,
+                y: rect.y + 1,
+                width: edit_width,
+                height: 1,
+            };
+            
+            // Store edit rect 
+            app.edit_area = Some((msg_idx, edit_rect));
+
+            let edit_widget = Paragraph::new("\u{1F4DD}").alignment(Alignment::Center);
+            f.render_widget(edit_widget, edit_rect);
+        }
+    }
+}
+
+/// Two branches of the active session in columns of half the width, both
+/// scrolled by `app.msg_scroll` as far as each one goes.
+fn draw_compare(f: &mut Frame, app: &mut App, area: Rect) {
+    let Some(mut compare) = app.compare.take() else {
+        return;
+    };
+    // Nothing in this view can be clicked or dragged.
+    app.user_msg_hitboxes.clear();
+    app.msg_line_hitboxes.clear();
+    app.collapse_hitboxes.clear();
+    app.msg_scrollbar_area = None;
+    app.edit_area = None;
+
+    let columns: [Rect; 2] = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Ratio(1, 2), Constraint::Ratio(1, 2)])
+        .areas(area);
+    let viewport_height = area.height.saturating_sub(1).max(1) as usize;
+    let session = &app.sessions[app.active_idx];
+    let spacer_lines = app.config.density.spacer_lines();
+
+    let mut columns_lines = Vec::new();
+    for (side, column) in columns.iter().enumerate() {
+        let branch_idx = compare.branches[side].min(session.branches.len() - 1);
+        compare.branches[side] = branch_idx;
+        let branch = &session.branches[branch_idx];
+        let settings = WrapSettings {
+            width: column.width.saturating_sub(1) as usize,
+            markdown: app.config.render_markdown,
+            highlight: app.config.highlight_code,
+            line_numbers: app.config.code_line_numbers,
+        };
+        let cache = &mut compare.caches[side];
+        cache.select((app.active_idx, branch_idx));
+        let mut lines: Vec<Line> = Vec::new();
+        for (idx, m) in branch.messages.iter().enumerate() {
+            lines.extend(cache.lines(idx, m, settings).iter().map(|(_, line)| line.clone()));
+            lines.extend(std::iter::repeat_n(Line::from(""), spacer_lines));
+        }
+        columns_lines.push(lines);
+    }
+
+    // Scroll until the longer branch ends; the shorter one stops at its own end.
+    let max_scroll = columns_lines
+        .iter()
+        .map(|lines| lines.len().saturating_sub(viewport_height))
+        .max()
+        .unwrap_or(0);
+    app.msg_scroll_max = max_scroll;
+    app.msg_viewport_height = viewport_height;
+    app.msg_scroll = app.msg_scroll.min(max_scroll);
+
+    for (side, lines) in columns_lines.into_iter().enumerate() {
+        let branch = &session.branches[compare.branches[side]];
+        let scroll = app.msg_scroll.min(lines.len().saturating_sub(viewport_height));
+        let visible: Vec<Line> = lines.into_iter().skip(scroll).take(viewport_height).collect();
+
+        let marker = if compare.focus == side { "▶ " } else { "" };
+        let mut block = Block::default()
+            .borders(Borders::TOP | Borders::RIGHT)
+            .title(format!("{marker}{} ({} messages)", branch.name, branch.messages.len()));
+        if compare.focus == side {
+            block = block.border_style(Style::default().fg(Color::Cyan));
+        }
+        f.render_widget(Paragraph::new(visible).block(block), columns[side]);
+    }
+
+    app.compare = Some(compare);
+}
+
+/// What a message's wrapped lines depend on besides the message itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct WrapSettings {
+    width: usize,
+    markdown: bool,
+    highlight: bool,
+    line_numbers: bool,
+}
+
+/// Wrapped lines of the active branch's messages, so a frame only re-wraps
+/// the messages that changed (the streaming reply, an edit) and everything
+/// after a resize or a settings toggle.
+#[derive(Default)]
+pub struct WrapCache {
+    /// (session, branch) the entries belong to; switching drops them.
+    branch: (usize, usize),
+    entries: Vec<Option<WrappedMessage>>,
+}
+
+struct WrappedMessage {
+    /// Hash of the message content, error, sources and settings.
+    key: u64,
+    lines: Vec<(Option<usize>, Line<'static>)>,
+}
+
+impl WrapCache {
+    /// Keep the entries only if they belong to `branch`.
+    fn select(&mut self, branch: (usize, usize)) {
+        if self.branch != branch {
+            self.branch = branch;
+            self.entries.clear();
+        }
+    }
+
+    /// Lines of message `idx`, wrapped again only if something changed.
+    fn lines(&mut self, idx: usize, m: &Message, settings: WrapSettings) -> &[(Option<usize>, Line<'static>)] {
+        let mut hasher = DefaultHasher::new();
+        (m.from, &m.tool, &m.content, &m.error, &m.sources, settings).hash(&mut hasher);
+        let key = hasher.finish();
+
+        if self.entries.len() <= idx {
+            self.entries.resize_with(idx + 1, || None);
+        }
+        let entry = &mut self.entries[idx];
+        if entry.as_ref().is_none_or(|e| e.key != key) {
+            *entry = Some(WrappedMessage { key, lines: wrap_message(idx, m, settings) });
+        }
+        entry.as_ref().map(|e| e.lines.as_slice()).unwrap_or_default()
+    }
+}
+
+/// Message `idx` wrapped to `settings.width`: the "AI:"/"You:" body, then
+/// its error and sources footnote. Lines of user messages carry `Some(idx)`
+/// for the mouse hitboxes.
+fn wrap_message(idx: usize, m: &Message, settings: WrapSettings) -> Vec<(Option<usize>, Line<'static>)> {
+    let mut lines: Vec<(Option<usize>, Line<'static>)> = Vec::new();
+    match m.from {
+        MessageFrom::Assistant => {
+            // AI on the left
+            let prefix = "AI: ";
+            let raw = m.content.replace("\r\n", "\n");
+
+            let mut labelled = false;
+            let mut code_lang: Option<String> = None;
+            let mut code_line_no = 0;
+
+            for seg in raw.split('\n') {
+                if settings.markdown && (code_lang.is_some() || fence_lang(seg).is_some()) {
+                    // Code gets no prefix indentation, so keep the label on its own line.
+                    if !labelled {
+                        lines.push((None, Line::from(prefix.trim_end())));
+                        labelled = true;
+                    }
+
+                    if let Some(lang) = fence_lang(seg) {
+                        code_lang = match code_lang {
+                            Some(_) => None,
+                            None => Some(lang.to_string()),
+                        };
+                        code_line_no = 0;
+                        let fence = Style::default().fg(Color::DarkGray);
+                        lines.push((None, Line::from(Span::styled(seg.trim().to_string(), fence))));
+                    } else if let Some(lang) = &code_lang {
+                        let (spans, pad) = if settings.highlight {
+                            (highlight_code(seg, lang), Style::default().bg(CODE_BG))
+                        } else {
+                            (vec![Span::raw(seg.to_string())], Style::default())
+                        };
+                        if settings.line_numbers {
+                            // Gutter is drawn only; the message text (and copies of it) stay unnumbered.
+                            code_line_no += 1;
+                            let gutter = Style::default().fg(Color::DarkGray);
+                            let width = settings.width.saturating_sub(CODE_GUTTER_WIDTH);
+                            for (i, line) in wrap_code(spans, width, pad).into_iter().enumerate() {
+                                let label = if i == 0 {
+                                    format!("{:>w$} │ ", code_line_no, w = CODE_GUTTER_WIDTH - 3)
+                                } else {
+                                    format!("{:>w$} │ ", "", w = CODE_GUTTER_WIDTH - 3)
+                                };
+                                let mut numbered = vec![Span::styled(label, gutter)];
+                                numbered.extend(line.spans);
+                                lines.push((None, Line::from(numbered)));
+                            }
+                        } else {
+                            for line in wrap_code(spans, settings.width, pad) {
+                                lines.push((None, line));
+                            }
+                        }
+                    }
+                    continue;
+                }
+
+                // first visual line uses "AI: ", following lines are indented
+                let lead = if labelled { " ".repeat(prefix.len()) } else { prefix.to_string() };
+                labelled = true;
+                let spans = if settings.markdown {
+                    markdown_line(seg)
+                } else {
+                    vec![Span::raw(seg.to_string())]
+                };
+
+                for line in wrap_spans(&lead, spans, settings.width, prefix.len()) {
+                    lines.push((None, line));
+                }
+            }
+        }
+
+        MessageFrom::Tool => {
+            // A wrench and the tool name, then the result indented below.
+            let name = m.tool.as_deref().unwrap_or("tool");
+            let header = Style::default().fg(Color::Magenta).add_modifier(Modifier::BOLD);
+            lines.push((None, Line::from(Span::styled(format!("🔧 {name}"), header))));
+            let body = Style::default().fg(Color::Gray);
+            let indent = "AI: ".len();
+            for seg in m.content.replace("\r\n", "\n").split('\n') {
+                let spans = vec![Span::styled(seg.to_string(), body)];
+                for line in wrap_spans(&" ".repeat(indent), spans, settings.width, indent) {
+                    lines.push((None, line));
+                }
+            }
+        }
+
+        MessageFrom::User => {
+            // User on the right: the first visual line is padded so it ends
+            // at the right edge; the others line up after "You: ".
+            let prefix = "You: ";
+            let raw = m.content.replace("\r\n", "\n");
+            let mut first_line = true;
+
+            for (i, seg) in raw.split('\n').enumerate() {
+                let lead = if i == 0 { prefix.to_string() } else { " ".repeat(prefix.len()) };
+                for line in wrap_spans(&lead, vec![Span::raw(seg.to_string())], settings.width, prefix.len()) {
+                    let text: String = line.spans.iter().map(|s| s.content.as_ref()).collect();
+                    let visual = if first_line {
+                        let padding = settings.width.saturating_sub(display_width(&text));
+                        format!("{}{}", " ".repeat(padding), text)
+                    } else {
+                        text
+                    };
+                    first_line = false;
+                    lines.push((Some(idx), Line::from(visual)));
+                }
+            }
+        }
+    }
+
+    // Why the reply failed, in red under what did arrive.
+    if let Some(error) = &m.error {
+        let style = Style::default().fg(Color::Red).add_modifier(Modifier::BOLD);
+        let indent = "AI: ".len();
+        for (i, seg) in error.lines().enumerate() {
+            let lead = if i == 0 { "⚠️ ".to_string() } else { " ".repeat(indent) };
+            let spans = vec![Span::styled(seg.to_string(), style)];
+            for line in wrap_spans(&lead, spans, settings.width, indent) {
+                lines.push((None, line));
+            }
+        }
+    }
+
+    // Compact footnote with the sources the provider cited.
+    if !m.sources.is_empty() {
+        let list: Vec<String> = m
+            .sources
+            .iter()
+            .enumerate()
+            .map(|(i, source)| format!("[{}] {}", i + 1, source))
+            .collect();
+        let footnote = Span::styled(
+            format!("Sources: {}", list.join("  ")),
+            Style::default().fg(Color::DarkGray),
+        );
+        let indent = "AI: ".len();
+        for line in wrap_spans(&" ".repeat(indent), vec![footnote], settings.width, indent) {
+            lines.push((None, line));
+        }
+    }
+
+    lines
+}
+
+/// Lines of a collapsed message shown above its marker.
+const COLLAPSED_PREVIEW_LINES: usize = 6;
+
+/// Tool results longer than this are collapsed even below `collapse_after`.
+const TOOL_COLLAPSE_AFTER: usize = 12;
+
+/// "… +N lines (press z to expand)" under a collapsed message.
+fn collapsed_marker(hidden: usize, hint: &str) -> Line<'static> {
+    let style = Style::default().fg(Color::DarkGray).add_modifier(Modifier::ITALIC);
+    Line::from(Span::styled(format!("{}… +{hidden} lines ({hint})", " ".repeat("AI: ".len())), style))
+}
+
+/// Vertical scrollbar drawn over a right border: no arrows, a light track.
+fn thin_scrollbar() -> Scrollbar<'static> {
+    Scrollbar::new(ScrollbarOrientation::VerticalRight)
+        .begin_symbol(None)
+        .end_symbol(None)
+        .track_symbol(Some("│"))
+        .thumb_symbol("┃")
+        .thumb_style(Style::default().fg(Color::Cyan))
+}
+
+/// Frames of the waiting indicator, advanced every 100 ms.
+const SPINNER_FRAMES: [&str; 10] = ["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+
+/// Most lines the pinned header shows when expanded.
+const PINNED_HEADER_MAX_LINES: usize = 4;
+
+/// The pinned system prompt wrapped to `width`, capped at
+/// `PINNED_HEADER_MAX_LINES` (one line when collapsed); cut text ends in "…".
+fn pinned_header_lines(prompt: &str, width: usize, collapsed: bool) -> Vec<Line<'static>> {
+    let style = Style::default().fg(Color::DarkGray);
+    let text = prompt.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut lines = wrap_spans("", vec![Span::styled(text, style)], width, 0);
+
+    let max = if collapsed { 1 } else { PINNED_HEADER_MAX_LINES };
+    if lines.len() > max {
+        lines.truncate(max);
+        if let Some(last) = lines.last_mut() {
+            last.spans.push(Span::styled("…", style));
+        }
+    }
+    lines
+}
+
+/// Rows the header takes (its lines plus the top border), leaving at least
+/// three rows of `available` to the messages; 0 when there is no header.
+fn pinned_header_height(lines: &[Line], available: u16) -> u16 {
+    if lines.is_empty() {
+        return 0;
+    }
+    (lines.len() as u16 + 1).min(available.saturating_sub(3))
+}
+
+/// "~1.2k / 6k tokens · input 42 chars" for the bottom of the input box,
+/// yellow from 80% of the context limit and red past it, where older
+/// messages get trimmed.
+fn context_line(app: &App) -> Line<'static> {
+    let tokens = app.token_estimate();
+    let limit = app.context_limit().max(1);
+    let style = if tokens >= limit {
+        Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)
+    } else if tokens * 5 >= limit * 4 {
+        Style::default().fg(Color::Yellow)
+    } else {
+        Style::default().fg(Color::DarkGray)
+    };
+    let text = format!(
+        " ~{} / {} tokens · input {} chars ",
+        short_count(tokens),
+        short_count(limit),
+        app.input.chars().count()
+    );
+    Line::styled(text, style).right_aligned()
+}
+
+/// 950, 1.2k, 6k, 128k.
+fn short_count(n: usize) -> String {
+    match n {
+        0..1_000 => n.to_string(),
+        1_000..10_000 => format!("{}k", format!("{:.1}", n as f64 / 1000.0).trim_end_matches(".0")),
+        _ => format!("{}k", n / 1000),
+    }
+}
+
+/// Columns taken by the line-number gutter of code blocks ("123 │ ").
+const CODE_GUTTER_WIDTH: usize = 6;
+
+/// Byte ranges of `input` shown on each row of the input box: its lines,
+/// wrapped at word boundaries to `width` display columns. Spaces at a break
+/// stay on the row before it so every byte has a place for the cursor.
+fn input_rows(input: &str, width: usize) -> Vec<Range<usize>> {
+    let mut rows = Vec::new();
+    let mut offset = 0;
+    // One pass over each line: large pastes must not make this quadratic.
+    for seg in input.split('\n') {
+        let text = seg.strip_suffix('\r').unwrap_or(seg);
+        let indices: Vec<(usize, &str)> = text.grapheme_indices(true).collect();
+        let graphemes: Vec<&str> = indices.iter().map(|(_, g)| *g).collect();
+        let ranges = wrap_ranges(&graphemes, width, width, false);
+        if ranges.is_empty() {
+            rows.push(offset..offset);
+        }
+        for range in ranges {
+            let start = indices[range.start].0;
+            let end = indices.get(range.end).map_or(text.len(), |(i, _)| *i);
+            rows.push(offset + start..offset + end);
+        }
+        offset += seg.len() + 1;
+    }
+    rows
+}
+
+/// Row and column of byte offset `cursor` in `input` drawn as `rows`. At a
+/// wrap the cursor goes to the start of the next row; at the end of a line
+/// it stays after the last character.
+fn cursor_visual_pos(input: &str, rows: &[Range<usize>], cursor: usize) -> (usize, usize) {
+    let cursor = cursor.min(input.len());
+    let row = rows
+        .iter()
+        .position(|r| r.contains(&cursor))
+        .or_else(|| rows.iter().position(|r| r.end == cursor))
+        .unwrap_or(rows.len().saturating_sub(1));
+    let start = rows.get(row).map_or(0, |r| r.start.min(cursor));
+    (row, display_width(&input[start..cursor]))
+}
+
+/// Centered list of the active session's branches with selection marks.
+fn draw_branch_overlay(f: &mut Frame, session: &Session, overlay: &BranchOverlay) {
+    let area = f.area();
+    let width = area.width.saturating_sub(4).min(64);
+    let height = (session.branches.len() as u16 + 4).min(area.height);
+    let rect = Rect::new(
+        area.x + (area.width.saturating_sub(width)) / 2,
+        area.y + (area.height.saturating_sub(height)) / 2,
+        width,
+        height,
+    );
+
+    let mut lines: Vec<Line> = session
+        .branch_tree()
+        .into_iter()
+        .map(|(idx, tree)| {
+            let branch = &session.branches[idx];
+            let mark = if overlay.selected.contains(&idx) { "[x]" } else { "[ ]" };
+            let fork = branch
+                .fork_point
+                .map(|m| format!(" (from msg {})", m + 1))
+                .unwrap_or_default();
+            let mut style = Style::default();
+            if idx == session.active_branch {
+                style = style.fg(Color::Green).add_modifier(Modifier::BOLD);
+            }
+            if idx == overlay.cursor {
+                style = style.add_modifier(Modifier::REVERSED);
+            }
+            if branch.is_protected() && idx != session.active_branch {
+                style = style.fg(Color::DarkGray);
+            }
+            Line::from(vec![
+                Span::raw(format!("{mark} ")),
+                Span::styled(tree, Style::default().fg(Color::DarkGray)),
+                Span::styled(format!("{}{fork}", branch.name), style),
+            ])
+        })
+        .collect();
+
+    lines.push(Line::from(""));
+    lines.push(if overlay.confirm_delete {
+        Line::from(Span::styled(
+            format!("Delete {} branch(es)? [y] yes  [any] no", overlay.selected.len()),
+            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+        ))
+    } else {
+        Line::from("space select · enter switch · c copy · d delete · esc close")
+    });
+
+    let dialog = Paragraph::new(lines).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded)
+            .title("Branches"),
+    );
+
+    f.render_widget(Clear, rect);
+    f.render_widget(dialog, rect);
+}
+
+/// Centered list of every key binding over a dimmed screen. Returns
+/// `scroll` clamped to the list.
+fn draw_keys_overlay(f: &mut Frame, keymap: &KeyMap, scroll: usize) -> usize {
+    let area = f.area();
+    f.buffer_mut().set_style(area, Style::default().fg(Color::DarkGray).add_modifier(Modifier::DIM));
+
+    let mut rows: Vec<(String, &str)> = vec![(String::new(), "NORMAL MODE")];
+    rows.extend(keymap.entries().map(|(help, keys)| (KeyMap::keys_label(keys), help)));
+    rows.push((String::new(), ""));
+    rows.push((String::new(), "INSERT MODE"));
+    rows.extend(INSERT_KEYS.iter().map(|(keys, help)| (keys.to_string(), *help)));
+
+    let key_width = rows.iter().map(|(keys, _)| display_width(keys)).max().unwrap_or(0);
+    let key_style = Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD);
+    let lines: Vec<Line> = rows
+        .into_iter()
+        .map(|(keys, help)| {
+            if keys.is_empty() {
+                return Line::from(Span::styled(help, Style::default().add_modifier(Modifier::BOLD)));
+            }
+            let pad = " ".repeat(key_width.saturating_sub(display_width(&keys)) + 2);
+            Line::from(vec![Span::styled(format!("  {keys}"), key_style), Span::raw(format!("{pad}{help}"))])
+        })
+        .collect();
+
+    let width = area.width.saturating_sub(4).min(72);
+    let height = (lines.len() as u16 + 3).min(area.height.saturating_sub(2));
+    let rect = Rect::new(
+        area.x + (area.width.saturating_sub(width)) / 2,
+        area.y + (area.height.saturating_sub(height)) / 2,
+        width,
+        height,
+    );
+    // Borders and the footer take three rows.
+    let visible = height.saturating_sub(3) as usize;
+    let scroll = scroll.min(lines.len().saturating_sub(visible));
+
+    let mut shown: Vec<Line> = lines.into_iter().skip(scroll).take(visible).collect();
+    let close = match keymap.keys(Action::KeyHelp) {
+        [] => "esc".to_string(),
+        keys => format!("{} or esc", KeyMap::keys_label(keys)),
+    };
+    shown.push(Line::from(Span::styled(
+        format!("j/k scroll · {close} close"),
+        Style::default().fg(Color::DarkGray),
+    )));
+
+    let dialog = Paragraph::new(shown).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded)
+            .title("Keys"),
+    );
+
+    f.render_widget(Clear, rect);
+    f.render_widget(dialog, rect);
+    scroll
+}
+
+/// Small centered yes/no dialog.
+fn draw_confirm_modal(f: &mut Frame, title: &str, question: &str) {
+    let area = f.area();
+    let width = area.width.saturating_sub(4).min(60);
+    let height = 5.min(area.height);
+    let rect = Rect::new(
+        area.x + (area.width.saturating_sub(width)) / 2,
+        area.y + (area.height.saturating_sub(height)) / 2,
+        width,
+        height,
+    );
+
+    let dialog = Paragraph::new(vec![
+        Line::from(question.to_string()),
+        Line::from(""),
+        Line::from("[y] yes    [any other key] no"),
+    ])
+    .wrap(Wrap { trim: false })
+    .block(
+        Block::default()
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded)
+            .title(title.to_string()),
+    );
+
+    f.render_widget(Clear, rect);
+    f.render_widget(dialog, rect);
+}
+
+/// Centered dialog asking whether the agent may run `command`.
+fn draw_approval_modal(f: &mut Frame, command: &str) {
+    let area = f.area();
+    let width = area.width.saturating_sub(4).min(70);
+    let height = 7.min(area.height);
+    let rect = Rect::new(
+        area.x + (area.width.saturating_sub(width)) / 2,
+        area.y + (area.height.saturating_sub(height)) / 2,
+        width,
+        height,
+    );
+
+    let text = vec![
+        Line::from("The assistant wants to run:"),
+        Line::from(Span::styled(
+            command.to_string(),
+            Style::default().add_modifier(Modifier::BOLD),
+        )),
+        Line::from(""),
+        Line::from("[y] run    [n / Esc] reject"),
+    ];
+
+    let dialog = Paragraph::new(text)
+        .wrap(Wrap { trim: false })
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_type(BorderType::Rounded)
+                .title("Run shell command?"),
+        );
+
+    f.render_widget(Clear, rect);
+    f.render_widget(dialog, rect);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(width: usize) -> WrapSettings {
+        WrapSettings { width, markdown: true, highlight: false, line_numbers: false }
+    }
+
+    /// The text of each wrapped line, without the padding code lines get.
+    fn texts(lines: &[(Option<usize>, Line<'static>)]) -> Vec<String> {
+        lines
+            .iter()
+            .map(|(_, l)| l.spans.iter().map(|s| s.content.as_ref()).collect::<String>().trim_end().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn code_blocks_get_a_numbered_gutter_when_asked() {
+        let m = Message::new(MessageFrom::Assistant, "Look:\n```rs\nlet a = 1;\nlet b = 2;\n```");
+        let numbered = WrapSettings { line_numbers: true, ..settings(40) };
+
+        assert_eq!(
+            texts(&wrap_message(0, &m, numbered)),
+            ["AI: Look:", "```rs", "  1 │ let a = 1;", "  2 │ let b = 2;", "```"]
+        );
+        assert_eq!(
+            texts(&wrap_message(0, &m, settings(40))),
+            ["AI: Look:", "```rs", "let a = 1;", "let b = 2;", "```"]
+        );
+    }
+
+    #[test]
+    fn wrapped_code_lines_continue_under_an_empty_gutter() {
+        let m = Message::new(MessageFrom::Assistant, "```\nabcdefghij\n```");
+        let numbered = WrapSettings { line_numbers: true, ..settings(CODE_GUTTER_WIDTH + 5) };
+        assert_eq!(texts(&wrap_message(0, &m, numbered))[2..4], ["  1 │ abcde", "    │ fghij"]);
+    }
+
+    #[test]
+    fn cited_sources_get_a_numbered_footnote() {
+        let mut m = Message::new(MessageFrom::Assistant, "See docs.");
+        m.sources = vec!["https://a.example".into(), "Guide".into()];
+        let lines = wrap_message(3, &m, settings(60));
+        assert_eq!(texts(&lines), ["AI: See docs.", "    Sources: [1] https://a.example  [2] Guide"]);
+        assert_eq!(lines[1].0, None);
+    }
+
+    #[test]
+    fn pinned_header_is_capped_and_collapses_to_one_line() {
+        let line_text = |l: &Line| l.spans.iter().map(|s| s.content.as_ref()).collect::<String>();
+        let prompt = "one two\nthree four five six seven eight nine ten";
+
+        let expanded = pinned_header_lines(prompt, 10, false);
+        assert_eq!(expanded.iter().map(line_text).collect::<Vec<_>>(), ["one two", "three four", "five six", "seven…"]);
+        let collapsed = pinned_header_lines(prompt, 10, true);
+        assert_eq!(collapsed.iter().map(line_text).collect::<Vec<_>>(), ["one two…"]);
+        assert_eq!(pinned_header_lines("short", 10, false).len(), 1);
+    }
+
+    #[test]
+    fn pinned_header_leaves_room_for_messages() {
+        let lines = pinned_header_lines("a b c d e f g h", 2, false);
+        assert_eq!(pinned_header_height(&lines, 40), 5);
+        assert_eq!(pinned_header_height(&lines, 6), 3);
+        assert_eq!(pinned_header_height(&[], 40), 0);
+    }
+
+    #[test]
+    fn wrap_cache_rewraps_only_what_changed() {
+        let mut cache = WrapCache::default();
+        cache.select((0, 0));
+        let mut m = Message::new(MessageFrom::Assistant, "one two three");
+
+        let first = cache.lines(2, &m, settings(40)).as_ptr();
+        assert_eq!(cache.entries.len(), 3);
+        assert_eq!(cache.lines(2, &m, settings(40)).as_ptr(), first);
+
+        m.content.push_str(" four");
+        assert_eq!(texts(cache.lines(2, &m, settings(40))), ["AI: one two three four"]);
+        assert_eq!(texts(cache.lines(2, &m, settings(12))), ["AI: one two", "    three", "    four"]);
+
+        cache.select((0, 0));
+        assert!(cache.entries[2].is_some());
+        cache.select((0, 1));
+        assert!(cache.entries.is_empty());
+    }
+}
@@ -0,0 +1,68 @@
+//! The tool audit log, shared by the CLI and the TUI (each crate includes
+//! this file as its `audit` module).
+
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// One built-in tool run, a line of `audit.jsonl` in the log directory.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    /// Seconds since the Unix epoch.
+    pub ts: u64,
+    pub session: String,
+    pub tool: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Destination of filesystem.move / filesystem.restore.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dst: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    pub ok: bool,
+    /// Length of the result handed to the model, in bytes.
+    pub result_len: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+/// Append `entry` to the audit log at `path`. Best effort: a log that
+/// cannot be written must not fail the tool, so errors are dropped.
+pub fn append(path: &Path, entry: &AuditEntry) {
+    let Ok(mut line) = serde_json::to_string(entry) else {
+        return;
+    };
+    line.push('\n');
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    // One write per line, so concurrent writers do not interleave.
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+        let _ = file.write_all(line.as_bytes());
+    }
+}
+
+/// The last `n` entries of the audit log, oldest first, and the number of
+/// lines that could not be read.
+#[allow(dead_code)] // only the CLI reads the log back (`/audit tail`)
+pub fn tail(path: &Path, n: usize) -> std::io::Result<(Vec<AuditEntry>, usize)> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(e) => return Err(e),
+    };
+    let mut entries = Vec::new();
+    let mut unreadable = 0;
+    for line in text.lines().filter(|l| !l.trim().is_empty()) {
+        match serde_json::from_str(line) {
+            Ok(entry) => entries.push(entry),
+            Err(_) => unreadable += 1,
+        }
+    }
+    let skip = entries.len().saturating_sub(n);
+    Ok((entries.split_off(skip), unreadable))
+}
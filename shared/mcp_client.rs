@@ -0,0 +1,249 @@
+//! Client for external MCP servers (`[[mcp_servers]]`), shared by the CLI
+//! and the TUI (each crate includes this file as its `mcp_client` module).
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// MCP revision announced in `initialize`.
+const PROTOCOL_VERSION: &str = "2024-11-05";
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/// One `[[mcp_servers]]` entry in the config file: a program that speaks
+/// the Model Context Protocol (JSON-RPC) over its stdin / stdout.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct McpServerConfig {
+    /// Prefix of the server's tools in the prompt, e.g. `git` for `git.status`.
+    pub name: String,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Extra environment variables for the server process.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Seconds to wait for each answer (default 30).
+    pub timeout_secs: Option<u64>,
+}
+
+/// A tool listed by a server's `tools/list`.
+#[derive(Debug, Clone)]
+pub struct McpTool {
+    pub name: String,
+    pub description: String,
+    /// JSON Schema of the tool's arguments.
+    pub input_schema: Value,
+}
+
+/// A running MCP server process and the tools it offers.
+#[derive(Debug)]
+pub struct McpClient {
+    pub name: String,
+    pub tools: Vec<McpTool>,
+    timeout: Duration,
+    conn: Mutex<Connection>,
+}
+
+#[derive(Debug)]
+struct Connection {
+    child: Child,
+    stdin: ChildStdin,
+    /// Lines of the server's stdout, read on a thread of their own so a
+    /// server that never answers cannot hang the caller past `timeout`.
+    lines: Receiver<String>,
+    next_id: u64,
+}
+
+impl Connection {
+    /// Write one JSON-RPC message; messages are newline-delimited.
+    fn send(&mut self, message: &Value) -> Result<(), Box<dyn Error>> {
+        writeln!(self.stdin, "{message}")?;
+        self.stdin.flush()?;
+        Ok(())
+    }
+}
+
+impl McpClient {
+    /// Start the server, run the `initialize` handshake and fetch its tools.
+    pub fn connect(config: &McpServerConfig) -> Result<Self, Box<dyn Error>> {
+        let mut child = Command::new(&config.command)
+            .args(&config.args)
+            .envs(&config.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("cannot start `{}`: {e}", config.command))?;
+        let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            let _ = child.kill();
+            return Err("server pipes are not available".into());
+        };
+
+        let (tx, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut client = McpClient {
+            name: config.name.clone(),
+            tools: Vec::new(),
+            timeout: Duration::from_secs(config.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS)),
+            conn: Mutex::new(Connection { child, stdin, lines, next_id: 1 }),
+        };
+        client.request(
+            "initialize",
+            json!({
+                "protocolVersion": PROTOCOL_VERSION,
+                "capabilities": {},
+                "clientInfo": { "name": "rustai", "version": env!("CARGO_PKG_VERSION") },
+            }),
+        )?;
+        client.notify("notifications/initialized")?;
+        client.tools = client.list_tools()?;
+        Ok(client)
+    }
+
+    /// Every tool of the server, following `nextCursor` pages.
+    fn list_tools(&self) -> Result<Vec<McpTool>, Box<dyn Error>> {
+        let mut tools = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match &cursor {
+                Some(c) => json!({ "cursor": c }),
+                None => json!({}),
+            };
+            let result = self.request("tools/list", params)?;
+            for tool in result["tools"].as_array().into_iter().flatten() {
+                let Some(name) = tool["name"].as_str() else { continue };
+                tools.push(McpTool {
+                    name: name.to_string(),
+                    description: tool["description"].as_str().unwrap_or("").split_whitespace().collect::<Vec<_>>().join(" "),
+                    input_schema: tool.get("inputSchema").cloned().unwrap_or_else(|| json!({ "type": "object" })),
+                });
+            }
+            match result["nextCursor"].as_str() {
+                Some(next) => cursor = Some(next.to_string()),
+                None => return Ok(tools),
+            }
+        }
+    }
+
+    /// Name of the tool `full_name` (`<server>.<tool>`) refers to, if it is
+    /// one of this server's.
+    pub fn owns<'a>(&self, full_name: &'a str) -> Option<&'a str> {
+        let tool = full_name.strip_prefix(self.name.as_str())?.strip_prefix('.')?;
+        self.tools.iter().any(|t| t.name == tool).then_some(tool)
+    }
+
+    /// Run `tool` with `arguments` (`tools/call`). The text parts of the
+    /// result are joined; a result the server flags as failed starts with
+    /// "error: " like a failing built-in tool.
+    pub fn call_tool(&self, tool: &str, arguments: Value) -> Result<String, Box<dyn Error>> {
+        let arguments = if arguments.is_object() { arguments } else { json!({}) };
+        let result = self.request("tools/call", json!({ "name": tool, "arguments": arguments }))?;
+        let text = result["content"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|item| match item["type"].as_str() {
+                Some("text") => item["text"].as_str().unwrap_or("").to_string(),
+                Some(kind) => format!("[{kind} content not shown]"),
+                None => item.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        if result["isError"].as_bool() == Some(true) {
+            Ok(format!("error: {text}"))
+        } else {
+            Ok(text)
+        }
+    }
+
+    /// Send a request and wait for the answer with the same id. Lines that
+    /// are not JSON (logging) and other messages are skipped; requests from
+    /// the server are turned down, since this client offers no features.
+    fn request(&self, method: &str, params: Value) -> Result<Value, Box<dyn Error>> {
+        let mut conn = self.conn.lock().map_err(|_| "MCP connection is unusable")?;
+        let id = conn.next_id;
+        conn.next_id += 1;
+        conn.send(&json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))?;
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let line = match conn.lines.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(format!(
+                        "MCP server '{}' did not answer {method} within {}s",
+                        self.name,
+                        self.timeout.as_secs()
+                    )
+                    .into())
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(format!("MCP server '{}' exited", self.name).into())
+                }
+            };
+            let Ok(message) = serde_json::from_str::<Value>(&line) else { continue };
+            if message.get("method").is_some() {
+                if let Some(request_id) = message.get("id") {
+                    conn.send(&json!({
+                        "jsonrpc": "2.0",
+                        "id": request_id,
+                        "error": { "code": -32601, "message": "method not supported by this client" },
+                    }))?;
+                }
+                continue;
+            }
+            if message["id"] != json!(id) {
+                continue;
+            }
+            if let Some(error) = message.get("error") {
+                let text = error["message"].as_str().unwrap_or("unknown error");
+                return Err(format!("MCP server '{}': {text}", self.name).into());
+            }
+            return Ok(message.get("result").cloned().unwrap_or(Value::Null));
+        }
+    }
+
+    fn notify(&self, method: &str) -> Result<(), Box<dyn Error>> {
+        let mut conn = self.conn.lock().map_err(|_| "MCP connection is unusable")?;
+        conn.send(&json!({ "jsonrpc": "2.0", "method": method }))
+    }
+}
+
+impl Drop for McpClient {
+    fn drop(&mut self) {
+        if let Ok(conn) = self.conn.get_mut() {
+            let _ = conn.child.kill();
+            let _ = conn.child.wait();
+        }
+    }
+}
+
+/// System prompt lines for the tools of `servers`, in the same
+/// `<use_tool>` syntax as the built-in ones.
+pub fn prompt_lines<'a>(servers: impl IntoIterator<Item = &'a McpClient>) -> String {
+    let mut out = String::new();
+    for server in servers {
+        for tool in &server.tools {
+            let description = if tool.description.is_empty() { "no description" } else { &tool.description };
+            out.push_str(&format!(
+                "- {0}.{1} - {2} Arguments (JSON Schema): {3}. Example: <use_tool name=\"{0}.{1}\" params={{...}} />\n",
+                server.name, tool.name, description, tool.input_schema
+            ));
+        }
+    }
+    out
+}
@@ -0,0 +1,129 @@
+//! The trash under the log directory, shared by the CLI and the TUI (each
+//! crate includes this file as its `trash` module).
+//!
+//! An entry is named `<original>.<secs>`, or `<original>.<secs>-<n>` when
+//! something of the same name was trashed in the same second.
+
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A free path in `trash_dir` for `name`, stamped with the current time.
+/// Creates the trash when it is missing.
+pub fn trash_target(trash_dir: &Path, name: &str) -> Result<PathBuf, Box<dyn Error>> {
+    fs::create_dir_all(trash_dir)?;
+    let ts = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut target = trash_dir.join(format!("{name}.{ts}"));
+    // `fs::rename` replaces what is there, so never hand out a taken name.
+    let mut n = 0;
+    while target.symlink_metadata().is_ok() {
+        n += 1;
+        target = trash_dir.join(format!("{name}.{ts}-{n}"));
+    }
+    Ok(target)
+}
+
+/// Move `path` into `trash_dir`; returns the new location. `trashed_name`
+/// reads the name back.
+pub fn move_to_trash(path: &Path, trash_dir: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let name = path
+        .file_name()
+        .ok_or("cannot trash a path without a file name")?
+        .to_string_lossy()
+        .into_owned();
+    let target = trash_target(trash_dir, &name)?;
+    fs::rename(path, &target)?;
+    Ok(target)
+}
+
+/// Split a trash entry name into the original name and when it was
+/// trashed (epoch seconds), undoing `trash_target`.
+pub fn trashed_name(name: &str) -> Option<(&str, u64)> {
+    let (original, stamp) = name.rsplit_once('.')?;
+    let secs = stamp.split_once('-').map_or(stamp, |(secs, _)| secs);
+    Some((original, secs.parse().ok()?))
+}
+
+/// Permanently delete the entries of `trash_dir` trashed more than `days`
+/// days ago. Returns how many were removed.
+pub fn purge(trash_dir: &Path, days: u64) -> usize {
+    let Ok(entries) = fs::read_dir(trash_dir) else {
+        return 0;
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let cutoff = now.saturating_sub(days.saturating_mul(86_400));
+    let mut purged = 0;
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // Entries without a readable timestamp were not put there by us.
+        let Some((_, at)) = trashed_name(&name) else {
+            continue;
+        };
+        if at == 0 || at >= cutoff {
+            continue;
+        }
+        let path = entry.path();
+        let removed = if path.is_dir() { fs::remove_dir_all(&path) } else { fs::remove_file(&path) };
+        if removed.is_ok() {
+            purged += 1;
+        }
+    }
+    purged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("trash-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn move_to_trash_never_replaces_an_entry() {
+        let dir = scratch_dir("collide");
+        let trash = dir.join(".trash");
+        let mut trashed = Vec::new();
+        for content in ["one", "two", "three"] {
+            let path = dir.join("notes.txt");
+            fs::write(&path, content).unwrap();
+            trashed.push(move_to_trash(&path, &trash).unwrap());
+            assert!(!path.exists());
+        }
+        // Usually all three land in the same second and get `-n` suffixes.
+        let contents: Vec<_> = trashed.iter().map(|p| fs::read_to_string(p).unwrap()).collect();
+        assert_eq!(contents, ["one", "two", "three"]);
+        for path in &trashed {
+            let name = path.file_name().unwrap().to_str().unwrap();
+            assert_eq!(trashed_name(name).map(|(original, _)| original), Some("notes.txt"));
+        }
+    }
+
+    #[test]
+    fn trashed_name_reads_both_name_forms() {
+        assert_eq!(trashed_name("a.json.1700000000"), Some(("a.json", 1_700_000_000)));
+        assert_eq!(trashed_name("a.json.1700000000-2"), Some(("a.json", 1_700_000_000)));
+        assert_eq!(trashed_name("README"), None);
+        assert_eq!(trashed_name("notes.txt"), None);
+    }
+
+    #[test]
+    fn purge_removes_only_old_entries() {
+        let dir = scratch_dir("purge");
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let old = format!("old.txt.{}", now - 10 * 86_400);
+        let fresh = format!("new.txt.{now}");
+        for name in [old.as_str(), fresh.as_str(), "stray.txt"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+
+        assert_eq!(purge(&dir, 7), 1);
+        assert!(!dir.join(&old).exists());
+        assert!(dir.join(&fresh).exists());
+        assert!(dir.join("stray.txt").exists());
+    }
+}